@@ -0,0 +1,34 @@
+// Rough dispatch throughput check: how many tiny jobs per second the pool can get through
+// cargo run --release --example throughput -- [threads] [jobs]
+// With --features crossbeam it measures the shared crossbeam queue as well
+use std::time::Instant;
+
+use webserver::{ThreadPool, ThreadPoolBuilder};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let threads = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(4);
+    let jobs: usize = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(1_000_000);
+    run("", ThreadPoolBuilder::new().num_threads(threads).build().unwrap(), jobs);
+    #[cfg(feature = "crossbeam")]
+    run("shared ", ThreadPoolBuilder::new().num_threads(threads).shared_queue(true).build().unwrap(), jobs);
+}
+
+fn run(backend: &str, pool: ThreadPool, jobs: usize) {
+    let start = Instant::now();
+    for _ in 0..jobs {
+        drop(pool.execute(|| {}));
+    }
+    pool.wait_idle();
+    report(&format!("{}execute", backend), jobs, start);
+
+    let start = Instant::now();
+    drop(pool.execute_all((0..jobs).map(|_| || {})));
+    pool.wait_idle();
+    report(&format!("{}execute_all", backend), jobs, start);
+}
+
+fn report(name: &str, jobs: usize, start: Instant) {
+    let elapsed = start.elapsed();
+    println!("{:>19}: {} jobs in {:?} ({:.0} jobs/s)", name, jobs, elapsed, jobs as f64 / elapsed.as_secs_f64());
+}
@@ -0,0 +1,71 @@
+// Accept header content negotiation (RFC 9110 12.5.1)
+
+// The weight 'accept' gives 'mime', from the most specific range matching it: "text/html"
+// over "text/*" over "*/*". 0 means the client doesn't want it at all, and a header that
+// can't be parsed counts as no header, wanting anything at 1
+pub(crate) fn quality(accept: &str, mime: &str) -> f32 {
+    let Some((kind, subtype)) = mime.split_once('/') else {
+        return 0.0;
+    };
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let Some((range_kind, range_subtype)) = params.next().unwrap_or_default().trim().split_once('/') else {
+            continue;
+        };
+        let specificity = match (range_kind, range_subtype) {
+            ("*", "*") => 0,
+            (k, "*") if k.eq_ignore_ascii_case(kind) => 1,
+            (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => 2,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .map_or(1.0, |q| q.clamp(0.0, 1.0));
+        if best.is_none_or(|(seen, _)| specificity > seen) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+// Of the types the server can send, the one 'accept' rates highest, the earliest offered on a
+// tie. None if it wants none of them
+pub(crate) fn preferred<'a>(accept: &str, offered: &[&'a str]) -> Option<&'a str> {
+    let mut best: Option<(&str, f32)> = None;
+    for mime in offered {
+        let q = quality(accept, mime);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((mime, q));
+        }
+    }
+    best.map(|(mime, _)| mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality() {
+        let accept = "text/html, application/xhtml+xml, application/xml;q=0.9, */*;q=0.8";
+        assert_eq!(quality(accept, "text/html"), 1.0);
+        assert_eq!(quality(accept, "application/xml"), 0.9);
+        assert_eq!(quality(accept, "application/json"), 0.8);
+        assert_eq!(quality("text/*;q=0.5, text/plain;q=0", "text/plain"), 0.0);
+        assert_eq!(quality("text/*;q=0.5, text/plain;q=0", "text/css"), 0.5);
+        assert_eq!(quality("TEXT/HTML", "text/html"), 1.0);
+        assert_eq!(quality("image/png", "text/html"), 0.0);
+    }
+
+    #[test]
+    fn test_preferred() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert_eq!(preferred(browser, &["application/json", "text/html"]), Some("text/html"));
+        assert_eq!(preferred("application/json", &["text/html", "application/json"]), Some("application/json"));
+        assert_eq!(preferred("*/*", &["text/html", "application/json"]), Some("text/html"));
+        assert_eq!(preferred("image/*", &["text/html", "application/json"]), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::Duration,
+};
+
+use crate::{logging::log, socket::{self, ListenOptions}, Server, ShutdownHandle};
+
+/// Called for the admin socket's reload-config command, says what changed or why it couldn't reload
+pub type ReloadHook = Arc<dyn Fn() -> Result<String, String> + Send + Sync>;
+
+// Operators type these by hand, anything longer is not a command
+const MAX_COMMAND_LEN: u64 = 256;
+// A client that connects and says nothing mustn't hold the admin socket up for long
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The admin socket, only ever on a loopback address since nothing on it is authenticated
+pub(crate) fn bind(addr: &str) -> io::Result<TcpListener> {
+    let listener = socket::bind(addr, ListenOptions::default())?;
+    if !listener.local_addr()?.ip().is_loopback() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the admin socket has to be on a loopback address"));
+    }
+    Ok(listener)
+}
+
+impl Server {
+    /// Where the admin socket is listening, if there is one
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    // Answer one command per connection until shutdown, commands are:
+    //   stats          pool and connection counts
+    //   reload-config  run the reload hook
+    //   upgrade        start the binary on disk again on our sockets, then drain and exit
+    //   shutdown       same as a ShutdownHandle
+    pub(crate) fn admin_loop(&self, listener: &TcpListener, accepted: &AtomicUsize, shutdown: &io::Result<ShutdownHandle>) {
+        if let Ok(addr) = listener.local_addr() {
+            log!(Info, "Admin socket on {}", addr);
+        }
+        loop {
+            let stream = listener.accept();
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok((stream, _)) => {
+                    let stopping = self.admin_command(stream, accepted, shutdown);
+                    if stopping {
+                        break;
+                    }
+                }
+                // the accept timeout, so a stop is noticed even if the wake-up went to a newer process
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => log!(Error, "Error accepting admin connection: {}", e),
+            }
+        }
+    }
+
+    // Read a command off 'stream' and answer it, true if it was shutdown
+    fn admin_command(&self, mut stream: TcpStream, accepted: &AtomicUsize, shutdown: &io::Result<ShutdownHandle>) -> bool {
+        let mut line = String::new();
+        let read = stream
+            .set_read_timeout(Some(COMMAND_TIMEOUT))
+            .and_then(|()| BufReader::new((&stream).take(MAX_COMMAND_LEN)).read_line(&mut line));
+        if let Err(e) = read {
+            log!(Warn, "Failed to read admin command: {}", e);
+            return false;
+        }
+        // a shutdown's wake-up connection, maybe from the process we took over from
+        if line.is_empty() {
+            return false;
+        }
+        let command = line.trim();
+        log!(Info, "Admin command: {}", command);
+        let (reply, stopping) = match command {
+            "stats" => {
+                let stats = self.pool.stats();
+                let reply = format!(
+                    "ok queued={} in_flight={} completed={} panicked={} workers={} open_connections={} accepted={}",
+                    stats.queued,
+                    stats.in_flight,
+                    stats.completed,
+                    stats.panicked,
+                    stats.workers.len(),
+                    self.live.load(Ordering::SeqCst),
+                    accepted.load(Ordering::SeqCst)
+                );
+                (reply, false)
+            }
+            "reload-config" => match &self.reload {
+                Some(reload) => match reload() {
+                    Ok(message) => (format!("ok {}", message), false),
+                    Err(e) => (format!("error {}", e), false),
+                },
+                None => ("error this server has nothing to reload".to_string(), false),
+            },
+            "upgrade" => self.upgrade_command(),
+            "shutdown" => ("ok shutting down".to_string(), true),
+            other => (format!("error unknown command {:?}, try stats, reload-config, upgrade or shutdown", other), false),
+        };
+        if let Err(e) = writeln!(stream, "{}", reply) {
+            log!(Warn, "Failed to answer admin command: {}", e);
+        }
+        if stopping {
+            match shutdown {
+                Ok(shutdown) => shutdown.shutdown(),
+                Err(_) => self.stop.store(true, Ordering::SeqCst),
+            }
+        }
+        stopping
+    }
+
+    #[cfg(target_os = "linux")]
+    fn upgrade_command(&self) -> (String, bool) {
+        match self.upgrade() {
+            Ok(pid) => (format!("ok pid {} took over, draining", pid), true),
+            Err(e) => (format!("error {}", e), false),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn upgrade_command(&self) -> (String, bool) {
+        ("error upgrades are only supported on Linux".to_string(), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, thread};
+
+    fn command(addr: SocketAddr, command: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(format!("{}\n", command).as_bytes()).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply.trim_end().to_string()
+    }
+
+    #[test]
+    fn test_admin_commands() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .admin_addr("127.0.0.1:0")
+            .on_reload(|| Ok("log level is now debug".to_string()))
+            .build()
+            .unwrap();
+        let admin = server.admin_addr().unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve(|mut stream| stream.write_all(b"hi").unwrap()));
+
+        TcpStream::connect(addr).unwrap().read_to_string(&mut String::new()).unwrap();
+        let stats = command(admin, "stats");
+        assert!(stats.starts_with("ok queued=0 "), "{}", stats);
+        assert!(stats.ends_with(" accepted=1"), "{}", stats);
+        assert_eq!(command(admin, "reload-config"), "ok log level is now debug");
+        assert!(command(admin, "restart").starts_with("error unknown command"));
+        assert_eq!(command(admin, "shutdown"), "ok shutting down");
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_admin_is_loopback_only() {
+        assert_eq!(bind("0.0.0.0:0").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(Server::builder().addr("127.0.0.1:0").admin_addr("0.0.0.0:0").build().is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::{io, os::raw::c_int};
+
+// Only Linux for now, other platforms just don't get the builder option
+// sched_setaffinity comes from libc which std already links, so no extra crate is needed
+
+// Matches glibc's cpu_set_t, room for 1024 cores
+const CPU_SETSIZE: usize = 1024;
+const WORD_BITS: usize = u64::BITS as usize;
+
+#[repr(C)]
+struct CpuSet {
+    bits: [u64; CPU_SETSIZE / WORD_BITS],
+}
+
+unsafe extern "C" {
+    fn sched_setaffinity(pid: c_int, cpusetsize: usize, mask: *const CpuSet) -> c_int;
+    #[cfg(test)]
+    fn sched_getaffinity(pid: c_int, cpusetsize: usize, mask: *mut CpuSet) -> c_int;
+}
+
+/// Pin the calling thread to 'core'
+pub(crate) fn pin_current_thread(core: usize) -> io::Result<()> {
+    if core >= CPU_SETSIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("core {} is out of range", core)));
+    }
+    let mut set = CpuSet { bits: [0; CPU_SETSIZE / WORD_BITS] };
+    set.bits[core / WORD_BITS] |= 1 << (core % WORD_BITS);
+    // SAFETY: set is a properly sized cpu_set_t, pid 0 means the calling thread
+    if unsafe { sched_setaffinity(0, size_of::<CpuSet>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The cores the calling thread is allowed to run on
+#[cfg(test)]
+fn current_cores() -> io::Result<Vec<usize>> {
+    let mut set = CpuSet { bits: [0; CPU_SETSIZE / WORD_BITS] };
+    // SAFETY: set is a properly sized cpu_set_t for the kernel to fill in
+    if unsafe { sched_getaffinity(0, size_of::<CpuSet>(), &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..CPU_SETSIZE).filter(|core| set.bits[core / WORD_BITS] & (1 << (core % WORD_BITS)) != 0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPoolBuilder;
+
+    #[test]
+    fn test_workers_are_pinned() {
+        // pin to whichever core this test is already allowed on, CI boxes may hide some
+        let core = current_cores().unwrap()[0];
+        let pool = ThreadPoolBuilder::new().num_threads(2).pin_to_cores([core]).build().unwrap();
+        let cores = pool.execute(current_cores).join().unwrap().unwrap();
+        assert_eq!(cores, [core]);
+    }
+
+    #[test]
+    fn test_pin_out_of_range() {
+        let err = pin_current_thread(CPU_SETSIZE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
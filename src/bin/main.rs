@@ -1,92 +1,69 @@
-use std::net::TcpListener; 
-use std::net::TcpStream;
-use std::io::prelude::*; // To read the tcp stream
-use std::fs;             // To access fs to fetch index.html
-use std::thread;         // thread::sleep
-use std::time::Duration; // Duration::from_secs(5)
-
-use webserver::ThreadPool; 
+use webserver::{set_log_level, ArgsError, Config};
 
 fn main() {
-    // 7878 spells out rust on a phone
-    let ip_port: String = "127.0.0.1:7878".to_string();
-    let pool = ThreadPool::new(4); // Use thread pool so we dont have infinite
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match Config::from_args(args.iter().cloned()) {
+        Ok(config) => config,
+        Err(ArgsError::Help) => {
+            println!("{}", webserver::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, webserver::USAGE);
+            std::process::exit(2);
+        }
+    };
+    set_log_level(config.log_level);
 
-    // Listen for connections
-    let listener = match TcpListener::bind(&ip_port) {
-        Ok(listener) => listener, 
+    // Listen for connections, each one is handled on the server's thread pool
+    let running_config = config.clone();
+    let builder = config.server_builder().on_reload(move || reload(&running_config, &args));
+    let server = match builder.build() {
+        Ok(server) => server,
         Err(e) => {
-            eprintln!("Failed to bind to {0}: {1}", ip_port, e);
+            eprintln!("Failed to start on {}: {}", config.describe_listeners(), e);
             std::process::exit(1);
         }
     };
+    // Ctrl-C lets in-flight requests finish instead of cutting them off
+    #[cfg(unix)]
+    if let Err(e) = server.shutdown_on_signals() {
+        eprintln!("Failed to install signal handlers, Ctrl-C will stop the server abruptly: {}", e);
+    }
 
-    // wait for messages which will either be a tcp stream or an error
-    for stream in listener.incoming().take(2) {
-        // NOTE: we call unwrap a bit in this code because it panics if it errors
-        match stream {
-            Ok(stream) => {
-                // when we execute the pool, we do have a thread max
-                pool.execute(|| { handler(stream); });
-            }
+    // Or accept on a tokio runtime, with idle connections waiting there rather than on workers
+    #[cfg(feature = "tokio")]
+    if config.tokio {
+        match tokio::runtime::Runtime::new() {
+            Ok(runtime) => runtime.block_on(server.serve_static_async()),
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                eprintln!("Failed to start the tokio runtime: {}", e);
+                std::process::exit(1);
             }
         }
-    }
-
-    println!("Shutting Down");
-}
-
-// This will handle /read the data from the tcp stream 
-fn handler(mut stream: TcpStream) {
-    // NOTE: b gives us a byte array of the string
-    let get: &[u8; 16] = b"GET / HTTP/1.1\r\n";
-    let sleep: &[u8; 21] = b"GET /sleep HTTP/1.1\r\n"; // if a req takes too long, we go here
-
-    // TODO: change 1024 to an arbitrary size
-    let mut buffer: [u8; 1024] = [0; 1024];
-    if let Err(e) = stream.read(&mut buffer) {
-        eprintln!("Failed to read from stream: {}", e);
         return;
     }
-    //println!(
-    //    "Request: {}",
-    //    String::from_utf8_lossy(&buffer[..])
-    //);
-    // Now check if buffer starts with the expected byte array AKA the GET call
-    let (status_line, filename) = 
-    if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK", "static/index.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK", "static/index.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND", "static/404.html")
-    };
 
-    let contents = match fs::read_to_string(filename) {
-        Ok(contents) => contents,
+    // Accept on a thread of its own, anything holding its shutdown handle can stop it
+    let running = match server.spawn_static() {
+        Ok(running) => running,
         Err(e) => {
-            eprintln!("Failed to read {}: {}", filename, e);
-            let error_response = "HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\nServer Error";
-            stream.write(error_response.as_bytes()).unwrap_or_else(|e| {
-                eprintln!("Failed to write error response: {}", e);
-                0 // Return 0 to indicate no bytes were written
-            });
-            return;
+            eprintln!("Failed to start the accept thread: {}", e);
+            std::process::exit(1);
         }
     };
-
-    let response = format!(
-        "{}\r\nContent-Length: {}\r\n\r\n{}",
-        status_line, contents.len(), contents
-    );
-
-    if let Err(e) = stream.write(response.as_bytes()) {
-        eprintln!("Failed to write response: {}", e);
-    }
-    if let Err(e) = stream.flush() {
-        eprintln!("Failed to flush stream: {}", e);
+    if running.join().is_err() {
+        eprintln!("The accept thread panicked");
+        std::process::exit(1);
     }
 }
+
+// Read the config file and flags again for the admin socket's reload-config
+// Only the log level can change while running, the rest waits for a restart
+fn reload(running: &Config, args: &[String]) -> Result<String, String> {
+    let config = Config::from_args(args.iter().cloned()).map_err(|e| e.to_string())?;
+    set_log_level(config.log_level);
+    let unchanged = Config { log_level: running.log_level, ..config.clone() } == *running;
+    let restart = if unchanged { "" } else { ", other changes need a restart" };
+    Ok(format!("log level is {}{}", config.log_level, restart))
+}
@@ -0,0 +1,407 @@
+use std::{
+    any::Any,
+    io,
+    sync::{atomic::{AtomicBool, AtomicUsize}, Arc, Condvar, Mutex, OnceLock, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    scheduler::Scheduler,
+    stats::{PoolCounters, Timings},
+    watchdog::{default_timeout_hook, JobTimeout, TimeoutHook, Watched, Watchdog},
+    NoopObserver, PanicHook, PoolObserver, SaturationPolicy, Shared, StateFactory, ThreadPool, Worker, WorkerExitPolicy,
+};
+
+/// Called with the worker id on the worker's own thread
+pub type ThreadHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Configures a ThreadPool before any of its threads are spawned
+///
+/// ```
+/// let pool = webserver::ThreadPoolBuilder::new()
+///     .num_threads(8)
+///     .thread_name("web-worker")
+///     .stack_size(256 * 1024)
+///     .build()
+///     .unwrap();
+/// assert_eq!(pool.size(), 8);
+/// ```
+pub struct ThreadPoolBuilder {
+    num_threads: usize,
+    max_threads: Option<usize>,
+    idle_timeout: Option<Duration>,
+    queue_len: Option<usize>,
+    panic_hook: Option<PanicHook>,
+    observer: Arc<dyn PoolObserver>,
+    thread: ThreadConfig,
+    job_timeout: Option<Duration>,
+    timeout_hook: TimeoutHook,
+    replace_timed_out: bool,
+    saturation: SaturationPolicy,
+    record_timings: bool,
+    exit_policy: WorkerExitPolicy,
+    #[cfg(feature = "crossbeam")]
+    shared_queue: bool,
+}
+
+// How each worker thread gets spawned, kept around so respawned and added workers match the originals
+#[derive(Default)]
+pub(crate) struct ThreadConfig {
+    pub(crate) name_prefix: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) on_start: Option<ThreadHook>,
+    pub(crate) on_stop: Option<ThreadHook>,
+    pub(crate) state_factory: Option<StateFactory>,
+    // worker id i is pinned to cores[i % cores.len()], empty means leave scheduling to the OS
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    pub(crate) cores: Vec<usize>,
+}
+
+impl ThreadConfig {
+    pub(crate) fn thread_builder(&self, id: usize) -> std::thread::Builder {
+        let mut builder = std::thread::Builder::new();
+        if let Some(prefix) = &self.name_prefix {
+            builder = builder.name(format!("{}-{}", prefix, id));
+        }
+        if let Some(stack_size) = self.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        builder
+    }
+
+    // Called on the worker thread itself, gives back the core it couldn't pin to
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    pub(crate) fn pin_current(&self, id: usize) -> Result<(), (usize, io::Error)> {
+        if self.cores.is_empty() {
+            return Ok(());
+        }
+        let core = self.cores[id % self.cores.len()];
+        crate::affinity::pin_current_thread(core).map_err(|e| (core, e))
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        ThreadPoolBuilder::new()
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Start from the same defaults as ThreadPool::new(4)
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            num_threads: 4,
+            max_threads: None,
+            idle_timeout: None,
+            queue_len: None,
+            panic_hook: None,
+            observer: Arc::new(NoopObserver),
+            thread: ThreadConfig::default(),
+            job_timeout: None,
+            timeout_hook: Arc::new(default_timeout_hook),
+            replace_timed_out: false,
+            saturation: SaturationPolicy::Block,
+            record_timings: false,
+            exit_policy: WorkerExitPolicy::Respawn,
+            #[cfg(feature = "crossbeam")]
+            shared_queue: false,
+        }
+    }
+
+    /// Number of worker threads to start with
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Let the pool spawn extra workers on demand, up to 'max_threads' in total
+    /// num_threads becomes the floor the pool never shrinks below on its own
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// Workers above num_threads exit after sitting idle this long
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Cap the job queue at 'queue_len', see ThreadPool::with_capacity
+    pub fn queue_len(mut self, queue_len: usize) -> Self {
+        self.queue_len = Some(queue_len);
+        self
+    }
+
+    /// What 'execute' does once the queue set by queue_len is full, blocking by default
+    pub fn saturation_policy(mut self, policy: SaturationPolicy) -> Self {
+        self.saturation = policy;
+        self
+    }
+
+    /// What to do when a worker's thread ends unexpectedly, respawning it by default
+    pub fn worker_exit_policy(mut self, policy: WorkerExitPolicy) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+
+    /// Name worker threads "{prefix}-{id}", e.g. "web-worker-3"
+    pub fn thread_name(mut self, prefix: impl Into<String>) -> Self {
+        self.thread.name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Stack size in bytes for every worker thread
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.thread.stack_size = Some(stack_size);
+        self
+    }
+
+    /// Run 'hook' on each worker thread right after it starts
+    pub fn on_thread_start<H>(mut self, hook: H) -> Self
+    where H: Fn(usize) + Send + Sync + 'static
+    {
+        self.thread.on_start = Some(Arc::new(hook));
+        self
+    }
+
+    /// Run 'hook' on each worker thread just before it exits
+    pub fn on_thread_stop<H>(mut self, hook: H) -> Self
+    where H: Fn(usize) + Send + Sync + 'static
+    {
+        self.thread.on_stop = Some(Arc::new(hook));
+        self
+    }
+
+    /// Give every worker its own state, built by 'factory' on the worker thread when it starts
+    /// (e.g. a reusable request buffer), see ThreadPool::execute_with_state
+    pub fn worker_state<S, F>(mut self, factory: F) -> Self
+    where
+        S: 'static,
+        F: Fn(usize) -> S + Send + Sync + 'static,
+    {
+        self.thread.state_factory = Some(Arc::new(move |id| Box::new(factory(id))));
+        self
+    }
+
+    /// Pin each worker to a core, worker 'i' goes to the i-th core listed, wrapping around
+    /// when there are more workers than cores. Only with the "affinity" feature on Linux
+    #[cfg(all(feature = "affinity", target_os = "linux"))]
+    pub fn pin_to_cores(mut self, cores: impl IntoIterator<Item = usize>) -> Self {
+        self.thread.cores = cores.into_iter().collect();
+        self
+    }
+
+    /// Hand jobs out through crossbeam channels all the workers receive from, instead of
+    /// spreading them over per-worker queues and stealing. Only with the "crossbeam" feature;
+    /// which is faster depends on the hardware and the jobs, examples/throughput.rs compares them
+    #[cfg(feature = "crossbeam")]
+    pub fn shared_queue(mut self, shared: bool) -> Self {
+        self.shared_queue = shared;
+        self
+    }
+
+    /// Called instead of the observer's on_message when a job panics
+    pub fn panic_hook<H>(mut self, hook: H) -> Self
+    where H: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static
+    {
+        self.panic_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Hear about jobs starting, finishing and panicking and workers exiting
+    /// The default observer stays quiet, LogObserver prints to stdout
+    pub fn observer(mut self, observer: impl PoolObserver + 'static) -> Self {
+        self.observer = Arc::new(observer);
+        self
+    }
+
+    /// Report jobs that run longer than 'limit', by default to the log as warnings, see on_job_timeout
+    /// The job itself keeps running, a thread can't be stopped from the outside
+    pub fn job_timeout(mut self, limit: Duration) -> Self {
+        self.job_timeout = Some(limit);
+        self
+    }
+
+    /// Called from the pool's watchdog thread, once per job, when a job passes the job timeout
+    pub fn on_job_timeout<H>(mut self, hook: H) -> Self
+    where H: Fn(usize, Duration) + Send + Sync + 'static
+    {
+        self.timeout_hook = Arc::new(hook);
+        self
+    }
+
+    /// Stop handing work to a worker whose job timed out and spawn a new one in its place
+    /// on the next submit, the old thread exits once its job finally returns
+    pub fn replace_timed_out_workers(mut self, replace: bool) -> Self {
+        self.replace_timed_out = replace;
+        self
+    }
+
+    /// Keep queue wait and run times of recent jobs for ThreadPool::timings
+    /// Off by default, it costs two clock reads and two short locks per job
+    pub fn record_timings(mut self, record: bool) -> Self {
+        self.record_timings = record;
+        self
+    }
+
+    /// Spawn the workers and hand back the pool
+    ///
+    /// # Errors
+    /// Returns the OS error if a worker thread couldn't be spawned
+    ///
+    /// # Panics
+    /// Panics if num_threads or queue_len is 0, or max_threads is below num_threads
+    pub fn build(self) -> io::Result<ThreadPool> {
+        let size = self.num_threads;
+        assert!(size > 0); // we need to have more than size of 0
+        let max_size = self.max_threads.unwrap_or(size);
+        assert!(max_size >= size, "max_threads must be at least num_threads");
+        if let Some(queue_len) = self.queue_len {
+            assert!(queue_len > 0, "queue_len must be greater than 0");
+        }
+
+        // set up the per-worker queues, workers register with the scheduler as they are spawned
+        #[cfg(feature = "crossbeam")]
+        let scheduler = if self.shared_queue { Scheduler::with_shared_queue() } else { Scheduler::new() };
+        #[cfg(not(feature = "crossbeam"))]
+        let scheduler = Scheduler::new();
+        let shared = Arc::new(Shared {
+            scheduler,
+            panic_hook: self.panic_hook,
+            panic_handler: RwLock::new(None),
+            error_handler: RwLock::new(None),
+            observer: self.observer,
+            queued: AtomicUsize::new(0),
+            capacity: self.queue_len,
+            slot_lock: Mutex::new(()),
+            slot_freed: Condvar::new(),
+            thread: self.thread,
+            closed: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            saturation: self.saturation,
+            exit_policy: self.exit_policy,
+            counters: PoolCounters::default(),
+            live: AtomicUsize::new(0),
+            min_size: AtomicUsize::new(size),
+            // without room to grow there is nothing to shrink back from
+            idle_timeout: self.idle_timeout.filter(|_| max_size > size),
+            idle_lock: Mutex::new(()),
+            idle: Condvar::new(),
+            job_timeout: self.job_timeout.map(|limit| JobTimeout {
+                limit,
+                hook: self.timeout_hook,
+                replace: self.replace_timed_out,
+            }),
+            watched: Watched::default(),
+            timings: self.record_timings.then(Timings::default),
+        });
+
+        let mut workers = Vec::with_capacity(size);
+
+        for id in 0..size {
+            workers.push(Worker::new(id, Arc::clone(&shared))?);
+        }
+        let watchdog = match shared.job_timeout {
+            Some(_) => Some(Watchdog::start(Arc::clone(&shared))?),
+            None => None,
+        };
+        Ok(ThreadPool {
+            workers: Mutex::new(workers),
+            shared,
+            max_size: AtomicUsize::new(max_size),
+            next_id: AtomicUsize::new(size),
+            timer: OnceLock::new(),
+            watchdog,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    #[test]
+    fn test_builder_names_threads() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name("web-worker")
+            .build()
+            .unwrap();
+        let name = pool.execute(|| thread::current().name().map(String::from)).join();
+        assert_eq!(name, Ok(Some("web-worker-0".to_string())));
+    }
+
+    #[cfg(feature = "crossbeam")]
+    #[test]
+    fn test_shared_queue_pool() {
+        let pool = ThreadPoolBuilder::new().num_threads(4).shared_queue(true).build().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let handles = pool.execute_all((0..1000).map(|_| {
+            let ran = Arc::clone(&ran);
+            move || ran.fetch_add(1, Ordering::SeqCst)
+        }));
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(ran.load(Ordering::SeqCst), 1000);
+        pool.resize(2);
+        assert_eq!(pool.execute(|| 42).join(), Ok(42));
+        drop(pool);
+    }
+
+    #[test]
+    fn test_builder_start_stop_hooks() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (started_clone, stopped_clone) = (Arc::clone(&started), Arc::clone(&stopped));
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(3)
+            .stack_size(128 * 1024)
+            .on_thread_start(move |_| { started_clone.fetch_add(1, Ordering::SeqCst); })
+            .on_thread_stop(move |_| { stopped_clone.fetch_add(1, Ordering::SeqCst); })
+            .build()
+            .unwrap();
+        // a round trip through the pool doesn't guarantee every worker has started, so drop first
+        drop(pool);
+
+        assert_eq!(started.load(Ordering::SeqCst), 3);
+        assert_eq!(stopped.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_builder_elastic_pool() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .max_threads(3)
+            .idle_timeout(Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+
+        // three jobs that all wait on us force the pool up to its max
+        let handles: Vec<_> = (0..3)
+            .map(|_| {
+                let blocked = Arc::clone(&blocked);
+                pool.execute(move || { blocked.lock().unwrap().recv().unwrap(); })
+            })
+            .collect();
+        assert_eq!(pool.size(), 3);
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // and once they sit idle the extras go away again
+        while pool.size() > 1 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(pool.execute(|| 1).join(), Ok(1));
+    }
+}
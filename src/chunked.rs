@@ -0,0 +1,239 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::{
+    handler::read_body,
+    headers::{is_token, is_valid_value},
+    HeaderMap,
+};
+
+// Longest chunk size line or trailer line we'll buffer looking for its end
+const MAX_LINE: usize = 4096;
+
+// Most trailer fields we'll take after a chunked body
+const MAX_TRAILERS: usize = 100;
+
+/// Writes a response body in chunked transfer encoding, from Response::write_chunked
+///
+/// Every write goes out as one chunk, so wrap it in a BufWriter when writing lots of small
+/// pieces. finish() ends the body, dropping the writer without calling it ends the body too
+/// but loses any error
+///
+/// ```
+/// use std::io::Write;
+///
+/// let mut written = Vec::new();
+/// let mut body = webserver::Response::new(webserver::StatusCode::Ok).write_chunked(&mut written).unwrap();
+/// body.write_all(b"hello, ").unwrap();
+/// body.write_all(b"world").unwrap();
+/// body.finish().unwrap();
+/// assert!(written.ends_with(b"Transfer-Encoding: chunked\r\n\r\n7\r\nhello, \r\n5\r\nworld\r\n0\r\n\r\n"));
+/// ```
+pub struct ChunkedWriter<W: Write> {
+    stream: W,
+    finished: bool,
+    // the fields the response's Trailer header said would follow the body, lowercased
+    declared: Vec<String>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub(crate) fn new(stream: W, declared: Vec<String>) -> ChunkedWriter<W> {
+        ChunkedWriter { stream, finished: false, declared }
+    }
+
+    /// Write the last, empty, chunk that tells the client the body is complete
+    ///
+    /// # Errors
+    /// Returns the error writing to the stream
+    pub fn finish(self) -> io::Result<()> {
+        self.finish_with_trailers(&HeaderMap::new())
+    }
+
+    /// End the body like finish, then send 'trailers', fields worked out while the body was
+    /// written, like a checksum of it. Each one has to be named in the response's Trailer
+    /// header, so the client knows to expect it
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use webserver::{HeaderMap, Response, StatusCode};
+    ///
+    /// let mut written = Vec::new();
+    /// let response = Response::new(StatusCode::Ok).with_header("Trailer", "X-Length");
+    /// let mut body = response.write_chunked(&mut written).unwrap();
+    /// body.write_all(b"hello").unwrap();
+    /// body.finish_with_trailers(&[("X-Length", "5")].into_iter().collect()).unwrap();
+    /// assert!(written.ends_with(b"5\r\nhello\r\n0\r\nX-Length: 5\r\n\r\n"));
+    /// ```
+    ///
+    /// # Errors
+    /// Returns the error writing to the stream. A trailer that wasn't declared, or that isn't
+    /// fit to send as a header, is an InvalidInput error, and the body is ended without any
+    pub fn finish_with_trailers(mut self, trailers: &HeaderMap) -> io::Result<()> {
+        let mut end = b"0\r\n".to_vec();
+        for (name, value) in trailers {
+            if !self.declared.iter().any(|declared| declared.eq_ignore_ascii_case(name)) {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("trailer {:?} isn't in the Trailer header", name)));
+            }
+            if !is_token(name) || !is_valid_value(value) {
+                return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid trailer {:?}", name)));
+            }
+            end.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        end.extend_from_slice(b"\r\n");
+        self.finished = true;
+        self.stream.write_all(&end)?;
+        self.stream.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut chunk = format!("{:x}\r\n", buf.len()).into_bytes();
+        chunk.extend_from_slice(buf);
+        chunk.extend_from_slice(b"\r\n");
+        self.stream.write_all(&chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<W: Write> Drop for ChunkedWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.stream.write_all(b"0\r\n\r\n").and_then(|()| self.stream.flush());
+        }
+    }
+}
+
+// Decode a chunked request body (RFC 9112 7.1) off the front of 'pending', reading the rest
+// from 'stream', and the trailer fields after it. Anything read past the end stays in
+// 'pending' for the next request
+// InvalidData means a malformed chunk or trailer (or more than MAX_TRAILERS of them),
+// FileTooLarge that the chunks add up to more than 'max_len'. Chunk extensions are thrown away
+pub(crate) fn read_chunked(stream: &mut impl Read, pending: &mut Vec<u8>, max_len: u64) -> io::Result<(Vec<u8>, HeaderMap)> {
+    let invalid = |what: &str| io::Error::new(ErrorKind::InvalidData, what.to_string());
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream, pending)?;
+        // "1a;name=value", the size in hex and optional extensions
+        let size = line.split(|b| *b == b';').next().unwrap_or_default().trim_ascii_end();
+        if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(invalid("invalid chunk size"));
+        }
+        // at most 16 hex digits always fits
+        let size = size.iter().fold(0, |total, digit| total << 4 | u64::from((*digit as char).to_digit(16).unwrap_or(0)));
+        if size == 0 {
+            break;
+        }
+        if size > max_len - body.len() as u64 {
+            return Err(io::Error::new(ErrorKind::FileTooLarge, "chunked body too large"));
+        }
+        body.extend_from_slice(&read_body(stream, pending, size)?);
+        if read_body(stream, pending, 2)? != b"\r\n" {
+            return Err(invalid("chunk data doesn't end with CRLF"));
+        }
+    }
+    // the trailer section, header fields up to the blank line that ends the message
+    let mut trailers = HeaderMap::new();
+    loop {
+        let line = read_line(stream, pending)?;
+        if line.is_empty() {
+            break;
+        }
+        if trailers.len() == MAX_TRAILERS {
+            return Err(invalid("too many trailer fields"));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("invalid trailer field"))?;
+        if !is_token(name) || !is_valid_value(value) {
+            return Err(invalid("invalid trailer field"));
+        }
+        trailers.append(name, value.trim_matches([' ', '\t']));
+    }
+    Ok((body, trailers))
+}
+
+// Split a CRLF terminated line off the front of 'pending', without the CRLF, reading more as needed
+fn read_line(stream: &mut impl Read, pending: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut searched: usize = 0;
+    loop {
+        // only look at what's new, plus a byte in case the CRLF straddles two reads
+        let from = searched.saturating_sub(1);
+        if let Some(end) = pending[from..].windows(2).position(|w| w == b"\r\n") {
+            let end = from + end;
+            let line = pending[..end].to_vec();
+            pending.drain(..end + 2);
+            return Ok(line);
+        }
+        if pending.len() > MAX_LINE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "chunk line too long"));
+        }
+        searched = pending.len();
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-body"));
+        }
+        pending.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_chunked() {
+        // split between what's already buffered and what's still to be read, with an extension and a trailer
+        let mut pending = b"5\r\nhel".to_vec();
+        let mut rest = Cursor::new(b"lo\r\n6;ext=1\r\n, worl\r\n1\r\nd\r\n0\r\nX-Sum: 1\r\nX-More:  two \r\n\r\nGET / HTTP/1.1".to_vec());
+        let (body, trailers) = read_chunked(&mut rest, &mut pending, 100).unwrap();
+        assert_eq!(body, b"hello, world");
+        assert_eq!(trailers.iter().collect::<Vec<_>>(), [("X-Sum", "1"), ("X-More", "two")]);
+        assert_eq!(pending, b"GET / HTTP/1.1");
+
+        let decode = |body: &[u8], max| read_chunked(&mut Cursor::new(body.to_vec()), &mut Vec::new(), max).map(|(body, _)| body).map_err(|e| e.kind());
+        assert_eq!(decode(b"0\r\n\r\n", 0), Ok(Vec::new()));
+        assert_eq!(decode(b"A\r\n0123456789\r\n0\r\n\r\n", 10).unwrap().len(), 10);
+        assert_eq!(decode(b"B\r\n0123456789A\r\n0\r\n\r\n", 10), Err(ErrorKind::FileTooLarge));
+        assert_eq!(decode(b"zz\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"-1\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"ffffffffffffffffff\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"2\r\nabc\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"2\r\nab", 10), Err(ErrorKind::UnexpectedEof));
+        assert_eq!(decode(b"0\r\nno colon\r\n\r\n", 10), Err(ErrorKind::InvalidData));
+        assert_eq!(decode(b"0\r\nX Y: 1\r\n\r\n", 10), Err(ErrorKind::InvalidData));
+        let flood = format!("0\r\n{}\r\n", "X: 1\r\n".repeat(MAX_TRAILERS + 1));
+        assert_eq!(decode(flood.as_bytes(), 10), Err(ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut out = Vec::new();
+        {
+            let mut writer = ChunkedWriter::new(&mut out, Vec::new());
+            writer.write_all(&[b'a'; 26]).unwrap();
+            assert_eq!(writer.write(b"").unwrap(), 0);
+            // dropped without finish
+        }
+        let mut expected = b"1a\r\n".to_vec();
+        expected.extend_from_slice(&[b'a'; 26]);
+        expected.extend_from_slice(b"\r\n0\r\n\r\n");
+        assert_eq!(out, expected);
+
+        // an undeclared trailer still ends the body, without any
+        let mut out = Vec::new();
+        let writer = ChunkedWriter::new(&mut out, vec!["x-sum".to_string()]);
+        let trailers: HeaderMap = [("X-Sum", "1"), ("X-Other", "2")].into_iter().collect();
+        assert_eq!(writer.finish_with_trailers(&trailers).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(out, b"0\r\n\r\n");
+    }
+}
@@ -0,0 +1,353 @@
+// gzip response compression, std only: LZ77 matching and DEFLATE's fixed Huffman codes
+// (RFC 1951), in a gzip wrapper (RFC 1952). Fixed codes give up some ratio against a full
+// zlib, but text still shrinks to a third or so of its size
+
+use std::io::{self, Read};
+
+// LZ77 window and match limits DEFLATE allows
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier positions with the same hash we try, more is smaller output but slower
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+// how much input goes into each DEFLATE block, all a Gzip reader holds besides the window
+const BLOCK: usize = 64 * 1024;
+
+// Base values and extra bits for length codes 257..=285 and distance codes 0..=29
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385,
+    24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// What gets compressed unless configured otherwise: text, and the formats that are text underneath
+pub(crate) const DEFAULT_TYPES: [&str; 6] = ["text/*", "application/javascript", "application/json", "application/xml", "image/svg+xml", "application/wasm"];
+
+// Whether 'content_type' is on the 'types' allowlist, entries are exact types or "type/*"
+pub(crate) fn compressible(content_type: &str, types: &[String]) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+        Some(top) => mime.split_once('/').is_some_and(|(mime_top, _)| mime_top.eq_ignore_ascii_case(top)),
+        None => mime.eq_ignore_ascii_case(allowed),
+    })
+}
+
+// The ETag for the gzipped body, which is a different representation from the file itself
+// so can't share its tag: W/"1a-2b" becomes W/"1a-2b-gzip"
+pub(crate) fn gzip_etag(etag: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(opaque) => format!("{}-gzip\"", opaque),
+        None => etag.to_string(),
+    }
+}
+
+// Whether an Accept-Encoding header lets us send gzip: "gzip" or "*" without q=0
+pub(crate) fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut wildcard = None;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(q > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+// no file name or modification time, unknown OS
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// A gzip member of everything 'inner' reads, compressed a block at a time as it's read, so a
+/// big file costs no more memory than a small one and nothing has to wait for all of it
+pub(crate) struct Gzip<R> {
+    inner: R,
+    bits: BitWriter,
+    // the last WINDOW bytes of input, which the next block can still match against
+    history: Vec<u8>,
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    crc: u32,
+    len: u32,
+    out: Vec<u8>,
+    written: usize,
+    done: bool,
+}
+
+impl<R: Read> Gzip<R> {
+    pub(crate) fn new(inner: R) -> Gzip<R> {
+        Gzip {
+            inner,
+            bits: BitWriter { out: HEADER.to_vec(), ..BitWriter::default() },
+            history: Vec::new(),
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; WINDOW],
+            crc: 0,
+            len: 0,
+            out: Vec::new(),
+            written: 0,
+            done: false,
+        }
+    }
+
+    // Compress the next block of input into 'out', or end the member once there's no more
+    fn fill(&mut self) -> io::Result<()> {
+        let start = self.history.len();
+        let mut data = std::mem::take(&mut self.history);
+        (&mut self.inner).take(BLOCK as u64).read_to_end(&mut data)?;
+        if data.len() == start {
+            // an empty final block, since which block was the last is only known now
+            self.bits.write(1, 1);
+            self.bits.write(1, 2);
+            write_literal(&mut self.bits, 256);
+            let mut out = std::mem::take(&mut self.bits).finish();
+            out.extend_from_slice(&self.crc.to_le_bytes());
+            // the size is kept modulo 2^32, as gzip has it
+            out.extend_from_slice(&self.len.to_le_bytes());
+            self.out = out;
+            self.done = true;
+        } else {
+            self.crc = crc32(self.crc, &data[start..]);
+            self.len = self.len.wrapping_add((data.len() - start) as u32);
+            deflate_block(&mut self.bits, &data, start, &mut self.head, &mut self.prev);
+            self.out = std::mem::take(&mut self.bits.out);
+            self.history = data[data.len().saturating_sub(WINDOW)..].to_vec();
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Gzip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.written == self.out.len() && !self.done {
+            self.fill()?;
+        }
+        let n = buf.len().min(self.out.len() - self.written);
+        buf[..n].copy_from_slice(&self.out[self.written..self.written + n]);
+        self.written += n;
+        Ok(n)
+    }
+}
+
+// One DEFLATE block with the fixed Huffman codes for 'data[start..]', not the final one;
+// 'data[..start]' is input already sent, there to match against
+fn deflate_block(bits: &mut BitWriter, data: &[u8], start: usize, head: &mut [usize], prev: &mut [usize]) {
+    // BFINAL clear, then BTYPE 01
+    bits.write(0, 1);
+    bits.write(1, 2);
+
+    head.fill(usize::MAX);
+    prev.fill(usize::MAX);
+    let hash = |i: usize| {
+        let key = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+        (key.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    };
+    // chain position 'i' in under its hash, once there are three bytes to hash
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            prev[i % WINDOW] = head[h];
+            head[h] = i;
+        }
+    };
+    for i in 0..start {
+        insert(i, head, prev);
+    }
+
+    let mut i = start;
+    while i < data.len() {
+        let (length, distance) = longest_match(data, i, head, prev, &hash);
+        if length >= MIN_MATCH {
+            write_length(bits, length);
+            write_distance(bits, distance);
+            for j in i..i + length {
+                insert(j, head, prev);
+            }
+            i += length;
+        } else {
+            write_literal(bits, u16::from(data[i]));
+            insert(i, head, prev);
+            i += 1;
+        }
+    }
+    // end of block
+    write_literal(bits, 256);
+}
+
+// The longest earlier match for the bytes at 'i' within the window, as (length, distance)
+fn longest_match(data: &[u8], i: usize, head: &[usize], prev: &[usize], hash: &impl Fn(usize) -> usize) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max = MAX_MATCH.min(data.len() - i);
+    let (mut best_len, mut best_dist) = (0, 0);
+    let mut candidate = head[hash(i)];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || i - candidate > WINDOW - 1 || candidate >= i {
+            break;
+        }
+        let len = data[candidate..].iter().zip(&data[i..i + max]).take_while(|(a, b)| a == b).count();
+        if len > best_len {
+            (best_len, best_dist) = (len, i - candidate);
+            if len == max {
+                break;
+            }
+        }
+        let next = prev[candidate % WINDOW];
+        // the slot may since have been reused by a position a window further on
+        if next != usize::MAX && next >= candidate {
+            break;
+        }
+        candidate = next;
+    }
+    (best_len, best_dist)
+}
+
+// Literal/length symbols 0..=287 with the fixed code lengths of RFC 1951 3.2.6
+fn write_literal(bits: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+    bits.write_code(code, len);
+}
+
+fn write_length(bits: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE.iter().rposition(|base| usize::from(*base) <= length).unwrap_or(0);
+    write_literal(bits, 257 + index as u16);
+    bits.write((length - usize::from(LENGTH_BASE[index])) as u32, LENGTH_EXTRA[index]);
+}
+
+fn write_distance(bits: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE.iter().rposition(|base| usize::from(*base) <= distance).unwrap_or(0);
+    bits.write_code(index as u16, 5);
+    bits.write((distance - usize::from(DISTANCE_BASE[index])) as u32, DISTANCE_EXTRA[index]);
+}
+
+// Packs bits least significant first, as DEFLATE wants everything but Huffman codes
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u8) {
+        for bit in 0..count {
+            self.buffer |= ((value >> bit) & 1) << self.count;
+            self.count += 1;
+            if self.count == 8 {
+                self.out.push(self.buffer as u8);
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn write_code(&mut self, code: u16, len: u8) {
+        let reversed = (u32::from(code).reverse_bits()) >> (32 - u32::from(len));
+        self.write(reversed, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+// CRC-32 as gzip uses it, the reflected 0xedb88320 polynomial, carried on from the 'crc'
+// of the data before (0 to start)
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"12345"), b"6789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_compressible() {
+        let types: Vec<String> = DEFAULT_TYPES.iter().map(|t| t.to_string()).collect();
+        assert!(compressible("text/html; charset=utf-8", &types));
+        assert!(compressible("image/svg+xml", &types));
+        assert!(!compressible("image/png", &types));
+        assert!(!compressible("textual/plain", &types));
+        assert!(!compressible("text/css", &[]));
+        assert_eq!(gzip_etag("W/\"1a-2b\""), "W/\"1a-2b-gzip\"");
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        Gzip::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_gzip() {
+        assert_eq!(gzip(b""), [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(gunzip(&gzip(b"")), b"");
+        let text = "<p>The quick brown fox jumps over the lazy dog</p>\n".repeat(200);
+        let compressed = gzip(text.as_bytes());
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(gunzip(&compressed), text.as_bytes());
+
+        // past a block, with matches reaching back into the one before
+        let mut seed = 1u32;
+        let mut noise = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        let mut data: Vec<u8> = (0..BLOCK * 2 + 1000).map(|_| noise() % 16 + b'a').collect();
+        data.extend_from_within(BLOCK * 2 - 5000..BLOCK * 2 + 1000);
+        let compressed = gzip(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(gunzip(&compressed), data);
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip("gzip, deflate, br"));
+        assert!(accepts_gzip("br;q=1.0, GZIP;q=0.5"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("gzip;q=0, *"));
+        assert!(!accepts_gzip("deflate, br"));
+        assert!(!accepts_gzip("identity"));
+    }
+}
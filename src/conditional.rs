@@ -0,0 +1,64 @@
+// Cache validators for served files, and the conditional requests that use them (RFC 9110 13)
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{date, Request};
+
+// W/"size-mtime" in hex, cheap to work out from the metadata alone. Weak, since a file
+// rewritten within the same second with the same length keeps its tag
+pub(crate) fn weak_etag(len: u64, modified: SystemTime) -> String {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("W/\"{:x}-{:x}\"", len, modified.as_secs())
+}
+
+// A strong tag from an FNV-1a hash of the contents, the same across restarts and machines
+pub(crate) fn content_etag(contents: &[u8]) -> String {
+    let hash = contents.iter().fold(0xcbf29ce484222325_u64, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3));
+    format!("\"{:016x}\"", hash)
+}
+
+// Whether the client's copy is current, so a 304 will do. If-None-Match wins when it's there,
+// If-Modified-Since is only looked at without it (RFC 9110 13.2.2)
+pub(crate) fn not_modified(request: &Request, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    if request.headers.contains("if-none-match") {
+        let Some(etag) = etag else {
+            return false;
+        };
+        // weak comparison, W/"x" matches "x", fine for GET and HEAD
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        let etag = opaque(etag);
+        return request.headers.get_all("if-none-match").flat_map(|value| value.split(',')).any(|tag| tag.trim() == "*" || opaque(tag) == etag);
+    }
+    let since = request.header("if-modified-since").and_then(date::parse);
+    let modified = last_modified.and_then(date::parse);
+    matches!((since, modified), (Some(since), Some(modified)) if modified <= since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_etags() {
+        let modified = UNIX_EPOCH + Duration::from_secs(0x5f5e100);
+        assert_eq!(weak_etag(1024, modified), "W/\"400-5f5e100\"");
+        assert_eq!(content_etag(b""), "\"cbf29ce484222325\"");
+        assert_ne!(content_etag(b"a"), content_etag(b"b"));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let request = |headers: &str| Request::parse(format!("GET / HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap();
+        let etag = Some("W/\"400-1\"");
+        let date = Some("Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(not_modified(&request("If-None-Match: \"x\", W/\"400-1\"\r\n"), etag, date));
+        assert!(not_modified(&request("If-None-Match: \"400-1\"\r\n"), etag, date));
+        assert!(not_modified(&request("If-None-Match: *\r\n"), etag, date));
+        assert!(!not_modified(&request("If-None-Match: \"x\"\r\n"), etag, date));
+        // If-None-Match overrules a matching date
+        assert!(!not_modified(&request("If-None-Match: \"x\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), etag, date));
+        assert!(not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), etag, date));
+        assert!(!not_modified(&request("If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n"), etag, date));
+        assert!(!not_modified(&request(""), etag, date));
+    }
+}
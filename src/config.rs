@@ -0,0 +1,803 @@
+use std::{fmt, fs, path::PathBuf, time::Duration};
+
+use crate::{
+    compress,
+    handler::DEFAULT_SERVER,
+    headers,
+    toml::{self, Value},
+    LogLevel, ServerBuilder, StatusCode,
+};
+
+/// Usage text for the server binary
+pub const USAGE: &str = "\
+Usage: main [OPTIONS]
+
+Options:
+  --config <FILE>         Read settings from a TOML file, other flags override it
+  --host <HOST>           Address to listen on, :: for IPv4 and IPv6 [default: 127.0.0.1]
+  --port <PORT>           Port to listen on [default: 7878]
+  --listen <ADDR>         Listen on host:port instead of --host/--port, repeat for more addresses
+  --unix <PATH>           Listen on a Unix socket instead of TCP, unless --listen is given too
+  --systemd <BOOL>        Use sockets passed in by systemd socket activation, if any [default: true]
+  --threads <N>           Worker threads [default: 4]
+  --queue-len <N>         Connections that can wait for a worker [default: 64]
+  --root <DIR>            Directory pages are served from [default: static]
+  --vhost <HOST=DIR>      Serve requests for HOST from DIR instead of --root, repeat for more sites
+  --mime-type <EXT=TYPE>  Serve files ending in .EXT as TYPE, over the built in table, repeat for more
+  --server-header <NAME>  The Server header on responses, empty for none [default: webserver/VERSION]
+  --redirect <FROM=TO>    Send requests for path FROM to TO with a 301, repeat for more
+  --canonical-redirects <BOOL> Redirect paths like /a/./b or /a//b to their normalized form [default: false]
+  --content-etags <BOOL>  ETags from hashing file contents instead of size and mtime [default: false]
+  --compression <BOOL>    Gzip responses for clients that accept it [default: true]
+  --compress-min-size <N> Smallest body in bytes worth compressing [default: 1024]
+  --compress-type <TYPE>  Content-Type to compress, like text/* or image/svg+xml, repeat for more [default: text and the like]
+  --error-page <CODE=FILE> Send CODE responses with FILE from the root, empty for plain text, repeat for more [default: 404=404.html, 503=503.html]
+  --job-timeout <SECS>    Log connections that keep a worker longer than this, 0 for never [default: 0]
+  --read-timeout <SECS>   Give up on clients that send nothing for this long, 0 to wait forever [default: 10]
+  --write-timeout <SECS>  Give up on clients that stop reading for this long, 0 to wait forever [default: 10]
+  --grace-period <SECS>   On shutdown, wait this long for open connections before closing them [default: 10]
+  --log-level <LEVEL>     off, error, warn, info or debug [default: info]
+  --max-header-size <N>   Biggest request head in bytes, bigger ones get a 431 [default: 8192]
+  --max-headers <N>       Most header lines a request can have, more get a 431 [default: 100]
+  --max-header-line <N>   Longest header line in bytes, longer ones get a 431 [default: 8190]
+  --max-body-size <N>     Biggest request body in bytes, bigger ones get a 413 [default: 1048576]
+  --read-buffer <N>       Bytes read off a connection at a time [default: 1024]
+  --backlog <N>           Connections the kernel queues before we accept them [default: 128]
+  --nodelay <BOOL>        Turn off Nagle's algorithm on client connections [default: true]
+  --reuse-port <BOOL>     Set SO_REUSEPORT so other processes can share the port [default: false]
+  --event-loop <BOOL>     Wait for requests on epoll so idle connections don't hold a worker (Linux) [default: false]
+  --tokio <BOOL>          Wait for requests on a tokio runtime instead, needs the tokio feature [default: false]
+  --acceptors <N>         Accept threads per address, each with its own SO_REUSEPORT listener [default: 1]
+  --proxy-protocol <BOOL> Expect a PROXY protocol header from a load balancer on every connection [default: false]
+  --http2 <BOOL>          Speak HTTP/2 to clients opening with its preface, or picking h2 over TLS [default: false]
+  --tls-cert <FILE>       Serve HTTPS with the PEM certificate chain in FILE, needs --tls-key and the tls feature
+  --tls-key <FILE>        The PEM private key for --tls-cert
+  --tls-client-ca <FILE>  Only let in TLS clients with a certificate from a CA in this PEM bundle
+  --tls-client-optional <BOOL> Let TLS clients without a certificate in too [default: false]
+  --user <NAME>           Switch to this user after binding, when started as root for a low port
+  --group <NAME>          Switch to this group after binding [default: the user's]
+  --admin <ADDR>          Take stats, reload-config, upgrade and shutdown commands on this loopback address
+  --max-connections <N>   Answer 503 while this many connections are open [default: unlimited]
+  --max-requests <N>      Exit after serving this many requests, for benchmarking
+  -h, --help              Print this help";
+
+/// Settings for the server binary, filled in from a config file and the command line
+///
+/// The file uses the same names as the flags, with underscores, under a [server] table:
+///
+/// ```toml
+/// [server]
+/// host = "0.0.0.0"
+/// port = 8080
+/// # or several addresses at once, used instead of host and port
+/// listen = ["[::]:8080", "127.0.0.1:8081"]
+/// # for a reverse proxy on the same box, replaces TCP unless listen is set
+/// unix_sockets = ["/run/webserver.sock"]
+/// # keep [::] from taking IPv4 clients, so 0.0.0.0 can be bound separately
+/// ipv6_only = false
+/// threads = 8
+/// root = "static"
+/// job_timeout = 30
+///
+/// # sites served from their own roots by Host header, the rest get root
+/// [hosts]
+/// site-a.example = "sites/a"
+///
+/// # Content-Types by file extension, over the built in ones
+/// [mime_types]
+/// gmi = "text/gemini"
+///
+/// # pages for error statuses, over 404.html and 503.html
+/// [error_pages]
+/// 500 = "oops.html"
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Addresses to listen on, when empty host and port are used
+    pub listen: Vec<String>,
+    /// Unix socket paths to listen on, without any listen addresses these replace host and port
+    pub unix_sockets: Vec<PathBuf>,
+    /// Take the sockets systemd passes a socket activated process over any of the above
+    pub systemd: bool,
+    /// Keep IPv6 listeners from taking IPv4 clients too
+    pub ipv6_only: bool,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+    pub backlog: u32,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_header_line: usize,
+    pub max_body_size: u64,
+    pub read_buffer: usize,
+    /// Accept threads per TCP address
+    pub acceptors: usize,
+    /// Idle connections wait on an event loop instead of a worker
+    pub event_loop: bool,
+    /// Accept and wait for requests on a tokio runtime, see Server::serve_static_async
+    pub tokio: bool,
+    pub nodelay: bool,
+    pub threads: usize,
+    pub queue_len: usize,
+    pub root: PathBuf,
+    /// Host names and the roots their requests are served from, others get root
+    pub virtual_hosts: Vec<(String, PathBuf)>,
+    /// File extensions and the Content-Type to serve them as, over the built in table
+    pub mime_types: Vec<(String, String)>,
+    /// The Server response header, empty for none
+    pub server_header: String,
+    /// Paths that get a 301 to another
+    pub redirects: Vec<(String, String)>,
+    /// Redirect paths with dot segments, doubled slashes and needless escapes to their normal form
+    pub canonical_redirects: bool,
+    /// ETags from file contents rather than size and modification time
+    pub content_etags: bool,
+    /// Gzip bodies of at least compress_min_size bytes, when their type is on compress_types
+    pub compression: bool,
+    pub compress_min_size: u64,
+    pub compress_types: Vec<String>,
+    /// Error statuses and the page to send with them, over the default 404.html and 503.html
+    pub error_pages: Vec<(StatusCode, PathBuf)>,
+    pub job_timeout: Option<Duration>,
+    /// None waits on clients forever
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    /// How long a shutdown waits for open connections
+    pub grace_period: Duration,
+    pub log_level: LogLevel,
+    pub max_connections: Option<usize>,
+    pub max_requests: Option<usize>,
+    /// Connections come through a load balancer sending the PROXY protocol
+    pub proxy_protocol: bool,
+    /// Answer clients that open with the HTTP/2 preface or pick h2 in ALPN in HTTP/2
+    pub http2: bool,
+    /// PEM files with the certificate chain and private key to serve HTTPS with, both or neither
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// CAs client certificates have to chain to, and whether a client can go without one
+    pub tls_client_ca: Option<PathBuf>,
+    pub tls_client_optional: bool,
+    /// Loopback address for the admin socket
+    pub admin: Option<String>,
+    /// Who to run as once the sockets are bound
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+/// Why the command line couldn't be turned into a Config
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgsError {
+    /// --help was passed, print USAGE and exit
+    Help,
+    Invalid(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::Help => f.write_str(USAGE),
+            ArgsError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            // 7878 spells out rust on a phone
+            port: 7878,
+            listen: Vec::new(),
+            unix_sockets: Vec::new(),
+            systemd: true,
+            ipv6_only: false,
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 128,
+            max_header_size: 8192,
+            max_headers: 100,
+            max_header_line: 8190,
+            max_body_size: 1024 * 1024,
+            read_buffer: 1024,
+            acceptors: 1,
+            event_loop: false,
+            tokio: false,
+            nodelay: true,
+            threads: 4,
+            queue_len: 64,
+            root: PathBuf::from("static"),
+            virtual_hosts: Vec::new(),
+            mime_types: Vec::new(),
+            server_header: DEFAULT_SERVER.to_string(),
+            redirects: Vec::new(),
+            canonical_redirects: false,
+            content_etags: false,
+            compression: true,
+            compress_min_size: 1024,
+            compress_types: compress::DEFAULT_TYPES.iter().map(|mime| mime.to_string()).collect(),
+            error_pages: Vec::new(),
+            job_timeout: None,
+            read_timeout: Some(Duration::from_secs(10)),
+            write_timeout: Some(Duration::from_secs(10)),
+            grace_period: Duration::from_secs(10),
+            log_level: LogLevel::Info,
+            max_connections: None,
+            max_requests: None,
+            proxy_protocol: false,
+            http2: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_client_optional: false,
+            admin: None,
+            user: None,
+            group: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parse flags like "--port 8080" or "--port=8080", 'args' shouldn't include the program name
+    /// A --config file is read first wherever it appears, so the other flags always win
+    ///
+    /// ```
+    /// let args = ["--port", "8080", "--threads=8"].map(String::from);
+    /// let config = webserver::Config::from_args(args).unwrap();
+    /// assert_eq!(config.addr(), "127.0.0.1:8080");
+    /// assert_eq!(config.threads, 8);
+    /// ```
+    ///
+    /// # Errors
+    /// Returns ArgsError::Help for -h/--help, and ArgsError::Invalid for unknown flags,
+    /// bad values or a config file that can't be read
+    pub fn from_args<I>(args: I) -> Result<Config, ArgsError>
+    where I: IntoIterator<Item = String>
+    {
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(ArgsError::Help);
+            }
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                None => {
+                    let value = args.next().ok_or_else(|| ArgsError::Invalid(format!("{} needs a value", arg)))?;
+                    (arg, value)
+                }
+            };
+            flags.push((flag, value));
+        }
+
+        let mut config = Config::default();
+        if let Some((_, path)) = flags.iter().rev().find(|(flag, _)| flag == "--config") {
+            config = Config::from_file(path).map_err(ArgsError::Invalid)?;
+        }
+        // listen addresses on the command line replace the file's rather than adding to them
+        if flags.iter().any(|(flag, _)| flag == "--listen") {
+            config.listen.clear();
+        }
+        if flags.iter().any(|(flag, _)| flag == "--unix") {
+            config.unix_sockets.clear();
+        }
+        if flags.iter().any(|(flag, _)| flag == "--vhost") {
+            config.virtual_hosts.clear();
+        }
+        if flags.iter().any(|(flag, _)| flag == "--mime-type") {
+            config.mime_types.clear();
+        }
+        if flags.iter().any(|(flag, _)| flag == "--redirect") {
+            config.redirects.clear();
+        }
+        if flags.iter().any(|(flag, _)| flag == "--compress-type") {
+            config.compress_types.clear();
+        }
+        for (flag, value) in flags {
+            match flag.as_str() {
+                "--config" => {}
+                "--host" => config.host = value,
+                "--port" => config.port = parse(&flag, &value)?,
+                "--listen" => config.listen.push(value),
+                "--unix" => config.unix_sockets.push(PathBuf::from(value)),
+                "--threads" => config.threads = parse(&flag, &value)?,
+                "--queue-len" => config.queue_len = parse(&flag, &value)?,
+                "--root" => config.root = PathBuf::from(value),
+                "--vhost" => {
+                    let (host, root) = value
+                        .split_once('=')
+                        .ok_or_else(|| ArgsError::Invalid(format!("--vhost wants HOST=DIR, not {:?}", value)))?;
+                    config.virtual_hosts.push((host.to_string(), PathBuf::from(root)));
+                }
+                "--mime-type" => {
+                    let (extension, mime) = value
+                        .split_once('=')
+                        .ok_or_else(|| ArgsError::Invalid(format!("--mime-type wants EXT=TYPE, not {:?}", value)))?;
+                    config.mime_types.push((extension.trim_start_matches('.').to_string(), mime.to_string()));
+                }
+                "--server-header" => config.server_header = value,
+                "--redirect" => config.redirects.push(redirect_rule(&value).map_err(ArgsError::Invalid)?),
+                "--canonical-redirects" => config.canonical_redirects = parse(&flag, &value)?,
+                "--content-etags" => config.content_etags = parse(&flag, &value)?,
+                "--compression" => config.compression = parse(&flag, &value)?,
+                "--compress-min-size" => config.compress_min_size = parse(&flag, &value)?,
+                "--compress-type" => config.compress_types.push(value),
+                "--error-page" => {
+                    let (code, page) = value
+                        .split_once('=')
+                        .ok_or_else(|| ArgsError::Invalid(format!("--error-page wants CODE=FILE, not {:?}", value)))?;
+                    config.error_pages.push((error_status(code).map_err(ArgsError::Invalid)?, PathBuf::from(page)));
+                }
+                "--job-timeout" => config.job_timeout = io_timeout(parse(&flag, &value)?),
+                "--read-timeout" => config.read_timeout = io_timeout(parse(&flag, &value)?),
+                "--write-timeout" => config.write_timeout = io_timeout(parse(&flag, &value)?),
+                "--grace-period" => config.grace_period = Duration::from_secs(parse(&flag, &value)?),
+                "--log-level" => config.log_level = value.parse().map_err(ArgsError::Invalid)?,
+                "--backlog" => config.backlog = parse(&flag, &value)?,
+                "--max-header-size" => config.max_header_size = parse(&flag, &value)?,
+                "--max-headers" => config.max_headers = parse(&flag, &value)?,
+                "--max-header-line" => config.max_header_line = parse(&flag, &value)?,
+                "--read-buffer" => config.read_buffer = parse(&flag, &value)?,
+                "--max-body-size" => config.max_body_size = parse(&flag, &value)?,
+                "--acceptors" => config.acceptors = parse(&flag, &value)?,
+                "--event-loop" => config.event_loop = parse(&flag, &value)?,
+                "--tokio" => config.tokio = parse(&flag, &value)?,
+                "--nodelay" => config.nodelay = parse(&flag, &value)?,
+                "--systemd" => config.systemd = parse(&flag, &value)?,
+                "--proxy-protocol" => config.proxy_protocol = parse(&flag, &value)?,
+                "--http2" => config.http2 = parse(&flag, &value)?,
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(value)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(value)),
+                "--tls-client-ca" => config.tls_client_ca = Some(PathBuf::from(value)),
+                "--tls-client-optional" => config.tls_client_optional = parse(&flag, &value)?,
+                "--admin" => config.admin = Some(value),
+                "--user" => config.user = Some(value),
+                "--group" => config.group = Some(value),
+                "--reuse-port" => config.reuse_port = parse(&flag, &value)?,
+                "--max-connections" => config.max_connections = Some(parse(&flag, &value)?),
+                "--max-requests" => config.max_requests = Some(parse(&flag, &value)?),
+                _ => return Err(ArgsError::Invalid(format!("unknown option {}", flag))),
+            }
+        }
+        config.check().map_err(ArgsError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Read a TOML config file, anything it leaves out keeps its default
+    ///
+    /// # Errors
+    /// Returns a message naming the file if it can't be read or holds an unknown key or bad value
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Config::from_toml(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Settings from the text of a TOML config file
+    ///
+    /// # Errors
+    /// Returns a message for syntax errors, unknown keys and values of the wrong type
+    pub fn from_toml(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (key, value) in toml::parse(text)? {
+            // [hosts] maps host names to their roots
+            if let Some(host) = key.strip_prefix("hosts.") {
+                let root = PathBuf::from(string(&key, value)?);
+                config.virtual_hosts.push((host.to_string(), root));
+                continue;
+            }
+            // [mime_types] maps extensions to Content-Types
+            if let Some(extension) = key.strip_prefix("mime_types.") {
+                let mime = string(&key, value)?;
+                config.mime_types.push((extension.to_string(), mime));
+                continue;
+            }
+            // [error_pages] maps status codes to pages
+            if let Some(code) = key.strip_prefix("error_pages.") {
+                let page = PathBuf::from(string(&key, value)?);
+                config.error_pages.push((error_status(code)?, page));
+                continue;
+            }
+            let name = key.strip_prefix("server.").unwrap_or(&key);
+            match name {
+                "host" => config.host = string(&key, value)?,
+                "port" => config.port = integer(&key, value)?,
+                "listen" => config.listen = strings(&key, value)?,
+                "unix_sockets" => config.unix_sockets = strings(&key, value)?.into_iter().map(PathBuf::from).collect(),
+                "ipv6_only" => config.ipv6_only = boolean(&key, value)?,
+                "reuse_address" => config.reuse_address = boolean(&key, value)?,
+                "reuse_port" => config.reuse_port = boolean(&key, value)?,
+                "backlog" => config.backlog = integer(&key, value)?,
+                "max_header_size" => config.max_header_size = integer(&key, value)?,
+                "max_headers" => config.max_headers = integer(&key, value)?,
+                "max_header_line" => config.max_header_line = integer(&key, value)?,
+                "read_buffer" => config.read_buffer = integer(&key, value)?,
+                "max_body_size" => config.max_body_size = integer(&key, value)?,
+                "acceptors" => config.acceptors = integer(&key, value)?,
+                "event_loop" => config.event_loop = boolean(&key, value)?,
+                "tokio" => config.tokio = boolean(&key, value)?,
+                "nodelay" => config.nodelay = boolean(&key, value)?,
+                "systemd" => config.systemd = boolean(&key, value)?,
+                "proxy_protocol" => config.proxy_protocol = boolean(&key, value)?,
+                "http2" => config.http2 = boolean(&key, value)?,
+                "tls_cert" => config.tls_cert = Some(PathBuf::from(string(&key, value)?)),
+                "tls_key" => config.tls_key = Some(PathBuf::from(string(&key, value)?)),
+                "tls_client_ca" => config.tls_client_ca = Some(PathBuf::from(string(&key, value)?)),
+                "tls_client_optional" => config.tls_client_optional = boolean(&key, value)?,
+                "admin" => config.admin = Some(string(&key, value)?),
+                "user" => config.user = Some(string(&key, value)?),
+                "group" => config.group = Some(string(&key, value)?),
+                "threads" => config.threads = integer(&key, value)?,
+                "queue_len" => config.queue_len = integer(&key, value)?,
+                "root" => config.root = PathBuf::from(string(&key, value)?),
+                "server_header" => config.server_header = string(&key, value)?,
+                "redirects" => config.redirects = strings(&key, value)?.iter().map(|rule| redirect_rule(rule)).collect::<Result<_, _>>()?,
+                "canonical_redirects" => config.canonical_redirects = boolean(&key, value)?,
+                "content_etags" => config.content_etags = boolean(&key, value)?,
+                "compression" => config.compression = boolean(&key, value)?,
+                "compress_min_size" => config.compress_min_size = integer(&key, value)?,
+                "compress_types" => config.compress_types = strings(&key, value)?,
+                "job_timeout" => config.job_timeout = io_timeout(integer(&key, value)?),
+                "read_timeout" => config.read_timeout = io_timeout(integer(&key, value)?),
+                "write_timeout" => config.write_timeout = io_timeout(integer(&key, value)?),
+                "grace_period" => config.grace_period = Duration::from_secs(integer(&key, value)?),
+                "log_level" => config.log_level = string(&key, value)?.parse()?,
+                "max_connections" => config.max_connections = Some(integer(&key, value)?),
+                "max_requests" => config.max_requests = Some(integer(&key, value)?),
+                _ => return Err(format!("unknown setting {}", key)),
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    // Catch what would otherwise panic deep inside the pool builder
+    fn check(&self) -> Result<(), String> {
+        if self.threads == 0 {
+            return Err("threads must be at least 1".to_string());
+        }
+        if self.acceptors == 0 {
+            return Err("acceptors must be at least 1".to_string());
+        }
+        if self.read_buffer == 0 {
+            return Err("read_buffer must be at least 1".to_string());
+        }
+        if self.queue_len == 0 {
+            return Err("queue_len must be at least 1".to_string());
+        }
+        if self.max_connections == Some(0) {
+            return Err("max_connections must be at least 1".to_string());
+        }
+        if self.max_requests == Some(0) {
+            return Err("max_requests must be at least 1".to_string());
+        }
+        if !headers::is_valid_value(&self.server_header) {
+            return Err("server_header can't have control characters in it".to_string());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls_cert and tls_key go together".to_string());
+        }
+        if self.tls_client_ca.is_some() && self.tls_cert.is_none() {
+            return Err("tls_client_ca needs tls_cert and tls_key".to_string());
+        }
+        if self.tls_cert.is_some() && !cfg!(feature = "tls") {
+            return Err("tls_cert needs a build with the tls feature".to_string());
+        }
+        if self.tokio && !cfg!(feature = "tokio") {
+            return Err("tokio needs a build with the tokio feature".to_string());
+        }
+        Ok(())
+    }
+
+    /// "host:port", with brackets around IPv6 hosts
+    pub fn addr(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// Every TCP address the server should listen on
+    pub fn addrs(&self) -> Vec<String> {
+        if !self.listen.is_empty() {
+            self.listen.clone()
+        } else if self.unix_sockets.is_empty() {
+            vec![self.addr()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Everything the server listens on, for messages
+    pub fn describe_listeners(&self) -> String {
+        let unix = self.unix_sockets.iter().map(|path| format!("unix:{}", path.display()));
+        self.addrs().into_iter().chain(unix).collect::<Vec<_>>().join(", ")
+    }
+
+    /// A ServerBuilder with these settings
+    pub fn server_builder(&self) -> ServerBuilder {
+        let builder = self.addrs().into_iter().fold(ServerBuilder::new(), ServerBuilder::addr);
+        #[cfg(unix)]
+        let builder = self.unix_sockets.iter().fold(builder, ServerBuilder::unix_socket).systemd_sockets(self.systemd);
+        let builder = self.virtual_hosts.iter().fold(builder, |builder, (host, root)| builder.virtual_host(host, root));
+        let builder = self.redirects.iter().fold(builder, |builder, (from, to)| builder.redirect(from, to));
+        let builder = self.mime_types.iter().fold(builder, |builder, (extension, mime)| builder.mime_type(extension, mime));
+        let builder = self.error_pages.iter().fold(builder, |builder, (status, page)| builder.error_page(*status, page));
+        let builder = builder
+            .ipv6_only(self.ipv6_only)
+            .reuse_address(self.reuse_address)
+            .reuse_port(self.reuse_port)
+            .backlog(self.backlog)
+            .max_header_size(self.max_header_size)
+            .max_headers(self.max_headers)
+            .max_header_line(self.max_header_line)
+            .read_buffer_size(self.read_buffer)
+            .max_body_size(self.max_body_size)
+            .acceptors(self.acceptors)
+            .event_loop(self.event_loop)
+            .nodelay(self.nodelay)
+            .threads(self.threads)
+            .queue_len(self.queue_len)
+            .doc_root(&self.root)
+            .canonical_redirects(self.canonical_redirects)
+            .content_etags(self.content_etags)
+            .compression(self.compression)
+            .compress_min_size(self.compress_min_size)
+            .compress_types(&self.compress_types)
+            .server_header(&self.server_header)
+            .job_timeout(self.job_timeout)
+            .read_timeout(self.read_timeout)
+            .write_timeout(self.write_timeout)
+            .grace_period(self.grace_period)
+            .proxy_protocol(self.proxy_protocol)
+            .http2(self.http2);
+        #[cfg(unix)]
+        let builder = match &self.user {
+            Some(user) => builder.user(user),
+            None => builder,
+        };
+        #[cfg(unix)]
+        let builder = match &self.group {
+            Some(group) => builder.group(group),
+            None => builder,
+        };
+        #[cfg(feature = "tls")]
+        let builder = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => builder.tls(cert, key),
+            _ => builder,
+        };
+        #[cfg(feature = "tls")]
+        let builder = match &self.tls_client_ca {
+            Some(ca) => builder.tls_client_ca(ca).tls_client_optional(self.tls_client_optional),
+            None => builder,
+        };
+        let builder = match &self.admin {
+            Some(addr) => builder.admin_addr(addr),
+            None => builder,
+        };
+        let builder = match self.max_connections {
+            Some(max) => builder.max_connections(max),
+            None => builder,
+        };
+        match self.max_requests {
+            Some(max) => builder.max_requests(max),
+            None => builder,
+        }
+    }
+}
+
+// 0 seconds means no timeout, like the flags say
+fn io_timeout(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+// "/old=/new", a redirect from one path to another page or URL
+fn redirect_rule(rule: &str) -> Result<(String, String), String> {
+    match rule.split_once('=') {
+        Some((from, to)) if from.starts_with('/') && !to.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(format!("a redirect wants /FROM=TO, not {:?}", rule)),
+    }
+}
+
+// "503", a status worth having a page for
+fn error_status(code: &str) -> Result<StatusCode, String> {
+    match code.parse::<u16>().ok().and_then(|code| StatusCode::try_from(code).ok()) {
+        Some(status) if status.is_client_error() || status.is_server_error() => Ok(status),
+        _ => Err(format!("an error page is for a 4xx or 5xx status, not {:?}", code)),
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ArgsError> {
+    value.parse().map_err(|_| ArgsError::Invalid(format!("invalid value {:?} for {}", value, flag)))
+}
+
+fn string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(format!("{} should be a string, not {}", key, other.type_name())),
+    }
+}
+
+fn boolean(key: &str, value: Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(b),
+        other => Err(format!("{} should be a boolean, not {}", key, other.type_name())),
+    }
+}
+
+fn strings(key: &str, value: Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(items) => items.into_iter().map(|item| string(key, item)).collect(),
+        other => Err(format!("{} should be an array of strings, not {}", key, other.type_name())),
+    }
+}
+
+fn integer<T: TryFrom<i64>>(key: &str, value: Value) -> Result<T, String> {
+    match value {
+        Value::Integer(n) => T::try_from(n).map_err(|_| format!("{} is out of range: {}", key, n)),
+        other => Err(format!("{} should be an integer, not {}", key, other.type_name())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn args(list: &[&str]) -> Result<Config, ArgsError> {
+        Config::from_args(list.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_all_flags() {
+        let config = args(&[
+            "--host", "0.0.0.0", "--port=9000", "--threads", "2", "--queue-len", "8",
+            "--root", "www", "--job-timeout", "10", "--read-timeout", "5", "--write-timeout", "0", "--log-level", "debug",
+            "--backlog", "1024", "--nodelay=false", "--reuse-port", "true", "--max-requests", "100",
+            "--systemd", "false", "--acceptors", "2", "--proxy-protocol", "true", "--http2", "true",
+            "--vhost", "a.example=sites/a", "--admin", "127.0.0.1:7879",
+            "--user", "www-data", "--max-header-size", "16384", "--max-headers", "20", "--max-header-line", "1000", "--read-buffer", "4096",
+            "--max-body-size", "100", "--grace-period", "30", "--event-loop", "true", "--mime-type", ".md=text/plain",
+            "--content-etags", "true", "--redirect", "/old=/new", "--canonical-redirects", "true",
+            "--server-header=", "--compression", "false", "--compress-min-size", "0", "--compress-type", "text/*",
+            "--error-page", "503=busy.html",
+        ]).unwrap();
+        assert_eq!(config, Config {
+            host: "0.0.0.0".to_string(),
+            port: 9000,
+            listen: Vec::new(),
+            unix_sockets: Vec::new(),
+            systemd: false,
+            ipv6_only: false,
+            reuse_address: true,
+            reuse_port: true,
+            backlog: 1024,
+            max_header_size: 16384,
+            max_headers: 20,
+            max_header_line: 1000,
+            max_body_size: 100,
+            read_buffer: 4096,
+            acceptors: 2,
+            event_loop: true,
+            tokio: false,
+            nodelay: false,
+            threads: 2,
+            queue_len: 8,
+            root: PathBuf::from("www"),
+            virtual_hosts: vec![("a.example".to_string(), PathBuf::from("sites/a"))],
+            mime_types: vec![("md".to_string(), "text/plain".to_string())],
+            server_header: String::new(),
+            redirects: vec![("/old".to_string(), "/new".to_string())],
+            canonical_redirects: true,
+            content_etags: true,
+            compression: false,
+            compress_min_size: 0,
+            compress_types: vec!["text/*".to_string()],
+            error_pages: vec![(StatusCode::ServiceUnavailable, PathBuf::from("busy.html"))],
+            job_timeout: Some(Duration::from_secs(10)),
+            read_timeout: Some(Duration::from_secs(5)),
+            write_timeout: None,
+            grace_period: Duration::from_secs(30),
+            log_level: LogLevel::Debug,
+            max_connections: None,
+            max_requests: Some(100),
+            proxy_protocol: true,
+            http2: true,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_client_optional: false,
+            admin: Some("127.0.0.1:7879".to_string()),
+            user: Some("www-data".to_string()),
+            group: None,
+        });
+        assert_eq!(args(&[]).unwrap(), Config::default());
+        assert_eq!(args(&["--host", "::1"]).unwrap().addr(), "[::1]:7878");
+        assert_eq!(args(&["--listen", "127.0.0.1:80", "--listen=[::1]:80"]).unwrap().addrs(), ["127.0.0.1:80", "[::1]:80"]);
+        let unix = args(&["--unix", "/tmp/web.sock"]).unwrap();
+        assert!(unix.addrs().is_empty());
+        assert_eq!(unix.describe_listeners(), "unix:/tmp/web.sock");
+        let both = args(&["--unix", "/tmp/web.sock", "--listen", "127.0.0.1:80"]).unwrap();
+        assert_eq!(both.describe_listeners(), "127.0.0.1:80, unix:/tmp/web.sock");
+    }
+
+    #[test]
+    fn test_bad_args() {
+        assert_eq!(args(&["-h"]), Err(ArgsError::Help));
+        assert!(matches!(args(&["--port"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(args(&["--port", "http"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(args(&["--threads", "0"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(args(&["--verbose", "1"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(args(&["--config", "no/such/file.toml"]), Err(ArgsError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_tls_flags() {
+        let tls = args(&["--tls-cert", "cert.pem", "--tls-key=key.pem"]);
+        if cfg!(feature = "tls") {
+            let tls = tls.unwrap();
+            assert_eq!((tls.tls_cert.as_deref(), tls.tls_key.as_deref()), (Some(Path::new("cert.pem")), Some(Path::new("key.pem"))));
+            let file = Config::from_toml("tls_cert = \"/etc/web/cert.pem\"\ntls_key = \"/etc/web/key.pem\"\n").unwrap();
+            assert_eq!(file.tls_key.as_deref(), Some(Path::new("/etc/web/key.pem")));
+            let mutual = args(&["--tls-cert", "c.pem", "--tls-key", "k.pem", "--tls-client-ca", "ca.pem", "--tls-client-optional", "true"]).unwrap();
+            assert_eq!((mutual.tls_client_ca.as_deref(), mutual.tls_client_optional), (Some(Path::new("ca.pem")), true));
+        } else {
+            assert!(matches!(tls, Err(ArgsError::Invalid(_))));
+        }
+        assert!(matches!(args(&["--tls-cert", "cert.pem"]), Err(ArgsError::Invalid(_))));
+        assert!(matches!(args(&["--tls-client-ca", "ca.pem"]), Err(ArgsError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_tokio_flag() {
+        let tokio = args(&["--tokio", "true"]);
+        if cfg!(feature = "tokio") {
+            assert!(tokio.unwrap().tokio);
+            assert!(Config::from_toml("tokio = true\n").unwrap().tokio);
+        } else {
+            assert!(matches!(tokio, Err(ArgsError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_config_file_with_flag_overrides() {
+        let path = std::env::temp_dir().join(format!("webserver-config-{}.toml", std::process::id()));
+        fs::write(&path, "[server]\nhost = \"0.0.0.0\"\nport = 8080\nthreads = 8\nlog_level = \"warn\"\n").unwrap();
+        let listening = fs::read_to_string(&path).unwrap() + "listen = [\"127.0.0.1:80\", \"127.0.0.1:443\"]\n";
+        assert_eq!(Config::from_toml(&listening).unwrap().addrs(), ["127.0.0.1:80", "127.0.0.1:443"]);
+        let config = args(&["--port", "9090", "--config", path.to_str().unwrap()]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.addr(), "0.0.0.0:9090");
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.log_level, LogLevel::Warn);
+
+        let hosts = Config::from_toml("root = \"www\"\n[hosts]\nsite-a.example = \"sites/a\"\n").unwrap();
+        assert_eq!(hosts.virtual_hosts, [("site-a.example".to_string(), PathBuf::from("sites/a"))]);
+
+        let redirects = Config::from_toml("redirects = [\"/a=/b\", \"/c=https://example.com/\"]").unwrap();
+        assert_eq!(redirects.redirects[1], ("/c".to_string(), "https://example.com/".to_string()));
+        assert!(Config::from_toml("redirects = [\"/a\"]").is_err());
+
+        let types = Config::from_toml("[mime_types]\ngmi = \"text/gemini\"\n").unwrap();
+        assert_eq!(types.mime_types, [("gmi".to_string(), "text/gemini".to_string())]);
+        let pages = Config::from_toml("[error_pages]\n500 = \"/srv/oops.html\"\n").unwrap();
+        assert_eq!(pages.error_pages, [(StatusCode::InternalServerError, PathBuf::from("/srv/oops.html"))]);
+        assert!(Config::from_toml("[error_pages]\n200 = \"ok.html\"\n").is_err());
+        assert!(args(&["--error-page", "999=x.html"]).is_err());
+        assert!(args(&["--server-header", "x\r\nSet-Cookie: a=1"]).is_err());
+    }
+
+    #[test]
+    fn test_bad_config_file() {
+        assert_eq!(Config::from_toml("prot = 80").unwrap_err(), "unknown setting prot");
+        assert_eq!(Config::from_toml("[server]\nport = \"80\"").unwrap_err(), "server.port should be an integer, not a string");
+        assert_eq!(Config::from_toml("port = 70000").unwrap_err(), "port is out of range: 70000");
+        assert_eq!(Config::from_toml("listen = [80]").unwrap_err(), "listen should be a string, not an integer");
+        assert_eq!(Config::from_toml("ipv6_only = 1").unwrap_err(), "ipv6_only should be a boolean, not an integer");
+        assert!(Config::from_toml("ipv6_only = true").unwrap().ipv6_only);
+        assert!(Config::from_toml("threads = 0").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, time::Duration};
+
+/// A cookie to set with Response::with_cookie, built up attribute by attribute
+///
+/// ```
+/// use std::time::Duration;
+/// use webserver::{Cookie, SameSite};
+///
+/// let cookie = Cookie::new("session", "abc123").path("/").max_age(Duration::from_secs(3600)).http_only(true).same_site(SameSite::Lax);
+/// assert_eq!(cookie.to_string(), "session=abc123; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+/// Whether the browser sends a cookie along with requests from other sites
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this on a Secure cookie
+    None,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Cookie {
+        Cookie { name: name.into(), value: value.into(), path: None, max_age: None, secure: false, http_only: false, same_site: None }
+    }
+
+    /// Only send the cookie back for paths under 'path'
+    pub fn path(mut self, path: impl Into<String>) -> Cookie {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Keep the cookie for 'max_age', whole seconds; zero deletes it. Without one it
+    /// lasts until the browser closes
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Keep the cookie away from JavaScript
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+// the Set-Cookie header value
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            let same_site = match same_site {
+                SameSite::Strict => "Strict",
+                SameSite::Lax => "Lax",
+                SameSite::None => "None",
+            };
+            write!(f, "; SameSite={}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+// "a=1; b=2" as a Cookie request header has it (RFC 6265 5.4), a value may be in quotes
+// and a pair without '=' is skipped
+pub(crate) fn parse_cookies(header: &str) -> impl Iterator<Item = (&str, &str)> {
+    header.split(';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let name = name.trim();
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        (!name.is_empty()).then_some((name, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cookies() {
+        let cookies: Vec<_> = parse_cookies(r#"a=1; b = "two" ;flag; =x; c="#).collect();
+        assert_eq!(cookies, [("a", "1"), ("b", "two"), ("c", "")]);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        assert_eq!(Cookie::new("a", "1").to_string(), "a=1");
+        let cookie = Cookie::new("a", "").max_age(Duration::ZERO).secure(true).same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "a=; Max-Age=0; Secure; SameSite=None");
+    }
+}
@@ -0,0 +1,99 @@
+// HTTP dates (RFC 9110 5.6.7), for Last-Modified, If-Modified-Since and Date
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+// 1970-01-01 was a Thursday
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// 'time' as an IMF-fixdate, "Sun, 06 Nov 1994 08:49:37 GMT", to the second
+// Times before 1970 come out as 1970
+pub(crate) fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Parse any of the three formats a recipient has to accept: IMF-fixdate, the obsolete RFC 850
+// "Sunday, 06-Nov-94 08:49:37 GMT" and asctime's "Sun Nov  6 08:49:37 1994". The weekday
+// isn't checked. None for anything else, or a date before 1970
+pub(crate) fn parse(date: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = date.split_ascii_whitespace().collect();
+    let (day, month, year, time) = match fields[..] {
+        [_, day, month, year, time, "GMT"] => (day, month, year.parse().ok()?, time),
+        [_, date, time, "GMT"] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            // two digit years are the most recent past year ending in them, near enough
+            let year: u64 = year.parse().ok()?;
+            (day, month, if year < 70 { 2000 + year } else { 1900 + year }, time)
+        }
+        [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let mut hms = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    if hms.next().is_some() || year < 1970 || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Howard Hinnant's algorithms, days since 1970-01-01 to and from year, month and day
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(UNIX_EPOCH + Duration::from_secs(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+
+        for date in ["Sun, 06 Nov 1994 08:49:37 GMT", "Sunday, 06-Nov-94 08:49:37 GMT", "Sun Nov  6 08:49:37 1994"] {
+            assert_eq!(parse(date), Some(time), "{}", date);
+        }
+        let now = UNIX_EPOCH + Duration::from_secs(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+        assert_eq!(parse(&format(now)), Some(now));
+        for date in ["", "yesterday", "Sun, 06 Nov 1994 08:49 GMT", "Sun, 06 Nov 1994 25:49:37 GMT", "Sun, 06 Foo 1994 08:49:37 GMT"] {
+            assert_eq!(parse(date), None, "{}", date);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{listener::Connection, logging::log};
+
+// The sockets of every connection being served, so a shutdown can close idle keep-alive
+// connections straight away and whatever outlives the grace period after that
+#[derive(Clone, Default)]
+pub(crate) struct OpenConnections(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    draining: bool,
+    connections: HashMap<u64, Open>,
+    // requests still to be served before the server stops, see ServerBuilder::max_requests
+    budget: Option<Budget>,
+}
+
+struct Budget {
+    left: usize,
+    stop: Box<dyn FnOnce() + Send>,
+}
+
+struct Open {
+    stream: Connection,
+    // waiting for the client's next request, nothing is lost closing it
+    idle: bool,
+}
+
+/// One connection in OpenConnections, for a handler to say when it's between requests
+pub(crate) struct TrackedConnection {
+    open: OpenConnections,
+    id: u64,
+}
+
+impl OpenConnections {
+    /// Track connection 'id' until remove is called
+    pub(crate) fn add(&self, id: u64, stream: &Connection) {
+        match stream.try_clone() {
+            Ok(stream) => {
+                self.lock().connections.insert(id, Open { stream, idle: false });
+            }
+            Err(e) => log!(Warn, "Failed to clone {}, shutdown can't close it: {}", stream, e),
+        }
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.lock().connections.remove(&id);
+    }
+
+    pub(crate) fn tracked(&self, id: u64) -> TrackedConnection {
+        TrackedConnection { open: self.clone(), id }
+    }
+
+    /// Call 'stop' once count_request has been called 'max' times
+    pub(crate) fn stop_after(&self, max: usize, stop: impl FnOnce() + Send + 'static) {
+        self.lock().budget = Some(Budget { left: max, stop: Box::new(stop) });
+    }
+
+    /// One more request is being served, which may be the last one stop_after allows
+    pub(crate) fn count_request(&self) {
+        let mut state = self.lock();
+        let Some(budget) = &mut state.budget else { return };
+        budget.left = budget.left.saturating_sub(1);
+        if budget.left == 0
+            && let Some(budget) = state.budget.take()
+        {
+            // stopping pokes the listeners, no need to hold everyone else up meanwhile
+            drop(state);
+            (budget.stop)();
+        }
+    }
+
+    /// Stop keep-alive: idle connections are closed now, busy ones after their current response
+    pub(crate) fn drain(&self) {
+        let mut state = self.lock();
+        state.draining = true;
+        for open in state.connections.values_mut().filter(|open| open.idle) {
+            // the handler's read sees the end of the stream and it returns
+            let _ = open.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Close every connection still open, handlers using them get errors and give up
+    /// Returns how many there were
+    pub(crate) fn close_all(&self) -> usize {
+        let mut state = self.lock();
+        for open in state.connections.values_mut() {
+            let _ = open.stream.shutdown(Shutdown::Both);
+        }
+        state.connections.len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl TrackedConnection {
+    /// Whether the server is shutting down, responses should say Connection: close
+    pub(crate) fn draining(&self) -> bool {
+        self.open.lock().draining
+    }
+
+    /// Count a request read off this connection towards OpenConnections::stop_after
+    pub(crate) fn count_request(&self) {
+        self.open.count_request();
+    }
+
+    /// Mark the connection as waiting for another request, or not any more
+    /// false means the server is shutting down and it should close instead of waiting
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        let mut state = self.open.lock();
+        if idle && state.draining {
+            return false;
+        }
+        if let Some(open) = state.connections.get_mut(&self.id) {
+            open.idle = idle;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn test_drain_closes_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _idle_client = TcpStream::connect(addr).unwrap();
+        let _busy_client = TcpStream::connect(addr).unwrap();
+        let mut idle = Connection::Tcp(listener.accept().unwrap().0);
+        let mut busy = Connection::Tcp(listener.accept().unwrap().0);
+
+        let open = OpenConnections::default();
+        open.add(1, &idle);
+        open.add(2, &busy);
+        let (first, second) = (open.tracked(1), open.tracked(2));
+        assert!(first.set_idle(true));
+        assert!(!first.draining());
+        open.drain();
+        assert!(first.draining());
+        // the idle one reads the end of the stream, the busy one can't wait for another request
+        assert_eq!(idle.read(&mut [0; 8]).unwrap(), 0);
+        assert!(!second.set_idle(true));
+
+        open.remove(1);
+        assert_eq!(open.close_all(), 1);
+        assert_eq!(busy.read(&mut [0; 8]).unwrap(), 0);
+    }
+}
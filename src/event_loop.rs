@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        raw::c_int,
+    },
+    ptr,
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    drain::OpenConnections,
+    handler::{has_head, refuse, resume_site, HttpOptions, Resume},
+    listener::Connection,
+    logging::log,
+    server::LiveConnection,
+    Server, StatusCode, VirtualHosts,
+};
+
+const EPOLL_CLOEXEC: c_int = 0o2000000;
+const EPOLL_CTL_ADD: c_int = 1;
+const EPOLL_CTL_DEL: c_int = 2;
+const EPOLLIN: u32 = 0x1;
+const EPOLLRDHUP: u32 = 0x2000;
+
+// How often the loop looks for connections that timed out, and for shutdown, in milliseconds
+const TICK: c_int = 250;
+const MAX_EVENTS: usize = 64;
+
+// The kernel's struct epoll_event, which is packed on x86_64 only
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+// epoll comes from libc which std already links, so no extra crate is needed
+unsafe extern "C" {
+    fn epoll_create1(flags: c_int) -> c_int;
+    fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+    fn epoll_wait(epfd: c_int, events: *mut EpollEvent, max_events: c_int, timeout: c_int) -> c_int;
+}
+
+// Connections waiting for their next request without a worker, and what the stock handler
+// needs to pick each one back up once it has arrived
+pub(crate) struct EventLoop {
+    epoll: OwnedFd,
+    parking: Mutex<Parking>,
+    hosts: VirtualHosts,
+    http: HttpOptions,
+    open: OpenConnections,
+    read_timeout: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Parking {
+    // the loop has stopped, connections that would be parked get closed instead
+    closed: bool,
+    parked: HashMap<u64, Parked>,
+}
+
+struct Parked {
+    stream: Connection,
+    resume: Resume,
+    live: LiveConnection,
+    last_read: Instant,
+    // when the request's first byte came, the head deadline counts from there
+    started: Option<Instant>,
+}
+
+impl EventLoop {
+    fn new(server: &Server) -> io::Result<EventLoop> {
+        // SAFETY: plain epoll_create1(2) call, the result is checked before use
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLoop {
+            // SAFETY: a fresh fd nothing else owns
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            parking: Mutex::default(),
+            hosts: server.site_hosts(),
+            http: server.http.clone(),
+            open: server.open.clone(),
+            read_timeout: server.read_timeout,
+        })
+    }
+
+    // Wait for 'stream' to send its next request without holding a worker, it's closed if it can't
+    fn park(&self, stream: Connection, id: u64, resume: Resume, live: LiveConnection) {
+        // only raw sockets get here, see ServerBuilder::event_loop
+        let Some(fd) = stream.raw_fd() else { return };
+        if let Err(e) = stream.set_nonblocking(true) {
+            log!(Warn, "Failed to park {}: {}", stream, e);
+            return;
+        }
+        let mut parking = self.lock();
+        if parking.closed {
+            return;
+        }
+        let mut event = EpollEvent { events: EPOLLIN | EPOLLRDHUP, data: id };
+        // SAFETY: both fds are open and event lives across the call
+        if unsafe { epoll_ctl(self.epoll.as_raw_fd(), EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            log!(Error, "Failed to add {} to the event loop: {}", stream, io::Error::last_os_error());
+            return;
+        }
+        let started = (!resume.pending.is_empty()).then(Instant::now);
+        parking.parked.insert(id, Parked { stream, resume, live, last_read: Instant::now(), started });
+    }
+
+    // Read what 'id' has sent, Some once it needs a worker: a whole head is in, or more than fits in one
+    fn readable(&self, id: u64) -> Option<Parked> {
+        let mut parking = self.lock();
+        let parked = parking.parked.get_mut(&id)?;
+        let mut buf = vec![0; self.http.read_buffer];
+        loop {
+            match parked.stream.read(&mut buf) {
+                // the client hung up, or drain shut our side
+                Ok(0) => break,
+                Ok(n) => {
+                    let pending = &mut parked.resume.pending;
+                    pending.extend_from_slice(&buf[..n]);
+                    parked.last_read = Instant::now();
+                    parked.started.get_or_insert_with(Instant::now);
+                    if has_head(pending) || pending.len() > self.http.max_head_len {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log!(Debug, "Dropping {}: {}", parked.stream, e);
+                    break;
+                }
+            }
+        }
+        let ready = has_head(&parked.resume.pending) || parked.resume.pending.len() > self.http.max_head_len;
+        let parked = self.unpark(&mut parking, id)?;
+        // anything else is a connection to close, which dropping it does
+        ready.then_some(parked)
+    }
+
+    // Take the connections whose client has been quiet too long off the loop
+    fn expire(&self) -> Vec<Parked> {
+        let mut parking = self.lock();
+        let expired: Vec<u64> = parking
+            .parked
+            .iter()
+            .filter(|(_, parked)| {
+                self.read_timeout.is_some_and(|timeout| parked.last_read.elapsed() > timeout)
+                    || parked.started.is_some_and(|started| started.elapsed() > self.http.head_deadline)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        expired.into_iter().filter_map(|id| self.unpark(&mut parking, id)).collect()
+    }
+
+    // Stop parking and close everything parked, they're all between requests
+    fn close(&self) {
+        let mut parking = self.lock();
+        parking.closed = true;
+        let ids: Vec<u64> = parking.parked.keys().copied().collect();
+        for id in ids {
+            self.unpark(&mut parking, id);
+        }
+    }
+
+    // epoll watches the socket, not the fd, so the copy in OpenConnections would keep it registered
+    // after we drop ours, it has to come off explicitly
+    fn unpark(&self, parking: &mut Parking, id: u64) -> Option<Parked> {
+        let parked = parking.parked.remove(&id)?;
+        if let Some(fd) = parked.stream.raw_fd() {
+            // SAFETY: both fds are open, DEL ignores the event
+            unsafe { epoll_ctl(self.epoll.as_raw_fd(), EPOLL_CTL_DEL, fd, ptr::null_mut()) };
+        }
+        Some(parked)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Parking> {
+        self.parking.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Server {
+    // serve_static, with connections waiting for their requests on an EventLoop instead of a worker
+    pub(crate) fn serve_events(mut self) {
+        let events = match EventLoop::new(&self) {
+            Ok(events) => Arc::new(events),
+            Err(e) => {
+                log!(Error, "Failed to start the event loop, serving without it: {}", e);
+                self.event_loop = false;
+                self.serve_static();
+                return;
+            }
+        };
+        self.run(
+            &|stream, id, live| events.park(stream, id, Resume::default(), live),
+            Some(("web-events", &|| self.event_loop(&events))),
+        );
+    }
+
+    // Hand parked connections to the pool as their requests come in, until shutdown
+    fn event_loop(&self, events: &Arc<EventLoop>) {
+        let mut ready = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+        while !self.stop.load(Ordering::SeqCst) {
+            // SAFETY: ready has room for MAX_EVENTS events
+            let n = unsafe { epoll_wait(events.epoll.as_raw_fd(), ready.as_mut_ptr(), MAX_EVENTS as c_int, TICK) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                log!(Error, "Event loop failed, closing its connections: {}", e);
+                break;
+            }
+            for event in &ready[..n as usize] {
+                let id = event.data;
+                if let Some(parked) = events.readable(id) {
+                    self.dispatch_parked(events, id, parked);
+                }
+            }
+            for mut parked in events.expire() {
+                // an idle keep-alive connection just gets closed, a client we're still waiting on gets told
+                if parked.resume.served == 0 || !parked.resume.pending.is_empty() {
+                    log!(Debug, "{} didn't finish its request in time", parked.stream);
+                    refuse(&mut parked.stream, StatusCode::RequestTimeout, events.hosts.default_root(), &self.http);
+                }
+            }
+        }
+        events.close();
+    }
+
+    // Serve the request 'parked' has sent on the pool, it comes back to the loop between requests
+    fn dispatch_parked(&self, events: &Arc<EventLoop>, id: u64, parked: Parked) {
+        let Parked { stream, resume, live, .. } = parked;
+        if let Err(e) = stream.set_nonblocking(false) {
+            log!(Warn, "Dropping {}: {}", stream, e);
+            return;
+        }
+        let overflow = stream.try_clone();
+        let events = Arc::clone(events);
+        let job = move || {
+            let tracked = events.open.tracked(id);
+            if let Some((stream, resume)) = resume_site(stream, &events.hosts, &events.http, &tracked, resume) {
+                events.park(stream, id, resume, live);
+            }
+        };
+        if self.pool.try_execute(job).is_err() {
+            log!(Warn, "Queue full, turning a client away");
+            match overflow {
+                Ok(stream) => self.turn_away(stream),
+                Err(e) => log!(Error, "Queue full and failed to clone stream: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Server;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    fn get(stream: &mut TcpStream, body: &str) -> String {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(body.as_bytes()) {
+            let mut buf = [0; 256];
+            let n = stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0, "closed after {:?}", String::from_utf8_lossy(&reply));
+            reply.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_idle_connections_free_the_worker() {
+        let root = std::env::temp_dir().join(format!("webserver-events-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hi").unwrap();
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .doc_root(&root)
+            .event_loop(true)
+            .read_timeout(Some(Duration::from_millis(500)))
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = server.spawn_static().unwrap();
+
+        // with one worker, the second connection only gets served if the first gave it up
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        for _ in 0..3 {
+            assert!(get(&mut first, "hi").contains("Connection: keep-alive"));
+            assert!(get(&mut second, "hi").starts_with("HTTP/1.1 200"));
+        }
+        // a request that arrives in pieces is only handed over once it's all there
+        first.write_all(b"GET / HT").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(get(&mut second, "hi").starts_with("HTTP/1.1 200"));
+        first.write_all(b"TP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = [0; 15];
+        first.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"HTTP/1.1 200 OK");
+
+        // quiet connections are closed after the read timeout
+        let mut quiet = TcpStream::connect(addr).unwrap();
+        get(&mut quiet, "hi");
+        assert_eq!(quiet.read(&mut [0; 16]).unwrap(), 0);
+
+        running.shutdown().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
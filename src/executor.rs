@@ -0,0 +1,177 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Mutex, Weak},
+    task::{Context, Poll, Wake, Waker},
+};
+
+use crate::{JobHandle, JobToken, Shared, ThreadPool};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+// A spawned future, each wake queues one more poll of it as an ordinary job
+struct Task {
+    future: Mutex<Option<BoxFuture>>,
+    shared: Weak<Shared>,
+    token: JobToken,
+    // already waiting in a queue, so further wakes don't need to queue another poll
+    scheduled: AtomicBool,
+}
+
+impl Task {
+    fn poll(self: Arc<Self>) {
+        self.scheduled.store(false, Ordering::SeqCst);
+        let mut slot = self.future.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(future) = slot.as_mut() else { return };
+        if self.token.is_cancelled() {
+            // dropping the future drops its result sender, which the handle reports as cancelled
+            *slot = None;
+            return;
+        }
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+            Ok(Poll::Pending) => {}
+            Ok(Poll::Ready(())) => *slot = None,
+            Err(payload) => {
+                // drop it before the worker sees the panic so the handle doesn't wait forever
+                *slot = None;
+                drop(slot);
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        if self.scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // pool is gone, so is anyone who could want the result
+        let Some(shared) = self.shared.upgrade() else { return };
+        shared.push_unbounded(Box::new(move || self.poll()));
+    }
+}
+
+impl ThreadPool {
+    /// Drive 'future' to completion on the pool, each poll runs as a job on whichever worker is free
+    /// Meant for small async tasks alongside the usual blocking handlers, not as a full runtime:
+    /// a future that blocks inside poll holds up a worker like any other job
+    ///
+    /// The first poll is queued like 'execute', later ones skip the bounded queue check
+    /// Cancelling the handle drops the future at its next poll
+    ///
+    /// ```
+    /// let pool = webserver::ThreadPool::new(2);
+    /// let handle = pool.spawn_future(async { 20 + 22 });
+    /// assert_eq!(handle.join(), Ok(42));
+    /// ```
+    pub fn spawn_future<Fut>(&self, future: Fut) -> JobHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (result_sender, receiver) = mpsc::channel();
+        let token = JobToken::new();
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(async move {
+                // the caller may have dropped the handle, that's fine
+                let _ = result_sender.send(future.await);
+            }))),
+            shared: Arc::downgrade(&self.shared),
+            token: token.clone(),
+            scheduled: AtomicBool::new(true),
+        });
+        // if this job is rejected or lost the task goes with it, and so does the result sender
+        drop(self.execute(move || task.poll()));
+        JobHandle::new(receiver, token)
+    }
+}
+
+// futures::task::Spawn is the trait re-exported from futures-task
+#[cfg(feature = "futures")]
+impl futures_task::Spawn for ThreadPool {
+    /// Runs 'future' as spawn_future would, with nobody waiting on the result
+    /// Only a pool that's shutting down refuses it, one whose queue is full treats it like any other job
+    fn spawn_obj(&self, future: futures_task::FutureObj<'static, ()>) -> Result<(), futures_task::SpawnError> {
+        self.status()?;
+        drop(self.spawn_future(future));
+        Ok(())
+    }
+
+    fn status(&self) -> Result<(), futures_task::SpawnError> {
+        if self.is_shut_down() { Err(futures_task::SpawnError::shutdown()) } else { Ok(()) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JobError;
+    use std::{thread, time::Duration};
+
+    // Completes once some other thread flips 'ready' and wakes us, like a tiny I/O future would
+    struct Flag {
+        ready: Arc<AtomicBool>,
+        waker: Arc<Mutex<Option<Waker>>>,
+    }
+
+    impl Future for Flag {
+        type Output = &'static str;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            if self.ready.load(Ordering::SeqCst) { Poll::Ready("done") } else { Poll::Pending }
+        }
+    }
+
+    #[test]
+    fn test_future_woken_from_another_thread() {
+        let pool = ThreadPool::new(2);
+        let ready = Arc::new(AtomicBool::new(false));
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+        let handle = pool.spawn_future(Flag { ready: Arc::clone(&ready), waker: Arc::clone(&waker) });
+
+        while waker.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        ready.store(true, Ordering::SeqCst);
+        waker.lock().unwrap().take().unwrap().wake();
+        assert_eq!(handle.join(), Ok("done"));
+    }
+
+    #[test]
+    fn test_cancelled_future_is_dropped() {
+        let pool = ThreadPool::new(1);
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::default();
+        let handle = pool.spawn_future(Flag { ready: Arc::default(), waker: Arc::clone(&waker) });
+        while waker.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.cancel();
+        waker.lock().unwrap().take().unwrap().wake();
+        assert_eq!(handle.join(), Err(JobError::Cancelled));
+    }
+
+    #[test]
+    fn test_panicking_future_reports_lost() {
+        let pool = ThreadPool::with_panic_hook(1, |_, _| {});
+        let handle = pool.spawn_future(async { panic!("boom") });
+        assert_eq!(handle.join(), Err::<(), _>(JobError::Lost));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_spawn_trait() {
+        use futures_task::{FutureObj, Spawn};
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+        pool.spawn_obj(FutureObj::new(Box::new(async move { sender.send(42).unwrap() }))).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(42));
+        assert!(pool.status().is_ok());
+        pool.shutdown(Duration::from_secs(1));
+        assert!(pool.spawn_obj(FutureObj::new(Box::new(async {}))).unwrap_err().is_shutdown());
+    }
+}
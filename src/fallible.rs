@@ -0,0 +1,81 @@
+use std::{
+    error::Error,
+    sync::{mpsc, Arc, Mutex},
+};
+
+use crate::{JobHandle, LogLevel, ThreadPool};
+
+/// An error returned by a job queued with ThreadPool::execute_fallible
+pub type JobFailure = Box<dyn Error + Send + Sync>;
+
+/// Receives every JobFailure, set with ThreadPool::set_error_handler
+pub type ErrorHandler = Arc<dyn Fn(JobFailure) + Send + Sync>;
+
+impl ThreadPool {
+    /// Queue a job that can fail, an Err is passed on to the pool's error handler instead of
+    /// having to be dealt with inside the closure. Without a handler it goes to the observer's on_message
+    /// The handle gives back true if the job returned Ok
+    ///
+    /// ```
+    /// let pool = webserver::ThreadPool::new(1);
+    /// let errors = pool.error_channel();
+    /// let handle = pool.execute_fallible(|| std::fs::read("no/such/file").map(drop));
+    /// assert_eq!(handle.join(), Ok(false));
+    /// assert!(errors.recv().unwrap().to_string().contains("No such file"));
+    /// ```
+    pub fn execute_fallible<F, E>(&self, f: F) -> JobHandle<bool>
+    where
+        F: FnOnce() -> Result<(), E> + Send + 'static,
+        E: Into<JobFailure>,
+    {
+        // weak so a job left sitting in a queue doesn't keep the pool's state alive
+        let shared = Arc::downgrade(&self.shared);
+        self.execute(move || match f() {
+            Ok(()) => true,
+            Err(e) => {
+                let Some(shared) = shared.upgrade() else { return false };
+                let handler = shared.error_handler.read().unwrap_or_else(|e| e.into_inner()).clone();
+                match handler {
+                    Some(handler) => handler(e.into()),
+                    None => shared.report(LogLevel::Error, format_args!("Job failed: {}", e.into())),
+                }
+                false
+            }
+        })
+    }
+
+    /// Install a handler for errors returned by jobs from execute_fallible, it runs on the worker
+    /// that ran the job. Replaces any handler (or error_channel) set before
+    pub fn set_error_handler<H>(&self, handler: H)
+    where H: Fn(JobFailure) + Send + Sync + 'static
+    {
+        *self.shared.error_handler.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(handler));
+    }
+
+    /// Collect errors from execute_fallible jobs on a channel instead of a callback
+    /// Errors are dropped once the receiver is, replaces any handler set before
+    pub fn error_channel(&self) -> mpsc::Receiver<JobFailure> {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        self.set_error_handler(move |e| {
+            // nobody listening any more, that's fine
+            let _ = sender.lock().unwrap_or_else(|e| e.into_inner()).send(e);
+        });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execute_fallible_forwards_errors() {
+        let pool = ThreadPool::new(2);
+        let errors = pool.error_channel();
+        assert_eq!(pool.execute_fallible(|| Ok::<(), String>(())).join(), Ok(true));
+        assert_eq!(pool.execute_fallible(|| Err("bad request")).join(), Ok(false));
+        assert_eq!(errors.recv().unwrap().to_string(), "bad request");
+        assert!(errors.try_recv().is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    io,
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+use crate::{logging::log, ThreadPool, ThreadPoolBuilder};
+
+/// Overrides the global pool's size, read once when it is first used
+pub const THREADS_ENV: &str = "WEBSERVER_THREADS";
+
+static GLOBAL: OnceLock<ThreadPool> = OnceLock::new();
+// build_global and the lazy default both go through here so only one pool ever gets spawned
+static INIT: Mutex<()> = Mutex::new(());
+
+impl ThreadPool {
+    /// The process wide pool, created on first use unless build_global got there first
+    /// Sized from $WEBSERVER_THREADS, or one worker per core if that isn't set
+    ///
+    /// # Panics
+    /// Panics if the pool has to be created and its threads can't be spawned
+    pub fn global() -> &'static ThreadPool {
+        if let Some(pool) = GLOBAL.get() {
+            return pool;
+        }
+        let _guard = INIT.lock().unwrap_or_else(|e| e.into_inner());
+        GLOBAL.get_or_init(|| {
+            ThreadPoolBuilder::new()
+                .num_threads(default_threads())
+                .thread_name("global-worker")
+                .build()
+                .expect("failed to spawn global pool worker thread")
+        })
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Build this pool as the one ThreadPool::global hands out
+    ///
+    /// # Errors
+    /// Returns an AlreadyExists error if the global pool was already set up,
+    /// or the OS error if a worker thread couldn't be spawned
+    pub fn build_global(self) -> io::Result<&'static ThreadPool> {
+        let _guard = INIT.lock().unwrap_or_else(|e| e.into_inner());
+        if GLOBAL.get().is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the global thread pool is already initialized"));
+        }
+        let pool = self.build()?;
+        Ok(GLOBAL.get_or_init(|| pool))
+    }
+}
+
+fn default_threads() -> usize {
+    if let Ok(value) = std::env::var(THREADS_ENV) {
+        match value.trim().parse::<usize>() {
+            Ok(threads) if threads > 0 => return threads,
+            _ => log!(Warn, "Ignoring {}={:?}, expected a positive number", THREADS_ENV, value),
+        }
+    }
+    thread::available_parallelism().map_or(4, |threads| threads.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_global_pool_is_shared() {
+        let pool = ThreadPool::global();
+        assert!(std::ptr::eq(pool, ThreadPool::global()));
+        assert_eq!(pool.execute(|| 5).join(), Ok(5));
+
+        let err = ThreadPoolBuilder::new().num_threads(1).build_global().err();
+        assert_eq!(err.map(|e| e.kind()), Some(io::ErrorKind::AlreadyExists));
+    }
+}
@@ -0,0 +1,1443 @@
+// HTTP/2 (RFC 9113) for the stock handler, as much of it as serving pages takes. A client
+// that opens with the connection preface, knowing beforehand that we speak it (h2c) or having
+// picked h2 with ALPN, gets its streams answered one at a time, in the order their requests
+// are complete; several can be open at once, they just don't get answered side by side.
+// Header blocks come in HPACK (RFC 7541) and go back out as plain literals, which leave the
+// client's table alone. There's no server push, and no Upgrade: h2c, which RFC 9113 dropped
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    path::Path,
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use crate::{
+    date,
+    drain::TrackedConnection,
+    handler::{self, HttpOptions, MAX_REQUESTS_PER_CONNECTION},
+    headers::is_token,
+    logging::log,
+    Body, Method, ParseError, Request, Response, StatusCode, Version,
+};
+
+// What's left of the client's preface after its "PRI * HTTP/2.0" head
+const PREFACE_REST: &[u8] = b"SM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes for RST_STREAM and GOAWAY
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// The frame size and flow control window both sides start with. We never ask for bigger frames
+const DEFAULT_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+// Streams a client can have open at once, more are refused
+const MAX_STREAMS: usize = 100;
+
+// Headers about the connection rather than the message, which HTTP/2 handles itself (RFC 9113 8.2.2)
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Serve HTTP/2 on 'stream', whose "PRI * HTTP/2.0" head has been read as if it were HTTP/1.1,
+/// 'pending' being whatever came after it. 'doc_root' picks the pages for a request's host as
+/// serve_requests' does, 'tracked' lets a shutdown end the connection with a GOAWAY
+pub(crate) fn serve<'a, S: Read + Write>(
+    stream: &mut S,
+    pending: Vec<u8>,
+    options: &HttpOptions,
+    tracked: Option<&TrackedConnection>,
+    doc_root: impl Fn(Option<&str>) -> &'a Path,
+) {
+    let mut conn = Conn::new(stream, pending, options);
+    match conn.run(tracked, &doc_root) {
+        Ok(()) => {}
+        Err(Failure::Protocol(code, why)) => {
+            log!(Debug, "Closing HTTP/2 connection: {}", why);
+            conn.goaway(code);
+        }
+        // an idle connection timing out is the usual way for one to end
+        Err(Failure::Io(e)) if handler::is_timeout(&e) => conn.goaway(NO_ERROR),
+        Err(Failure::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => log!(Debug, "HTTP/2 client went away: {}", e),
+        Err(Failure::Io(e)) => log!(Error, "HTTP/2 connection failed: {}", e),
+    }
+    if let Err(e) = conn.flush() {
+        log!(Debug, "Failed to write to HTTP/2 client: {}", e);
+    }
+}
+
+// Why a connection ends before the client is done with it
+#[derive(Debug)]
+enum Failure {
+    Io(io::Error),
+    // the client broke the protocol, and gets a GOAWAY with this code
+    Protocol(u32, String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+fn protocol<T>(code: u32, why: impl Into<String>) -> Result<T, Failure> {
+    Err(Failure::Protocol(code, why.into()))
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// A stream the client has opened and we haven't finished answering
+struct Stream {
+    // the request, its body filling in until the client ends the stream, or the status to
+    // refuse it with. Taken once it's being answered
+    request: Option<Result<Request, StatusCode>>,
+    // whether the client has sent all it's going to
+    ended: bool,
+    // how much we may send on it before the client makes room with a WINDOW_UPDATE
+    window: i64,
+}
+
+// One HTTP/2 connection, from our side
+struct Conn<'s, S> {
+    stream: &'s mut S,
+    options: &'s HttpOptions,
+    // read off the socket but not made into a frame yet
+    pending: Vec<u8>,
+    // frames waiting to go out in one write
+    out: Vec<u8>,
+    decoder: Decoder,
+    streams: BTreeMap<u32, Stream>,
+    // the highest stream id the client has used, and how many it has opened
+    last_stream: u32,
+    opened: usize,
+    // what the client's settings let us send: frame size, the window new streams start with,
+    // and what's left of the connection's own window
+    frame_size: usize,
+    initial_window: i64,
+    window: i64,
+    // either side has said GOAWAY, no more streams are taken
+    going_away: bool,
+}
+
+impl<'s, S: Read + Write> Conn<'s, S> {
+    fn new(stream: &'s mut S, pending: Vec<u8>, options: &'s HttpOptions) -> Self {
+        Conn {
+            stream,
+            options,
+            pending,
+            out: Vec::new(),
+            decoder: Decoder::new(options.max_head_len),
+            streams: BTreeMap::new(),
+            last_stream: 0,
+            opened: 0,
+            frame_size: DEFAULT_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            window: DEFAULT_WINDOW,
+            going_away: false,
+        }
+    }
+
+    fn run<'a>(&mut self, tracked: Option<&TrackedConnection>, doc_root: &dyn Fn(Option<&str>) -> &'a Path) -> Result<(), Failure> {
+        if !self.fill(PREFACE_REST.len())? || !self.pending.starts_with(PREFACE_REST) {
+            return protocol(PROTOCOL_ERROR, "not an HTTP/2 connection preface");
+        }
+        self.pending.drain(..PREFACE_REST.len());
+        // our half of the preface, the limits we hold the client to
+        let max_head = u32::try_from(self.options.max_head_len).unwrap_or(u32::MAX);
+        let settings = [(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32), (SETTINGS_MAX_HEADER_LIST_SIZE, max_head)];
+        let payload: Vec<u8> = settings.iter().flat_map(|(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()].concat()).collect();
+        self.frame(SETTINGS, 0, 0, &payload);
+        loop {
+            // like HTTP/1.1's keep-alive, a connection gets so many requests, and none once we're shutting down
+            if !self.going_away && (self.opened >= MAX_REQUESTS_PER_CONNECTION || tracked.is_some_and(TrackedConnection::draining)) {
+                self.goaway(NO_ERROR);
+            }
+            if let Some(id) = self.next_ready() {
+                if let Some(tracked) = tracked {
+                    tracked.count_request();
+                }
+                self.answer(id, doc_root)?;
+                continue;
+            }
+            if self.going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+            self.flush()?;
+            // nothing's lost closing a connection without open streams, so shutdown can
+            let idle = self.streams.is_empty();
+            if idle
+                && let Some(tracked) = tracked
+                && !tracked.set_idle(true)
+            {
+                return Ok(());
+            }
+            let frame = self.read_frame();
+            if idle && let Some(tracked) = tracked {
+                tracked.set_idle(false);
+            }
+            match frame? {
+                Some(frame) => self.on_frame(frame)?,
+                // the client hung up between frames
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The lowest stream whose request has all arrived, or that's to be refused
+    fn next_ready(&self) -> Option<u32> {
+        self.streams.iter().find(|(_, stream)| matches!(stream.request, Some(Err(_))) || (stream.ended && stream.request.is_some())).map(|(id, _)| *id)
+    }
+
+    // Read until 'n' bytes are pending. False if the client closed the connection with
+    // nothing pending, an UnexpectedEof if it closed it part way
+    fn fill(&mut self, n: usize) -> io::Result<bool> {
+        while self.pending.len() < n {
+            let start = self.pending.len();
+            self.pending.resize(start + self.options.read_buffer.max(n - start), 0);
+            let read = self.stream.read(&mut self.pending[start..]);
+            self.pending.truncate(start + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) if start == 0 => return Ok(false),
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-frame")),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    // The next frame, None if the client closed the connection between frames
+    fn read_frame(&mut self) -> Result<Option<Frame>, Failure> {
+        if !self.fill(9)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.pending[0], self.pending[1], self.pending[2]]) as usize;
+        if len > DEFAULT_FRAME_SIZE {
+            return protocol(FRAME_SIZE_ERROR, format!("a {} byte frame", len));
+        }
+        if !self.fill(9 + len)? {
+            return Ok(None);
+        }
+        let (kind, flags) = (self.pending[3], self.pending[4]);
+        let stream = u32::from_be_bytes([self.pending[5], self.pending[6], self.pending[7], self.pending[8]]) & 0x7fff_ffff;
+        let payload = self.pending[9..9 + len].to_vec();
+        self.pending.drain(..9 + len);
+        Ok(Some(Frame { kind, flags, stream, payload }))
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<(), Failure> {
+        match frame.kind {
+            DATA => self.on_data(frame),
+            HEADERS => self.on_headers(frame),
+            PRIORITY if frame.stream == 0 => protocol(PROTOCOL_ERROR, "PRIORITY for the connection"),
+            RST_STREAM => {
+                if frame.payload.len() != 4 {
+                    return protocol(FRAME_SIZE_ERROR, "RST_STREAM isn't 4 bytes");
+                }
+                if frame.stream == 0 || frame.stream > self.last_stream {
+                    return protocol(PROTOCOL_ERROR, format!("RST_STREAM for idle stream {}", frame.stream));
+                }
+                self.streams.remove(&frame.stream);
+                Ok(())
+            }
+            SETTINGS => self.on_settings(frame),
+            PUSH_PROMISE => protocol(PROTOCOL_ERROR, "PUSH_PROMISE from a client"),
+            PING => {
+                if frame.stream != 0 {
+                    return protocol(PROTOCOL_ERROR, "PING on a stream");
+                }
+                if frame.payload.len() != 8 {
+                    return protocol(FRAME_SIZE_ERROR, "PING isn't 8 bytes");
+                }
+                if frame.flags & ACK == 0 {
+                    self.frame(PING, ACK, 0, &frame.payload);
+                }
+                Ok(())
+            }
+            GOAWAY if frame.stream != 0 => protocol(PROTOCOL_ERROR, "GOAWAY on a stream"),
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(frame),
+            CONTINUATION => protocol(PROTOCOL_ERROR, "CONTINUATION without HEADERS"),
+            // PRIORITY is advice we don't take, unknown frame types are to be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, frame: Frame) -> Result<(), Failure> {
+        let id = frame.stream;
+        if id == 0 || id > self.last_stream {
+            return protocol(PROTOCOL_ERROR, format!("DATA on idle stream {}", id));
+        }
+        // padding counts against the window too; it all goes straight back, the body limit
+        // is what bounds how much we hold
+        let len = frame.payload.len();
+        if len > 0 {
+            self.window_update(0, len);
+        }
+        let data = unpad(&frame)?;
+        let end = frame.flags & END_STREAM != 0;
+        // a stream already answered or reset, whose data was in flight
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Ok(());
+        };
+        if stream.ended {
+            self.reset(id, STREAM_CLOSED);
+            return Ok(());
+        }
+        let max_body_size = self.options.max_body_size;
+        if let Some(Ok(request)) = &mut stream.request {
+            if (request.body.len() + data.len()) as u64 > max_body_size {
+                log!(Debug, "Refusing a body over {} bytes", max_body_size);
+                stream.request = Some(Err(StatusCode::ContentTooLarge));
+            } else {
+                request.body.extend_from_slice(data);
+                if !end && len > 0 {
+                    self.window_update(id, len);
+                }
+            }
+        }
+        if end {
+            self.end_stream(id);
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: Frame) -> Result<(), Failure> {
+        let id = frame.stream;
+        if id == 0 {
+            return protocol(PROTOCOL_ERROR, "HEADERS for the connection");
+        }
+        let mut fragment = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            fragment = fragment.get(5..).ok_or_else(|| Failure::Protocol(FRAME_SIZE_ERROR, "HEADERS too short for its priority".to_string()))?;
+        }
+        // the rest of the block comes in CONTINUATION frames, with nothing in between
+        let mut block = fragment.to_vec();
+        let mut flags = frame.flags;
+        while flags & END_HEADERS == 0 {
+            let Some(next) = self.read_frame()? else {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-headers").into());
+            };
+            if next.kind != CONTINUATION || next.stream != id {
+                return protocol(PROTOCOL_ERROR, "header block interrupted");
+            }
+            block.extend_from_slice(&next.payload);
+            flags = next.flags;
+            // still compressed, so bigger than this can only be bigger than max_head_len too
+            if block.len() > self.options.max_head_len {
+                return protocol(ENHANCE_YOUR_CALM, format!("a header block over {} bytes", self.options.max_head_len));
+            }
+        }
+        // decoded even for a stream we refuse, the table has to stay in step with the client's
+        let fields = self.decoder.decode(&block)?;
+        let end = frame.flags & END_STREAM != 0;
+        if let Some(stream) = self.streams.get_mut(&id) {
+            // trailers, which have to end the stream
+            if stream.ended {
+                self.reset(id, STREAM_CLOSED);
+                return Ok(());
+            }
+            if let Some(Ok(request)) = &mut stream.request {
+                match trailers_from(fields) {
+                    Ok(trailers) if end => request.trailers = trailers,
+                    _ => stream.request = Some(Err(StatusCode::BadRequest)),
+                }
+            }
+            if end {
+                self.end_stream(id);
+            }
+            return Ok(());
+        }
+        if id.is_multiple_of(2) {
+            return protocol(PROTOCOL_ERROR, format!("HEADERS opening even stream {}", id));
+        }
+        // a stream we've answered and forgotten, or one the client skipped, closed either way (RFC 9113 5.1)
+        if id <= self.last_stream {
+            self.reset(id, STREAM_CLOSED);
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.going_away || self.streams.len() >= MAX_STREAMS {
+            self.reset(id, REFUSED_STREAM);
+            return Ok(());
+        }
+        self.opened += 1;
+        let request = request_from(fields, self.options).map_err(|(status, why)| {
+            log!(Debug, "Refusing request: {}", why);
+            status
+        });
+        self.streams.insert(id, Stream { request: Some(request), ended: false, window: self.initial_window });
+        if end {
+            self.end_stream(id);
+        }
+        Ok(())
+    }
+
+    // The client has sent all of stream 'id', whose body has to add up to any Content-Length
+    fn end_stream(&mut self, id: u32) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        stream.ended = true;
+        if let Some(Ok(request)) = &stream.request
+            && let Some(len) = request.header("content-length")
+            && len.parse() != Ok(request.body.len())
+        {
+            log!(Debug, "Refusing a {} byte body with Content-Length {}", request.body.len(), len);
+            stream.request = Some(Err(StatusCode::BadRequest));
+        }
+    }
+
+    fn on_settings(&mut self, frame: Frame) -> Result<(), Failure> {
+        if frame.stream != 0 {
+            return protocol(PROTOCOL_ERROR, "SETTINGS on a stream");
+        }
+        if frame.flags & ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => protocol(FRAME_SIZE_ERROR, "SETTINGS ack with a payload"),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return protocol(FRAME_SIZE_ERROR, "SETTINGS not in 6 byte pieces");
+        }
+        for setting in frame.payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return protocol(PROTOCOL_ERROR, "ENABLE_PUSH past 1"),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return protocol(FLOW_CONTROL_ERROR, "INITIAL_WINDOW_SIZE past 2^31-1");
+                    }
+                    // open streams' windows move by the difference (RFC 9113 6.9.2)
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+                    for stream in self.streams.values_mut() {
+                        stream.window += delta;
+                        if stream.window > MAX_WINDOW {
+                            return protocol(FLOW_CONTROL_ERROR, "INITIAL_WINDOW_SIZE overflows a stream's window");
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => {
+                    return protocol(PROTOCOL_ERROR, format!("MAX_FRAME_SIZE of {}", value));
+                }
+                SETTINGS_MAX_FRAME_SIZE => self.frame_size = value as usize,
+                // the rest are about what the client takes from us, which plain literals and
+                // one stream at a time stay within
+                _ => {}
+            }
+        }
+        self.frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: Frame) -> Result<(), Failure> {
+        let Ok(increment) = <[u8; 4]>::try_from(frame.payload.as_slice()) else {
+            return protocol(FRAME_SIZE_ERROR, "WINDOW_UPDATE isn't 4 bytes");
+        };
+        let increment = i64::from(u32::from_be_bytes(increment) & 0x7fff_ffff);
+        if frame.stream == 0 {
+            self.window += increment;
+            return match increment == 0 || self.window > MAX_WINDOW {
+                true => protocol(FLOW_CONTROL_ERROR, "bad WINDOW_UPDATE for the connection"),
+                false => Ok(()),
+            };
+        }
+        if frame.stream > self.last_stream {
+            return protocol(PROTOCOL_ERROR, format!("WINDOW_UPDATE for idle stream {}", frame.stream));
+        }
+        if let Some(stream) = self.streams.get_mut(&frame.stream) {
+            stream.window += increment;
+            if increment == 0 || stream.window > MAX_WINDOW {
+                self.reset(frame.stream, FLOW_CONTROL_ERROR);
+            }
+        }
+        Ok(())
+    }
+
+    // Answer stream 'id' with the page for its request, like serve_requests does over HTTP/1.1
+    fn answer<'a>(&mut self, id: u32, doc_root: &dyn Fn(Option<&str>) -> &'a Path) -> Result<(), Failure> {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Ok(());
+        };
+        let ended = stream.ended;
+        let (request, response) = match stream.request.take() {
+            Some(Ok(mut request)) => {
+                request.id = handler::request_id(&request);
+                let response = handler::page(&request, doc_root(request.host()), self.options);
+                let response = handler::finish(&request, response);
+                (Some(request), response)
+            }
+            Some(Err(status)) => (None, handler::error_response(status, doc_root(None), self.options)),
+            None => return Ok(()),
+        };
+        let response = handler::stamp(response, self.options, true);
+        let status = response.status;
+        let head_only = request.as_ref().is_some_and(|request| request.method == Method::Head);
+        self.write_response(id, response, head_only)?;
+        if let Some(request) = &request {
+            handler::access_log(request, status);
+        }
+        // a response before the whole request, to a 413 say, leaves the client nothing to send
+        if !ended && self.streams.contains_key(&id) {
+            self.reset(id, NO_ERROR);
+        }
+        self.streams.remove(&id);
+        self.flush()?;
+        Ok(())
+    }
+
+    // Send 'response' on stream 'id': its headers, then the body in DATA frames as the
+    // client's windows allow, reading frames while they're shut. Just the headers if
+    // 'head_only'. A body that can't be read, or ends short of its Content-Length, gets the
+    // stream reset rather than the connection dropped
+    fn write_response(&mut self, id: u32, response: Response, head_only: bool) -> Result<(), Failure> {
+        let has_body = response.has_body() && !head_only;
+        self.header_frames(id, &encode_headers(&response), !has_body);
+        if !has_body {
+            return Ok(());
+        }
+        let declared = response.headers.get("content-length").and_then(|len| len.trim().parse::<u64>().ok());
+        let (mut body, limit): (Box<dyn Read + Send>, Option<u64>) = match response.body {
+            Body::Bytes(bytes) => {
+                let len = bytes.len() as u64;
+                (Box::new(Cursor::new(bytes)), Some(len))
+            }
+            Body::Reader(reader) => (reader, declared),
+        };
+        let mut buf = Vec::new();
+        let mut sent = 0;
+        loop {
+            let Some(room) = self.room(id)? else {
+                // the client reset the stream
+                return Ok(());
+            };
+            let want = room.min(self.frame_size).min(limit.map_or(usize::MAX, |limit| usize::try_from(limit - sent).unwrap_or(usize::MAX)));
+            buf.resize(want, 0);
+            let n = match body.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log!(Error, "Failed to read response body: {}", e);
+                    self.reset(id, INTERNAL_ERROR);
+                    return Ok(());
+                }
+            };
+            if n == 0 {
+                if limit.is_some_and(|limit| sent < limit) {
+                    log!(Error, "Response body ended {} bytes short of its Content-Length", limit.unwrap_or(0) - sent);
+                    self.reset(id, INTERNAL_ERROR);
+                } else {
+                    self.frame(DATA, END_STREAM, id, &[]);
+                }
+                return Ok(());
+            }
+            sent += n as u64;
+            let last = limit == Some(sent);
+            self.frame(DATA, if last { END_STREAM } else { 0 }, id, &buf[..n]);
+            self.window -= n as i64;
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.window -= n as i64;
+            }
+            self.flush()?;
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    // How much stream 'id' may send now, reading frames until both its window and the
+    // connection's have room. None once the client has reset the stream
+    fn room(&mut self, id: u32) -> Result<Option<usize>, Failure> {
+        loop {
+            let Some(stream) = self.streams.get(&id) else {
+                return Ok(None);
+            };
+            let room = self.window.min(stream.window);
+            if room > 0 {
+                return Ok(Some(room as usize));
+            }
+            self.flush()?;
+            match self.read_frame()? {
+                Some(frame) => self.on_frame(frame)?,
+                None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-response").into()),
+            }
+        }
+    }
+
+    // A header block as a HEADERS frame and as many CONTINUATIONs as it takes
+    fn header_frames(&mut self, id: u32, block: &[u8], end_stream: bool) {
+        let mut pieces = block.chunks(self.frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        loop {
+            let piece = pieces.next().unwrap_or_default();
+            if pieces.peek().is_none() {
+                self.frame(kind, flags | END_HEADERS, id, piece);
+                return;
+            }
+            self.frame(kind, flags, id, piece);
+            (kind, flags) = (CONTINUATION, 0);
+        }
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        self.out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.out.extend_from_slice(&[kind, flags]);
+        self.out.extend_from_slice(&stream.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+
+    fn reset(&mut self, id: u32, code: u32) {
+        self.frame(RST_STREAM, 0, id, &code.to_be_bytes());
+        self.streams.remove(&id);
+    }
+
+    fn window_update(&mut self, id: u32, increment: usize) {
+        self.frame(WINDOW_UPDATE, 0, id, &(increment as u32).to_be_bytes());
+    }
+
+    // Tell the client no more streams will be taken, the ones up to last_stream still get answered
+    fn goaway(&mut self, code: u32) {
+        self.frame(GOAWAY, 0, 0, &[self.last_stream.to_be_bytes(), code.to_be_bytes()].concat());
+        self.going_away = true;
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.out.is_empty() {
+            return Ok(());
+        }
+        let out = std::mem::take(&mut self.out);
+        self.stream.write_all(&out)?;
+        self.stream.flush()
+    }
+}
+
+// A DATA or HEADERS frame's payload without its padding
+fn unpad(frame: &Frame) -> Result<&[u8], Failure> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    match frame.payload.split_first() {
+        Some((&pad, rest)) if usize::from(pad) <= rest.len() => Ok(&rest[..rest.len() - usize::from(pad)]),
+        _ => protocol(PROTOCOL_ERROR, "more padding than frame"),
+    }
+}
+
+// A Request from a header block, by way of the HTTP/1.1 head it stands for, so it's held to
+// everything one of those would be. Malformed ones (RFC 9113 8.1.1) get a 400, those over
+// the server's limits the status an HTTP/1.1 request would
+fn request_from(fields: Vec<Field>, options: &HttpOptions) -> Result<Request, (StatusCode, String)> {
+    let bad = |why: String| (StatusCode::BadRequest, why);
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        // any of these would end the line it goes on in the head (RFC 9113 8.2.1)
+        if value.iter().any(|b| matches!(b, 0 | b'\r' | b'\n')) {
+            return Err(bad(format!("control character in {}", String::from_utf8_lossy(&name))));
+        }
+        if let Some(pseudo) = name.strip_prefix(b":") {
+            let slot = match pseudo {
+                b"method" => &mut method,
+                b"scheme" => &mut scheme,
+                b"path" => &mut path,
+                b"authority" => &mut authority,
+                _ => return Err(bad(format!("unknown pseudo-header {}", String::from_utf8_lossy(&name)))),
+            };
+            if !headers.is_empty() || !cookies.is_empty() || slot.replace(value).is_some() {
+                return Err(bad(format!("misplaced or repeated {}", String::from_utf8_lossy(&name))));
+            }
+            continue;
+        }
+        let name = String::from_utf8(name).map_err(|e| bad(format!("header name {:?}", e.as_bytes())))?;
+        // names go in lowercase, and the connection is HTTP/2's business
+        if !is_token(&name) || name.bytes().any(|b| b.is_ascii_uppercase()) || CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != b"trailers") {
+            return Err(bad(format!("header {:?}", name)));
+        }
+        match name.as_str() {
+            // a client can split Cookie up for better compression (RFC 9113 8.2.3)
+            "cookie" => cookies.push(value),
+            // :authority stands in for Host, and wins over it
+            "host" if authority.is_some() => {}
+            _ => headers.push((name, value)),
+        }
+    }
+    let (Some(method), Some(_), Some(path)) = (method, scheme, path) else {
+        return Err(bad("missing :method, :scheme or :path".to_string()));
+    };
+    // the request line has no room for spaces or control characters
+    if path.is_empty() || method.iter().chain(&path).any(|b| *b <= b' ' || *b == 0x7f) {
+        return Err(bad(format!("request line {:?} {:?}", String::from_utf8_lossy(&method), String::from_utf8_lossy(&path))));
+    }
+    let mut head = [&method[..], b" ", &path, b" HTTP/1.1\r\n"].concat();
+    let host = authority.map(|authority| ("host".to_string(), authority));
+    let cookie = (!cookies.is_empty()).then(|| ("cookie".to_string(), cookies.join(&b"; "[..])));
+    for (name, value) in host.into_iter().chain(headers).chain(cookie) {
+        head.extend_from_slice(&[name.as_bytes(), b": ", &value, b"\r\n"].concat());
+    }
+    head.extend_from_slice(b"\r\n");
+    if head.len() > options.max_head_len {
+        return Err((StatusCode::RequestHeaderFieldsTooLarge, "request head too large".to_string()));
+    }
+    handler::check_header_limits(&head, options).map_err(|why| (StatusCode::RequestHeaderFieldsTooLarge, why))?;
+    let mut request = Request::parse(&head).map_err(|e| match e {
+        ParseError::UnknownMethod(_) => (StatusCode::NotImplemented, e.to_string()),
+        _ => bad(e.to_string()),
+    })?;
+    request.version = Version::Http2;
+    // said no to before the client sends it all, rather than after
+    if let Some(len) = request.header("content-length") {
+        match len.parse::<u64>() {
+            Ok(len) if len > options.max_body_size => return Err((StatusCode::ContentTooLarge, format!("a {} byte body", len))),
+            Ok(_) => {}
+            Err(_) => return Err(bad(format!("invalid Content-Length {:?}", len))),
+        }
+    }
+    Ok(request)
+}
+
+// The fields of a trailer block, which can't have pseudo-headers or anything a head couldn't
+fn trailers_from(fields: Vec<Field>) -> Result<crate::HeaderMap, ()> {
+    let mut trailers = crate::HeaderMap::new();
+    for (name, value) in fields {
+        let (Ok(name), Ok(value)) = (String::from_utf8(name), String::from_utf8(value)) else {
+            return Err(());
+        };
+        if !is_token(&name) || name.bytes().any(|b| b.is_ascii_uppercase()) || !crate::headers::is_valid_value(&value) {
+            return Err(());
+        }
+        trailers.append(name, value);
+    }
+    Ok(trailers)
+}
+
+// A response's header block: its status, a Date unless it has one, its headers in lowercase
+// without the ones about the connection, and the length of a body in memory
+fn encode_headers(response: &Response) -> Vec<u8> {
+    let mut block = Vec::new();
+    literal(&mut block, ":status", response.status.as_u16().to_string().as_bytes());
+    if !response.headers.contains("date") {
+        literal(&mut block, "date", date::format(SystemTime::now()).as_bytes());
+    }
+    for (name, value) in &response.headers {
+        let name = name.to_ascii_lowercase();
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            literal(&mut block, &name, value.as_bytes());
+        }
+    }
+    if let Body::Bytes(bytes) = &response.body
+        && !response.headers.contains("content-length")
+        && response.has_body()
+    {
+        literal(&mut block, "content-length", bytes.len().to_string().as_bytes());
+    }
+    block
+}
+
+// A literal header field without indexing, name and all (RFC 7541 6.2.2), neither string
+// Huffman coded
+fn literal(block: &mut Vec<u8>, name: &str, value: &[u8]) {
+    block.push(0);
+    for string in [name.as_bytes(), value] {
+        encode_integer(block, 0, 7, string.len());
+        block.extend_from_slice(string);
+    }
+}
+
+// 'value' in an HPACK integer with a 'prefix' bit prefix, after the bits of 'first' above it (RFC 7541 5.1)
+fn encode_integer(block: &mut Vec<u8>, first: u8, prefix: u8, value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        block.push(first | value as u8);
+        return;
+    }
+    block.push(first | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        block.push(0x80 | (rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    block.push(rest as u8);
+}
+
+// The table size clients start with, which we never change
+const TABLE_SIZE: usize = 4096;
+
+// A header field's name and value, as bytes straight off the wire
+type Field = (Vec<u8>, Vec<u8>);
+
+// HPACK's decoding side: the table of fields the client has told us to remember (RFC 7541 2.3.2)
+struct Decoder {
+    // newest first, so an index counts from the front
+    table: VecDeque<(Vec<u8>, Vec<u8>)>,
+    size: usize,
+    max_size: usize,
+    // the most a block may decode to, counted as the table counts entries (RFC 7541 4.1)
+    max_list_size: usize,
+}
+
+impl Decoder {
+    fn new(max_list_size: usize) -> Decoder {
+        Decoder { table: VecDeque::new(), size: 0, max_size: TABLE_SIZE, max_list_size }
+    }
+
+    // The fields in a header block, names and values as sent. A COMPRESSION_ERROR if it's
+    // garbled, ENHANCE_YOUR_CALM if it decodes to more than max_list_size: a byte naming a
+    // big table entry copies all of it, so a small block can decode to a huge list
+    fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Field>, Failure> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        while !block.is_empty() {
+            let field = self.next(&mut block, fields.is_empty()).map_err(|why| Failure::Protocol(COMPRESSION_ERROR, why))?;
+            let Some(field) = field else { continue };
+            list_size += field.0.len() + field.1.len() + 32;
+            if list_size > self.max_list_size {
+                return protocol(ENHANCE_YOUR_CALM, format!("a header list over {} bytes", self.max_list_size));
+            }
+            fields.push(field);
+        }
+        Ok(fields)
+    }
+
+    // The next field off 'block', None for a table size change, which only comes 'first'
+    fn next(&mut self, block: &mut &[u8], first: bool) -> Result<Option<Field>, String> {
+        let kind = block[0];
+        if kind & 0x80 != 0 {
+            let index = decode_integer(block, 7)?;
+            return self.entry(index).map(Some);
+        }
+        if kind & 0x40 != 0 {
+            let field = self.field(block, 6)?;
+            self.insert(field.clone());
+            return Ok(Some(field));
+        }
+        if kind & 0x20 != 0 {
+            let size = decode_integer(block, 5)?;
+            if !first || size > TABLE_SIZE {
+                return Err(format!("table size update to {}", size));
+            }
+            self.max_size = size;
+            self.evict(0);
+            return Ok(None);
+        }
+        // without indexing, or never indexed, which is all the same to us
+        self.field(block, 4).map(Some)
+    }
+
+    // A literal field: its name by index, or as a string when the index is 0, then its value
+    fn field(&self, block: &mut &[u8], prefix: u8) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let name = match decode_integer(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, decode_string(block)?))
+    }
+
+    fn entry(&self, index: usize) -> Result<(Vec<u8>, Vec<u8>), String> {
+        match index {
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index.wrapping_sub(62)).cloned().ok_or_else(|| format!("no table entry {}", index)),
+        }
+    }
+
+    // Remember 'field', forgetting the oldest ones to make room. One too big for the table
+    // just empties it
+    fn insert(&mut self, field: (Vec<u8>, Vec<u8>)) {
+        let size = field.0.len() + field.1.len() + 32;
+        self.evict(size);
+        if size <= self.max_size {
+            self.table.push_front(field);
+            self.size += size;
+        }
+    }
+
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size
+            && let Some((name, value)) = self.table.pop_back()
+        {
+            self.size -= name.len() + value.len() + 32;
+        }
+    }
+}
+
+// An HPACK integer with a 'prefix' bit prefix off the front of 'block'
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, String> {
+    let max = (1 << prefix) - 1;
+    let (&first, rest) = block.split_first().ok_or("header block ends mid-integer")?;
+    *block = rest;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    // anything past 2^28 is no length or index we'd take
+    for shift in (0..28).step_by(7) {
+        let (&byte, rest) = block.split_first().ok_or("header block ends mid-integer")?;
+        *block = rest;
+        value += usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("integer too big".to_string())
+}
+
+// An HPACK string, Huffman coded or not, off the front of 'block'
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, String> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_integer(block, 7)?;
+    if len > block.len() {
+        return Err("header block ends mid-string".to_string());
+    }
+    let (string, rest) = block.split_at(len);
+    *block = rest;
+    if huffman { huffman_decode(string) } else { Ok(string.to_vec()) }
+}
+
+// The lengths of HPACK's Huffman codes by symbol, 256 being EOS (RFC 7541 Appendix B). The
+// code is canonical, so the lengths are all it takes to rebuild it
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+// The Huffman code rebuilt for decoding: for each length, the first code that long and where
+// the symbols with codes that long start in 'symbols', which is by length then symbol
+struct Huffman {
+    first: [u32; 31],
+    count: [u32; 31],
+    offset: [usize; 31],
+    symbols: Vec<u16>,
+}
+
+fn huffman() -> &'static Huffman {
+    static HUFFMAN: OnceLock<Huffman> = OnceLock::new();
+    HUFFMAN.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=256).collect();
+        symbols.sort_by_key(|&symbol| HUFFMAN_LENGTHS[usize::from(symbol)]);
+        let mut huffman = Huffman { first: [0; 31], count: [0; 31], offset: [0; 31], symbols };
+        let (mut code, mut offset) = (0, 0);
+        for len in 1..=30 {
+            let count = HUFFMAN_LENGTHS.iter().filter(|&&l| usize::from(l) == len).count();
+            (huffman.first[len], huffman.count[len], huffman.offset[len]) = (code, count as u32, offset);
+            code = (code + count as u32) << 1;
+            offset += count;
+        }
+        huffman
+    })
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let huffman = huffman();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0);
+    for bit in bytes.iter().flat_map(|byte| (0..8).rev().map(move |i| u32::from(byte >> i) & 1)) {
+        code = code << 1 | bit;
+        len += 1;
+        if len > 30 {
+            return Err("invalid Huffman code".to_string());
+        }
+        let index = code.wrapping_sub(huffman.first[len]);
+        if index < huffman.count[len] {
+            match huffman.symbols[huffman.offset[len] + index as usize] {
+                256 => return Err("EOS in a Huffman string".to_string()),
+                symbol => decoded.push(symbol as u8),
+            }
+            (code, len) = (0, 0);
+        }
+    }
+    // padded out to a byte with the start of EOS, which is all ones
+    if len > 7 || code != (1 << len) - 1 {
+        return Err("bad Huffman padding".to_string());
+    }
+    Ok(decoded)
+}
+
+// HPACK's static table, indexes 1 to 61 (RFC 7541 Appendix A)
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Router;
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    // An in-memory connection: reads come from 'input', writes pile up in 'output'
+    struct Fake {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Fake {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Fake {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(u8::is_ascii_hexdigit).collect();
+        digits.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap()).collect()
+    }
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend_from_slice(&[kind, flags]);
+        frame.extend_from_slice(&stream.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // A HEADERS frame with 'fields' as plain literals, as we send them
+    fn headers(stream: u32, fields: &[(&str, &str)], flags: u8) -> Vec<u8> {
+        let mut block = Vec::new();
+        for (name, value) in fields {
+            literal(&mut block, name, value.as_bytes());
+        }
+        frame(HEADERS, flags | END_HEADERS, stream, &block)
+    }
+
+    fn request(stream: u32, method: &str, path: &str, flags: u8) -> Vec<u8> {
+        headers(stream, &[(":method", method), (":scheme", "http"), (":path", path), (":authority", "localhost")], flags)
+    }
+
+    fn get(stream: u32, path: &str) -> Vec<u8> {
+        request(stream, "GET", path, END_STREAM)
+    }
+
+    // Everything the server writes back, as frames, for a client sending the preface then 'input'
+    fn exchange(input: &[u8], options: &HttpOptions) -> Vec<Frame> {
+        let input = [PREFACE_REST, &frame(SETTINGS, 0, 0, &[]), input].concat();
+        let mut fake = Fake { input: Cursor::new(input), output: Vec::new() };
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("static");
+        serve(&mut fake, Vec::new(), options, None, |_| root.as_path());
+        frames(&fake.output)
+    }
+
+    fn frames(mut output: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while output.len() >= 9 {
+            let len = u32::from_be_bytes([0, output[0], output[1], output[2]]) as usize;
+            let stream = u32::from_be_bytes(output[5..9].try_into().unwrap());
+            frames.push(Frame { kind: output[3], flags: output[4], stream, payload: output[9..9 + len].to_vec() });
+            output = &output[9 + len..];
+        }
+        assert!(output.is_empty(), "half a frame left over");
+        frames
+    }
+
+    // A stream's response: its header fields, body, and whether it was ended
+    #[derive(Default)]
+    struct Answer {
+        fields: Vec<(String, String)>,
+        body: Vec<u8>,
+        ended: bool,
+    }
+
+    impl Answer {
+        fn get(&self, name: &str) -> Option<&str> {
+            self.fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+        }
+    }
+
+    // The responses in 'frames', in the order they were started
+    fn answers(frames: &[Frame]) -> Vec<(u32, Answer)> {
+        let mut decoder = Decoder::new(8192);
+        let mut answers: Vec<(u32, Answer)> = Vec::new();
+        for frame in frames.iter().filter(|frame| matches!(frame.kind, HEADERS | DATA)) {
+            if frame.kind == HEADERS {
+                let fields = decoder.decode(&frame.payload).unwrap();
+                let fields = fields.into_iter().map(|(name, value)| (String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap()));
+                answers.push((frame.stream, Answer { fields: fields.collect(), ..Answer::default() }));
+            }
+            let answer = &mut answers.iter_mut().find(|(id, _)| *id == frame.stream).expect("DATA before HEADERS").1;
+            assert!(!answer.ended, "frame after END_STREAM");
+            if frame.kind == DATA {
+                answer.body.extend_from_slice(&frame.payload);
+            }
+            answer.ended = frame.flags & END_STREAM != 0;
+        }
+        answers
+    }
+
+    // The error code of the GOAWAY the server ended with
+    fn goaway(frames: &[Frame]) -> Option<u32> {
+        let last = frames.last().filter(|frame| frame.kind == GOAWAY)?;
+        Some(u32::from_be_bytes(last.payload[4..8].try_into().unwrap()))
+    }
+
+    fn page(name: &str) -> Vec<u8> {
+        fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("static").join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_hpack_examples() {
+        // RFC 7541 C.3, one connection's requests with plain strings
+        let mut decoder = Decoder::new(8192);
+        let first = decoder.decode(&hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d")).unwrap();
+        let pairs = |fields: Vec<Field>| fields.into_iter().map(|(n, v)| (String::from_utf8(n).unwrap(), String::from_utf8(v).unwrap())).collect::<Vec<_>>();
+        let expected = [(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")];
+        assert_eq!(pairs(first), expected.map(|(n, v)| (n.to_string(), v.to_string())));
+        assert_eq!(decoder.size, 57);
+        let second = decoder.decode(&hex("8286 84be 5808 6e6f 2d63 6163 6865")).unwrap();
+        assert_eq!(pairs(second)[3..], [(":authority".to_string(), "www.example.com".to_string()), ("cache-control".to_string(), "no-cache".to_string())]);
+        let third = decoder.decode(&hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65")).unwrap();
+        assert_eq!(pairs(third)[2..], [(":path", "/index.html"), (":authority", "www.example.com"), ("custom-key", "custom-value")].map(|(n, v)| (n.to_string(), v.to_string())));
+        assert_eq!((decoder.table.len(), decoder.size), (3, 164));
+
+        // C.4, the same with Huffman coded strings
+        let mut decoder = Decoder::new(8192);
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff")).unwrap();
+        assert_eq!(pairs(first)[3], (":authority".to_string(), "www.example.com".to_string()));
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf")).unwrap();
+        assert_eq!(pairs(second)[4], ("cache-control".to_string(), "no-cache".to_string()));
+        let third = decoder.decode(&hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf")).unwrap();
+        assert_eq!(pairs(third)[4], ("custom-key".to_string(), "custom-value".to_string()));
+        assert_eq!(decoder.size, 164);
+
+        // C.1.2, an integer past its prefix
+        let mut block = Vec::new();
+        encode_integer(&mut block, 0, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&mut &block[..], 5), Ok(1337));
+    }
+
+    #[test]
+    fn test_hpack_errors() {
+        let bad = [
+            &[0x80][..],             // index 0
+            &[0xbe],                 // past an empty dynamic table
+            &[0x00, 0x01],           // literal name cut short
+            &[0x00, 0x81, 0x00, 0x00], // Huffman string padded with zeros
+            &[0x3f, 0xe2, 0x1f],     // table size past 4096
+            &[0x82, 0x20],           // table size update after a field
+            &[0x7f, 0xff, 0xff, 0xff, 0xff, 0x7f], // absurd integer
+        ];
+        for block in bad {
+            assert!(Decoder::new(8192).decode(block).is_err(), "{:?}", block);
+        }
+        // an entry too big for the table empties it rather than going in
+        let mut decoder = Decoder::new(8192);
+        decoder.decode(&hex("4001 6101 62")).unwrap();
+        let mut block = vec![0x40, 0x01, b'a'];
+        encode_integer(&mut block, 0, 7, 5000);
+        block.extend_from_slice(&[b'x'; 5000]);
+        decoder.decode(&block).unwrap();
+        assert_eq!((decoder.table.len(), decoder.size), (0, 0));
+    }
+
+    #[test]
+    fn test_pages() {
+        let frames = exchange(&[get(1, "/"), request(3, "HEAD", "/", END_STREAM), get(5, "/missing")].concat(), &HttpOptions::default());
+        // our settings, then the ack for theirs
+        assert_eq!((frames[0].kind, frames[0].flags, frames[0].payload.len()), (SETTINGS, 0, 12));
+        assert_eq!((frames[1].kind, frames[1].flags), (SETTINGS, ACK));
+        let answers = answers(&frames);
+        assert_eq!(answers.iter().map(|(id, _)| *id).collect::<Vec<_>>(), [1, 3, 5]);
+        let (get, head, missing) = (&answers[0].1, &answers[1].1, &answers[2].1);
+        assert_eq!((get.fields[0].0.as_str(), get.get(":status")), (":status", Some("200")));
+        assert_eq!(get.get("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(get.get("content-length"), Some(page("index.html").len().to_string().as_str()));
+        assert!(get.get("date").is_some() && get.get("server").is_some() && get.get("x-request-id").is_some());
+        assert!(get.get("connection").is_none());
+        assert!(get.ended && get.body == page("index.html"));
+        assert_eq!((head.get(":status"), head.get("content-length"), head.body.len()), (Some("200"), get.get("content-length"), 0));
+        assert!(head.ended);
+        assert_eq!(missing.get(":status"), Some("404"));
+        assert_eq!(missing.body, page("404.html"));
+        assert_eq!(goaway(&frames), None);
+    }
+
+    #[test]
+    fn test_control_frames() {
+        // a ping gets its payload back, a header block can carry on in CONTINUATIONs
+        let ping = frame(PING, 0, 0, b"12345678");
+        let whole = get(1, "/");
+        let block = &whole[9..];
+        let split = [frame(HEADERS, END_STREAM, 1, &block[..10]), frame(CONTINUATION, 0, 1, &block[10..20]), frame(CONTINUATION, END_HEADERS, 1, &block[20..])].concat();
+        let frames = exchange(&[ping, split].concat(), &HttpOptions::default());
+        assert!(frames.iter().any(|frame| frame.kind == PING && frame.flags == ACK && frame.payload == b"12345678"));
+        assert_eq!(answers(&frames)[0].1.get(":status"), Some("200"));
+
+        // streams reset before they're answered aren't
+        let frames = exchange(&[request(1, "GET", "/", 0), frame(RST_STREAM, 0, 1, &[0, 0, 0, 8]), get(3, "/")].concat(), &HttpOptions::default());
+        assert_eq!(answers(&frames).iter().map(|(id, _)| *id).collect::<Vec<_>>(), [3]);
+
+        // the client going away still gets what it asked for first
+        let frames = exchange(&[get(1, "/"), frame(GOAWAY, 0, 0, &[0; 8])].concat(), &HttpOptions::default());
+        assert_eq!(answers(&frames).len(), 1);
+
+        // streams past MAX_STREAMS open at once are refused
+        let open: Vec<u8> = (0..=MAX_STREAMS as u32).flat_map(|i| request(2 * i + 1, "GET", "/", 0)).collect();
+        let frames = exchange(&open, &HttpOptions::default());
+        let refused: Vec<u32> = frames.iter().filter(|frame| frame.kind == RST_STREAM && frame.payload == REFUSED_STREAM.to_be_bytes()).map(|frame| frame.stream).collect();
+        assert_eq!(refused, [2 * MAX_STREAMS as u32 + 1]);
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let mut router = Router::new();
+        router.get("/big", |_: &Request| Response::new(StatusCode::Ok).with_body(vec![b'x'; 100_000]));
+        let options = HttpOptions { router: Some(Arc::new(router)), compress: false, ..HttpOptions::default() };
+        // 100 bytes go out, then the rest once the client makes room
+        let small_window = frame(SETTINGS, 0, 0, &[0, 4, 0, 0, 0, 100]);
+        let room = [frame(WINDOW_UPDATE, 0, 0, &100_000u32.to_be_bytes()), frame(WINDOW_UPDATE, 0, 1, &100_000u32.to_be_bytes())].concat();
+        let frames = exchange(&[small_window.clone(), get(1, "/big"), room].concat(), &options);
+        let data: Vec<&Frame> = frames.iter().filter(|frame| frame.kind == DATA).collect();
+        assert_eq!(data[0].payload.len(), 100);
+        assert!(data.iter().all(|frame| frame.payload.len() <= DEFAULT_FRAME_SIZE));
+        let answer = &answers(&frames)[0].1;
+        assert!(answer.ended && answer.body.len() == 100_000);
+
+        // and a client that never does gets no more
+        let frames = exchange(&[small_window, get(1, "/big")].concat(), &options);
+        assert_eq!(frames.iter().filter(|frame| frame.kind == DATA).map(|frame| frame.payload.len()).sum::<usize>(), 100);
+        assert!(!answers(&frames)[0].1.ended);
+    }
+
+    #[test]
+    fn test_request_bodies() {
+        let mut router = Router::new();
+        router.post("/echo", |request: &Request| {
+            let trailer = request.trailers.get("checksum").unwrap_or_default().to_string();
+            Response::new(StatusCode::Ok).with_body([&request.body[..], trailer.as_bytes()].concat())
+        });
+        let options = HttpOptions { router: Some(Arc::new(router)), max_body_size: 10, ..HttpOptions::default() };
+        let post = |stream, fields: &[(&str, &str)]| {
+            let mut all = vec![(":method", "POST"), (":scheme", "https"), (":path", "/echo"), (":authority", "localhost")];
+            all.extend_from_slice(fields);
+            headers(stream, &all, 0)
+        };
+        let input = [
+            post(1, &[("content-length", "5")]),
+            frame(DATA, 0, 1, b"hel"),
+            frame(DATA, END_STREAM | PADDED, 1, &[3, b'l', b'o', 0, 0, 0]),
+            post(3, &[]),
+            frame(DATA, 0, 3, b"abc"),
+            headers(3, &[("checksum", "123")], END_STREAM),
+        ]
+        .concat();
+        let frames = exchange(&input, &options);
+        let answered = answers(&frames);
+        assert_eq!((answered[0].1.get(":status"), answered[0].1.body.as_slice()), (Some("200"), &b"hello"[..]));
+        assert_eq!(answered[1].1.body, b"abc123");
+        // what was read gets given back, padding and all
+        assert!(frames.iter().any(|frame| frame.kind == WINDOW_UPDATE && frame.stream == 0 && frame.payload == 6u32.to_be_bytes()));
+
+        let input = [
+            // a Content-Length over the limit, a body that turns out to be, and one that doesn't add up
+            post(1, &[("content-length", "11")]),
+            post(3, &[]),
+            frame(DATA, 0, 3, &[b'x'; 11]),
+            post(5, &[("content-length", "4")]),
+            frame(DATA, END_STREAM, 5, b"abc"),
+        ]
+        .concat();
+        let frames = exchange(&input, &options);
+        let statuses: Vec<_> = answers(&frames).iter().map(|(id, answer)| (*id, answer.get(":status").unwrap().to_string())).collect();
+        assert_eq!(statuses, [(1, "413".to_string()), (3, "413".to_string()), (5, "400".to_string())]);
+        // the client that hadn't finished is told not to bother
+        assert!(frames.iter().any(|frame| frame.kind == RST_STREAM && frame.stream == 1 && frame.payload == NO_ERROR.to_be_bytes()));
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let malformed: [&[(&str, &str)]; 7] = [
+            &[(":method", "GET"), (":scheme", "http")],
+            &[(":method", "GET"), (":scheme", "http"), (":path", "/"), ("X-Upper", "a")],
+            &[(":method", "GET"), (":scheme", "http"), (":path", "/"), ("connection", "close")],
+            &[(":method", "GET"), (":scheme", "http"), (":path", "/"), ("te", "gzip")],
+            &[(":method", "GET"), (":scheme", "http"), (":path", "/"), ("x", "a\r\nb: c")],
+            &[(":method", "GET"), (":scheme", "http"), ("x", "a"), (":path", "/")],
+            &[(":method", "GET"), (":scheme", "http"), (":path", "/a b")],
+        ];
+        for (i, fields) in malformed.iter().enumerate() {
+            let frames = exchange(&headers(1, fields, END_STREAM), &HttpOptions::default());
+            assert_eq!(answers(&frames)[0].1.get(":status"), Some("400"), "{}", i);
+        }
+        let frames = exchange(&request(1, "BREW", "/", END_STREAM), &HttpOptions::default());
+        assert_eq!(answers(&frames)[0].1.get(":status"), Some("501"));
+        // cookies split up for compression are put back together
+        let fields = [(":method", "GET"), (":scheme", "http"), (":path", "/"), ("cookie", "a=1"), ("cookie", "b=2")];
+        let mut block = Vec::new();
+        for (name, value) in fields {
+            literal(&mut block, name, value.as_bytes());
+        }
+        let request = request_from(Decoder::new(8192).decode(&block).unwrap(), &HttpOptions::default()).unwrap();
+        assert_eq!((request.header("cookie"), request.version, request.host()), (Some("a=1; b=2"), Version::Http2, None));
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let errors = [
+            (frame(DATA, END_STREAM, 0, b"x"), PROTOCOL_ERROR),
+            (get(2, "/"), PROTOCOL_ERROR),
+            (frame(HEADERS, END_HEADERS, 1, &[0x80]), COMPRESSION_ERROR),
+            ([frame(HEADERS, 0, 1, &get(1, "/")[9..]), frame(PING, 0, 0, &[0; 8])].concat(), PROTOCOL_ERROR),
+            (frame(HEADERS, END_HEADERS | PADDED, 1, &[5, 0x82]), PROTOCOL_ERROR),
+            (frame(PING, 0, 0, &[0; 7]), FRAME_SIZE_ERROR),
+            (frame(SETTINGS, 0, 0, &[0, 5, 0, 0, 0, 1]), PROTOCOL_ERROR),
+            (frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes()), FLOW_CONTROL_ERROR),
+            (frame(PUSH_PROMISE, END_HEADERS, 1, &[0; 4]), PROTOCOL_ERROR),
+            (frame(DATA, 0, 1, &vec![0; DEFAULT_FRAME_SIZE + 1]), FRAME_SIZE_ERROR),
+        ];
+        for (i, (input, code)) in errors.into_iter().enumerate() {
+            let frames = exchange(&input, &HttpOptions::default());
+            assert_eq!(goaway(&frames), Some(code), "{}", i);
+        }
+        // a 4000 byte table entry, then a thousand one byte references to it: small on the wire,
+        // 4MB decoded, and it's refused before all that is copied out
+        let mut bomb = vec![0x40, 0x01, b'x'];
+        encode_integer(&mut bomb, 0, 7, 4000);
+        bomb.extend_from_slice(&[b'a'; 4000]);
+        bomb.extend_from_slice(&[0xbe; 1000]);
+        let bombed = exchange(&frame(HEADERS, END_STREAM | END_HEADERS, 1, &bomb), &HttpOptions::default());
+        assert_eq!(goaway(&bombed), Some(ENHANCE_YOUR_CALM));
+        let mut decoder = Decoder::new(8192);
+        assert!(matches!(decoder.decode(&bomb), Err(Failure::Protocol(ENHANCE_YOUR_CALM, _))));
+
+        // HEADERS for a stream that's been and gone only resets that stream
+        let replayed = exchange(&[get(3, "/"), get(1, "/")].concat(), &HttpOptions::default());
+        assert_eq!(answers(&replayed).iter().map(|(id, _)| *id).collect::<Vec<_>>(), [3]);
+        let reset = replayed.iter().find(|frame| frame.kind == RST_STREAM).unwrap();
+        assert_eq!((reset.stream, &reset.payload[..]), (1, &STREAM_CLOSED.to_be_bytes()[..]));
+        assert_eq!(goaway(&replayed), None);
+        let mut fake = Fake { input: Cursor::new(b"XX\r\n\r\n".to_vec()), output: Vec::new() };
+        serve(&mut fake, Vec::new(), &HttpOptions::default(), None, |_| Path::new("static"));
+        assert_eq!(goaway(&frames(&fake.output)), Some(PROTOCOL_ERROR));
+    }
+}
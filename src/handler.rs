@@ -0,0 +1,1399 @@
+use std::{
+    fs,
+    io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    chunked, compress, conditional, date,
+    drain::TrackedConnection,
+    h2,
+    listener::SetTimeouts,
+    logging::log,
+    mime,
+    range::{self, ByteRange},
+    request,
+    switch::{self, UpgradeHandler, Upgraded},
+    url, HeaderMap, Method, ParseError, Redirect, Request, Response, Router, StatusCode, Version, VirtualHosts,
+};
+
+// The request line an HTTP/2 client opens with when it assumes the server speaks h2 (RFC 9113 3.4)
+const H2_REQUEST_LINE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+// Our side of the h2 preface, an empty SETTINGS frame, then GOAWAY with HTTP_1_1_REQUIRED
+// so a client we aren't speaking HTTP/2 to retries over HTTP/1.1 instead of choking on a 404
+const H2_GOAWAY: [u8; 26] = [
+    0, 0, 0, 0x4, 0, 0, 0, 0, 0, // SETTINGS, no payload
+    0, 0, 8, 0x7, 0, 0, 0, 0, 0, // GOAWAY, 8 byte payload
+    0, 0, 0, 0, // last stream id
+    0, 0, 0, 0xd, // HTTP_1_1_REQUIRED
+];
+
+// What we call ourselves in the Server header unless told otherwise
+pub(crate) const DEFAULT_SERVER: &str = concat!("webserver/", env!("CARGO_PKG_VERSION"));
+
+// Requests served on one connection before we ask the client to reconnect,
+// so a single client can't keep a worker to itself indefinitely
+pub(crate) const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+// What the stock handler holds clients to, ServerBuilder fills it in
+#[derive(Clone)]
+pub(crate) struct HttpOptions {
+    // request heads bigger than this get a 431
+    pub(crate) max_head_len: usize,
+    // and heads with more header lines than max_headers, or a longer one than max_header_line
+    pub(crate) max_headers: usize,
+    pub(crate) max_header_line: usize,
+    // request bodies bigger than this get a 413 before we read any of them
+    pub(crate) max_body_size: u64,
+    // how much we ask the socket for at a time
+    pub(crate) read_buffer: usize,
+    // How long a client gets to send a whole request head once it starts one. The read timeout
+    // alone only bounds the gap between bytes, so a client trickling a byte every few seconds
+    // (slow loris) could otherwise hold a worker for as long as it liked
+    pub(crate) head_deadline: Duration,
+    // extension and Content-Type pairs that win over the built in table
+    pub(crate) mime_types: Vec<(String, String)>,
+    // ETags from hashing file contents rather than their size and modification time
+    pub(crate) content_etags: bool,
+    // paths that get a 301 to somewhere else, before any page is looked for
+    pub(crate) redirects: Vec<(String, String)>,
+    // requests for a path spelled other than its normalized form ("/a/./b", "//b", "/%62")
+    // get redirected to that, rather than served under both
+    pub(crate) canonical_redirects: bool,
+    // the Server header every response gets, None for no header
+    pub(crate) server: Option<String>,
+    // gzip bodies for clients that take it, at least compress_min_size bytes and of a type on
+    // compress_types (exact, or "text/*")
+    pub(crate) compress: bool,
+    pub(crate) compress_min_size: u64,
+    pub(crate) compress_types: Vec<String>,
+    // the page each error status is sent with, from the doc root unless the path is absolute
+    // Statuses not on it (or whose page is missing) get a line of text
+    pub(crate) error_pages: Vec<(StatusCode, PathBuf)>,
+    // protocols a request can Upgrade to, and who takes those connections over
+    pub(crate) upgrades: Vec<(String, UpgradeHandler)>,
+    // handlers that get first go at requests, the stock pages answer whatever they don't route
+    pub(crate) router: Option<Arc<Router>>,
+    // answer clients opening with the HTTP/2 preface in HTTP/2, rather than a GOAWAY
+    pub(crate) http2: bool,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            max_head_len: 8 * 1024,
+            max_headers: 100,
+            max_header_line: 8190,
+            max_body_size: 1024 * 1024,
+            read_buffer: 1024,
+            head_deadline: Duration::from_secs(10),
+            mime_types: Vec::new(),
+            content_etags: false,
+            redirects: Vec::new(),
+            canonical_redirects: false,
+            server: Some(DEFAULT_SERVER.to_string()),
+            compress: true,
+            compress_min_size: 1024,
+            compress_types: compress::DEFAULT_TYPES.iter().map(|mime| mime.to_string()).collect(),
+            error_pages: vec![(StatusCode::NotFound, PathBuf::from("404.html")), (StatusCode::ServiceUnavailable, PathBuf::from("503.html"))],
+            upgrades: Vec::new(),
+            router: None,
+            http2: false,
+        }
+    }
+}
+
+/// The stock handler: serves index.html for / and /sleep (after a 5 second nap) and 404.html for anything else,
+/// or a line of text if there's no 404.html
+/// Pages are read from 'doc_root'
+///
+/// Connections are kept alive between requests, until the client sends Connection: close
+/// (or is HTTP/1.0 and doesn't ask for keep-alive), goes quiet past the read timeout,
+/// or has made 100 requests. A request head has to arrive within 10 seconds of its first
+/// byte and fit in 8KB with at most 100 header lines, bigger ones get a 431. Bodies over 1MB get a 413
+pub fn handle_connection(stream: impl Read + Write, doc_root: &Path) {
+    serve_requests(Untimed(stream), &HttpOptions::default(), None, None, |_| doc_root);
+}
+
+/// handle_connection, with each request's pages read from the root its Host header picks in 'hosts'
+pub fn handle_virtual_hosts(stream: impl Read + Write, hosts: &VirtualHosts) {
+    handle_site(Untimed(stream), hosts, &HttpOptions::default(), None);
+}
+
+// A stream from outside the server, which may not even be a socket, so has no timeouts to change
+struct Untimed<S>(S);
+
+impl<S: Read> Read for Untimed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Write> Write for Untimed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S> SetTimeouts for Untimed<S> {
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::Unsupported, "not a socket the server accepted"))
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::Unsupported, "not a socket the server accepted"))
+    }
+}
+
+// handle_virtual_hosts with the server's limits rather than the defaults, 'tracked' lets a
+// shutdown close the connection between requests
+pub(crate) fn handle_site(
+    stream: impl Read + Write + SetTimeouts,
+    hosts: &VirtualHosts,
+    options: &HttpOptions,
+    tracked: Option<&TrackedConnection>,
+) {
+    serve_requests(stream, options, tracked, None, |host| hosts.doc_root(host));
+}
+
+/// Where a keep-alive connection stands between requests, so it can wait off a worker and
+/// pick up where it left off
+#[derive(Default)]
+pub(crate) struct Resume {
+    /// Read but not handled yet, the start of the next request
+    pub(crate) pending: Vec<u8>,
+    pub(crate) served: usize,
+}
+
+// handle_site for a connection coming back from the event loop, which gets it back once
+// the next request isn't all there
+pub(crate) fn resume_site<S: Read + Write + SetTimeouts>(
+    stream: S,
+    hosts: &VirtualHosts,
+    options: &HttpOptions,
+    tracked: &TrackedConnection,
+    resume: Resume,
+) -> Option<(S, Resume)> {
+    serve_requests(stream, options, Some(tracked), Some(resume), |host| hosts.doc_root(host))
+}
+
+// The keep-alive loop both handlers share
+// 'doc_root' picks the pages for a request's Host header, None when we don't know it
+// With 'resume' it starts from there and hands the connection back instead of waiting on a partial request
+fn serve_requests<'a, S: Read + Write + SetTimeouts>(
+    mut stream: S,
+    options: &HttpOptions,
+    tracked: Option<&TrackedConnection>,
+    resume: Option<Resume>,
+    doc_root: impl Fn(Option<&str>) -> &'a Path,
+) -> Option<(S, Resume)> {
+    let parking = resume.is_some();
+    // pending is bytes read off the socket that aren't part of a request we've handled yet
+    let Resume { mut pending, served: first } = resume.unwrap_or_default();
+    for served in first..MAX_REQUESTS_PER_CONNECTION {
+        // nothing's lost closing a keep-alive connection that's waiting, so shutdown can
+        if served > 0
+            && pending.is_empty()
+            && let Some(tracked) = tracked
+            && !tracked.set_idle(true)
+        {
+            return None;
+        }
+        // an oversized head is refused below without reading any more
+        if parking && served > first && !has_head(&pending) && pending.len() <= options.max_head_len {
+            return Some((stream, Resume { pending, served }));
+        }
+        let head = read_head(&mut stream, &mut pending, options);
+        if let Some(tracked) = tracked {
+            tracked.set_idle(false);
+        }
+        let head = match head {
+            Ok(Some(head)) => head,
+            // the client hung up between requests
+            Ok(None) => return None,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                log!(Debug, "Refusing request: {}", e);
+                count_request(tracked);
+                refuse(&mut stream, StatusCode::RequestHeaderFieldsTooLarge, doc_root(None), options);
+                return None;
+            }
+            // it may only have shut its end, and still be reading
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                log!(Debug, "Refusing request: {}", e);
+                count_request(tracked);
+                refuse(&mut stream, StatusCode::BadRequest, doc_root(None), options);
+                return None;
+            }
+            // WouldBlock is what a read timeout looks like on Unix, TimedOut on Windows
+            Err(e) if is_timeout(&e) => {
+                // an idle keep-alive connection just gets closed, a client we're still waiting on gets told
+                if served == 0 || !pending.is_empty() {
+                    log!(Debug, "Client didn't finish its request in time: {}", e);
+                    count_request(tracked);
+                    refuse(&mut stream, StatusCode::RequestTimeout, doc_root(None), options);
+                }
+                return None;
+            }
+            Err(e) => {
+                log!(Error, "Failed to read from stream: {}", e);
+                return None;
+            }
+        };
+        if head.starts_with(H2_REQUEST_LINE) {
+            // the preface comes first, and its head is just the request line
+            if options.http2 && served == 0 && head.len() == H2_REQUEST_LINE.len() + 2 {
+                h2::serve(&mut stream, std::mem::take(&mut pending), options, tracked, &doc_root);
+                return None;
+            }
+            // tell h2 clients we're only speaking HTTP/1.1 in a way they understand
+            if let Err(e) = stream.write_all(&H2_GOAWAY) {
+                log!(Error, "Failed to write HTTP/2 GOAWAY: {}", e);
+            }
+            return None;
+        }
+        count_request(tracked);
+        if let Err(e) = check_header_limits(&head, options) {
+            log!(Debug, "Refusing request: {}", e);
+            refuse(&mut stream, StatusCode::RequestHeaderFieldsTooLarge, doc_root(None), options);
+            return None;
+        }
+        let mut request = match Request::parse(&head) {
+            Ok(request) => request,
+            Err(e @ ParseError::UnknownMethod(_)) => {
+                log!(Debug, "Refusing request: {}", e);
+                refuse(&mut stream, StatusCode::NotImplemented, doc_root(None), options);
+                return None;
+            }
+            Err(e) => {
+                log!(Debug, "Refusing request: {}", e);
+                refuse(&mut stream, StatusCode::BadRequest, doc_root(None), options);
+                return None;
+            }
+        };
+        let root = doc_root(request.host());
+        // RFC 9112 3.2: an HTTP/1.1 request names exactly one host, even if it's empty, and
+        // even if an absolute-form target's authority is what it goes by
+        let hosts = request.headers.get_all("host").count();
+        if hosts > 1 || (hosts == 0 && request.version == Version::Http11) {
+            log!(Debug, "Refusing request with {} Host headers", hosts);
+            refuse(&mut stream, StatusCode::BadRequest, root, options);
+            return None;
+        }
+        request.id = request_id(&request);
+        let framing = match body_framing(&request) {
+            Ok(framing) => framing,
+            Err((status, e)) => {
+                log!(Debug, "Refusing request: {}", e);
+                refuse(&mut stream, status, root, options);
+                return None;
+            }
+        };
+        if let Framing::Length(len) = framing
+            && len > options.max_body_size
+        {
+            // say no before the client sends it all, rather than reading it just to throw it away
+            log!(Debug, "Refusing a {} byte body, the limit is {}", len, options.max_body_size);
+            refuse(&mut stream, StatusCode::ContentTooLarge, root, options);
+            return None;
+        }
+        // a client holding its body back until we say so gets told now, the 413 above already
+        // told it no; any other expectation is one we can't meet
+        if let Some(expect) = request.header("expect")
+            && request.version == Version::Http11
+        {
+            if !expect.eq_ignore_ascii_case("100-continue") {
+                log!(Debug, "Refusing request expecting {:?}", expect);
+                refuse(&mut stream, StatusCode::ExpectationFailed, root, options);
+                return None;
+            }
+            let has_body = framing != Framing::Length(0);
+            if has_body
+                && pending.is_empty()
+                && let Err(e) = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").and_then(|()| stream.flush())
+            {
+                log!(Error, "Failed to write 100 Continue: {}", e);
+                return None;
+            }
+        }
+        // the stock pages don't take a body, but it has to come off the connection before the next request
+        let body = match framing {
+            Framing::Length(len) => read_body(&mut stream, &mut pending, len).map(|body| (body, HeaderMap::new())),
+            Framing::Chunked => chunked::read_chunked(&mut stream, &mut pending, options.max_body_size),
+        };
+        (request.body, request.trailers) = match body {
+            Ok(body) => body,
+            Err(e) => {
+                log!(Debug, "Failed to read request body: {}", e);
+                match e.kind() {
+                    ErrorKind::InvalidData => refuse(&mut stream, StatusCode::BadRequest, root, options),
+                    ErrorKind::FileTooLarge => refuse(&mut stream, StatusCode::ContentTooLarge, root, options),
+                    _ if is_timeout(&e) => refuse(&mut stream, StatusCode::RequestTimeout, root, options),
+                    _ => {}
+                }
+                return None;
+            }
+        };
+        // a switch to another protocol leaves HTTP behind for good, whatever the handler makes of it
+        if let Some((protocol, handler)) = switch::handler_for(&request, &options.upgrades) {
+            let pending = std::mem::take(&mut pending);
+            handler(&request, Upgraded::new(&mut stream, pending, protocol, &request, options));
+            return None;
+        }
+        let keep_alive = request.keep_alive()
+            && served + 1 < MAX_REQUESTS_PER_CONNECTION
+            && !tracked.is_some_and(TrackedConnection::draining);
+        if !respond(&mut stream, &request, root, options, keep_alive) || !keep_alive {
+            return None;
+        }
+    }
+    None
+}
+
+// A request read, or refused, towards the server's max_requests
+fn count_request(tracked: Option<&TrackedConnection>) {
+    if let Some(tracked) = tracked {
+        tracked.count_request();
+    }
+}
+
+/// Whether 'pending' starts with a whole request head
+pub(crate) fn has_head(pending: &[u8]) -> bool {
+    request::head_len(pending).is_some()
+}
+
+// Read until a full request head (request line and headers) is buffered and split it off 'pending'
+// Ok(None) means the client closed the connection before starting another request
+// TimedOut means it took longer than the head deadline from its first byte, InvalidData that
+// it's bigger than max_head_len
+fn read_head(stream: &mut impl Read, pending: &mut Vec<u8>, options: &HttpOptions) -> io::Result<Option<Vec<u8>>> {
+    let mut searched: usize = 0;
+    // the clock starts with the request, not while an idle keep-alive connection waits for one
+    let mut started = (!pending.is_empty()).then(Instant::now);
+    loop {
+        // only look at what's new, plus 3 bytes in case the blank line straddles two reads
+        let from = searched.saturating_sub(3);
+        if let Some(end) = request::head_len(&pending[from..]) {
+            let len = from + end;
+            if len > options.max_head_len {
+                return Err(io::Error::new(ErrorKind::InvalidData, "request head too large"));
+            }
+            return Ok(Some(pending.drain(..len).collect()));
+        }
+        if pending.len() > options.max_head_len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request head too large"));
+        }
+        if started.is_some_and(|started| started.elapsed() > options.head_deadline) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "request head took too long"));
+        }
+        searched = pending.len();
+        // read straight into the spare room at the end, growing it as the head does
+        pending.resize(searched + options.read_buffer, 0);
+        let n = match stream.read(&mut pending[searched..]) {
+            Ok(n) => n,
+            Err(e) => {
+                pending.truncate(searched);
+                return Err(e);
+            }
+        };
+        pending.truncate(searched + n);
+        if n == 0 {
+            if pending.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-request"));
+        }
+        started.get_or_insert_with(Instant::now);
+    }
+}
+
+// Whether the header lines in 'head' are within max_headers and max_header_line. Each line
+// counts, so a header folded over several is several
+pub(crate) fn check_header_limits(head: &[u8], options: &HttpOptions) -> Result<(), String> {
+    let lines = head.split(|b| *b == b'\n').skip(1).map(|line| line.strip_suffix(b"\r").unwrap_or(line)).filter(|line| !line.is_empty());
+    let mut count = 0;
+    for line in lines {
+        count += 1;
+        if count > options.max_headers {
+            return Err(format!("more than {} header lines", options.max_headers));
+        }
+        if line.len() > options.max_header_line {
+            return Err(format!("a {} byte header line", line.len()));
+        }
+    }
+    Ok(())
+}
+
+// Take a 'len' byte body off the front of what's been read, reading the rest from 'stream'
+// It never holds more than 'len' bytes, which the caller has checked against max_body_size
+pub(crate) fn read_body(stream: &mut impl Read, pending: &mut Vec<u8>, len: u64) -> io::Result<Vec<u8>> {
+    let buffered = pending.len().min(usize::try_from(len).unwrap_or(usize::MAX));
+    let mut body: Vec<u8> = pending.drain(..buffered).collect();
+    let rest = len - buffered as u64;
+    let read = stream.take(rest).read_to_end(&mut body)?;
+    if (read as u64) < rest {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed mid-body"));
+    }
+    Ok(body)
+}
+
+// The client's X-Request-Id if it's one we'd put in a log line and a header as it is,
+// otherwise a new one: 16 hex digits, a count from a starting point picked at random
+// (enough, from the clock and our pid) so ids don't repeat across restarts either
+pub(crate) fn request_id(request: &Request) -> String {
+    static NEXT: OnceLock<AtomicU64> = OnceLock::new();
+    if let Some(id) = request.header("x-request-id")
+        && (1..=128).contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:+/=".contains(&b))
+    {
+        return id.to_string();
+    }
+    let next = NEXT.get_or_init(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seed = (now.as_nanos() as u64 ^ u64::from(process::id()).rotate_left(32)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        AtomicU64::new(seed)
+    });
+    format!("{:016x}", next.fetch_add(1, Ordering::Relaxed))
+}
+
+// How a request says where its body ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+// Chunked if Transfer-Encoding says so, otherwise the Content-Length, 0 without one
+// Anything ambiguous is an error with the status to refuse it with: a request with both
+// headers, or that a proxy in front of us might have read differently, could smuggle a
+// second request past it (RFC 9112 6.3)
+fn body_framing(request: &Request) -> Result<Framing, (StatusCode, String)> {
+    let codings: Vec<&str> = request.headers.get_all("transfer-encoding").flat_map(|value| value.split(',')).map(str::trim).collect();
+    if codings.is_empty() {
+        return content_length(request).map(Framing::Length).map_err(|e| (StatusCode::BadRequest, e));
+    }
+    if request.headers.contains("content-length") {
+        return Err((StatusCode::BadRequest, "both Transfer-Encoding and Content-Length".to_string()));
+    }
+    match codings[..] {
+        [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked),
+        // chunked has to come last for a request, or there's no telling where the body ends
+        [.., last] if !last.eq_ignore_ascii_case("chunked") => Err((StatusCode::BadRequest, format!("body isn't chunked last: {:?}", codings))),
+        _ => Err((StatusCode::NotImplemented, format!("unsupported transfer coding {:?}", codings))),
+    }
+}
+
+// The Content-Length header, 0 without one
+// Repeats have to agree, or a proxy in front of us might have read a different body
+fn content_length(request: &Request) -> Result<u64, String> {
+    let mut length = None;
+    for value in request.headers.get_all("content-length") {
+        let parsed: u64 = value.parse().map_err(|_| format!("invalid Content-Length {:?}", value))?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err("conflicting Content-Length headers".to_string());
+        }
+        length = Some(parsed);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// Answer with 'status' and its error page from 'doc_root', then the connection gets closed
+pub(crate) fn refuse(stream: &mut impl Write, status: StatusCode, doc_root: &Path, options: &HttpOptions) {
+    let response = stamp(error_response(status, doc_root, options), options, false);
+    if let Err(e) = response.write_to(stream) {
+        log!(Error, "Failed to write {} response: {}", status, e);
+    }
+}
+
+// The page error_pages has for 'status', if any
+fn error_page(status: StatusCode, options: &HttpOptions) -> Option<&Path> {
+    options.error_pages.iter().find(|(code, _)| *code == status).map(|(_, page)| page.as_path()).filter(|page| !page.as_os_str().is_empty())
+}
+
+// A response for 'status' with its page from error_pages, read from 'doc_root', or without one
+// a line saying what went wrong, for whoever is poking at us with curl or nc
+pub(crate) fn error_response(status: StatusCode, doc_root: &Path, options: &HttpOptions) -> Response {
+    if let Some(page) = error_page(status, options) {
+        let path = doc_root.join(page);
+        match fs::read(&path) {
+            Ok(body) => return Response::new(status).with_header("Content-Type", mime::content_type(&path, &options.mime_types)).with_body(body),
+            Err(e) => log!(Debug, "No {} page at {}: {}", status.as_u16(), path.display(), e),
+        }
+    }
+    plain(status)
+}
+
+// 'status' with a line of text saying it
+pub(crate) fn plain(status: StatusCode) -> Response {
+    Response::new(status).with_header("Content-Type", "text/plain; charset=utf-8").with_body(format!("{}\n", status))
+}
+
+// The headers every response gets: Server, if there is one and the response hasn't its own,
+// and Connection saying what becomes of the connection, keep-alive or close, over whatever
+// the response said. Date is added when the response is written
+pub(crate) fn stamp(mut response: Response, options: &HttpOptions, keep_alive: bool) -> Response {
+    if let Some(server) = &options.server
+        && !response.headers.contains("server")
+    {
+        response.headers.append("Server", server.as_str());
+    }
+    response.headers.insert("Connection", if keep_alive { "keep-alive" } else { "close" });
+    response
+}
+
+// What the stock pages answer to, for Allow headers
+const STOCK_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// Write the response for one request, false if the connection is no good any more
+fn respond(stream: &mut impl Write, request: &Request, doc_root: &Path, options: &HttpOptions, keep_alive: bool) -> bool {
+    send(stream, request, options, keep_alive, page(request, doc_root, options))
+}
+
+// The response to one request, whatever it goes back over: a redirect, the router's, or
+// one of the stock pages from 'doc_root'
+pub(crate) fn page(request: &Request, doc_root: &Path, options: &HttpOptions) -> Response {
+    // request.path is normalized already, this is just about which spelling the client sees
+    if options.canonical_redirects
+        && request.raw_path.starts_with('/')
+        && let canonical = url::encode_path(&request.path)
+        && canonical != request.raw_path
+    {
+        let location = if request.query_string.is_empty() { canonical } else { format!("{}?{}", canonical, request.query_string) };
+        // 308 keeps the method and body, which a 301 can lose; GETs get the one everything knows
+        let kind = if matches!(request.method, Method::Get | Method::Head) { Redirect::MovedPermanently } else { Redirect::PermanentRedirect };
+        return Response::redirect(&location, kind);
+    }
+    if let Some((_, to)) = options.redirects.iter().find(|(from, _)| *from == request.path) {
+        // the query string comes along, unless the rule has its own
+        let location = if request.query_string.is_empty() || to.contains('?') {
+            to.clone()
+        } else {
+            format!("{}?{}", to, request.query_string)
+        };
+        return Response::redirect(&location, Redirect::MovedPermanently);
+    }
+    if let Some(router) = &options.router
+        && let Some(response) = router.dispatch(request)
+    {
+        return response;
+    }
+    let (response, filename) = match (request.method, request.path.as_str()) {
+        (Method::Get | Method::Head, "/") => (Response::new(StatusCode::Ok), Path::new("index.html")),
+        // if a req takes too long, we go here
+        (Method::Get | Method::Head, "/sleep") => {
+            thread::sleep(Duration::from_secs(5));
+            (Response::new(StatusCode::Ok), Path::new("index.html"))
+        }
+        // what can be done with a page, or "*" for the server as a whole
+        (Method::Options, "/" | "/sleep" | "*") => {
+            return Response::new(StatusCode::NoContent).with_header("Allow", STOCK_METHODS);
+        }
+        // the pages are there, they just can't be posted to or deleted
+        (_, "/" | "/sleep") => {
+            return Response::new(StatusCode::MethodNotAllowed).with_header("Allow", STOCK_METHODS);
+        }
+        // API clients get their 404 as JSON, browsers and everyone else the page
+        _ if request.preferred(&["text/html", "application/json"]) == Some("application/json") => {
+            let body = format!("{{\"status\":404,\"error\":\"{}\"}}\n", StatusCode::NotFound.reason());
+            return Response::new(StatusCode::NotFound).with_header("Content-Type", "application/json").with_header("Vary", "Accept").with_body(body);
+        }
+        // the page goes out like any other, compressed and all, if it's there
+        _ => match error_page(StatusCode::NotFound, options) {
+            Some(page) if doc_root.join(page).is_file() => (Response::new(StatusCode::NotFound).with_header("Vary", "Accept"), page),
+            _ => {
+                return error_response(StatusCode::NotFound, doc_root, options).with_header("Vary", "Accept");
+            }
+        },
+    };
+
+    let path = doc_root.join(filename);
+    let content_type = mime::content_type(&path, &options.mime_types);
+    let mut response = response;
+    // gzip for clients that take it, though not for ranges, which count bytes of the file as it is
+    let compressible = options.compress && compress::compressible(content_type, &options.compress_types);
+    let mut gzip = compressible && !request.headers.contains("range") && request.headers.get("accept-encoding").is_some_and(compress::accepts_gzip);
+    if compressible {
+        response.headers.append("Vary", "Accept-Encoding");
+    }
+    let mut contents = None;
+    if response.status == StatusCode::Ok
+        && let Ok(metadata) = fs::metadata(&path)
+    {
+        gzip &= metadata.len() >= options.compress_min_size;
+        let modified = metadata.modified().ok();
+        let etag = if options.content_etags {
+            // hashing takes reading the file, a match still saves sending it
+            contents = fs::read(&path).ok();
+            contents.as_deref().map(conditional::content_etag)
+        } else {
+            modified.map(|modified| conditional::weak_etag(metadata.len(), modified))
+        };
+        let etag = etag.map(|etag| if gzip { compress::gzip_etag(&etag) } else { etag });
+        let validators = [("ETag", etag), ("Last-Modified", modified.map(date::format))];
+        let validators = validators.into_iter().filter_map(|(name, value)| Some((name, value?)));
+        response = validators.fold(response, |response, (name, value)| response.with_header(name, value));
+        // a client with an up to date copy gets a 304, usually without the file being read at all
+        if conditional::not_modified(request, response.headers.get("etag"), response.headers.get("last-modified")) {
+            let mut not_modified = Response::new(StatusCode::NotModified);
+            not_modified.headers = response.headers;
+            return not_modified;
+        }
+    }
+    let response = response.with_header("Content-Type", content_type);
+    match with_file(response, request, &path, contents, gzip, options) {
+        Ok(response) => response,
+        Err(e) => {
+            log!(Error, "Failed to read {}: {}", path.display(), e);
+            error_response(StatusCode::InternalServerError, doc_root, options).with_header("Connection", "close")
+        }
+    }
+}
+
+// 'response' with the file at 'path' as its body: all of it, the range asked for (or a 416
+// instead), or gzipped when 'gzip' and it's big enough. It's streamed from disk, compressed
+// as it goes, unless it's been read into 'contents' for its ETag already
+fn with_file(mut response: Response, request: &Request, path: &Path, contents: Option<Vec<u8>>, gzip: bool, options: &HttpOptions) -> io::Result<Response> {
+    let mut file = fs::File::open(path)?;
+    let len = match &contents {
+        Some(contents) => contents.len() as u64,
+        None => file.metadata()?.len(),
+    };
+    if response.status == StatusCode::Ok {
+        response = response.with_header("Accept-Ranges", "bytes");
+    }
+    if gzip && len >= options.compress_min_size {
+        // the compressed length isn't known until the end, so it goes out chunked
+        let response = response.with_header("Content-Encoding", "gzip");
+        return Ok(match contents {
+            Some(contents) => response.with_reader(compress::Gzip::new(Cursor::new(contents)), None),
+            None => response.with_reader(compress::Gzip::new(file), None),
+        });
+    }
+    let mut range = 0..len;
+    if response.status == StatusCode::Ok {
+        match range::requested(request, len, response.headers.get("etag"), response.headers.get("last-modified")) {
+            Some(ByteRange::Partial(partial)) => {
+                response.status = StatusCode::PartialContent;
+                response.headers.append("Content-Range", format!("bytes {}-{}/{}", partial.start, partial.end - 1, len));
+                range = partial;
+            }
+            Some(ByteRange::Unsatisfiable) => {
+                return Ok(Response::new(StatusCode::RangeNotSatisfiable).with_header("Content-Range", format!("bytes */{}", len)));
+            }
+            None => {}
+        }
+    }
+    match contents {
+        Some(contents) => Ok(response.with_body(contents[range.start as usize..range.end as usize].to_vec())),
+        None => {
+            file.seek(SeekFrom::Start(range.start))?;
+            Ok(response.with_reader(file.take(range.end - range.start), Some(range.end - range.start)))
+        }
+    }
+}
+
+// Write 'response' with the standard headers, in the request's HTTP version and just its
+// head if 'request' is a HEAD. False if that failed, or the connection has to close after
+// it: the response said Connection: close, or its body ran to the connection's end
+pub(crate) fn send(stream: &mut impl Write, request: &Request, options: &HttpOptions, keep_alive: bool, response: Response) -> bool {
+    let response = finish(request, response);
+    // a 1.0 client can only tell where a body of unknown length ends by the connection closing
+    let close = response.headers.has_token("connection", "close") || (response.close_delimited() && request.method != Method::Head);
+    let response = stamp(response, options, keep_alive && !close);
+    let status = response.status;
+    let written = match request.method {
+        Method::Head => response.write_head(stream),
+        _ => response.write_to(stream),
+    };
+    if let Err(e) = written {
+        log!(Error, "{} Failed to write response: {}", request.id, e);
+        return false;
+    }
+    access_log(request, status);
+    !close
+}
+
+// 'response' as it goes back to 'request', in its version and with its id, or a 500 if it
+// has a header that can't be sent
+pub(crate) fn finish(request: &Request, mut response: Response) -> Response {
+    // a header that could split the response in two is a bug (or an attack) somewhere upstream,
+    // the client gets a 500 rather than whatever it would have made of it
+    if let Some(name) = response.invalid_header() {
+        log!(Error, "{} Refusing to send invalid header {:?}", request.id, name);
+        response = plain(StatusCode::InternalServerError).with_header("Connection", "close");
+    }
+    response.version = request.version;
+    if !request.id.is_empty() && !response.headers.contains("x-request-id") {
+        response.headers.append("X-Request-Id", request.id.as_str());
+    }
+    response
+}
+
+// The access log line for a request answered with 'status': id, request line and status
+pub(crate) fn access_log(request: &Request, status: StatusCode) {
+    let query = if request.query_string.is_empty() { String::new() } else { format!("?{}", request.query_string) };
+    log!(Info, "{} \"{} {}{} {}\" {}", request.id, request.method, request.raw_path, query, request.version, status.as_u16());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // An in-memory connection: reads come from 'input', writes pile up in 'output'
+    struct Fake {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Fake {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Fake {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SetTimeouts for Fake {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn serve(request: &[u8]) -> Vec<u8> {
+        let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+        handle_connection(&mut fake, Path::new(env!("CARGO_MANIFEST_DIR")).join("static").as_path());
+        fake.output
+    }
+
+    #[test]
+    fn test_http2_preface_gets_goaway() {
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        let reply = serve(&request);
+        assert_eq!(reply, H2_GOAWAY);
+        // GOAWAY's error code is the last four bytes
+        assert_eq!(u32::from_be_bytes(reply[22..].try_into().unwrap()), 0xd);
+    }
+
+    #[test]
+    fn test_http2_preface_served_with_http2_on() {
+        let options = HttpOptions { http2: true, ..HttpOptions::default() };
+        let mut request = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        request.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        let mut fake = Fake { input: Cursor::new(request), output: Vec::new() };
+        serve_requests(&mut fake, &options, None, None, |_| Path::new("static"));
+        // our SETTINGS, then the ACK of the client's
+        assert_eq!(fake.output[3..5], [0x4, 0]);
+        assert!(fake.output.ends_with(&[0, 0, 0, 0x4, 0x1, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_http1_still_served() {
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    fn responses(output: &[u8]) -> usize {
+        output.windows(9).filter(|w| w.starts_with(b"HTTP/1.") && w[8] == b' ').count()
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        // pipelined, and the second one split across the read boundary doesn't matter
+        let output = serve(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET /nope HTTP/1.1\r\nHost: h\r\n\r\n");
+        assert_eq!(responses(&output), 2);
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.contains("HTTP/1.1 404"));
+        assert!(text.contains("Connection: keep-alive"));
+
+        let output = serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: Close\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\n\r\n");
+        assert_eq!(responses(&output), 1);
+        assert!(String::from_utf8_lossy(&output).contains("Connection: close"));
+
+        // HTTP/1.0 closes unless it asks not to
+        assert_eq!(responses(&serve(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n")), 1);
+        let output = serve(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+        assert_eq!(responses(&output), 2);
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.contains("\r\nConnection: keep-alive\r\n") && text.contains("\r\nConnection: close\r\n"));
+        // close wins wherever it is in the list
+        assert_eq!(responses(&serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: keep-alive, Close\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\n\r\n")), 1);
+
+        // a response saying close gets the connection closed, one saying keep-alive can't keep
+        // open a connection that's closing
+        let request = Request::parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let sent = |keep_alive, connection| {
+            let mut output = Vec::new();
+            let response = Response::new(StatusCode::Ok).with_header("Connection", connection);
+            let open = send(&mut output, &request, &HttpOptions::default(), keep_alive, response);
+            (open, String::from_utf8(output).unwrap())
+        };
+        let (open, output) = sent(true, "close");
+        assert!(!open && output.matches("Connection:").count() == 1 && output.contains("\r\nConnection: close\r\n"));
+        let (_, output) = sent(false, "keep-alive");
+        assert!(output.matches("Connection:").count() == 1 && output.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        for request in [
+            &b"GET / HTTP/1.1\r\nHost: h\r\nX: a\0b\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nHost: h\r\nX: a\r\n b\0c\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: h\r\nX: a\r\n b\rc\r\n\r\n",
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: h",
+        ] {
+            let output = String::from_utf8(serve(request)).unwrap();
+            assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", String::from_utf8_lossy(request));
+            assert!(output.contains("\r\nConnection: close\r\n") && output.ends_with("\r\n\r\n400 Bad Request\n"));
+        }
+        // a client ending its lines in bare LFs is understood rather than left waiting
+        assert!(serve(b"GET / HTTP/1.1\nHost: h\n\n").starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let options = HttpOptions {
+            error_pages: vec![(StatusCode::NotFound, PathBuf::new()), (StatusCode::BadRequest, PathBuf::from("404.html")), (StatusCode::ContentTooLarge, PathBuf::from("missing.html"))],
+            ..HttpOptions::default()
+        };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let fetch = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        // a branded 400, even for a request we couldn't make sense of
+        let bad = fetch(b"GET /\r\n\r\n");
+        assert!(bad.starts_with("HTTP/1.1 400 Bad Request\r\n") && bad.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert!(bad.ends_with("</html>"));
+        // no page, or one that isn't there, is a line of text
+        let not_found = fetch(b"GET /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(not_found.starts_with("HTTP/1.1 404") && not_found.ends_with("\r\n\r\n404 Not Found\n"));
+        assert!(not_found.contains("\r\nVary: Accept\r\n"));
+        let too_large = fetch(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 9999999\r\n\r\n");
+        assert!(too_large.starts_with("HTTP/1.1 413") && too_large.ends_with("\r\n\r\n413 Content Too Large\n"));
+        // and the stock 503 is a page
+        let busy = error_response(StatusCode::ServiceUnavailable, &root, &HttpOptions::default());
+        assert!(busy.body.as_bytes().unwrap().starts_with(b"<!DOCTYPE html>"));
+    }
+
+    #[test]
+    fn test_header_injection() {
+        let options = HttpOptions { mime_types: vec![("html".to_string(), "text/html\r\nSet-Cookie: admin=1".to_string())], ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let mut fake = Fake { input: Cursor::new(b"GET / HTTP/1.1\r\nHost: h\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\n\r\n".to_vec()), output: Vec::new() };
+        serve_requests(&mut fake, &options, None, None, |_| &root);
+        let output = String::from_utf8(fake.output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n") && !output.contains("Set-Cookie"), "{}", output);
+        assert!(output.contains("\r\nConnection: close\r\n") && responses(output.as_bytes()) == 1);
+    }
+
+    #[test]
+    fn test_canonical_redirects() {
+        let options = HttpOptions { canonical_redirects: true, ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let fetch = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        let moved = fetch(b"GET /x/./../%73leep//?a=1 HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(moved.starts_with("HTTP/1.1 301") && moved.contains("\r\nLocation: /sleep/?a=1\r\n"), "{}", moved);
+        let posted = fetch(b"POST /a/../b HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(posted.starts_with("HTTP/1.1 308") && posted.contains("\r\nLocation: /b\r\n"));
+        let spaced = fetch(b"GET /hello%20world HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(spaced.starts_with("HTTP/1.1 404"));
+        assert!(fetch(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 200"));
+        assert!(fetch(b"OPTIONS * HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 204"));
+        // off by default, the page is just served
+        assert!(serve(b"GET /./ HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        // as a proxy would pass it on, the page is looked for by path and the site by authority
+        let output = serve(b"GET http://example.com/x/..?a=1 HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n");
+        assert!(output.starts_with(b"HTTP/1.1 200"));
+        let hosts = VirtualHosts::new("missing").host("example.com", Path::new(env!("CARGO_MANIFEST_DIR")).join("static"));
+        let mut fake = Fake { input: Cursor::new(b"GET http://example.com/ HTTP/1.1\r\nHost: elsewhere\r\nConnection: close\r\n\r\n".to_vec()), output: Vec::new() };
+        handle_site(&mut fake, &hosts, &HttpOptions::default(), None);
+        assert!(fake.output.starts_with(b"HTTP/1.1 200"), "{}", String::from_utf8_lossy(&fake.output));
+        assert!(serve(b"GET http://user@example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let echo = |request: &Request, mut upgraded: Upgraded<'_>| {
+            if request.path != "/echo" {
+                return upgraded.refuse(Response::new(StatusCode::BadRequest));
+            }
+            if upgraded.switch(Response::new(StatusCode::Ok).with_header("X-Echo", "yes")).is_ok() {
+                let mut echoed = Vec::new();
+                upgraded.read_to_end(&mut echoed).unwrap();
+                upgraded.write_all(&echoed).unwrap();
+            }
+        };
+        let mut options = HttpOptions::default();
+        options.upgrades.push(("echo".to_string(), Arc::new(echo)));
+        options.upgrades.push(("silent".to_string(), Arc::new(|_: &Request, _: Upgraded<'_>| {})));
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let fetch = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        // what follows the request is the new protocol's, not another request
+        let switched = fetch(b"GET /echo HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: Echo\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        assert!(switched.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", switched);
+        assert!(switched.contains("\r\nX-Echo: yes\r\n") && switched.contains("\r\nConnection: Upgrade\r\nUpgrade: Echo\r\n"));
+        assert!(switched.ends_with("\r\n\r\nGET / HTTP/1.1\r\n\r\n") && responses(switched.as_bytes()) == 1);
+        let refused = fetch(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\n\r\n");
+        assert!(refused.starts_with("HTTP/1.1 400") && refused.contains("\r\nConnection: close\r\n") && responses(refused.as_bytes()) == 1);
+        assert!(fetch(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: silent\r\n\r\n").starts_with("HTTP/1.1 500"));
+        // protocols nobody takes, or an Upgrade without Connection: upgrade, are plain HTTP
+        assert!(fetch(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n").starts_with("HTTP/1.1 200"));
+        assert!(fetch(b"GET /echo HTTP/1.1\r\nHost: h\r\nUpgrade: echo\r\n\r\n").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_router() {
+        let mut router = Router::new();
+        router
+            .post("/submit", |request| Response::new(StatusCode::Created).with_body(request.body.clone()))
+            .get("/", |_| Response::new(StatusCode::Ok).with_body("routed"));
+        let options = HttpOptions { router: Some(Arc::new(router)), ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let fetch = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        let output = fetch(b"POST /submit HTTP/1.1\r\nHost: h\r\nContent-Length: 2\r\n\r\nhiGET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 201 Created\r\n") && output.contains("\r\n\r\nhiHTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nrouted") && responses(output.as_bytes()) == 2);
+        let head = fetch(b"HEAD / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(head.contains("\r\nContent-Length: 6\r\n") && head.ends_with("\r\n\r\n"));
+        let wrong = fetch(b"GET /submit HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(wrong.starts_with("HTTP/1.1 405") && wrong.contains("\r\nAllow: POST, OPTIONS\r\n"));
+        // what isn't routed is the stock pages'
+        assert!(fetch(b"GET /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let ids = |request: &[u8]| {
+            let output = String::from_utf8(serve(request)).unwrap();
+            output.lines().filter_map(|line| line.strip_prefix("X-Request-Id: ")).map(str::to_string).collect::<Vec<_>>()
+        };
+        let made_up = ids(b"GET / HTTP/1.1\r\nHost: h\r\n\r\nGET /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert_eq!(made_up.len(), 2);
+        assert!(made_up.iter().all(|id| id.len() == 16 && id.bytes().all(|b| b.is_ascii_hexdigit())));
+        assert_ne!(made_up[0], made_up[1]);
+        // the client's own is kept, unless it's something we'd rather not echo
+        assert_eq!(ids(b"GET / HTTP/1.1\r\nHost: h\r\nX-Request-Id: abc-123\r\n\r\n"), ["abc-123"]);
+        let sneaky = ids(b"GET / HTTP/1.1\r\nHost: h\r\nX-Request-Id: a\" b\r\n\r\n");
+        assert!(sneaky.len() == 1 && sneaky[0].len() == 16);
+    }
+
+    #[test]
+    fn test_versions() {
+        // HTTP/1.1 has to say which host, exactly once; 1.0 needn't
+        assert!(serve(b"GET / HTTP/1.1\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost:\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+        assert!(serve(b"GET / HTTP/1.0\r\n\r\n").starts_with(b"HTTP/1.0 200"));
+        // and a later 1.x is answered as 1.1
+        assert!(serve(b"GET / HTTP/1.2\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+
+        // a body of unknown length goes chunked to 1.1 and to the connection's end for 1.0
+        let streamed = |request: &[u8]| {
+            let request = Request::parse(request).unwrap();
+            let response = Response::new(StatusCode::Ok).with_reader(Cursor::new("abc"), None);
+            let mut output = Vec::new();
+            let keep_open = send(&mut output, &request, &HttpOptions::default(), true, response);
+            (String::from_utf8(output).unwrap(), keep_open)
+        };
+        let (chunked, keep_open) = streamed(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n");
+        assert!(keep_open && chunked.contains("\r\nTransfer-Encoding: chunked\r\n") && chunked.ends_with("\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+        let (close_delimited, keep_open) = streamed(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+        assert!(!keep_open && close_delimited.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(close_delimited.contains("\r\nConnection: close\r\n") && !close_delimited.contains("Transfer-Encoding"));
+        assert!(close_delimited.ends_with("\r\n\r\nabc"));
+    }
+
+    #[test]
+    fn test_virtual_hosts() {
+        let root = std::env::temp_dir().join(format!("webserver-vhost-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "site a").unwrap();
+        let hosts = VirtualHosts::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("static")).host("a.example", &root);
+
+        let mut fake = Fake {
+            input: Cursor::new(b"GET / HTTP/1.1\r\nHost: A.example:7878\r\n\r\nGET / HTTP/1.1\r\nHost: b.example\r\n\r\n".to_vec()),
+            output: Vec::new(),
+        };
+        handle_virtual_hosts(&mut fake, &hosts);
+        fs::remove_dir_all(&root).unwrap();
+        let text = String::from_utf8_lossy(&fake.output);
+        assert!(text.contains("\r\n\r\nsite aHTTP/1.1 200 OK"));
+        assert!(text.ends_with(&fs::read_to_string("static/index.html").unwrap()));
+    }
+
+    #[test]
+    fn test_request_bodies() {
+        // the body doesn't get mistaken for the next request
+        let output = serve(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert_eq!(responses(&output), 2);
+        assert!(!String::from_utf8_lossy(&output).contains("400"));
+
+        let output = serve(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 999999999\r\n\r\nhello");
+        assert!(output.starts_with(b"HTTP/1.1 413 "));
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: lots\r\n\r\n").starts_with(b"HTTP/1.1 400 "));
+        // the client hanging up partway through just closes the connection
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 10\r\n\r\nhello").is_empty());
+
+        let mut pending = b"hel".to_vec();
+        let body = read_body(&mut Cursor::new(b"lo, and the next request".to_vec()), &mut pending, 5).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn test_keep_alive_request_limit() {
+        let request = b"GET / HTTP/1.1\r\nHost: h\r\n\r\n".repeat(MAX_REQUESTS_PER_CONNECTION + 5);
+        let output = serve(&request);
+        assert_eq!(responses(&output), MAX_REQUESTS_PER_CONNECTION);
+        assert!(String::from_utf8_lossy(&output).ends_with(&fs::read_to_string("static/index.html").unwrap()));
+    }
+
+    // hands out one byte per read, a little slower each time
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn test_slow_head_times_out() {
+        let request = b"GET / HTTP/1.1\r\nHost: a\r\nX-Padding: aaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+        let options = HttpOptions { head_deadline: Duration::from_millis(50), ..HttpOptions::default() };
+        let mut pending = Vec::new();
+        let err = read_head(&mut Trickle(Cursor::new(request.to_vec())), &mut pending, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(!pending.is_empty());
+
+        // the same bytes all at once are fine
+        let mut pending = Vec::new();
+        let head = read_head(&mut Cursor::new(request.to_vec()), &mut pending, &options).unwrap();
+        assert_eq!(head.unwrap(), request);
+    }
+
+    #[test]
+    fn test_requests_are_parsed() {
+        let ok = String::from_utf8(serve(b"GET / HTTP/1.0\r\n\r\n")).unwrap();
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(ok.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 2\r\n\r\nab").starts_with(b"HTTP/1.1 405"));
+        assert!(serve(b"DELETE /nope HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+        let post = String::from_utf8(serve(b"PUT / HTTP/1.1\r\nHost: h\r\n\r\n")).unwrap();
+        assert!(post.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        assert!(serve(b"BREW / HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 501"));
+        assert!(serve(b"GET /index.html?x=1 HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+        assert!(serve(b"GET /?foo=bar HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 200 OK"));
+        assert!(serve(b"GET /x/../%73leep/ HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+        assert!(serve(b"GET /%00 HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nbroken header\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(serve(b"GARBAGE\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        let conflicting = b"POST / HTTP/1.1\r\nHost: h\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab";
+        assert!(serve(conflicting).starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_long_bodies_are_read_whole() {
+        // several times the read buffer, or a leftover would be taken for the next request
+        let body = "a".repeat(HttpOptions::default().read_buffer * 5);
+        let request = format!("POST / HTTP/1.1\r\nHost: h\r\nContent-Length: {}\r\n\r\n{}GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n", body.len(), body);
+        let output = String::from_utf8(serve(request.as_bytes())).unwrap();
+        assert!(output.starts_with("HTTP/1.1 405"));
+        assert!(output.contains("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_standard_headers() {
+        let output = String::from_utf8(serve(b"GET / HTTP/1.1\r\nHost: h\r\n\r\nGET /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\n\r\n")).unwrap();
+        let heads: Vec<&str> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(heads.len(), 2);
+        for head in &heads {
+            let date = head.lines().find_map(|line| line.strip_prefix("Date: ")).unwrap();
+            assert!(date::parse(date).is_some(), "{}", date);
+            assert!(head.contains(&format!("\r\nServer: {}\r\n", DEFAULT_SERVER)));
+        }
+        assert!(heads[0].contains("\r\nConnection: keep-alive\r\n"));
+        assert!(heads[1].contains("\r\nConnection: close\r\n"));
+        // refusals get them too
+        let refused = String::from_utf8(serve(b"GARBAGE\r\n\r\n")).unwrap();
+        assert!(refused.contains("\r\nDate: ") && refused.contains("\r\nServer: ") && refused.contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn test_json_404() {
+        let json = String::from_utf8(serve(b"GET /nope HTTP/1.1\r\nHost: h\r\nAccept: application/json\r\n\r\n")).unwrap();
+        assert!(json.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(json.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(json.ends_with("\r\n\r\n{\"status\":404,\"error\":\"Not Found\"}\n"));
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        let html = String::from_utf8(serve(format!("GET /nope HTTP/1.1\r\nHost: h\r\nAccept: {}\r\n\r\n", browser).as_bytes())).unwrap();
+        assert!(html.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn test_head() {
+        // the Date might tick over between the two, and the request ids differ
+        let undated = |output: Vec<u8>| {
+            let output = String::from_utf8(output).unwrap();
+            output.split("\r\n").filter(|line| !line.starts_with("Date: ") && !line.starts_with("X-Request-Id: ")).collect::<Vec<_>>().join("\r\n")
+        };
+        let get = undated(serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n"));
+        let head = undated(serve(b"HEAD / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n"));
+        let end = get.find("\r\n\r\n").unwrap() + 4;
+        assert!(end < get.len());
+        assert_eq!(head, &get[..end]);
+        assert!(serve(b"HEAD /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").ends_with(b"\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_options() {
+        for request in [&b"OPTIONS / HTTP/1.1\r\nHost: h\r\n\r\n"[..], b"OPTIONS * HTTP/1.1\r\nHost: h\r\n\r\n"] {
+            let output = String::from_utf8(serve(request)).unwrap();
+            assert!(output.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", output);
+            assert!(output.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+            assert!(!output.contains("Content-Length"));
+        }
+        assert!(serve(b"OPTIONS /nope HTTP/1.1\r\nHost: h\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let ok = String::from_utf8(serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n")).unwrap();
+        let last_modified = ok.lines().find_map(|line| line.strip_prefix("Last-Modified: ")).unwrap();
+        let conditional = |since: &str| serve(format!("GET / HTTP/1.1\r\nHost: h\r\nIf-Modified-Since: {}\r\nConnection: close\r\n\r\n", since).as_bytes());
+
+        let not_modified = String::from_utf8(conditional(last_modified)).unwrap();
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(not_modified.ends_with("\r\n\r\n") && !not_modified.contains("Content-Length"));
+        assert!(conditional("Thu, 01 Jan 1970 00:00:00 GMT").starts_with(b"HTTP/1.1 200 OK"));
+        assert!(conditional("not a date").starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let ok = String::from_utf8(serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n")).unwrap();
+        let etag = ok.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap();
+        assert!(etag.starts_with("W/\""));
+        let conditional = |tags: &str| serve(format!("GET / HTTP/1.1\r\nHost: h\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n", tags).as_bytes());
+        let not_modified = String::from_utf8(conditional(&format!("\"other\", {}", etag))).unwrap();
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(not_modified.contains(&format!("\r\nETag: {}\r\n", etag)));
+        assert!(conditional("\"other\"").starts_with(b"HTTP/1.1 200 OK"));
+
+        // with content hashes the tag is strong, and the same whatever the file's dates
+        let mut fake = Fake { input: Cursor::new(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n".to_vec()), output: Vec::new() };
+        let options = HttpOptions { content_etags: true, ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        serve_requests(&mut fake, &options, None, None, |_| &root);
+        let strong = String::from_utf8(fake.output).unwrap();
+        let contents = fs::read(root.join("index.html")).unwrap();
+        assert!(strong.contains(&format!("\r\nETag: {}\r\n", conditional::content_etag(&contents))));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let full = serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        let body_start = full.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = &full[body_start..];
+        assert!(String::from_utf8_lossy(&full).contains("\r\nAccept-Ranges: bytes\r\n"));
+
+        let partial = serve(b"GET / HTTP/1.1\r\nHost: h\r\nRange: bytes=1-4\r\nConnection: close\r\n\r\n");
+        let text = String::from_utf8_lossy(&partial);
+        assert!(text.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(text.contains(&format!("\r\nContent-Range: bytes 1-4/{}\r\n", body.len())));
+        assert!(text.contains("\r\nContent-Length: 4\r\n"));
+        assert!(partial.ends_with(&body[1..5]));
+
+        let unsatisfiable = serve(format!("GET / HTTP/1.1\r\nHost: h\r\nRange: bytes={}-\r\n\r\n", body.len()).as_bytes());
+        let text = String::from_utf8_lossy(&unsatisfiable);
+        assert!(text.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(text.contains(&format!("\r\nContent-Range: bytes */{}\r\n", body.len())));
+        // 404s and ranges nobody understands get the whole thing
+        assert!(serve(b"GET /nope HTTP/1.1\r\nHost: h\r\nRange: bytes=0-0\r\n\r\n").starts_with(b"HTTP/1.1 404"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nRange: lines=1-2\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_binary_pages() {
+        // not UTF-8, and spanning several of the reads a streamed body takes
+        let contents: Vec<u8> = (0..=255).cycle().take(40_000).collect();
+        let root = std::env::temp_dir().join(format!("webserver-binary-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), &contents).unwrap();
+
+        let serve = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &HttpOptions::default(), None, None, |_| &root);
+            fake.output
+        };
+        let output = serve(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(output.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with(&contents));
+        // bytes that aren't UTF-8 in a header value don't stop the request being understood
+        let output = serve(b"GET / HTTP/1.1\r\nHost: h\r\nX-Name: caf\xe9\r\nRange: bytes=-3\r\nConnection: close\r\n\r\n");
+        assert!(output.starts_with(b"HTTP/1.1 206") && output.ends_with(&contents[contents.len() - 3..]));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let options = HttpOptions { compress_min_size: 0, ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let fetch = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            let head_end = fake.output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (String::from_utf8(fake.output[..head_end].to_vec()).unwrap(), fake.output[head_end..].to_vec())
+        };
+        let (head, body) = fetch(b"GET / HTTP/1.1\r\nHost: h\r\nAccept-Encoding: gzip, br\r\nConnection: close\r\n\r\n");
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"), "{}", head);
+        assert!(head.contains("\r\nVary: Accept-Encoding\r\n"));
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(head.contains("-gzip\"\r\n"));
+        let (body, _) = chunked::read_chunked(&mut Cursor::new(body), &mut Vec::new(), u64::MAX).unwrap();
+        let mut unzipped = Vec::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut unzipped).unwrap();
+        assert_eq!(unzipped, fs::read(root.join("index.html")).unwrap());
+
+        // the plain file for clients that don't take gzip and for ranges, varying all the same
+        let (plain, _) = fetch(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(!plain.contains("Content-Encoding") && plain.contains("\r\nVary: Accept-Encoding\r\n"));
+        let (partial, _) = fetch(b"GET / HTTP/1.1\r\nHost: h\r\nAccept-Encoding: gzip\r\nRange: bytes=0-9\r\nConnection: close\r\n\r\n");
+        assert!(partial.starts_with("HTTP/1.1 206") && !partial.contains("Content-Encoding"));
+        // and under the default 1KB minimum the stock pages are too small to bother with
+        let small = serve(b"GET / HTTP/1.1\r\nHost: h\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n");
+        assert!(!String::from_utf8_lossy(&small).contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn test_redirect_rules() {
+        let options = HttpOptions { redirects: vec![("/old".to_string(), "/".to_string())], ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let serve = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        let moved = serve(b"GET /old?page=2 HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n");
+        assert!(moved.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", moved);
+        assert!(moved.contains("\r\nLocation: /?page=2\r\n"));
+        assert!(serve(b"GET /old/ HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        // the client hangs up rather than send the body, but it was asked for
+        let output = serve(b"POST / HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n");
+        assert_eq!(output, b"HTTP/1.1 100 Continue\r\n\r\n");
+        // the body's already on its way, or there isn't one
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nab").starts_with(b"HTTP/1.1 405"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\n\r\n").starts_with(b"HTTP/1.1 200"));
+        let max = HttpOptions::default().max_body_size;
+        let huge = format!("POST / HTTP/1.1\r\nHost: h\r\nExpect: 100-continue\r\nContent-Length: {}\r\n\r\n", max + 1);
+        assert!(serve(huge.as_bytes()).starts_with(b"HTTP/1.1 413"));
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nExpect: teapot\r\nContent-Length: 0\r\n\r\n").starts_with(b"HTTP/1.1 417"));
+        // HTTP/1.0 clients can't expect anything
+        assert!(serve(b"GET / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").starts_with(b"HTTP/1.0 200"));
+    }
+
+    #[test]
+    fn test_chunked_request_bodies() {
+        // the body comes off the connection, so the pipelined request behind it gets answered
+        let chunked = b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n";
+        let output = String::from_utf8(serve(chunked)).unwrap();
+        assert!(output.starts_with("HTTP/1.1 405"));
+        assert!(output.contains("HTTP/1.1 200 OK"));
+
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n").starts_with(b"HTTP/1.1 400"));
+        let both = b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n0\r\n\r\n";
+        assert!(serve(both).starts_with(b"HTTP/1.1 400"));
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").starts_with(b"HTTP/1.1 501"));
+        assert!(serve(b"POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked, gzip\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        let max = HttpOptions::default().max_body_size;
+        let huge = format!("POST / HTTP/1.1\r\nHost: h\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n", max + 1);
+        assert!(serve(huge.as_bytes()).starts_with(b"HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let options = HttpOptions { max_headers: 3, max_header_line: 20, ..HttpOptions::default() };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let serve = |request: &[u8]| {
+            let mut fake = Fake { input: Cursor::new(request.to_vec()), output: Vec::new() };
+            serve_requests(&mut fake, &options, None, None, |_| &root);
+            String::from_utf8(fake.output).unwrap()
+        };
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nA: 1\r\nB: 2\r\n\r\n").starts_with("HTTP/1.1 200"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").starts_with("HTTP/1.1 431"));
+        // the request line doesn't count, however long
+        assert!(serve(b"GET /?a-rather-long-query-string HTTP/1.1\r\nHost: h\r\nA: 12345678901234\r\n\r\n").starts_with("HTTP/1.1 200"));
+        assert!(serve(b"GET / HTTP/1.1\r\nHost: h\r\nA: 123456789012345678\r\n\r\n").starts_with("HTTP/1.1 431"));
+    }
+
+    #[test]
+    fn test_oversized_head_gets_431() {
+        let max = HttpOptions::default().max_head_len;
+        let mut request = b"GET / HTTP/1.1\r\nHost: h\r\nX: ".to_vec();
+        request.resize(max * 2, b'a');
+        assert!(serve(&request).starts_with(b"HTTP/1.1 431 "));
+
+        // a long cookie under the limit still gets through, whatever the read size
+        let mut request = b"GET / HTTP/1.1\r\nHost: h\r\nCookie: ".to_vec();
+        request.resize(max - 4, b'a');
+        request.extend_from_slice(b"\r\n\r\n");
+        let options = HttpOptions { read_buffer: 7, ..HttpOptions::default() };
+        let mut pending = Vec::new();
+        let head = read_head(&mut Cursor::new(request.clone()), &mut pending, &options).unwrap().unwrap();
+        assert_eq!(head, request);
+        request.insert(20, b'a');
+        let err = read_head(&mut Cursor::new(request), &mut Vec::new(), &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{fmt, slice};
+
+/// HTTP headers: names compare case-insensitively, a name can repeat (Set-Cookie does)
+/// and everything stays in the order it was added
+///
+/// ```
+/// let mut headers = webserver::HeaderMap::new();
+/// headers.append("Set-Cookie", "a=1");
+/// headers.append("set-cookie", "b=2");
+/// headers.insert("Content-Type", "text/html");
+/// assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+/// assert_eq!(headers.get_all("set-cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+/// assert_eq!(headers.len(), 3);
+/// ```
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// The first value of 'name'
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of 'name', in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries.iter().filter(move |(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether any value of 'name', a comma separated list like Connection's, has 'token' in
+    /// it, ignoring case
+    ///
+    /// ```
+    /// let headers: webserver::HeaderMap = [("Connection", "keep-alive, Upgrade")].into_iter().collect();
+    /// assert!(headers.has_token("connection", "upgrade"));
+    /// assert!(!headers.has_token("connection", "close"));
+    /// ```
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name).flat_map(|value| value.split(',')).any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Add a value for 'name', after any it already has
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Set 'name' to just 'value': it takes the place of the first existing value, the rest go
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        match self.entries.iter().position(|(key, _)| key.eq_ignore_ascii_case(&name)) {
+            Some(first) => {
+                let mut index = 0;
+                self.entries.retain(|(key, _)| {
+                    let keep = index <= first || !key.eq_ignore_ascii_case(&name);
+                    index += 1;
+                    keep
+                });
+                self.entries[first] = (name, value.into());
+            }
+            None => self.entries.push((name, value.into())),
+        }
+    }
+
+    /// Take every value of 'name' out, returning them
+    pub fn remove(&mut self, name: &str) -> Vec<String> {
+        let (removed, kept) = std::mem::take(&mut self.entries).into_iter().partition(|(key, _)| key.eq_ignore_ascii_case(name));
+        self.entries = kept;
+        removed.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// Names and values in the order they were added, a repeated name once per value
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self.entries.iter())
+    }
+
+    /// Counts every value, so a repeated name more than once
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // the last value added, for continuing a folded header line
+    pub(crate) fn last_mut(&mut self) -> Option<&mut String> {
+        self.entries.last_mut().map(|(_, value)| value)
+    }
+}
+
+/// Iterator over a HeaderMap's names and values, from HeaderMap::iter
+pub struct Iter<'a>(slice::Iter<'a, (String, String)>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+// RFC 9110 5.6.2 token, what methods and header names are made of
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// RFC 9110 5.5, anything but control characters, tab aside. A CR or LF would end the header
+// early and let what follows pass for another header, or the body (response splitting)
+pub(crate) fn is_valid_value(value: &str) -> bool {
+    !value.chars().any(|c| c.is_ascii_control() && c != '\t')
+}
+
+impl fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_map() {
+        let mut headers: HeaderMap = [("Accept", "*/*"), ("Set-Cookie", "a=1"), ("X", "1"), ("set-cookie", "b=2")].into_iter().collect();
+        assert!(headers.contains("ACCEPT"));
+        assert_eq!(headers.get("x"), Some("1"));
+        assert_eq!(headers.get("missing"), None);
+
+        headers.insert("SET-COOKIE", "c=3");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Accept", "*/*"), ("SET-COOKIE", "c=3"), ("X", "1")]);
+        headers.insert("Content-Length", "0");
+        assert_eq!(headers.len(), 4);
+
+        assert_eq!(headers.remove("x"), ["1"]);
+        assert!(headers.remove("x").is_empty());
+        assert_eq!((&headers).into_iter().map(|(name, _)| name).collect::<Vec<_>>(), ["Accept", "SET-COOKIE", "Content-Length"]);
+    }
+}
@@ -0,0 +1,137 @@
+// JSON request bodies and responses through serde, with the json feature
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{handler, logging::log, Request, Response, StatusCode};
+
+/// Why Request::json couldn't read a body as the type asked for
+#[derive(Debug)]
+pub enum JsonError {
+    /// The request's Content-Type isn't application/json, or a +json type like application/problem+json
+    NotJson,
+    /// The body isn't JSON, or isn't the shape the type wants
+    Invalid(serde_json::Error),
+}
+
+impl JsonError {
+    /// The status to answer with: 415 when the body isn't JSON at all, otherwise 400
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JsonError::NotJson => StatusCode::UnsupportedMediaType,
+            JsonError::Invalid(_) => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::NotJson => write!(f, "not application/json"),
+            JsonError::Invalid(e) => write!(f, "invalid JSON body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::NotJson => None,
+            JsonError::Invalid(e) => Some(e),
+        }
+    }
+}
+
+impl Request {
+    /// The body deserialized from JSON into a T
+    ///
+    /// ```
+    /// let mut request = webserver::Request::parse(b"POST /items HTTP/1.1\r\nContent-Type: application/json\r\n\r\n").unwrap();
+    /// request.body = br#"{"name": "pen", "count": 3}"#.to_vec();
+    /// let item: std::collections::HashMap<String, serde_json::Value> = request.json().unwrap();
+    /// assert_eq!(item["count"], 3);
+    ///
+    /// request.body = b"{".to_vec();
+    /// let error = request.json::<serde_json::Value>().unwrap_err();
+    /// assert_eq!(error.status(), webserver::StatusCode::BadRequest);
+    /// ```
+    ///
+    /// # Errors
+    /// JsonError::NotJson for another Content-Type, JsonError::Invalid for a body that doesn't
+    /// deserialize. JsonError::status says what to answer with
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        let content_type = self.headers.get("content-type").unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if mime != "application/json" && !(mime.starts_with("application/") && mime.ends_with("+json")) {
+            return Err(JsonError::NotJson);
+        }
+        serde_json::from_slice(&self.body).map_err(JsonError::Invalid)
+    }
+}
+
+impl Response {
+    /// A 200 with 'value' serialized as its application/json body. A value serde can't
+    /// serialize, like a map with keys that aren't strings, gets a 500 instead
+    ///
+    /// ```
+    /// use webserver::{Response, StatusCode};
+    ///
+    /// let response = Response::json(&serde_json::json!({"ok": true}));
+    /// assert_eq!(response.status, StatusCode::Ok);
+    /// assert_eq!(response.headers.get("content-type"), Some("application/json"));
+    /// assert_eq!(response.body.as_bytes(), Some(&br#"{"ok":true}"#[..]));
+    /// ```
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::new(StatusCode::Ok).with_header("Content-Type", "application/json").with_body(body),
+            Err(e) => {
+                log!(Error, "Failed to serialize JSON response: {}", e);
+                handler::plain(StatusCode::InternalServerError)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Item {
+        name: String,
+        count: u32,
+    }
+
+    fn request(content_type: &str, body: &str) -> Request {
+        let mut request = Request::parse(format!("POST / HTTP/1.1\r\nContent-Type: {}\r\n\r\n", content_type).as_bytes()).unwrap();
+        request.body = body.as_bytes().to_vec();
+        request
+    }
+
+    #[test]
+    fn test_request_json() {
+        let item = request("application/json; charset=utf-8", r#"{"name": "pen", "count": 3}"#).json::<Item>().unwrap();
+        assert_eq!(item, Item { name: "pen".to_string(), count: 3 });
+        assert!(request("Application/Problem+JSON", r#"{"name": "", "count": 0}"#).json::<Item>().is_ok());
+        for (content_type, body) in [("application/json", r#"{"name": "pen"}"#), ("application/json", "{"), ("application/json", r#"{"name": "pen", "count": -1}"#)] {
+            let error = request(content_type, body).json::<Item>().unwrap_err();
+            assert!(matches!(error, JsonError::Invalid(_)) && error.status() == StatusCode::BadRequest, "{}", body);
+        }
+        let error = request("text/plain", r#"{"name": "pen", "count": 3}"#).json::<Item>().unwrap_err();
+        assert!(matches!(error, JsonError::NotJson) && error.status() == StatusCode::UnsupportedMediaType);
+        assert!(matches!(Request::parse(b"POST / HTTP/1.1\r\n\r\n").unwrap().json::<Item>(), Err(JsonError::NotJson)));
+    }
+
+    #[test]
+    fn test_response_json() {
+        let response = Response::json(&[1, 2, 3]);
+        assert_eq!((response.status, response.body.as_bytes()), (StatusCode::Ok, Some(&b"[1,2,3]"[..])));
+        // JSON object keys have to be strings
+        let unserializable = BTreeMap::from([(vec![1], "a")]);
+        let response = Response::json(&unserializable);
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        assert_eq!(response.headers.get("content-type"), Some("text/plain; charset=utf-8"));
+    }
+}
@@ -1,17 +1,283 @@
-use std::{sync::{mpsc, Arc, Mutex}, thread};
+mod accept;
+mod admin;
+#[cfg(all(feature = "affinity", target_os = "linux"))]
+mod affinity;
+mod builder;
+mod chunked;
+mod compress;
+mod conditional;
+mod config;
+mod cookie;
+mod date;
+mod drain;
+#[cfg(target_os = "linux")]
+mod event_loop;
+mod executor;
+mod fallible;
+mod global;
+mod h2;
+mod handler;
+mod headers;
+#[cfg(feature = "json")]
+mod json;
+mod listener;
+mod logging;
+mod mime;
+mod multipart;
+mod observer;
+mod pool_set;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
+mod range;
+mod request;
+mod response;
+mod router;
+#[cfg(feature = "tokio")]
+mod runtime;
+mod scheduler;
+mod scope;
+mod server;
+#[cfg(feature = "crossbeam")]
+mod shared_queue;
+#[cfg(unix)]
+mod signal;
+mod socket;
+mod state;
+mod stats;
+mod status;
+mod switch;
+#[cfg(unix)]
+mod systemd;
+mod timer;
+#[cfg(feature = "tls")]
+mod tls;
+mod toml;
+#[cfg(target_os = "linux")]
+mod upgrade;
+mod url;
+mod vhost;
+mod watchdog;
+
+pub use admin::ReloadHook;
+pub use builder::{ThreadHook, ThreadPoolBuilder};
+pub use chunked::ChunkedWriter;
+pub use config::{ArgsError, Config, USAGE};
+pub use cookie::{Cookie, SameSite};
+pub use fallible::{ErrorHandler, JobFailure};
+pub use global::THREADS_ENV;
+pub use handler::{handle_connection, handle_virtual_hosts};
+pub use headers::HeaderMap;
+#[cfg(feature = "json")]
+pub use json::JsonError;
+pub use listener::{Connection, ConnectionInfo, ConnectionWrapper, ListenAddr, Stream, TlsInfo};
+pub use logging::{log_level, set_log_level, LogLevel};
+pub use multipart::{Multipart, MultipartError, Part};
+pub use observer::{ExitReason, LogObserver, NoopObserver, PoolObserver};
+pub use pool_set::{PoolSet, PoolTag};
+pub use request::{Method, ParseError, Request, Version};
+pub use response::{Body, Redirect, Response};
+pub use router::{RouteHandler, Router};
+#[cfg(feature = "tokio")]
+pub use runtime::AsyncConnection;
+pub use scope::Scope;
+pub use server::{RunningServer, Server, ServerBuilder, ShutdownHandle};
+pub use state::{with_worker_state, StateFactory};
+pub use stats::{Percentiles, PoolStats, TimingStats, WorkerStats};
+pub use status::StatusCode;
+pub use switch::{UpgradeHandler, Upgraded};
+pub use timer::RecurringHandle;
+#[cfg(feature = "tls")]
+pub use tls::TlsStream;
+pub use url::{FormError, QueryPairs};
+pub use vhost::VirtualHosts;
+pub use watchdog::TimeoutHook;
+
+use std::{
+    any::Any,
+    fmt,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use scheduler::{LocalQueue, Scheduler};
+use stats::{PoolCounters, Timings, WorkerCounters};
+use timer::Timer;
+use watchdog::{JobTimeout, Watched, Watchdog};
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    workers: Mutex<Vec<Worker>>,
+    shared: Arc<Shared>,
+    // we spawn extra workers on demand up to this many, only changed while holding the workers lock
+    max_size: AtomicUsize,
+    next_id: AtomicUsize,
+    // only started once something is scheduled with execute_after/execute_at
+    timer: OnceLock<Timer>,
+    // only when built with a job timeout
+    watchdog: Option<Watchdog>,
+}
+
+/// Called with the worker id and the panic payload whenever a job panics
+pub type PanicHook = Arc<dyn Fn(usize, &(dyn Any + Send)) + Send + Sync>;
+
+/// Like PanicHook but takes ownership of the payload, set with ThreadPool::set_panic_handler
+pub type PanicHandler = Arc<dyn Fn(usize, Box<dyn Any + Send>) + Send + Sync>;
+
+// Everything the workers need a handle on, shared between the pool and every worker
+struct Shared {
+    scheduler: Scheduler,
+    // None passes panics to the observer's on_message
+    panic_hook: Option<PanicHook>,
+    // can be swapped while the pool is running, unlike the hook
+    panic_handler: RwLock<Option<PanicHandler>>,
+    // for errors returned by execute_fallible jobs
+    error_handler: RwLock<Option<ErrorHandler>>,
+    observer: Arc<dyn PoolObserver>,
+    // jobs sent but not yet picked up by a worker
+    queued: AtomicUsize,
+    // max queued jobs, None means unbounded
+    capacity: Option<usize>,
+    // lets blocked submitters wait for a worker to take a job off a full queue
+    slot_lock: Mutex<()>,
+    slot_freed: Condvar,
+    thread: builder::ThreadConfig,
+    // set once shutdown starts, no new jobs are accepted after that
+    closed: AtomicBool,
+    // between pause() and resume(), workers spawned meanwhile start out paused
+    paused: AtomicBool,
+    saturation: SaturationPolicy,
+    exit_policy: WorkerExitPolicy,
+    counters: PoolCounters,
+    // running workers, idle ones above min_size retire themselves after idle_timeout
+    live: AtomicUsize,
+    min_size: AtomicUsize,
+    idle_timeout: Option<Duration>,
+    // wait_idle sleeps on this until nothing is queued or running
+    idle_lock: Mutex<()>,
+    idle: Condvar,
+    job_timeout: Option<JobTimeout>,
+    watched: Watched,
+    // only kept when built with record_timings
+    timings: Option<Timings>,
+}
+
+impl Shared {
+    fn is_idle(&self) -> bool {
+        self.queued.load(Ordering::SeqCst) == 0 && self.counters.in_flight.load(Ordering::SeqCst) == 0
+    }
+
+    // With timings on, wrap the job so it records how long it sat in the queue
+    fn stamp(self: &Arc<Self>, job: Job) -> Job {
+        if self.timings.is_none() {
+            return job;
+        }
+        // weak so a job left sitting in a queue doesn't keep the pool's state alive
+        let shared = Arc::downgrade(self);
+        let queued_at = Instant::now();
+        Box::new(move || {
+            if let Some(shared) = shared.upgrade()
+                && let Some(timings) = &shared.timings
+            {
+                timings.record_wait(queued_at.elapsed());
+            }
+            job()
+        })
+    }
+
+    // Hand a job that was already accepted to the workers, skipping the bounded queue check
+    // so threads like the timer never block on a full queue
+    fn push_unbounded(self: &Arc<Self>, job: Job) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.scheduler.push_job(self.stamp(job), Priority::Normal).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.report(LogLevel::Error, format_args!("Failed to send job: no workers to run it"));
+        }
+    }
+
+    // What the pool has to say, passed to the observer rather than printed
+    fn report(&self, level: LogLevel, message: fmt::Arguments<'_>) {
+        self.observer.on_message(level, &message.to_string());
+    }
+
+    // Drop worker 'id's queue, reporting any jobs nobody was left to take over
+    fn unregister(&self, id: usize) {
+        let dropped = self.scheduler.unregister(id);
+        if dropped > 0 {
+            self.report(LogLevel::Error, format_args!("Dropping {} queued job(s), no workers left to run them", dropped));
+        }
+    }
+
+    fn notify_if_idle(&self) {
+        if self.is_idle() {
+            let _guard = self.idle_lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.idle.notify_all();
+        }
+    }
 }
 
-// What we will send down our channel
+// What we will push onto the worker queues
 // Job is a type alias for a trait object that the type closer expects
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 enum Message {
     NewJob(Job),
     Terminate,
+    // stop taking jobs until Resume, unlike Terminate these skip ahead of queued jobs
+    Pause,
+    Resume,
+}
+
+/// How urgently a job should be picked up, see ThreadPool::execute_with_priority
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Latency sensitive work like health checks
+    High,
+    #[default]
+    Normal,
+    /// Bulk work that can wait
+    Low,
+}
+
+impl Priority {
+    // highest first, the order workers look through their queues in
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+    const COUNT: usize = Priority::ALL.len();
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What the pool does about a worker whose thread ended without being asked to,
+/// e.g. because a thread hook panicked. Set with ThreadPoolBuilder::worker_exit_policy
+/// Either way observers hear about it as ExitReason::Lost
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkerExitPolicy {
+    /// Start a new worker with the same id
+    #[default]
+    Respawn,
+    /// Carry on with one worker fewer
+    ShrinkSilently,
+    /// Abort the whole process, for deployments where a supervisor restarts it
+    PanicProcess,
+}
+
+/// What 'execute' does when a bounded queue is full, set with ThreadPoolBuilder::saturation_policy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    /// Wait for a worker to free up a slot
+    #[default]
+    Block,
+    /// Throw the new job away, its handle reports JobError::Rejected
+    DropNewest,
+    /// Run the job right away on the thread that submitted it, which also slows the submitter down
+    CallerRuns,
 }
 
 impl ThreadPool {
@@ -21,89 +287,876 @@ impl ThreadPool {
     /// # Panics
     /// The 'new' function will panic if the size is less than 0
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::spawn(ThreadPoolBuilder::new().num_threads(size))
+    }
+
+    /// Same as 'new' but calls 'hook' instead of the observer's on_message when a job panics
+    /// The worker survives the panic either way
+    ///
+    /// # Panics
+    /// Panics if the size is 0
+    pub fn with_panic_hook<H>(size: usize, hook: H) -> ThreadPool
+    where H: Fn(usize, &(dyn Any + Send)) + Send + Sync + 'static
+    {
+        ThreadPool::spawn(ThreadPoolBuilder::new().num_threads(size).panic_hook(hook))
+    }
+
+    /// Create a ThreadPool whose queue holds at most 'queue_len' jobs waiting for a worker
+    /// Once full, 'execute' blocks and 'try_execute' hands the job back
+    ///
+    /// # Panics
+    /// Panics if either the size or the queue_len is 0
+    pub fn with_capacity(size: usize, queue_len: usize) -> ThreadPool {
+        ThreadPool::spawn(ThreadPoolBuilder::new().num_threads(size).queue_len(queue_len))
+    }
+
+    /// Shortcut for ThreadPoolBuilder::new()
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    // like thread::spawn, failing to start a thread is fatal for the simple constructors
+    fn spawn(builder: ThreadPoolBuilder) -> ThreadPool {
+        builder.build().expect("failed to spawn worker thread")
+    }
+
+    /// Number of workers running right now
+    pub fn size(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Grow or shrink the pool to 'size' workers while it keeps serving jobs
+    /// The min/max bounds of an elastic pool are widened to fit if needed
+    ///
+    /// # Panics
+    /// Panics if the size is 0
+    pub fn resize(&self, size: usize) {
         assert!(size > 0); // we need to have more than size of 0
+        let current = self.size();
+        if size > current {
+            self.grow(size - current);
+        } else if size < current {
+            self.shrink(current - size);
+        }
+    }
 
-        let (sender, receiver) = mpsc::channel();
+    /// Spawn 'additional' workers on top of the current ones
+    pub fn grow(&self, additional: usize) {
+        let Some(mut workers) = self.lock_workers() else { return };
+        for _ in 0..additional {
+            self.spawn_worker(&mut workers);
+        }
+        self.max_size.fetch_max(workers.len(), Ordering::SeqCst);
+    }
+
+    fn spawn_worker(&self, workers: &mut Vec<Worker>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(&self.shared)) {
+            Ok(worker) => workers.push(worker),
+            Err(e) => self.shared.report(LogLevel::Error, format_args!("Failed to spawn worker {}: {}", id, e)),
+        }
+    }
 
-        // set up a thread-safe, shared channel receiver that can be accessed by multiple threads in a concurrent program
-        let receiver = Arc::new(Mutex::new(receiver));
-        
-        let mut workers = Vec::with_capacity(size);
+    // Elastic pools add a worker when there is more work than idle workers to take it
+    fn spawn_on_demand(&self, workers: &mut Vec<Worker>) {
+        let waiting = self.shared.scheduler.pending() + self.shared.counters.in_flight.load(Ordering::SeqCst);
+        if waiting > self.size() && workers.len() < self.max_size.load(Ordering::SeqCst) {
+            self.spawn_worker(workers);
+        }
+    }
+
+    /// Retire the 'fewer' newest workers, blocking until they have finished their current job and exited
+    /// Their Terminate messages queue behind their pending jobs, anything left over goes to the other workers
+    ///
+    /// # Panics
+    /// Panics if this would leave the pool with no workers
+    pub fn shrink(&self, fewer: usize) {
+        let retired = {
+            let Some(mut workers) = self.lock_workers() else { return };
+            self.maintain_workers(&mut workers);
+            let current = workers.len();
+            assert!(fewer < current, "cannot shrink a pool of {} workers by {}", current, fewer);
+            self.shared.min_size.fetch_min(current - fewer, Ordering::SeqCst);
+            let retired = workers.split_off(current - fewer);
+            for worker in &retired {
+                if !self.shared.scheduler.push_to(worker.id, Message::Terminate) {
+                    self.shared.report(LogLevel::Warn, format_args!("Failed to send terminate message to worker {}", worker.id));
+                }
+            }
+            retired
+        };
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        // don't hold the lock while we wait so execute keeps working
+        for mut worker in retired {
+            if let Some(thread) = worker.thread.take()
+                && let Err(e) = thread.join()
+            {
+                self.shared.report(LogLevel::Error, format_args!("Failed to join worker {}: {:?}", worker.id, e));
+            }
         }
-        ThreadPool { workers, sender }
+    }
+
+    /// Install a handler that is given every job panic from now on, along with the worker id
+    /// It runs on the worker after the panic hook and gets to keep the payload
+    /// Replaces any handler set before
+    pub fn set_panic_handler<H>(&self, handler: H)
+    where H: Fn(usize, Box<dyn Any + Send>) + Send + Sync + 'static
+    {
+        *self.shared.panic_handler.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(handler));
     }
 
     // our version of Thread::spawn
-    pub fn execute<F>(&self, f: F)
-    where F: FnOnce() + Send + 'static
+    /// Queue a closure on the pool and get back a JobHandle for its return value
+    /// With a bounded queue that is full, what happens depends on the pool's SaturationPolicy,
+    /// by default this blocks until there is room
+    pub fn execute<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        // when one channel is called, we can use the closer to send data to the workers
-        let job = Box::new(f);
-        if let Err(e) = self.sender.send(Message::NewJob(job)) {
-            eprintln!("Failed to send job: {0}", e);
+        self.execute_with_priority(f, Priority::Normal)
+    }
+
+    /// Same as 'execute' but idle workers pick up higher priority jobs first
+    /// Jobs of the same priority still run in the order they were queued
+    pub fn execute_with_priority<F, T>(&self, f: F, priority: Priority) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.dispatch(package(f), priority)
+    }
+
+    /// Like 'execute' but the job is handed its own JobToken, long running jobs should
+    /// poll it and return early once it is cancelled
+    ///
+    /// ```
+    /// let pool = webserver::ThreadPool::new(1);
+    /// let handle = pool.execute_cancellable(|token| {
+    ///     while !token.is_cancelled() {
+    ///         std::thread::sleep(std::time::Duration::from_millis(1));
+    ///     }
+    /// });
+    /// handle.cancel();
+    /// // Ok(()) if it saw the cancel while running, Err(JobError::Cancelled) if it never started
+    /// let _ = handle.join();
+    /// ```
+    pub fn execute_cancellable<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce(&JobToken) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.dispatch(package_cancellable(f), Priority::Normal)
+    }
+
+    /// Queue every job from 'jobs' in one go, handles come back in the same order
+    /// Cheaper than calling 'execute' in a loop since each worker's queue is only locked once per batch
+    /// With a bounded queue the batch is sent in pieces as room frees up, whatever the SaturationPolicy
+    pub fn execute_all<I, F, T>(&self, jobs: I) -> Vec<JobHandle<T>>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut handles = Vec::new();
+        let mut batch: Vec<Job> = Vec::new();
+        for f in jobs {
+            if !self.try_reserve_slot() {
+                // queue is full, send what we have so workers can start freeing slots
+                self.submit_batch(std::mem::take(&mut batch));
+                if !self.wait_for_slot() {
+                    handles.push(JobHandle::lost());
+                    continue;
+                }
+            }
+            let (job, handle) = package(f);
+            batch.push(job);
+            handles.push(handle);
         }
+        self.submit_batch(batch);
+        handles
     }
+
+    /// Like 'execute' but never blocks, the closure is given back if the queue is full
+    pub fn try_execute<F, T>(&self, f: F) -> Result<JobHandle<T>, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.is_shut_down() || !self.try_reserve_slot() {
+            return Err(f);
+        }
+        Ok(self.send_job(f, Priority::Normal))
+    }
+
+    // Queue a packaged job, applying the saturation policy if the queue is full
+    fn dispatch<T>(&self, (job, handle): (Job, JobHandle<T>), priority: Priority) -> JobHandle<T> {
+        if self.is_shut_down() {
+            self.shared.report(LogLevel::Warn, format_args!("Rejecting job, the pool is shutting down"));
+            return JobHandle::lost();
+        }
+        if !self.try_reserve_slot() {
+            match self.shared.saturation {
+                SaturationPolicy::Block => {
+                    if !self.wait_for_slot() {
+                        self.shared.report(LogLevel::Warn, format_args!("Rejecting job, the pool is shutting down"));
+                        return JobHandle::lost();
+                    }
+                }
+                SaturationPolicy::DropNewest => return JobHandle::rejected(),
+                SaturationPolicy::CallerRuns => {
+                    // a panic stays with the job like it would on a worker, the handle reports it lost
+                    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                        let message = panic_message(payload.as_ref());
+                        self.shared.report(LogLevel::Error, format_args!("Job panicked on the submitting thread: {}", message));
+                    }
+                    return handle;
+                }
+            }
+        }
+        self.submit(job, priority);
+        handle
+    }
+
+    // Caller must already hold a slot in the queue
+    fn send_job<F, T>(&self, f: F, priority: Priority) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = package(f);
+        self.submit(job, priority);
+        handle
+    }
+
+    // Push an already boxed job, caller must already hold a slot in the queue
+    // If it can't be queued the job is dropped, which its JobHandle reports as JobError::Lost
+    fn submit(&self, job: Job, priority: Priority) -> bool {
+        if let Some(mut workers) = self.lock_workers() {
+            self.maintain_workers(&mut workers);
+        }
+
+        let job = self.shared.stamp(job);
+        if self.is_shut_down() || self.shared.scheduler.push_job(job, priority).is_err() {
+            self.shared.queued.fetch_sub(1, Ordering::SeqCst);
+            self.shared.report(LogLevel::Error, format_args!("Failed to send job: no workers to run it"));
+            return false;
+        }
+        if let Some(mut workers) = self.lock_workers() {
+            self.spawn_on_demand(&mut workers);
+        }
+        true
+    }
+
+    // Batch version of 'submit', the caller holds a slot for every job
+    fn submit_batch(&self, jobs: Vec<Job>) {
+        if jobs.is_empty() {
+            return;
+        }
+        if let Some(mut workers) = self.lock_workers() {
+            self.maintain_workers(&mut workers);
+        }
+        let count = jobs.len();
+        let jobs = jobs.into_iter().map(|job| self.shared.stamp(job)).collect();
+        if self.is_shut_down() || self.shared.scheduler.push_jobs(jobs, Priority::Normal).is_err() {
+            self.shared.queued.fetch_sub(count, Ordering::SeqCst);
+            self.shared.report(LogLevel::Error, format_args!("Failed to send {} jobs: no workers to run them", count));
+            return;
+        }
+        if let Some(mut workers) = self.lock_workers() {
+            self.spawn_on_demand(&mut workers);
+        }
+    }
+
+    // Claim a place in the queue, false if a bounded queue is already full
+    fn try_reserve_slot(&self) -> bool {
+        let queued = &self.shared.queued;
+        match self.shared.capacity {
+            None => {
+                queued.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(capacity) => queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < capacity).then_some(n + 1))
+                .is_ok(),
+        }
+    }
+
+    // Block until we hold a slot, false if the pool shut down while we were waiting
+    fn wait_for_slot(&self) -> bool {
+        if self.is_shut_down() {
+            return false;
+        }
+        if self.try_reserve_slot() {
+            return true;
+        }
+        // workers and shutdown take slot_lock before notifying so we can't miss a wakeup between the check and the wait
+        let mut guard = self.shared.slot_lock.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if self.is_shut_down() {
+                return false;
+            }
+            if self.try_reserve_slot() {
+                return true;
+            }
+            guard = self.shared.slot_freed.wait(guard).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Snapshot of the pool's counters, cheap enough to call on every request
+    pub fn stats(&self) -> PoolStats {
+        let workers = match self.lock_workers() {
+            Some(workers) => workers.iter().map(|worker| worker.counters.snapshot(worker.id)).collect(),
+            None => Vec::new(),
+        };
+        let counters = &self.shared.counters;
+        PoolStats {
+            queued: self.shared.scheduler.pending(),
+            in_flight: counters.in_flight.load(Ordering::SeqCst),
+            completed: counters.completed.load(Ordering::SeqCst),
+            panicked: counters.panicked.load(Ordering::SeqCst),
+            workers,
+        }
+    }
+
+    /// Block until the queue is empty and no worker is running a job, the pool stays usable
+    /// Jobs queued by other threads in the meantime are waited for too, and jobs still
+    /// waiting on execute_after aren't counted until they come due
+    /// Don't call this from a job on the same pool, it would wait for itself
+    pub fn wait_idle(&self) {
+        let mut guard = self.shared.idle_lock.lock().unwrap_or_else(|e| e.into_inner());
+        while !self.shared.is_idle() {
+            guard = self.shared.idle.wait(guard).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Queue wait and run time percentiles over recent jobs, None unless the pool
+    /// was built with ThreadPoolBuilder::record_timings
+    pub fn timings(&self) -> Option<TimingStats> {
+        self.shared.timings.as_ref().map(Timings::snapshot)
+    }
+
+    /// Stop workers from picking up jobs until 'resume', without tearing down any threads
+    /// Jobs already running finish, new ones can still be queued and wait for the resume
+    /// Don't call wait_idle while paused with jobs queued, it would never return
+    pub fn pause(&self) {
+        self.broadcast_pause(true);
+    }
+
+    /// Let workers pick up jobs again after 'pause'
+    pub fn resume(&self) {
+        self.broadcast_pause(false);
+    }
+
+    /// True between 'pause' and 'resume'
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::SeqCst)
+    }
+
+    fn broadcast_pause(&self, pause: bool) {
+        // holding the workers lock means a worker spawned right now sees the flag or gets the message
+        let Some(workers) = self.lock_workers() else { return };
+        if self.shared.paused.swap(pause, Ordering::SeqCst) == pause {
+            return;
+        }
+        for worker in workers.iter() {
+            let message = if pause { Message::Pause } else { Message::Resume };
+            if !self.shared.scheduler.push_to(worker.id, message) {
+                self.shared.report(LogLevel::Warn, format_args!("Failed to send pause/resume message to worker {}", worker.id));
+            }
+        }
+    }
+
+    /// True once 'shutdown' has been called, new jobs are rejected from then on
+    pub fn is_shut_down(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Stop taking new jobs and give the queued ones until 'timeout' to finish
+    /// Whatever is still queued at the deadline is dropped (their JobHandles return JobError::Lost)
+    /// and workers stuck in a long job are left to finish it on their own
+    /// Calling it again, or dropping the pool afterwards, is a no-op
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        self.shared.closed.store(true, Ordering::SeqCst);
+        // paused workers would leave their queued jobs behind rather than drain them
+        self.resume();
+        // scheduled jobs that haven't come due yet never will
+        let dropped_timers = self.timer.get().map_or(0, |timer| timer.stop(&self.shared));
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.stop(&self.shared);
+        }
+        {
+            // wake anyone blocked on a full queue so they see we are closed
+            let _guard = self.shared.slot_lock.lock().unwrap_or_else(|e| e.into_inner());
+            self.shared.slot_freed.notify_all();
+        }
+
+        let Some(mut workers) = self.lock_workers() else { return ShutdownReport::default() };
+        self.maintain_workers(&mut workers);
+        let mut workers = std::mem::take(&mut *workers);
+        // Terminate goes behind each worker's own jobs, so they drain their queue first
+        for worker in &workers {
+            if !self.shared.scheduler.push_to(worker.id, Message::Terminate) {
+                self.shared.report(LogLevel::Warn, format_args!("Failed to send terminate message to worker {}", worker.id));
+            }
+        }
+
+        while Instant::now() < deadline
+            && workers.iter().any(|worker| worker.thread.as_ref().is_some_and(|thread| !thread.is_finished()))
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let dropped_queued = self.shared.scheduler.drain_jobs();
+        self.shared.queued.fetch_sub(dropped_queued, Ordering::SeqCst);
+        self.shared.notify_if_idle();
+        let dropped_jobs = dropped_queued + dropped_timers;
+        let mut unfinished_workers = 0;
+        for worker in &mut workers {
+            match worker.thread.take() {
+                Some(thread) if thread.is_finished() => {
+                    if let Err(e) = thread.join() {
+                        self.shared.report(LogLevel::Error, format_args!("Failed to join worker {}: {:?}", worker.id, e));
+                    }
+                }
+                // detach it, it exits on its own once the current job returns
+                Some(_) => unfinished_workers += 1,
+                None => {}
+            }
+        }
+        if dropped_jobs > 0 || unfinished_workers > 0 {
+            self.shared.report(
+                LogLevel::Warn,
+                format_args!("Shutdown deadline hit: dropped {} queued jobs, {} workers still busy", dropped_jobs, unfinished_workers),
+            );
+        }
+        ShutdownReport { dropped_jobs, unfinished_workers }
+    }
+
+    fn lock_workers(&self) -> Option<MutexGuard<'_, Vec<Worker>>> {
+        match self.workers.lock() {
+            Ok(workers) => Some(workers),
+            Err(e) => {
+                self.shared.report(LogLevel::Error, format_args!("Failed to lock workers: {}", e));
+                None
+            }
+        }
+    }
+
+    // Workers shrink retires are taken out of the list before they exit, and ones that timed out
+    // while idle flag themselves as retired, so those just get dropped. Anything else we find finished
+    // died unexpectedly (e.g. a panicking thread hook) and gets replaced so the pool doesn't quietly lose capacity
+    fn maintain_workers(&self, workers: &mut Vec<Worker>) {
+        // a worker stuck past the job timeout is cut loose and exits by itself once its job returns
+        let mut replaced = 0;
+        workers.retain_mut(|worker| {
+            if !worker.counters.replacement_requested() {
+                return true;
+            }
+            drop(worker.thread.take());
+            // in case the flag went up just after its job finished and it is sitting idle
+            self.shared.scheduler.push_to(worker.id, Message::Terminate);
+            replaced += 1;
+            false
+        });
+        for _ in 0..replaced {
+            self.spawn_worker(workers);
+        }
+
+        let mut i = 0;
+        while i < workers.len() {
+            let worker = &mut workers[i];
+            if !worker.thread.as_ref().is_some_and(|thread| thread.is_finished()) {
+                i += 1;
+                continue;
+            }
+            let id = worker.id;
+            if let Some(thread) = worker.thread.take()
+                && let Err(e) = thread.join()
+            {
+                self.shared.report(LogLevel::Error, format_args!("Worker {} exited with: {:?}", id, e));
+            }
+            if worker.retired.load(Ordering::SeqCst) {
+                workers.remove(i);
+                continue;
+            }
+            self.shared.observer.on_worker_exit(id, ExitReason::Lost);
+            match self.shared.exit_policy {
+                WorkerExitPolicy::Respawn => self.shared.report(LogLevel::Warn, format_args!("Worker {} died, respawning it.", id)),
+                WorkerExitPolicy::ShrinkSilently => {
+                    workers.remove(i);
+                    continue;
+                }
+                WorkerExitPolicy::PanicProcess => {
+                    self.shared.report(LogLevel::Error, format_args!("Worker {} died, aborting.", id));
+                    std::process::abort();
+                }
+            }
+            match Worker::new(id, Arc::clone(&self.shared)) {
+                Ok(new_worker) => *worker = new_worker,
+                // leave the dead worker in place, we'll try again on the next call
+                Err(e) => self.shared.report(LogLevel::Error, format_args!("Failed to respawn worker {}: {}", id, e)),
+            }
+            i += 1;
+        }
+    }
+}
+
+// Box up a closure as a Job that reports its return value to the JobHandle we give back
+fn package<F, T>(f: F) -> (Job, JobHandle<T>)
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    package_cancellable(move |_| f())
 }
 
+fn package_cancellable<F, T>(f: F) -> (Job, JobHandle<T>)
+where
+    F: FnOnce(&JobToken) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // each job gets its own one-shot channel so the result finds its way back to the caller
+    let (result_sender, receiver) = mpsc::channel();
+    let token = JobToken::new();
+    let job_token = token.clone();
+    // when one channel is called, we can use the closer to send data to the workers
+    let job: Job = Box::new(move || {
+        // cancelled while still queued, drop it without running
+        if job_token.is_cancelled() {
+            return;
+        }
+        // the caller may have dropped the handle, that's fine
+        let _ = result_sender.send(f(&job_token));
+    });
+    (job, JobHandle::new(receiver, token))
+}
+
+/// Best effort extraction of the message passed to panic!
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// What 'ThreadPool::shutdown' had to give up on when the deadline passed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Queued or scheduled jobs that never got to run
+    pub dropped_jobs: usize,
+    /// Workers still running a job when we stopped waiting
+    pub unfinished_workers: usize,
+}
+
+/// Owned handle to a job queued with ThreadPool::execute
+/// Dropping the handle detaches the job, it will still run
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<T>,
+    token: JobToken,
+    // turned away by SaturationPolicy::DropNewest
+    rejected: bool,
+}
+
+impl<T> JobHandle<T> {
+    fn new(receiver: mpsc::Receiver<T>, token: JobToken) -> JobHandle<T> {
+        JobHandle { receiver, token, rejected: false }
+    }
+
+    // handle for a job that was never queued, joining it reports JobError::Lost
+    fn lost() -> JobHandle<T> {
+        let (_, receiver) = mpsc::channel();
+        JobHandle::new(receiver, JobToken::new())
+    }
+
+    // handle for a job a full queue turned away, joining it reports JobError::Rejected
+    fn rejected() -> JobHandle<T> {
+        JobHandle { rejected: true, ..JobHandle::lost() }
+    }
+
+    // What joining reports when the job produced nothing
+    fn error(&self) -> JobError {
+        if self.rejected { JobError::Rejected } else { self.token.error() }
+    }
+
+    /// Block until the job finishes and return what the closure returned
+    ///
+    /// # Errors
+    /// Returns JobError::Cancelled if the handle was cancelled and the job gave up,
+    /// and JobError::Lost if it never produced a value otherwise (it panicked or was never run)
+    pub fn join(self) -> Result<T, JobError> {
+        self.receiver.recv().map_err(|_| self.error())
+    }
+
+    /// Cancel the job, shorthand for token().cancel()
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// The job's cancellation token, it can be cloned and cancelled from anywhere
+    pub fn token(&self) -> JobToken {
+        self.token.clone()
+    }
+
+    /// Return the result if the job is already done, without blocking
+    /// Gives back the handle when the job is still pending
+    pub fn try_join(self) -> Result<Result<T, JobError>, JobHandle<T>> {
+        match self.receiver.try_recv() {
+            Ok(value) => Ok(Ok(value)),
+            Err(mpsc::TryRecvError::Disconnected) => Ok(Err(self.error())),
+            Err(mpsc::TryRecvError::Empty) => Err(self),
+        }
+    }
+}
+
+/// Cancels a queued job so it is thrown away instead of run
+/// A job that is already running keeps going unless it polls is_cancelled itself,
+/// see ThreadPool::execute_cancellable
+#[derive(Debug, Clone)]
+pub struct JobToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobToken {
+    fn new() -> JobToken {
+        JobToken { cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // What a handle reports when its job produced nothing
+    fn error(&self) -> JobError {
+        if self.is_cancelled() { JobError::Cancelled } else { JobError::Lost }
+    }
+}
+
+/// Reasons a JobHandle can't give back a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError {
+    /// The job was dropped before returning, either it panicked or the pool shut down first
+    Lost,
+    /// The job was cancelled through its JobToken before it produced a result
+    Cancelled,
+    /// The queue was full and the pool's SaturationPolicy is DropNewest, or the job came from
+    /// execute_async, which never waits for room
+    Rejected,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Lost => write!(f, "job finished without producing a result"),
+            JobError::Cancelled => write!(f, "job was cancelled"),
+            JobError::Rejected => write!(f, "job was rejected, the queue was full"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
 // This will ensure reqs dont automatically drop when the server goes down
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
+        // stop the timer first so it can't hand the workers anything while they wind down
+        if let Some(timer) = self.timer.get() {
+            timer.stop(&self.shared);
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.stop(&self.shared);
+        }
+
+        // paused workers would leave their queued jobs behind rather than drain them
+        self.resume();
+        // we have &mut self here so a poisoned lock can't block us
+        let mut workers = std::mem::take(self.workers.get_mut().unwrap_or_else(|e| e.into_inner()));
+        // clear out workers that retired while idle so we only message live ones
+        workers.retain(|worker| !worker.retired.load(Ordering::SeqCst));
 
-        for _ in &self.workers {
-            if let Err(e) = self.sender.send(Message::Terminate) {
-                eprintln!("Failed to send terminate message: {}", e);
+        for worker in workers.iter() {
+            if !self.shared.scheduler.push_to(worker.id, Message::Terminate) {
+                self.shared.report(LogLevel::Warn, format_args!("Failed to send terminate message to worker {}", worker.id));
             }
         }
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker: {0}", worker.id);
+        for worker in workers.iter_mut() {
             // worker.thread.join().unwrap();
-            if let Some(thread) = worker.thread.take() {
-                if let Err(e) = thread.join() {
-                    eprintln!("Failed to join worker {}: {:?}", worker.id, e);
-                }
+            if let Some(thread) = worker.thread.take()
+                && let Err(e) = thread.join()
+            {
+                self.shared.report(LogLevel::Error, format_args!("Failed to join worker {}: {:?}", worker.id, e));
             }
         }
     }
 }
 struct Worker {
     id: usize, 
-    thread: Option<thread::JoinHandle<()>>
+    thread: Option<thread::JoinHandle<()>>,
+    counters: Arc<WorkerCounters>,
+    // set by the worker itself when it exits after sitting idle
+    retired: Arc<AtomicBool>,
+}
+
+// Keeps Shared::live honest however the worker thread ends, including a panic escaping the loop
+struct LiveGuard<'a> {
+    live: &'a AtomicUsize,
+    armed: bool,
+}
+
+impl Drop for LiveGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
 }
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        // register before spawning so jobs can be queued for this worker straight away
+        let queue = shared.scheduler.register(id);
+        let counters = Arc::new(WorkerCounters::new());
+        let retired = Arc::new(AtomicBool::new(false));
+        let (thread_counters, thread_retired) = (Arc::clone(&counters), Arc::clone(&retired));
+        let thread_shared = Arc::clone(&shared);
+        let builder = shared.thread.thread_builder(id);
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        if shared.job_timeout.is_some() {
+            shared.watched.add(id, &counters);
+        }
+        let spawned = builder.spawn(move || {
+            let shared = thread_shared;
+            let mut live = LiveGuard { live: &shared.live, armed: true };
+            #[cfg(all(feature = "affinity", target_os = "linux"))]
+            if let Err((core, e)) = shared.thread.pin_current(id) {
+                shared.report(LogLevel::Warn, format_args!("Failed to pin worker {} to core {}: {}", id, core, e));
+            }
+            if let Some(factory) = &shared.thread.state_factory {
+                state::init_worker_state(factory, id);
+            }
+            if let Some(on_start) = &shared.thread.on_start {
+                on_start(id);
+            }
+            let reason = Worker::run(id, &shared, &queue, &thread_counters);
+            shared.watched.remove(&thread_counters);
+            if reason == ExitReason::IdleTimeout {
+                // run already took us off the live count
+                live.armed = false;
+                thread_retired.store(true, Ordering::SeqCst);
+            }
+            shared.observer.on_worker_exit(id, reason);
+            if let Some(on_stop) = &shared.thread.on_stop {
+                on_stop(id);
+            }
+        });
+        let thread = match spawned {
+            Ok(thread) => thread,
+            Err(e) => {
+                shared.live.fetch_sub(1, Ordering::SeqCst);
+                shared.watched.remove(&counters);
+                // hand anything already queued for us to the others
+                shared.unregister(id);
+                return Err(e);
+            }
+        };
+        Ok(Worker { id, thread: Some(thread), counters, retired })
+    }
+
+    fn run(id: usize, shared: &Shared, queue: &LocalQueue, counters: &WorkerCounters) -> ExitReason {
+        // spawned while the pool is paused, wait for the Resume like everyone else
+        if shared.paused.load(Ordering::SeqCst)
+            && let Some(reason) = Worker::paused(id, shared, queue, counters)
+        {
+            return reason;
+        }
         // we have to keep looping to look for threads to execute
-        let thread = thread::spawn(move || loop {
-            // lock to get mutex (might fail) & recv to recieve job from channel (also might fail)
-            let message = match receiver.lock() {
-                Ok(guard) => match guard.recv() {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        println!("Worker {}: Channel disconnected: {}", id, e);
-                        break;
-                    }
-                },
-                Err(e) => {
-                    println!("Worker {}: Failed to lock receiver: {}", id, e);
-                    break;
+        loop {
+            // blocks until our own queue or someone else's has something for us
+            let Some(message) = shared.scheduler.next(queue, shared.idle_timeout) else {
+                // idle for a whole timeout, leave if the pool stays above its minimum without us
+                let min = shared.min_size.load(Ordering::SeqCst);
+                if shared.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n > min).then(|| n - 1)).is_ok() {
+                    shared.unregister(id);
+                    return ExitReason::IdleTimeout;
                 }
+                continue;
             };
             match message {
                 Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job();
+                    // count it as in flight before it leaves the queue so wait_idle never sees neither
+                    counters.job_started(&shared.counters);
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
+                    if shared.capacity.is_some() {
+                        let _guard = shared.slot_lock.lock().unwrap_or_else(|e| e.into_inner());
+                        shared.slot_freed.notify_one();
+                    }
+                    shared.observer.on_job_start(id);
+                    let started = Instant::now();
+                    // a panicking job shouldn't take the worker down with it
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    let ran_for = started.elapsed();
+                    counters.job_finished(&shared.counters, ran_for, result.is_err());
+                    if let Some(timings) = &shared.timings {
+                        timings.record_run(ran_for);
+                    }
+                    shared.notify_if_idle();
+                    shared.observer.on_job_end(id, ran_for);
+                    if let Err(payload) = result {
+                        shared.observer.on_panic(id, payload.as_ref());
+                        match &shared.panic_hook {
+                            Some(hook) => hook(id, payload.as_ref()),
+                            None => {
+                                let message = panic_message(payload.as_ref());
+                                shared.report(LogLevel::Error, format_args!("Worker {} panicked while running a job: {}", id, message));
+                            }
+                        }
+                        let handler = shared.panic_handler.read().unwrap_or_else(|e| e.into_inner()).clone();
+                        if let Some(handler) = handler {
+                            handler(id, payload);
+                        }
+                    }
+                    if counters.replacement_requested() {
+                        // the pool has already spawned someone to take our place
+                        shared.unregister(id);
+                        return ExitReason::TimedOut;
+                    }
                 }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
-                    break;
+                Message::Terminate => return Worker::terminate(id, shared, counters),
+                Message::Pause => {
+                    if let Some(reason) = Worker::paused(id, shared, queue, counters) {
+                        return reason;
+                    }
                 }
+                // not paused, nothing to resume
+                Message::Resume => {}
             }
-        });
-        Worker {id, thread:Some(thread) }
+        }
+    }
+
+    // Sit on our control messages only, leaving jobs where they are, until told to resume
+    // Returns the exit reason if we were terminated instead
+    fn paused(id: usize, shared: &Shared, queue: &LocalQueue, counters: &WorkerCounters) -> Option<ExitReason> {
+        loop {
+            match shared.scheduler.next_control(queue) {
+                Message::Resume => return None,
+                Message::Terminate => return Some(Worker::terminate(id, shared, counters)),
+                Message::Pause => {}
+                Message::NewJob(_) => unreachable!("jobs never go in the control lane"),
+            }
+        }
+    }
+
+    fn terminate(id: usize, shared: &Shared, counters: &WorkerCounters) -> ExitReason {
+        shared.unregister(id);
+        if counters.replacement_requested() { ExitReason::TimedOut } else { ExitReason::Terminated }
     }
 }
 
@@ -114,8 +1167,6 @@ impl Worker {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::time::Duration;
 
     #[test]
     #[should_panic(expected = "assertion failed: size > 0")]
@@ -126,7 +1177,7 @@ mod tests {
     #[test]
     fn test_thread_pool_new_valid_size() {
         let pool = ThreadPool::new(2);
-        assert_eq!(pool.workers.len(), 2);
+        assert_eq!(pool.workers.lock().unwrap().len(), 2);
     }
 
     #[test]
@@ -139,8 +1190,7 @@ mod tests {
             counter_clone.fetch_add(1, Ordering::SeqCst);
         });
 
-        // Give workers time to process the job
-        thread::sleep(Duration::from_millis(100));
+        pool.wait_idle();
         assert_eq!(counter.load(Ordering::SeqCst), 1);
     }
 
@@ -154,9 +1204,370 @@ mod tests {
             counter_clone.fetch_add(1, Ordering::SeqCst);
         });
 
-        // Drop the pool to trigger shutdown
+        // Drop the pool to trigger shutdown, it waits for the queued job
         drop(pool);
-        thread::sleep(Duration::from_millis(100));
         assert_eq!(counter.load(Ordering::SeqCst), 1);
     }
+
+    #[test]
+    fn test_wait_idle() {
+        let pool = ThreadPool::new(3);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.wait_idle();
+        assert_eq!(counter.load(Ordering::SeqCst), 20);
+
+        // still usable afterwards, and returns straight away when there is nothing to do
+        pool.wait_idle();
+        assert_eq!(pool.execute(|| 1).join(), Ok(1));
+    }
+
+    #[test]
+    fn test_job_handle_join_returns_value() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.execute(|| 6 * 7);
+        assert_eq!(handle.join(), Ok(42));
+    }
+
+    #[test]
+    fn test_job_handle_join_after_panic() {
+        let pool = ThreadPool::new(2);
+        let handle = pool.execute(|| -> usize { panic!("boom") });
+        assert_eq!(handle.join(), Err(JobError::Lost));
+    }
+
+    #[test]
+    fn test_worker_survives_panic() {
+        let panics = Arc::new(AtomicUsize::new(0));
+        let panics_clone = Arc::clone(&panics);
+        let pool = ThreadPool::with_panic_hook(1, move |id, payload| {
+            assert_eq!(id, 0);
+            assert_eq!(panic_message(payload), "boom");
+            panics_clone.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let handle = pool.execute(|| -> usize { panic!("boom") });
+        assert_eq!(handle.join(), Err(JobError::Lost));
+        // the only worker is still around to run the next job
+        assert_eq!(pool.execute(|| 1).join(), Ok(1));
+        assert_eq!(panics.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_panic_handler_gets_payload() {
+        let pool = ThreadPool::with_panic_hook(1, |_, _| {});
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        pool.set_panic_handler(move |id, payload| {
+            let message = payload.downcast::<String>().map(|message| *message);
+            sender.lock().unwrap().send((id, message.ok())).unwrap();
+        });
+        let id = 7;
+        pool.execute(move || panic!("job {} failed", id)).join().unwrap_err();
+        assert_eq!(receiver.recv().unwrap(), (0, Some("job 7 failed".to_string())));
+    }
+
+    #[test]
+    fn test_dead_worker_respawned() {
+        let pool = ThreadPool::new(1);
+        // kill the only worker behind the pool's back
+        assert!(pool.shared.scheduler.push_to(0, Message::Terminate));
+        while !pool.workers.lock().unwrap()[0].thread.as_ref().unwrap().is_finished() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(pool.execute(|| 5).join(), Ok(5));
+        assert_eq!(pool.workers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_dead_worker_shrinks_silently() {
+        let (exits, exited) = mpsc::channel();
+        let exits = Mutex::new(exits);
+        struct Exits(Mutex<mpsc::Sender<(usize, ExitReason)>>);
+        impl PoolObserver for Exits {
+            fn on_worker_exit(&self, worker: usize, reason: ExitReason) {
+                self.0.lock().unwrap().send((worker, reason)).unwrap();
+            }
+        }
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .on_thread_start(|id| assert_ne!(id, 1, "worker 1 refuses to start"))
+            .worker_exit_policy(WorkerExitPolicy::ShrinkSilently)
+            .observer(Exits(exits))
+            .build()
+            .unwrap();
+        while !pool.workers.lock().unwrap()[1].thread.as_ref().unwrap().is_finished() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(pool.execute(|| 5).join(), Ok(5));
+        assert_eq!(exited.recv().unwrap(), (1, ExitReason::Lost));
+        assert_eq!(pool.workers.lock().unwrap().len(), 1);
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn test_thread_pool_resize() {
+        let pool = ThreadPool::new(2);
+
+        pool.resize(4);
+        assert_eq!(pool.size(), 4);
+        assert_eq!(pool.workers.lock().unwrap().len(), 4);
+
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.workers.lock().unwrap().len(), 1);
+
+        // the survivor still picks up work
+        assert_eq!(pool.execute(|| "still here").join(), Ok("still here"));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot shrink a pool of 2 workers by 2")]
+    fn test_thread_pool_shrink_to_zero() {
+        ThreadPool::new(2).shrink(2);
+    }
+
+    #[test]
+    fn test_try_execute_rejects_when_full() {
+        let pool = ThreadPool::with_capacity(1, 1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel();
+
+        // occupy the only worker, then fill the only queue slot
+        let busy = pool.execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        has_started.recv().unwrap();
+        let queued = pool.try_execute(|| 1).ok().expect("queue should have room");
+
+        let rejected = pool.try_execute(|| 2);
+        let job = rejected.err().expect("queue should be full");
+        assert_eq!(job(), 2);
+
+        release.send(()).unwrap();
+        busy.join().unwrap();
+        assert_eq!(queued.join(), Ok(1));
+    }
+
+    #[test]
+    fn test_cancel_queued_job() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let busy = pool.execute(move || blocked.recv().unwrap());
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        let queued = pool.execute(move || flag.store(true, Ordering::SeqCst));
+
+        queued.cancel();
+        release.send(()).unwrap();
+        busy.join().unwrap();
+        assert_eq!(queued.join(), Err(JobError::Cancelled));
+        assert!(!ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_cancel_running_job() {
+        let pool = ThreadPool::new(1);
+        let (started, has_started) = mpsc::channel();
+        let handle = pool.execute_cancellable(move |token| {
+            started.send(()).unwrap();
+            while !token.is_cancelled() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            "gave up"
+        });
+        has_started.recv().unwrap();
+        handle.token().cancel();
+        // the job saw the cancel and chose to return a value anyway
+        assert_eq!(handle.join(), Ok("gave up"));
+    }
+
+    #[test]
+    fn test_execute_blocks_until_slot_frees() {
+        let pool = ThreadPool::with_capacity(2, 1);
+        let handles: Vec<_> = (0..10).map(|i| pool.execute(move || i * 2)).collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_saturation_policies() {
+        for policy in [SaturationPolicy::DropNewest, SaturationPolicy::CallerRuns] {
+            let pool = ThreadPoolBuilder::new().num_threads(1).queue_len(1).saturation_policy(policy).build().unwrap();
+            let (release, blocked) = mpsc::channel::<()>();
+            let (started, has_started) = mpsc::channel();
+            let busy = pool.execute(move || {
+                started.send(()).unwrap();
+                blocked.recv().unwrap();
+            });
+            has_started.recv().unwrap();
+            let queued = pool.execute(thread::current);
+
+            let overflow = pool.execute(thread::current).join().map(|thread| thread.id());
+            match policy {
+                SaturationPolicy::DropNewest => assert_eq!(overflow, Err(JobError::Rejected)),
+                _ => assert_eq!(overflow, Ok(thread::current().id())),
+            }
+            release.send(()).unwrap();
+            busy.join().unwrap();
+            assert_ne!(queued.join().unwrap().id(), thread::current().id());
+        }
+    }
+
+    #[test]
+    fn test_execute_with_priority() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // hold the only worker so the rest pile up in its queue
+        pool.execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        has_started.recv().unwrap();
+
+        let handles: Vec<_> = [Priority::Low, Priority::Normal, Priority::High]
+            .into_iter()
+            .map(|priority| {
+                let order = Arc::clone(&order);
+                pool.execute_with_priority(move || order.lock().unwrap().push(priority), priority)
+            })
+            .collect();
+        release.send(()).unwrap();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), [Priority::High, Priority::Normal, Priority::Low]);
+    }
+
+    #[test]
+    fn test_shutdown_drains_queue() {
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        let report = pool.shutdown(Duration::from_secs(5));
+        assert_eq!(report, ShutdownReport::default());
+        assert_eq!(counter.load(Ordering::SeqCst), 8);
+
+        // no new work once we are shut down
+        assert!(pool.is_shut_down());
+        assert_eq!(pool.execute(|| 1).join(), Err(JobError::Lost));
+        assert!(pool.try_execute(|| 1).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_deadline_drops_jobs() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        });
+        has_started.recv().unwrap();
+        let stranded = pool.execute(|| 1);
+
+        let report = pool.shutdown(Duration::from_millis(20));
+        assert_eq!(report, ShutdownReport { dropped_jobs: 1, unfinished_workers: 1 });
+        assert_eq!(stranded.join(), Err(JobError::Lost));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_thread_pool_stats() {
+        let pool = ThreadPool::with_panic_hook(2, |_, _| {});
+        pool.execute(|| {}).join().unwrap();
+        pool.execute(|| panic!("boom")).join().unwrap_err();
+
+        // join returns as soon as the result is sent, the counters are bumped just after
+        let mut stats = pool.stats();
+        while stats.completed < 2 {
+            thread::sleep(Duration::from_millis(1));
+            stats = pool.stats();
+        }
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.completed, 2);
+        assert_eq!(stats.panicked, 1);
+        assert_eq!(stats.workers.len(), 2);
+        assert_eq!(stats.workers.iter().map(|worker| worker.jobs_run).sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn test_record_timings() {
+        assert_eq!(ThreadPool::new(1).timings(), None);
+
+        let pool = ThreadPoolBuilder::new().num_threads(1).record_timings(true).build().unwrap();
+        pool.execute(|| thread::sleep(Duration::from_millis(5)));
+        pool.execute(|| {});
+        pool.wait_idle();
+        let timings = pool.timings().unwrap();
+        assert_eq!(timings.run_time.samples, 2);
+        assert!(timings.run_time.p99 >= Duration::from_millis(5));
+        // the second job waited behind the first
+        assert!(timings.queue_wait.p99 >= Duration::from_millis(4));
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let pool = ThreadPool::new(2);
+        pool.pause();
+        assert!(pool.is_paused());
+        // a worker spawned while paused starts out paused too
+        pool.grow(1);
+        let handles: Vec<_> = (0..6).map(|i| pool.execute(move || i)).collect();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(pool.stats().completed, 0);
+        assert_eq!(pool.stats().queued, 6);
+
+        pool.resume();
+        assert!(!pool.is_paused());
+        let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_drop_while_paused_runs_queued_jobs() {
+        let pool = ThreadPool::new(1);
+        pool.pause();
+        let handle = pool.execute(|| 7);
+        drop(pool);
+        assert_eq!(handle.join(), Ok(7));
+    }
+
+    #[test]
+    fn test_execute_all() {
+        let pool = ThreadPool::new(3);
+        let handles = pool.execute_all((0..20).map(|i| move || i * i));
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..20).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_execute_all_bounded() {
+        // a batch bigger than the queue has to go out in pieces
+        let pool = ThreadPool::with_capacity(2, 3);
+        let handles = pool.execute_all((0..10).map(|i| move || i));
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+    }
 }
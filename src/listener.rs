@@ -0,0 +1,445 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(feature = "tls")]
+use crate::TlsStream;
+#[cfg(unix)]
+use std::{
+    fs,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Where a Server is listening
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// An accepted client connection, what a Server hands its handler
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// HTTPS, once the handshake is done, see ServerBuilder::tls
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    /// Another layer over a connection, see ServerBuilder::wrap_connections
+    Wrapped {
+        stream: Box<dyn Stream>,
+        peer: Option<SocketAddr>,
+        // who the wrapper authenticated the client as, e.g. a client certificate's subject
+        identity: Option<String>,
+        tls: Option<TlsInfo>,
+    },
+}
+
+/// What the TLS handshake negotiated, for handlers and logs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// e.g. "TLSv1.3"
+    pub version: String,
+    /// e.g. "TLS13_AES_128_GCM_SHA256"
+    pub cipher: String,
+    /// The ALPN protocol agreed on, if any
+    pub alpn: Option<String>,
+}
+
+/// Everything known about a connection besides its bytes, handed to Server::serve_with_info handlers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Counts up from 1 per Server, for telling connections apart in logs
+    pub id: u64,
+    /// The client, as reported by the PROXY protocol or wrapper when there is one
+    /// None for Unix socket clients
+    pub peer_addr: Option<SocketAddr>,
+    /// The address the client connected to, which for a wildcard listener is the specific local IP
+    pub local_addr: Option<ListenAddr>,
+    pub tls: Option<TlsInfo>,
+    /// See Connection::peer_identity
+    pub peer_identity: Option<String>,
+}
+
+/// Anything a Connection can wrap
+pub trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+// A connection whose socket timeouts can still be changed once a handler has it, so an
+// upgraded one can wait on its client as long as its new protocol likes
+pub(crate) trait SetTimeouts {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl SetTimeouts for Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Connection::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Connection::set_write_timeout(self, timeout)
+    }
+}
+
+impl<T: SetTimeouts + ?Sized> SetTimeouts for &mut T {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
+
+/// Turns a freshly accepted Connection into the one handlers see, set with ServerBuilder::wrap_connections
+pub type ConnectionWrapper = Arc<dyn Fn(Connection) -> io::Result<Connection> + Send + Sync>;
+
+// What the accept loops pull connections from
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+        // we bound it so we clean up the socket file, not so for one handed to us by systemd,
+        // or once we've handed it on to an upgraded process
+        owned: AtomicBool,
+    },
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl ListenAddr {
+    // accept() doesn't wake up on its own, so connect to ourselves to get it to check for shutdown
+    pub(crate) fn wake(&self) -> io::Result<()> {
+        match self {
+            ListenAddr::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr.ip() {
+                        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    });
+                }
+                TcpStream::connect_timeout(&addr, Duration::from_secs(1)).map(drop)
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+impl Connection {
+    /// Wrap 'stream', which usually sits on top of a connection whose address was 'peer'
+    pub fn wrapped(stream: impl Stream + 'static, peer: Option<SocketAddr>) -> Connection {
+        Connection::Wrapped { stream: Box::new(stream), peer, identity: None, tls: None }
+    }
+
+    /// Record who the client proved to be to a wrapper, e.g. the subject of a client
+    /// certificate a TLS library of its own checked. Does nothing unless the connection is
+    /// wrapped, a plain socket can't have authenticated anyone
+    pub fn with_peer_identity(mut self, subject: impl Into<String>) -> Connection {
+        if let Connection::Wrapped { identity, .. } = &mut self {
+            *identity = Some(subject.into());
+        }
+        self
+    }
+
+    /// Record what the TLS layer negotiated. Like with_peer_identity it only sticks to wrapped connections
+    pub fn with_tls(mut self, info: TlsInfo) -> Connection {
+        if let Connection::Wrapped { tls, .. } = &mut self {
+            *tls = Some(info);
+        }
+        self
+    }
+
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        match self {
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => Some(stream.info()),
+            Connection::Wrapped { tls, .. } => tls.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Who the client proved to be, for handlers making authorization decisions: the subject
+    /// of the client certificate ServerBuilder::tls_client_ca checked, or what a wrapper vouched for
+    pub fn peer_identity(&self) -> Option<&str> {
+        match self {
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.peer_identity(),
+            Connection::Wrapped { identity, .. } => identity.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// A second handle on the same connection
+    ///
+    /// # Errors
+    /// Returns the OS error if the socket can't be duplicated, and Unsupported for TLS and wrapped connections
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+            #[cfg(feature = "tls")]
+            Connection::Tls(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "can't clone a TLS connection")),
+            Connection::Wrapped { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "can't clone a wrapped connection")),
+        }
+    }
+
+    /// The client's address, None for Unix socket clients which don't have one worth showing
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket().peer_addr(),
+            Connection::Wrapped { peer, .. } => *peer,
+        }
+    }
+
+    /// Our end of the connection, None once wrapped
+    pub fn local_addr(&self) -> Option<ListenAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.local_addr().ok().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                let addr = stream.local_addr().ok()?;
+                addr.as_pathname().map(|path| ListenAddr::Unix(path.to_path_buf()))
+            }
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket().local_addr(),
+            Connection::Wrapped { .. } => None,
+        }
+    }
+
+    /// How long a read waits for the client before failing with WouldBlock or TimedOut
+    /// Set it before wrapping, a wrapped connection can't reach its socket any more
+    ///
+    /// # Errors
+    /// Returns the OS error, and Unsupported for wrapped connections
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket().set_read_timeout(timeout),
+            Connection::Wrapped { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "can't set timeouts on a wrapped connection")),
+        }
+    }
+
+    /// How long a write waits for the client to make room before failing
+    ///
+    /// # Errors
+    /// Returns the OS error, and Unsupported for wrapped connections
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket().set_write_timeout(timeout),
+            Connection::Wrapped { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "can't set timeouts on a wrapped connection")),
+        }
+    }
+
+    /// Turn Nagle's algorithm off (true) or on, only means anything for TCP connections
+    ///
+    /// # Errors
+    /// Returns the OS error
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.socket().set_nodelay(nodelay),
+            _ => Ok(()),
+        }
+    }
+
+    // Reads and writes fail with WouldBlock instead of waiting, for the event loop
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Connection::Tls(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "can't wait on a TLS connection's socket")),
+            Connection::Wrapped { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "can't reach a wrapped connection's socket")),
+        }
+    }
+
+    // The socket, None for TLS or once wrapped since there may be bytes buffered above it
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        match self {
+            Connection::Tcp(stream) => Some(stream.as_raw_fd()),
+            Connection::Unix(stream) => Some(stream.as_raw_fd()),
+            #[cfg(feature = "tls")]
+            Connection::Tls(_) => None,
+            Connection::Wrapped { .. } => None,
+        }
+    }
+
+    /// Shut down the read, write or both halves of the connection
+    /// A TLS connection sends its close_notify first. A wrapped one is only flushed, the
+    /// wrapper closes the socket when it's dropped
+    ///
+    /// # Errors
+    /// Returns the OS error, e.g. if the client is already gone
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(how),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.shutdown(how),
+            Connection::Wrapped { stream, .. } => stream.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connection::Tcp(stream) => f.debug_tuple("Tcp").field(stream).finish(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => f.debug_tuple("Unix").field(stream).finish(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => f.debug_tuple("Tls").field(stream).finish(),
+            Connection::Wrapped { peer, identity, tls, .. } => {
+                f.debug_struct("Wrapped").field("peer", peer).field("identity", identity).field("tls", tls).finish_non_exhaustive()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer_addr() {
+            Some(addr) => addr.fmt(f),
+            None => f.write_str("unix socket client"),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.read(buf),
+            Connection::Wrapped { stream, .. } => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.write(buf),
+            Connection::Wrapped { stream, .. } => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.flush(),
+            Connection::Wrapped { stream, .. } => stream.flush(),
+        }
+    }
+}
+
+impl Listener {
+    /// Listen on a Unix socket at 'path', replacing a socket file left behind by an earlier run
+    #[cfg(unix)]
+    pub(crate) fn bind_unix(path: &Path) -> io::Result<Listener> {
+        if let Ok(meta) = fs::symlink_metadata(path)
+            && meta.file_type().is_socket()
+        {
+            fs::remove_file(path)?;
+        }
+        Ok(Listener::Unix { listener: UnixListener::bind(path)?, path: path.to_path_buf(), owned: AtomicBool::new(true) })
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.accept().map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    // Leave the socket file behind when we go, someone else is serving it now
+    #[cfg(unix)]
+    pub(crate) fn disown(&self) {
+        if let Listener::Unix { owned, .. } = self {
+            owned.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix { listener, .. } => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // clean up the socket file so the next run doesn't trip over it
+        if let Listener::Unix { path, owned, .. } = self
+            && owned.load(Ordering::SeqCst)
+            && let Err(e) = fs::remove_file(&*path)
+        {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::Path;
+
+// What a file with no extension, or one we don't know, is served as
+const FALLBACK: &str = "application/octet-stream";
+
+// Extensions we know, lowercase. Text types say they're UTF-8 so browsers don't guess
+const TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+// The Content-Type for 'path' by its extension, ignoring case. 'overrides' are extension and
+// type pairs that win over the table, and can add extensions it doesn't have
+pub(crate) fn content_type<'a>(path: &Path, overrides: &'a [(String, String)]) -> &'a str {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return FALLBACK;
+    };
+    if let Some((_, mime)) = overrides.iter().rev().find(|(ext, _)| ext.eq_ignore_ascii_case(extension)) {
+        return mime;
+    }
+    TYPES.iter().find(|(ext, _)| ext.eq_ignore_ascii_case(extension)).map_or(FALLBACK, |(_, mime)| mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        let none = &[];
+        assert_eq!(content_type(Path::new("static/index.html"), none), "text/html; charset=utf-8");
+        assert_eq!(content_type(Path::new("LOGO.PNG"), none), "image/png");
+        assert_eq!(content_type(Path::new("font.woff2"), none), "font/woff2");
+        assert_eq!(content_type(Path::new("archive.unknown"), none), "application/octet-stream");
+        assert_eq!(content_type(Path::new("Makefile"), none), "application/octet-stream");
+
+        let overrides = [("md".to_string(), "text/plain".to_string()), ("gmi".to_string(), "text/gemini".to_string())];
+        assert_eq!(content_type(Path::new("README.md"), &overrides), "text/plain");
+        assert_eq!(content_type(Path::new("page.GMI"), &overrides), "text/gemini");
+        assert_eq!(content_type(Path::new("app.js"), &overrides), "text/javascript; charset=utf-8");
+    }
+}
@@ -0,0 +1,302 @@
+// multipart/form-data request bodies (RFC 7578), what browsers send for forms with file inputs
+use std::{fmt, io::Read};
+
+use crate::{HeaderMap, StatusCode};
+
+/// The parts of a multipart/form-data body, in order, from Request::multipart
+///
+/// Each part's data is checked against max_part_size and the parts' data together against
+/// max_total_size as they're reached, so a handler can stop at the first error
+///
+/// ```
+/// let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHoliday\r\n\
+///              --XyZ\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+///              Content-Type: image/jpeg\r\n\r\n\xff\xd8...\r\n--XyZ--\r\n";
+/// let mut request = webserver::Request::parse(b"POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\r\n").unwrap();
+/// request.body = body.to_vec();
+///
+/// let parts: Vec<_> = request.multipart().unwrap().collect::<Result<_, _>>().unwrap();
+/// assert_eq!((parts[0].name.as_str(), parts[0].data()), ("title", &b"Holiday"[..]));
+/// assert_eq!(parts[1].filename.as_deref(), Some("beach.jpg"));
+/// assert_eq!(parts[1].content_type.as_deref(), Some("image/jpeg"));
+/// ```
+#[derive(Debug)]
+pub struct Multipart<'a> {
+    body: &'a [u8],
+    // "\r\n--" and the boundary, what ends a part's data
+    delimiter: Vec<u8>,
+    // where the next part starts looking, just past a delimiter once we've found the first
+    pos: usize,
+    started: bool,
+    done: bool,
+    total: usize,
+    max_part_size: usize,
+    max_total_size: usize,
+}
+
+/// One field of a multipart/form-data body
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part<'a> {
+    /// The form field's name, from Content-Disposition
+    pub name: String,
+    /// The name of the uploaded file, for file inputs
+    pub filename: Option<String>,
+    /// The part's Content-Type, browsers send one for files
+    pub content_type: Option<String>,
+    /// All of the part's headers
+    pub headers: HeaderMap,
+    data: &'a [u8],
+}
+
+/// Why a multipart/form-data body couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultipartError {
+    /// The request's Content-Type isn't multipart/form-data with a boundary
+    NotMultipart,
+    /// The body doesn't follow the format, with what was wrong
+    Malformed(&'static str),
+    /// A part's data is bigger than max_part_size
+    PartTooLarge,
+    /// The parts' data adds up to more than max_total_size
+    TooLarge,
+}
+
+impl<'a> Multipart<'a> {
+    // The parts of 'body', split on the boundary from Content-Type 'content_type'
+    pub(crate) fn new(body: &'a [u8], content_type: Option<&str>) -> Result<Multipart<'a>, MultipartError> {
+        let content_type = content_type.ok_or(MultipartError::NotMultipart)?;
+        let (mime, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+        if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+            return Err(MultipartError::NotMultipart);
+        }
+        let boundary = parameters(params).find(|(name, _)| name.eq_ignore_ascii_case("boundary")).map(|(_, value)| value);
+        // RFC 2046 5.1.1 caps boundaries at 70 characters
+        let boundary = boundary.filter(|boundary| (1..=70).contains(&boundary.len())).ok_or(MultipartError::NotMultipart)?;
+        Ok(Multipart {
+            body,
+            delimiter: [b"\r\n--", boundary.as_bytes()].concat(),
+            pos: 0,
+            started: false,
+            done: false,
+            total: 0,
+            max_part_size: 1024 * 1024,
+            max_total_size: 8 * 1024 * 1024,
+        })
+    }
+
+    /// Biggest a single part's data can be, 1MB by default
+    pub fn max_part_size(mut self, bytes: usize) -> Self {
+        self.max_part_size = bytes;
+        self
+    }
+
+    /// Biggest all the parts' data can be together, 8MB by default
+    pub fn max_total_size(mut self, bytes: usize) -> Self {
+        self.max_total_size = bytes;
+        self
+    }
+
+    fn next_part(&mut self) -> Result<Option<Part<'a>>, MultipartError> {
+        if !self.started {
+            // anything before the first boundary is a preamble to skip
+            let first = &self.delimiter[2..];
+            self.pos = match self.body.starts_with(first) {
+                true => first.len(),
+                false => find(self.body, &self.delimiter).ok_or(MultipartError::Malformed("no boundary"))? + self.delimiter.len(),
+            };
+            self.started = true;
+        }
+        // after a boundary either "--" for the end, or a line break, maybe after some whitespace
+        let rest = &self.body[self.pos..];
+        if rest.starts_with(b"--") {
+            return Ok(None);
+        }
+        let line_end = find(rest, b"\r\n").ok_or(MultipartError::Malformed("boundary line doesn't end"))?;
+        if !rest[..line_end].iter().all(|b| *b == b' ' || *b == b'\t') {
+            return Err(MultipartError::Malformed("junk after a boundary"));
+        }
+        let part = &rest[line_end + 2..];
+
+        // headers and a blank line, or just the blank line
+        let head_len = match part.starts_with(b"\r\n") {
+            true => 0,
+            false => find(part, b"\r\n\r\n").ok_or(MultipartError::Malformed("part headers don't end"))? + 2,
+        };
+        let mut headers = HeaderMap::new();
+        for line in part[..head_len].split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line)).filter(|line| !line.is_empty()) {
+            let line = String::from_utf8_lossy(line);
+            let (name, value) = line.split_once(':').ok_or(MultipartError::Malformed("part header without a colon"))?;
+            headers.append(name.trim(), value.trim());
+        }
+        let data = &part[head_len + 2..];
+        let len = find(data, &self.delimiter).ok_or(MultipartError::Malformed("no closing boundary"))?;
+        self.pos = self.body.len() - data.len() + len + self.delimiter.len();
+
+        if len > self.max_part_size {
+            return Err(MultipartError::PartTooLarge);
+        }
+        self.total += len;
+        if self.total > self.max_total_size {
+            return Err(MultipartError::TooLarge);
+        }
+        let disposition = headers.get("content-disposition").ok_or(MultipartError::Malformed("part without a Content-Disposition"))?;
+        let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+        if !kind.trim().eq_ignore_ascii_case("form-data") {
+            return Err(MultipartError::Malformed("part isn't form-data"));
+        }
+        let (mut name, mut filename) = (None, None);
+        for (key, value) in parameters(params) {
+            if key.eq_ignore_ascii_case("name") {
+                name = Some(value);
+            } else if key.eq_ignore_ascii_case("filename") {
+                filename = Some(value);
+            }
+        }
+        let name = name.ok_or(MultipartError::Malformed("part without a name"))?;
+        let content_type = headers.get("content-type").map(str::to_string);
+        Ok(Some(Part { name, filename, content_type, headers, data: &data[..len] }))
+    }
+}
+
+impl<'a> Iterator for Multipart<'a> {
+    type Item = Result<Part<'a>, MultipartError>;
+
+    // the first error is the last item, the rest of the body can't be trusted after it
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_part().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+impl<'a> Part<'a> {
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The data as a Read, for code that wants to stream it somewhere
+    pub fn reader(&self) -> impl Read + 'a {
+        self.data
+    }
+}
+
+impl MultipartError {
+    /// The status to answer with: 413 for the size limits, otherwise 400 (415 when it isn't
+    /// multipart at all)
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MultipartError::NotMultipart => StatusCode::UnsupportedMediaType,
+            MultipartError::Malformed(_) => StatusCode::BadRequest,
+            MultipartError::PartTooLarge | MultipartError::TooLarge => StatusCode::ContentTooLarge,
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultipartError::NotMultipart => write!(f, "not multipart/form-data with a boundary"),
+            MultipartError::Malformed(what) => write!(f, "malformed multipart body: {}", what),
+            MultipartError::PartTooLarge => write!(f, "multipart part too large"),
+            MultipartError::TooLarge => write!(f, "multipart body too large"),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+// The ';' separated name=value parameters of a header value, values unquoted:
+// ` name="a \"b\"; c"; filename=d` is ("name", `a "b"; c`) and ("filename", "d")
+fn parameters(s: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        loop {
+            rest = rest.trim_start_matches([' ', '\t', ';']);
+            if rest.is_empty() {
+                return None;
+            }
+            let name_end = rest.find(['=', ';']).unwrap_or(rest.len());
+            let name = rest[..name_end].trim().to_string();
+            rest = &rest[name_end..];
+            let Some(value) = rest.strip_prefix('=') else {
+                // a bare name, no value to give
+                continue;
+            };
+            let value = value.trim_start();
+            let mut unquoted = String::new();
+            if let Some(quoted) = value.strip_prefix('"') {
+                let mut chars = quoted.char_indices();
+                rest = "";
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => unquoted.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            rest = &quoted[i + 1..];
+                            break;
+                        }
+                        c => unquoted.push(c),
+                    }
+                }
+            } else {
+                let end = value.find(';').unwrap_or(value.len());
+                unquoted.push_str(value[..end].trim_end());
+                rest = &value[end..];
+            }
+            return Some((name, unquoted));
+        }
+    })
+}
+
+// Where 'needle' first turns up in 'haystack'
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &[u8]) -> Vec<Result<Part<'_>, MultipartError>> {
+        Multipart::new(body, Some("multipart/form-data; boundary=\"b\"")).unwrap().collect()
+    }
+
+    #[test]
+    fn test_parameters() {
+        let params: Vec<_> = parameters(r#" name="a \"b\"; c"; bare; filename=d.txt ;x="#).collect();
+        assert_eq!(params, [("name".into(), r#"a "b"; c"#.into()), ("filename".into(), "d.txt".into()), ("x".into(), String::new())]);
+    }
+
+    #[test]
+    fn test_multipart() {
+        // a preamble, padding after a boundary, a part without headers besides the disposition
+        // and data that looks a bit like a boundary
+        let body = b"ignored\r\n--b  \r\nContent-Disposition: form-data; name=a\r\n\r\nline\r\n-b\r\n--b\r\n\
+                     Content-Disposition: form-data; name=\"f\"; filename=\"x.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n\0\xff\r\n--b--";
+        let parts: Vec<_> = parse(body).into_iter().map(Result::unwrap).collect();
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].name.as_str(), parts[0].data()), ("a", &b"line\r\n-b"[..]));
+        assert_eq!(parts[1].filename.as_deref(), Some("x.bin"));
+        let mut data = Vec::new();
+        parts[1].reader().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"\0\xff");
+
+        assert_eq!(Multipart::new(b"", Some("application/json")).unwrap_err(), MultipartError::NotMultipart);
+        assert_eq!(Multipart::new(b"", Some("multipart/form-data")).unwrap_err(), MultipartError::NotMultipart);
+        assert!(matches!(parse(b"--b\r\nContent-Disposition: form-data; name=a\r\n\r\nno end")[..], [Err(MultipartError::Malformed(_))]));
+        assert!(matches!(parse(b"--b\r\nContent-Type: text/plain\r\n\r\nx\r\n--b--")[..], [Err(MultipartError::Malformed(_))]));
+        assert!(parse(b"--b--").is_empty());
+    }
+
+    #[test]
+    fn test_multipart_limits() {
+        let body = b"--b\r\nContent-Disposition: form-data; name=a\r\n\r\n1234\r\n--b\r\nContent-Disposition: form-data; name=b\r\n\r\n5678\r\n--b--";
+        let multipart = || Multipart::new(body, Some("multipart/form-data; boundary=b")).unwrap();
+        assert_eq!(multipart().max_part_size(3).next(), Some(Err(MultipartError::PartTooLarge)));
+        let limited: Vec<_> = multipart().max_total_size(6).collect();
+        assert!(matches!(limited[..], [Ok(_), Err(MultipartError::TooLarge)]));
+        assert_eq!(multipart().max_total_size(8).filter(Result::is_ok).count(), 2);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{any::Any, time::Duration};
+
+use crate::LogLevel;
+
+/// Gets told what the workers are up to, install one with ThreadPoolBuilder::observer
+/// Every method defaults to doing nothing so only the interesting ones need implementing
+/// They are called on the worker's own thread, so keep them quick
+pub trait PoolObserver: Send + Sync {
+    /// A worker picked up a job and is about to run it
+    fn on_job_start(&self, _worker: usize) {}
+
+    /// A job returned or panicked after running for 'ran_for'
+    fn on_job_end(&self, _worker: usize, _ran_for: Duration) {}
+
+    /// A job panicked, this is called on top of the panic hook
+    fn on_panic(&self, _worker: usize, _payload: &(dyn Any + Send)) {}
+
+    /// A worker left its loop and its thread is about to end, or for ExitReason::Lost,
+    /// the pool found its thread already gone. Lost is reported from whichever thread noticed
+    fn on_worker_exit(&self, _worker: usize, _reason: ExitReason) {}
+
+    /// Something the pool has to say that no handle or hook would hear: a job it had to drop or
+    /// refuse, a worker it couldn't spawn, reach or join, a shutdown that ran out of time, and
+    /// job panics when there's no panic_hook. Printed to stderr by default, at or under the log level
+    fn on_message(&self, level: LogLevel, message: &str) {
+        if level.enabled() {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// Why a worker stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Shutdown, drop or shrink asked it to
+    Terminated,
+    /// It sat idle past the pool's idle timeout
+    IdleTimeout,
+    /// Its job ran past the job timeout and it was replaced, see ThreadPoolBuilder::replace_timed_out_workers
+    TimedOut,
+    /// Its thread ended without being asked to, reported by the pool once it notices
+    /// What happens next is up to the WorkerExitPolicy
+    Lost,
+}
+
+/// The default observer, ignores everything but messages, which are printed as on_message does
+pub struct NoopObserver;
+
+impl PoolObserver for NoopObserver {}
+
+/// Prints the same per job chatter to stdout that the pool used to, handy while debugging
+pub struct LogObserver;
+
+impl PoolObserver for LogObserver {
+    fn on_job_start(&self, worker: usize) {
+        println!("Worker {} got a job; executing.", worker);
+    }
+
+    fn on_worker_exit(&self, worker: usize, reason: ExitReason) {
+        match reason {
+            ExitReason::Terminated => println!("Worker {} was told to terminate.", worker),
+            ExitReason::IdleTimeout => println!("Worker {} was idle too long; retiring.", worker),
+            ExitReason::TimedOut => println!("Worker {} was replaced after a job timed out.", worker),
+            ExitReason::Lost => println!("Worker {} exited unexpectedly.", worker),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ThreadPoolBuilder;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl PoolObserver for Arc<Recorder> {
+        fn on_job_start(&self, worker: usize) {
+            self.events.lock().unwrap().push(format!("start {}", worker));
+        }
+
+        fn on_job_end(&self, worker: usize, _ran_for: Duration) {
+            self.events.lock().unwrap().push(format!("end {}", worker));
+        }
+
+        fn on_panic(&self, worker: usize, payload: &(dyn Any + Send)) {
+            self.events.lock().unwrap().push(format!("panic {} {}", worker, crate::panic_message(payload)));
+        }
+
+        fn on_worker_exit(&self, worker: usize, reason: ExitReason) {
+            self.events.lock().unwrap().push(format!("exit {} {:?}", worker, reason));
+        }
+
+        fn on_message(&self, level: LogLevel, message: &str) {
+            self.events.lock().unwrap().push(format!("message {:?} {}", level, message));
+        }
+    }
+
+    #[test]
+    fn test_observer_sees_job_lifecycle() {
+        let recorder = Arc::new(Recorder::default());
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .observer(Arc::clone(&recorder))
+            .panic_hook(|_, _| {})
+            .build()
+            .unwrap();
+        pool.execute(|| {}).join().unwrap();
+        pool.execute(|| panic!("boom")).join().unwrap_err();
+        drop(pool);
+
+        let events = recorder.events.lock().unwrap();
+        assert_eq!(*events, ["start 0", "end 0", "start 0", "end 0", "panic 0 boom", "exit 0 Terminated"]);
+    }
+
+    #[test]
+    fn test_observer_hears_messages() {
+        let recorder = Arc::new(Recorder::default());
+        let pool = ThreadPoolBuilder::new().num_threads(1).observer(Arc::clone(&recorder)).build().unwrap();
+        // without a panic_hook the panic comes through on_message too
+        pool.execute(|| panic!("boom")).join().unwrap_err();
+        pool.shutdown(Duration::from_secs(5));
+        pool.execute(|| {}).join().unwrap_err();
+
+        let events = recorder.events.lock().unwrap();
+        let messages: Vec<_> = events.iter().filter(|event| event.starts_with("message ")).collect();
+        assert_eq!(messages, ["message Error Worker 0 panicked while running a job: boom", "message Warn Rejecting job, the pool is shutting down"]);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::{JobHandle, ShutdownReport, ThreadPool, ThreadPoolBuilder};
+
+/// Which of a PoolSet's pools a job belongs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PoolTag {
+    /// Jobs that may sit waiting on disk or the network
+    Blocking,
+    /// Quick jobs that should never queue behind slow ones
+    Fast,
+}
+
+/// Two pools behind one handle, so slow blocking work can't starve quick jobs of workers
+///
+/// ```
+/// use webserver::{PoolSet, PoolTag};
+///
+/// let pools = PoolSet::new(4, 1).unwrap();
+/// let page = pools.execute(PoolTag::Blocking, || "read from disk");
+/// let health = pools.execute(PoolTag::Fast, || "ok");
+/// assert_eq!(health.join(), Ok("ok"));
+/// assert_eq!(page.join(), Ok("read from disk"));
+/// ```
+pub struct PoolSet {
+    blocking: ThreadPool,
+    fast: ThreadPool,
+}
+
+impl PoolSet {
+    /// Build both pools with the given worker counts, their threads are named
+    /// "io-blocking-{id}" and "fast-{id}"
+    ///
+    /// # Errors
+    /// Returns the OS error if a worker thread couldn't be spawned
+    ///
+    /// # Panics
+    /// Panics if either count is 0
+    pub fn new(blocking_threads: usize, fast_threads: usize) -> io::Result<PoolSet> {
+        Ok(PoolSet {
+            blocking: ThreadPoolBuilder::new().num_threads(blocking_threads).thread_name("io-blocking").build()?,
+            fast: ThreadPoolBuilder::new().num_threads(fast_threads).thread_name("fast").build()?,
+        })
+    }
+
+    /// Use pools that were set up elsewhere, e.g. to give the blocking one a bounded queue
+    pub fn from_pools(blocking: ThreadPool, fast: ThreadPool) -> PoolSet {
+        PoolSet { blocking, fast }
+    }
+
+    /// The pool jobs tagged 'tag' go to
+    pub fn pool(&self, tag: PoolTag) -> &ThreadPool {
+        match tag {
+            PoolTag::Blocking => &self.blocking,
+            PoolTag::Fast => &self.fast,
+        }
+    }
+
+    /// Queue 'f' on the pool for 'tag', see ThreadPool::execute
+    pub fn execute<F, T>(&self, tag: PoolTag, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.pool(tag).execute(f)
+    }
+
+    /// Queue 'f' on the pool for 'tag' without blocking, see ThreadPool::try_execute
+    pub fn try_execute<F, T>(&self, tag: PoolTag, f: F) -> Result<JobHandle<T>, F>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.pool(tag).try_execute(f)
+    }
+
+    /// Shut both pools down, sharing 'timeout' between them, and add up what was left behind
+    pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let deadline = Instant::now() + timeout;
+        let fast = self.fast.shutdown(timeout);
+        let blocking = self.blocking.shutdown(deadline.saturating_duration_since(Instant::now()));
+        ShutdownReport {
+            dropped_jobs: fast.dropped_jobs + blocking.dropped_jobs,
+            unfinished_workers: fast.unfinished_workers + blocking.unfinished_workers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_fast_jobs_skip_blocked_pool() {
+        let pools = PoolSet::new(1, 1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let stuck = pools.execute(PoolTag::Blocking, move || blocked.recv().unwrap());
+        let queued = pools.execute(PoolTag::Blocking, || 1);
+
+        // the only blocking worker is busy but the fast pool still answers
+        assert_eq!(pools.execute(PoolTag::Fast, || 2).join(), Ok(2));
+
+        release.send(()).unwrap();
+        stuck.join().unwrap();
+        assert_eq!(queued.join(), Ok(1));
+    }
+
+    #[test]
+    fn test_jobs_run_on_tagged_pool() {
+        let pools = PoolSet::new(1, 1).unwrap();
+        let name = || std::thread::current().name().map(String::from);
+        assert_eq!(pools.execute(PoolTag::Blocking, name).join(), Ok(Some("io-blocking-0".to_string())));
+        assert_eq!(pools.execute(PoolTag::Fast, name).join(), Ok(Some("fast-0".to_string())));
+    }
+}
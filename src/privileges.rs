@@ -0,0 +1,117 @@
+use std::{
+    ffi::{c_char, CString},
+    io,
+    os::raw::c_int,
+};
+
+use crate::logging::log;
+
+// getpwnam, setuid and friends come from libc which std already links, so no extra crate is needed
+
+// Only the leading fields, which are laid out the same on Linux and the BSDs
+#[repr(C)]
+struct Passwd {
+    name: *const c_char,
+    passwd: *const c_char,
+    uid: u32,
+    gid: u32,
+}
+
+#[repr(C)]
+struct Group {
+    name: *const c_char,
+    passwd: *const c_char,
+    gid: u32,
+}
+
+unsafe extern "C" {
+    fn getpwnam(name: *const c_char) -> *const Passwd;
+    fn getgrnam(name: *const c_char) -> *const Group;
+    fn geteuid() -> u32;
+    #[cfg(target_os = "linux")]
+    fn setgroups(size: usize, list: *const u32) -> c_int;
+    #[cfg(not(target_os = "linux"))]
+    fn setgroups(size: c_int, list: *const u32) -> c_int;
+    fn setgid(gid: u32) -> c_int;
+    fn setuid(uid: u32) -> c_int;
+}
+
+/// Switch the process to 'user' (and its primary group, or 'group' when given) for good,
+/// meant for after binding ports below 1024 as root. Supplementary groups are dropped too
+///
+/// # Errors
+/// Returns NotFound for unknown names, and the OS error if we aren't root
+pub(crate) fn drop_to(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let (uid, user_gid) = match user {
+        Some(user) => {
+            let (uid, gid) = user_ids(user)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(group) => Some(group_id(group)?),
+        None => user_gid,
+    };
+    // the group goes first, once we've given up root we can't change it any more
+    if let Some(gid) = gid {
+        // SAFETY: a one element list that outlives the call
+        check(unsafe { setgroups(1, &gid) })?;
+        // SAFETY: plain syscall
+        check(unsafe { setgid(gid) })?;
+    }
+    if let Some(uid) = uid {
+        // SAFETY: plain syscall
+        check(unsafe { setuid(uid) })?;
+        // SAFETY: plain syscall
+        if uid != 0 && unsafe { setuid(0) } == 0 {
+            return Err(io::Error::other("could still get root back after dropping privileges"));
+        }
+    }
+    // SAFETY: plain syscall
+    log!(Info, "Running as uid {}, gid {:?}", unsafe { geteuid() }, gid);
+    Ok(())
+}
+
+// The uid and primary gid of 'name'
+fn user_ids(name: &str) -> io::Result<(u32, u32)> {
+    let cname = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "user name contains a NUL"))?;
+    // SAFETY: cname is NUL terminated; the result points into libc's static buffer and is
+    // read right away, we only look users up while starting, before serving on any other thread
+    let entry = unsafe { getpwnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no such user {}", name)));
+    }
+    // SAFETY: checked for null above
+    let entry = unsafe { &*entry };
+    Ok((entry.uid, entry.gid))
+}
+
+fn group_id(name: &str) -> io::Result<u32> {
+    let cname = CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "group name contains a NUL"))?;
+    // SAFETY: as in user_ids
+    let entry = unsafe { getgrnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no such group {}", name)));
+    }
+    // SAFETY: checked for null above
+    Ok(unsafe { (*entry).gid })
+}
+
+fn check(ret: c_int) -> io::Result<()> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // switching users would change it for the whole test process, so only the lookups get tested
+    #[test]
+    fn test_lookups() {
+        assert_eq!(user_ids("root").unwrap().0, 0);
+        assert_eq!(user_ids("no-such-user-here").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(group_id("no-such-group-here").is_err());
+        assert_eq!(drop_to(Some("no-such-user-here"), None).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
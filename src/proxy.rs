@@ -0,0 +1,148 @@
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// Every v2 header opens with this, chosen so it can't be the start of an HTTP request
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest a v1 line can be, CRLF included
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header (v1 or v2) a load balancer such as HAProxy sends before
+/// the client's bytes, and return the client's address from it
+/// Ok(None) means the proxy is talking for itself (a health check), not relaying a client
+/// Nothing past the header is read, so the request is still there for the handler
+///
+/// # Errors
+/// Returns InvalidData if the connection doesn't start with a valid header, and the read error
+/// if the connection fails or times out first
+pub(crate) fn read_header(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    // the shortest v1 header, "PROXY UNKNOWN\r\n", is longer than this so we can't overshoot
+    let mut start = [0; 12];
+    stream.read_exact(&mut start)?;
+    if start == V2_SIGNATURE {
+        read_v2(stream)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start)
+    } else {
+        Err(invalid("connection didn't start with a PROXY protocol header"))
+    }
+}
+
+// "PROXY TCP4 <src> <dst> <src port> <dst port>\r\n", or "PROXY UNKNOWN ...\r\n"
+fn read_v1(stream: &mut impl Read, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    // a byte at a time so we stop right at the CRLF, it's a hundred bytes at most
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 header isn't ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address in PROXY v1 header"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("PROXY v1 source address doesn't match its family"));
+            }
+            let port = src_port.parse().map_err(|_| invalid("bad source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+// Binary: version and command, address family, length, then the addresses and any TLVs
+fn read_v2(stream: &mut impl Read) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed)?;
+    let [version_command, family, len_hi, len_lo] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // the rest is read even when we don't use it, it isn't the client's request
+    let mut body = vec![0; usize::from(u16::from_be_bytes([len_hi, len_lo]))];
+    stream.read_exact(&mut body)?;
+    match version_command & 0xf {
+        // LOCAL, the proxy's own connection
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    // high nibble is the address family, low the transport, which doesn't matter for the address
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        }
+        1 | 2 => Err(invalid("PROXY v2 address block too short")),
+        // AF_UNSPEC or AF_UNIX, no IP address to report
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // the header's result, and what's left for the handler
+    fn parse(bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = Cursor::new(bytes.to_vec());
+        let result = read_header(&mut stream);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        (result, rest)
+    }
+
+    #[test]
+    fn test_v1() {
+        let (addr, rest) = parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let (addr, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n");
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").0.unwrap(), None);
+
+        assert!(parse(b"PROXY TCP4 2001:db8::1 10.0.0.1 1 2\r\n").0.is_err());
+        assert!(parse(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n").0.is_err());
+        assert!(parse(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat()).0.is_err());
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n").0.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY over TCP/IPv4, 12 bytes of addresses plus a 3 byte TLV we skip
+        header.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xdc, 0x04, 0, 80]);
+        header.extend_from_slice(&[0x04, 0, 0]);
+        let (addr, rest) = parse(&[header.as_slice(), b"GET /"].concat());
+        assert_eq!(addr.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let local = [V2_SIGNATURE.as_slice(), &[0x20, 0x00, 0, 0]].concat();
+        assert_eq!(parse(&local).0.unwrap(), None);
+        let short = [V2_SIGNATURE.as_slice(), &[0x21, 0x11, 0, 4, 1, 2, 3, 4]].concat();
+        assert!(parse(&short).0.is_err());
+        let version = [V2_SIGNATURE.as_slice(), &[0x11, 0x11, 0, 0]].concat();
+        assert!(parse(&version).0.is_err());
+    }
+}
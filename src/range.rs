@@ -0,0 +1,96 @@
+// Byte range requests (RFC 9110 14)
+use std::ops::Range;
+
+use crate::{date, Request};
+
+// What a Range header asks of a 'len' byte body
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    // send this much of it as a 206
+    Partial(Range<u64>),
+    // none of it exists, a 416
+    Unsatisfiable,
+}
+
+// The range 'request' wants of a body 'len' bytes long with the given validators. None means
+// send the whole body: there's no Range, it isn't one we understand (the header may be ignored,
+// and several ranges at once aren't worth a multipart response here), or an If-Range says the
+// client's copy is out of date
+pub(crate) fn requested(request: &Request, len: u64, etag: Option<&str>, last_modified: Option<&str>) -> Option<ByteRange> {
+    let header = request.header("range")?;
+    if let Some(if_range) = request.header("if-range") {
+        // an ETag has to match strongly, a weak one never does
+        let current = if if_range.starts_with('"') {
+            etag.is_some_and(|etag| etag == if_range)
+        } else {
+            let modified = last_modified.and_then(date::parse);
+            modified.is_some() && modified == date::parse(if_range)
+        };
+        if !current {
+            return None;
+        }
+    }
+    let spec = header.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let number = |s: &str| s.bytes().all(|b| b.is_ascii_digit()).then(|| s.parse::<u64>().ok()).flatten();
+    let range = match (first.trim(), last.trim()) {
+        // the last n bytes
+        ("", suffix) => {
+            let suffix = number(suffix)?;
+            len.saturating_sub(suffix)..len
+        }
+        (first, "") => number(first)?..len,
+        (first, last) => {
+            let (first, last) = (number(first)?, number(last)?);
+            if last < first {
+                return None;
+            }
+            first..last.saturating_add(1).min(len)
+        }
+    };
+    if range.start >= len || range.is_empty() {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Partial(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let range = |header: &str, len| {
+            let request = Request::parse(format!("GET / HTTP/1.1\r\nRange: {}\r\n\r\n", header).as_bytes()).unwrap();
+            requested(&request, len, None, None)
+        };
+        assert_eq!(range("bytes=0-99", 1000), Some(ByteRange::Partial(0..100)));
+        assert_eq!(range("bytes=500-", 1000), Some(ByteRange::Partial(500..1000)));
+        assert_eq!(range("bytes=-100", 1000), Some(ByteRange::Partial(900..1000)));
+        assert_eq!(range("bytes=-2000", 1000), Some(ByteRange::Partial(0..1000)));
+        assert_eq!(range("bytes=900-5000", 1000), Some(ByteRange::Partial(900..1000)));
+        assert_eq!(range("bytes=1000-", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(range("bytes=-0", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(range("bytes=0-", 0), Some(ByteRange::Unsatisfiable));
+        for ignored in ["items=0-1", "bytes=5-1", "bytes=a-b", "bytes=0-1,5-6", "bytes=+1-2", "bytes=-"] {
+            assert_eq!(range(ignored, 1000), None, "{}", ignored);
+        }
+    }
+
+    #[test]
+    fn test_if_range() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let range = |if_range: &str| {
+            let request = Request::parse(format!("GET / HTTP/1.1\r\nRange: bytes=0-0\r\nIf-Range: {}\r\n\r\n", if_range).as_bytes()).unwrap();
+            requested(&request, 10, Some("\"abc\""), Some(date))
+        };
+        assert!(range("\"abc\"").is_some());
+        assert!(range(date).is_some());
+        assert!(range("\"old\"").is_none());
+        assert!(range("W/\"abc\"").is_none());
+        assert!(range("Sat, 05 Nov 1994 08:49:37 GMT").is_none());
+    }
+}
@@ -0,0 +1,547 @@
+use std::{fmt, io::Read, str::FromStr};
+
+use crate::{
+    accept, cookie,
+    headers::{is_token, is_valid_value},
+    url, FormError, HeaderMap, Multipart, MultipartError, QueryPairs,
+};
+
+/// A parsed HTTP/1.x request
+///
+/// ```
+/// let request = webserver::Request::parse(b"GET /search?q=rust HTTP/1.1\r\nHost: example.com\r\n\r\n").unwrap();
+/// assert_eq!(request.method, webserver::Method::Get);
+/// assert_eq!(request.path, "/search");
+/// assert_eq!(request.query("q").as_deref(), Some("rust"));
+/// assert_eq!(request.header("host"), Some("example.com"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// The request target up to any '?', percent-decoded with its "." and ".." segments
+    /// and repeated slashes resolved: "/a/./b/../%63" is "/a/c"
+    pub path: String,
+    /// The path as the client sent it
+    pub raw_path: String,
+    /// The host and port of an absolute-form target ("GET http://example.com/ HTTP/1.1", as
+    /// sent to proxies), which takes the place of the Host header (RFC 9112 3.2.2)
+    pub authority: Option<String>,
+    /// Whatever came after the '?', still encoded, empty if there was none
+    pub query_string: String,
+    /// HTTP/1.0, 1.1 or 2, the response goes back in the same
+    pub version: Version,
+    /// In the order they came, names as the client spelled them
+    pub headers: HeaderMap,
+    /// All of it, going by Content-Length or chunked encoding, already read off the connection
+    pub body: Vec<u8>,
+    /// The fields sent after a chunked body, kept apart from the headers since they weren't
+    /// there when the request was routed. Empty for any other body
+    pub trailers: HeaderMap,
+    /// Identifies the request in the logs and the response's X-Request-Id header: the
+    /// client's own X-Request-Id if it sent a sensible one, otherwise made up by the server.
+    /// Empty from Request::parse, the server fills it in
+    pub id: String,
+}
+
+/// The request methods RFC 9110 defines, anything else gets a 501
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+}
+
+impl Method {
+    /// The method as it goes on the wire, e.g. "GET"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    // methods are case-sensitive, "get" is some other method we don't know
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        Ok(match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            other => return Err(ParseError::UnknownMethod(other.to_string())),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP versions we speak. A later HTTP/1.x is taken as 1.1, as RFC 9110 2.5 says to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    /// No keep-alive unless asked for, no chunked encoding, no Host header needed
+    Http10,
+    #[default]
+    Http11,
+    /// Over an HTTP/2 connection, with ServerBuilder::http2 on
+    Http2,
+}
+
+impl Version {
+    /// The version as it goes on the wire, e.g. "HTTP/1.1"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a request head couldn't be parsed, the client gets a 400 (501 for an unknown method)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Not "METHOD target HTTP/x.y"
+    RequestLine(String),
+    /// A version other than HTTP/1.x
+    Version(String),
+    /// A well formed method that isn't one of Method's
+    UnknownMethod(String),
+    /// A path that doesn't decode to UTF-8 or decodes to a NUL
+    Path(String),
+    /// A header line without a name and a colon, or folded onto nothing
+    Header(String),
+    /// A header value with a control character in it: a NUL, a CR not ending the line, ...
+    HeaderValue(String),
+    /// The head doesn't end with a blank line
+    Incomplete,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::RequestLine(line) => write!(f, "invalid request line {:?}", line),
+            ParseError::Version(version) => write!(f, "unsupported HTTP version {:?}", version),
+            ParseError::UnknownMethod(method) => write!(f, "unknown method {:?}", method),
+            ParseError::Path(path) => write!(f, "invalid path {}", path),
+            ParseError::Header(line) => write!(f, "invalid header line {:?}", line),
+            ParseError::HeaderValue(name) => write!(f, "control character in the value of header {:?}", name),
+            ParseError::Incomplete => write!(f, "request head doesn't end with a blank line"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Request {
+    /// Parse a request head, the request line and headers up to and including the blank line
+    /// The body is left empty for the caller to fill in
+    ///
+    /// Header lines folded onto the previous one (starting with a space or tab) are joined to it
+    /// with a single space, as RFC 9112 allows a server to do. Lines may end in a bare LF
+    /// rather than CRLF
+    ///
+    /// # Errors
+    /// Returns a ParseError for anything that isn't a well formed HTTP/1.x head
+    pub fn parse(head: &[u8]) -> Result<Request, ParseError> {
+        let head = strip_line_end(head).and_then(strip_line_end).ok_or(ParseError::Incomplete)?;
+        let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        let request_line = lines.next().unwrap_or_default();
+        let (method, target, version) = parse_request_line(request_line)?;
+        let (authority, target) = split_authority(&target)?;
+        let (raw_path, query_string) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), query.to_string()),
+            None => (target, String::new()),
+        };
+        // "*" for OPTIONS and CONNECT's host:port aren't paths to normalize
+        let path = if raw_path.starts_with('/') {
+            url::normalize_path(&raw_path).map_err(|why| ParseError::Path(format!("{} ({})", raw_path, why)))?
+        } else {
+            raw_path.clone()
+        };
+
+        let mut headers = HeaderMap::new();
+        for line in lines {
+            // header values may carry any byte past ASCII, names and the rest can't
+            let text = String::from_utf8_lossy(line);
+            if line.starts_with(b" ") || line.starts_with(b"\t") {
+                let name = headers.iter().last().map(|(name, _)| name.to_string()).ok_or_else(|| ParseError::Header(text.to_string()))?;
+                // held to the same bytes as the line it continues
+                if !is_valid_value(&text) {
+                    return Err(ParseError::HeaderValue(name));
+                }
+                let value = headers.last_mut().ok_or_else(|| ParseError::Header(text.to_string()))?;
+                let more = text.trim_matches([' ', '\t']);
+                if !more.is_empty() {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    value.push_str(more);
+                }
+                continue;
+            }
+            let (name, value) = text.split_once(':').ok_or_else(|| ParseError::Header(text.to_string()))?;
+            // no whitespace before the colon either, RFC 9112 5.1 says that's a 400
+            if !is_token(name) {
+                return Err(ParseError::Header(text.to_string()));
+            }
+            // a stray CR or NUL could mean something else to whatever reads it next
+            if !is_valid_value(value) {
+                return Err(ParseError::HeaderValue(name.to_string()));
+            }
+            headers.append(name, value.trim_matches([' ', '\t']));
+        }
+        Ok(Request { method, path, raw_path, authority, query_string, version, headers, body: Vec::new(), trailers: HeaderMap::new(), id: String::new() })
+    }
+
+    /// The first value of header 'name', ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The host the request is for: an absolute-form target's authority, otherwise the Host header
+    ///
+    /// ```
+    /// let request = webserver::Request::parse(b"GET http://example.com:8080/a?b HTTP/1.1\r\nHost: elsewhere\r\n\r\n").unwrap();
+    /// assert_eq!(request.host(), Some("example.com:8080"));
+    /// assert_eq!((request.path.as_str(), request.query_string.as_str()), ("/a", "b"));
+    /// ```
+    pub fn host(&self) -> Option<&str> {
+        self.authority.as_deref().or_else(|| self.header("host"))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The body as a Read, for code that wants one
+    pub fn body_reader(&self) -> impl Read + '_ {
+        self.body.as_slice()
+    }
+
+    /// The fields of an HTML form's application/x-www-form-urlencoded body, decoded like the
+    /// query string is, in order
+    ///
+    /// ```
+    /// let mut request = webserver::Request::parse(b"POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n").unwrap();
+    /// request.body = b"user=ann+lee&next=%2Fhome".to_vec();
+    /// let form = request.form().unwrap();
+    /// assert_eq!(form, [("user".to_string(), "ann lee".to_string()), ("next".to_string(), "/home".to_string())]);
+    /// ```
+    ///
+    /// # Errors
+    /// FormError::NotForm for another Content-Type, FormError::Encoding for a body that isn't UTF-8.
+    /// FormError::status says what to answer with
+    pub fn form(&self) -> Result<Vec<(String, String)>, FormError> {
+        let content_type = self.headers.get("content-type").unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Err(FormError::NotForm);
+        }
+        let body = std::str::from_utf8(&self.body).map_err(|_| FormError::Encoding)?;
+        Ok(QueryPairs::new(body).collect())
+    }
+
+    /// The parts of a multipart/form-data body, a browser's form with file inputs, with the
+    /// default size limits. MultipartError::status says what to answer a bad one with
+    ///
+    /// # Errors
+    /// MultipartError::NotMultipart when the Content-Type isn't multipart/form-data
+    pub fn multipart(&self) -> Result<Multipart<'_>, MultipartError> {
+        Multipart::new(&self.body, self.headers.get("content-type"))
+    }
+
+    /// The first value of query parameter 'name', decoded, "+" and "%XX" included
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    /// Every query parameter as decoded name and value pairs, in order
+    pub fn query_pairs(&self) -> QueryPairs<'_> {
+        QueryPairs::new(&self.query_string)
+    }
+
+    /// The cookies the client sent as name and value pairs, from every Cookie header
+    pub fn cookies(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.get_all("cookie").flat_map(cookie::parse_cookies)
+    }
+
+    /// The value of cookie 'name', the first if it came more than once
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies().find(|(key, _)| *key == name).map(|(_, value)| value)
+    }
+
+    /// Whether the client takes 'mime' as a response, going by its Accept headers with their
+    /// q-values and wildcards. No Accept header takes anything
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.accept().is_none_or(|accept| accept::quality(&accept, mime) > 0.0)
+    }
+
+    /// Of the types a handler can send, the one the client would most like, going by Accept;
+    /// the first of 'offered' when it doesn't care. None if it takes none of them, a 406
+    ///
+    /// ```
+    /// let request = webserver::Request::parse(b"GET / HTTP/1.1\r\nAccept: application/json, text/*;q=0.5\r\n\r\n").unwrap();
+    /// assert_eq!(request.preferred(&["text/html", "application/json"]), Some("application/json"));
+    /// assert!(!request.accepts("image/png"));
+    /// ```
+    pub fn preferred<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        match self.accept() {
+            Some(accept) => accept::preferred(&accept, offered),
+            None => offered.first().copied(),
+        }
+    }
+
+    // Every Accept header as one list, None without any
+    fn accept(&self) -> Option<String> {
+        let values: Vec<&str> = self.headers.get_all("accept").collect();
+        (!values.is_empty()).then(|| values.join(","))
+    }
+
+    /// Whether the client wants the connection kept open after this request:
+    /// HTTP/1.1 unless it says Connection: close, HTTP/1.0 only if it says keep-alive
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("connection", "close") {
+            return false;
+        }
+        self.version == Version::Http11 || self.headers.has_token("connection", "keep-alive")
+    }
+}
+
+// The length of the request head at the start of 'buf', up to and including the blank line
+// that ends it. Lines should end in CRLF, a bare LF is taken too (RFC 9112 2.2)
+pub(crate) fn head_len(buf: &[u8]) -> Option<usize> {
+    let mut from = 0;
+    while let Some(i) = buf[from..].iter().position(|b| *b == b'\n') {
+        let rest = &buf[from + i + 1..];
+        if rest.starts_with(b"\n") {
+            return Some(from + i + 2);
+        }
+        if rest.starts_with(b"\r\n") {
+            return Some(from + i + 3);
+        }
+        from += i + 1;
+    }
+    None
+}
+
+// 'head' without the LF or CRLF at its end, None if it doesn't end in one
+fn strip_line_end(head: &[u8]) -> Option<&[u8]> {
+    let head = head.strip_suffix(b"\n")?;
+    Some(head.strip_suffix(b"\r").unwrap_or(head))
+}
+
+// An absolute-form target split into its authority and the rest, "http://h:80/a?b" into
+// "h:80" and "/a?b", with the path "/" if there's none. Other targets come back as they are
+// The scheme is http or https, anything else isn't ours to serve, and an http URI always
+// names a host and never userinfo (RFC 9110 4.2)
+fn split_authority(target: &str) -> Result<(Option<String>, String), ParseError> {
+    let Some((scheme, rest)) = target.split_once("://") else {
+        return Ok((None, target.to_string()));
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Ok((None, target.to_string()));
+    }
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(end);
+    if authority.is_empty() || authority.contains('@') {
+        let why = if authority.is_empty() { "no host" } else { "userinfo" };
+        return Err(ParseError::Path(format!("{} ({})", target, why)));
+    }
+    let rest = if rest.starts_with('/') { rest.to_string() } else { format!("/{}", rest) };
+    Ok((Some(authority.to_string()), rest))
+}
+
+// "METHOD target HTTP/x.y", single spaces between, as RFC 9112 3 has it
+fn parse_request_line(line: &[u8]) -> Result<(Method, String, Version), ParseError> {
+    let text = String::from_utf8_lossy(line);
+    let invalid = || ParseError::RequestLine(text.to_string());
+    let mut parts = text.split(' ');
+    let (Some(method), Some(path), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if !is_token(method) || path.is_empty() || !path.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(invalid());
+    }
+    let Some((major, minor)) = version.strip_prefix("HTTP/").and_then(|v| v.split_once('.')) else {
+        return Err(invalid());
+    };
+    let digit = |s: &str| s.len() == 1 && s.bytes().all(|b| b.is_ascii_digit());
+    if !digit(major) || !digit(minor) {
+        return Err(invalid());
+    }
+    if major != "1" {
+        return Err(ParseError::Version(version.to_string()));
+    }
+    let version = if minor == "0" { Version::Http10 } else { Version::Http11 };
+    Ok((method.parse()?, path.to_string(), version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StatusCode;
+
+    #[test]
+    fn test_parse() {
+        let request = Request::parse(b"POST /a/b.html?x=1 HTTP/1.0\r\nHost: h\r\nX-Empty:\r\nAccept:  */* \r\n\r\n").unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/a/b.html");
+        assert_eq!(request.query_string, "x=1");
+        assert_eq!(request.version, Version::Http10);
+        assert_eq!(request.headers.iter().collect::<Vec<_>>(), [("Host", "h"), ("X-Empty", ""), ("Accept", "*/*")]);
+        assert_eq!(request.header("ACCEPT"), Some("*/*"));
+        assert_eq!(request.header("missing"), None);
+        assert!(!request.keep_alive());
+    }
+
+    #[test]
+    fn test_methods() {
+        for method in ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT", "TRACE"] {
+            let request = Request::parse(format!("{} / HTTP/1.1\r\n\r\n", method).as_bytes()).unwrap();
+            assert_eq!(request.method.to_string(), method);
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let request = Request::parse(b"GET /a/..//?page=2&q=a+b%21&page=3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.raw_path, "/a/..//");
+        assert_eq!(request.query("page").as_deref(), Some("2"));
+        assert_eq!(request.query("q").as_deref(), Some("a b!"));
+        assert_eq!(request.query("missing"), None);
+        assert_eq!(request.query_pairs().count(), 3);
+        assert_eq!(Request::parse(b"GET /? HTTP/1.1\r\n\r\n").unwrap().query_pairs().count(), 0);
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        let request = Request::parse(b"GET HTTP://Example.com/a/../b?x=1 HTTP/1.1\r\nHost: other\r\n\r\n").unwrap();
+        assert_eq!(request.authority.as_deref(), Some("Example.com"));
+        assert_eq!(request.host(), Some("Example.com"));
+        assert_eq!((request.path.as_str(), request.raw_path.as_str(), request.query_string.as_str()), ("/b", "/a/../b", "x=1"));
+        let bare = Request::parse(b"GET https://[::1]:8443?q HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((bare.host(), bare.path.as_str(), bare.query_string.as_str()), (Some("[::1]:8443"), "/", "q"));
+        // without one it's the Host header
+        let origin = Request::parse(b"GET /a HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        assert_eq!((origin.authority.as_deref(), origin.host()), (None, Some("h")));
+        assert!(Request::parse(b"GET http:///a HTTP/1.1\r\n\r\n").is_err());
+        assert!(Request::parse(b"GET http://user@h/a HTTP/1.1\r\n\r\n").is_err());
+        assert_eq!(Request::parse(b"GET ftp://h/a HTTP/1.1\r\n\r\n").unwrap().path, "ftp://h/a");
+    }
+
+    #[test]
+    fn test_body() {
+        let mut request = Request::parse(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n").unwrap();
+        assert!(request.body().is_empty());
+        request.body = b"abc".to_vec();
+        let mut read = String::new();
+        request.body_reader().read_to_string(&mut read).unwrap();
+        assert_eq!(read, "abc");
+    }
+
+    #[test]
+    fn test_form() {
+        let mut request = Request::parse(b"POST / HTTP/1.1\r\nContent-Type: Application/X-WWW-Form-Urlencoded; charset=utf-8\r\n\r\n").unwrap();
+        request.body = b"a=%E2%9C%93&&b".to_vec();
+        assert_eq!(request.form().unwrap(), [("a".to_string(), "\u{2713}".to_string()), ("b".to_string(), String::new())]);
+        request.body = b"a=\xff".to_vec();
+        assert_eq!(request.form(), Err(FormError::Encoding));
+        let json = Request::parse(b"POST / HTTP/1.1\r\nContent-Type: application/json\r\n\r\n").unwrap();
+        assert_eq!(json.form().unwrap_err().status(), StatusCode::UnsupportedMediaType);
+        assert_eq!(Request::parse(b"POST / HTTP/1.1\r\n\r\n").unwrap().form(), Err(FormError::NotForm));
+    }
+
+    #[test]
+    fn test_cookies() {
+        let request = Request::parse(b"GET / HTTP/1.1\r\nCookie: a=1; b=2\r\nCookie: a=3\r\n\r\n").unwrap();
+        assert_eq!(request.cookies().collect::<Vec<_>>(), [("a", "1"), ("b", "2"), ("a", "3")]);
+        assert_eq!(request.cookie("a"), Some("1"));
+        assert_eq!(request.cookie("c"), None);
+    }
+
+    #[test]
+    fn test_folded_headers() {
+        let request = Request::parse(b"GET / HTTP/1.1\r\nX-Long: one\r\n  two\r\n\tthree\r\n\r\n").unwrap();
+        assert_eq!(request.header("x-long"), Some("one two three"));
+        assert_eq!(Request::parse(b"GET / HTTP/1.1\r\n folded\r\n\r\n"), Err(ParseError::Header(" folded".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_heads() {
+        for head in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET  / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1 extra\r\n\r\n",
+            b"G(T / HTTP/1.1\r\n\r\n",
+            b"GET / FTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.10\r\n\r\n",
+        ] {
+            assert!(matches!(Request::parse(head), Err(ParseError::RequestLine(_))), "{:?}", String::from_utf8_lossy(head));
+        }
+        assert!(matches!(Request::parse(b"GET /a%00 HTTP/1.1\r\n\r\n"), Err(ParseError::Path(_))));
+        assert!(matches!(Request::parse(b"GET /%c3%28 HTTP/1.1\r\n\r\n"), Err(ParseError::Path(_))));
+        assert_eq!(Request::parse(b"GET / HTTP/2.0\r\n\r\n"), Err(ParseError::Version("HTTP/2.0".to_string())));
+        assert_eq!(Request::parse(b"BREW / HTTP/1.1\r\n\r\n"), Err(ParseError::UnknownMethod("BREW".to_string())));
+        assert_eq!(Request::parse(b"get / HTTP/1.1\r\n\r\n"), Err(ParseError::UnknownMethod("get".to_string())));
+        assert!(matches!(Request::parse(b"GET / HTTP/1.1\r\nHost : h\r\n\r\n"), Err(ParseError::Header(_))));
+        assert!(matches!(Request::parse(b"GET / HTTP/1.1\r\nno colon\r\n\r\n"), Err(ParseError::Header(_))));
+        assert_eq!(Request::parse(b"GET / HTTP/1.1\r\n"), Err(ParseError::Incomplete));
+        for value in [&b"a\0b"[..], b"a\rb", b"\x7f"] {
+            let head = [&b"GET / HTTP/1.1\r\nX-Bad: "[..], value, b"\r\n\r\n"].concat();
+            assert_eq!(Request::parse(&head), Err(ParseError::HeaderValue("X-Bad".to_string())));
+            // or on a line folded onto it
+            let head = [&b"GET / HTTP/1.1\r\nX-Bad: a\r\n "[..], value, b"\r\n\r\n"].concat();
+            assert_eq!(Request::parse(&head), Err(ParseError::HeaderValue("X-Bad".to_string())));
+        }
+    }
+
+    #[test]
+    fn test_bare_lf() {
+        let head = b"GET / HTTP/1.1\nHost: h\r\nX: y\n\n";
+        assert_eq!(head_len(head), Some(head.len()));
+        assert_eq!(Request::parse(head).unwrap().header("x"), Some("y"));
+        assert_eq!(head_len(b"GET / HTTP/1.1\r\n\r\nGET"), Some(18));
+        assert_eq!(head_len(b"GET / HTTP/1.1\n\r\n"), Some(17));
+        assert_eq!(head_len(b"GET / HTTP/1.1\r\nHost: h\r\n"), None);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let parse = |head: &[u8]| Request::parse(head).unwrap().keep_alive();
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!parse(b"GET / HTTP/1.1\r\nConnection: foo, Close\r\n\r\n"));
+        assert!(parse(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    time::SystemTime,
+};
+
+use crate::{
+    date,
+    headers::{is_token, is_valid_value},
+    ChunkedWriter, Cookie, HeaderMap, StatusCode, Version,
+};
+
+/// A response to write back to the client
+///
+/// ```
+/// use webserver::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::Ok).with_header("Content-Type", "text/plain").with_body("hi");
+/// let mut written = Vec::new();
+/// response.write_to(&mut written).unwrap();
+/// let written = String::from_utf8(written).unwrap();
+/// assert!(written.starts_with("HTTP/1.1 200 OK\r\nDate: "));
+/// assert!(written.ends_with(" GMT\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi"));
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    /// What the status line claims, HTTP/1.1 unless the request was 1.0 or 2. A 1.0 client gets
+    /// a Body::Reader without a Content-Length as the rest of the connection, not chunked;
+    /// HTTP/2 has no status line, and frames the body itself
+    pub version: Version,
+    /// Written with its canonical reason phrase, "404 Not Found"
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Body,
+}
+
+// How much of a Body::Reader is read and written at a time
+const CHUNK: usize = 16 * 1024;
+
+/// What a Response sends after its head
+///
+/// ```
+/// use std::io::Cursor;
+/// use webserver::{Response, StatusCode};
+///
+/// // a length up front goes out as Content-Length, otherwise the body is sent chunked
+/// let response = Response::new(StatusCode::Ok).with_reader(Cursor::new(vec![b'x'; 100_000]), Some(100_000));
+/// let mut written = Vec::new();
+/// response.write_to(&mut written).unwrap();
+/// assert!(String::from_utf8_lossy(&written).contains("\r\nContent-Length: 100000\r\n\r\nxxx"));
+///
+/// let response = Response::new(StatusCode::Ok).with_reader(Cursor::new("streamed"), None);
+/// let mut written = Vec::new();
+/// response.write_to(&mut written).unwrap();
+/// assert!(String::from_utf8(written).unwrap().ends_with("\r\nTransfer-Encoding: chunked\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+///
+/// // a reader that runs out early is an error, the connection can't be trusted after it
+/// let response = Response::new(StatusCode::Ok).with_reader(Cursor::new("short"), Some(10));
+/// assert!(response.write_to(&mut Vec::new()).is_err());
+/// ```
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Read to the end while it's written out, 16KB at a time, so a big file or generated
+    /// content never has to be in memory all at once
+    Reader(Box<dyn Read + Send>),
+}
+
+impl Body {
+    /// The body if it's in memory, None for a reader
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Reader(_) => None,
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::Bytes(Vec::new())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&String::from_utf8_lossy(bytes)).finish(),
+            Body::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+/// The kinds of redirect Response::redirect makes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Redirect {
+    /// 301, for good; clients may turn a POST into a GET on the way
+    MovedPermanently,
+    /// 302, for now; clients may turn a POST into a GET on the way
+    Found,
+    /// 303, fetch the other page with a GET, e.g. after a form POST
+    SeeOther,
+    /// 307, for now, with the same method and body
+    TemporaryRedirect,
+    /// 308, for good, with the same method and body
+    PermanentRedirect,
+}
+
+impl Redirect {
+    fn status(self) -> StatusCode {
+        match self {
+            Redirect::MovedPermanently => StatusCode::MovedPermanently,
+            Redirect::Found => StatusCode::Found,
+            Redirect::SeeOther => StatusCode::SeeOther,
+            Redirect::TemporaryRedirect => StatusCode::TemporaryRedirect,
+            Redirect::PermanentRedirect => StatusCode::PermanentRedirect,
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response { version: Version::Http11, status, headers: HeaderMap::new(), body: Body::default() }
+    }
+
+    /// A redirect to 'location', with a small HTML page linking there for clients that don't follow it
+    /// Spaces, control characters and anything past ASCII in 'location' are percent-encoded,
+    /// so a location made from user input can't end the header and start another
+    ///
+    /// ```
+    /// use webserver::{Redirect, Response};
+    ///
+    /// let response = Response::redirect("/new?a=1&b=2", Redirect::MovedPermanently);
+    /// assert_eq!(response.status, webserver::StatusCode::MovedPermanently);
+    /// assert_eq!(response.headers.get("location"), Some("/new?a=1&b=2"));
+    /// assert!(String::from_utf8_lossy(response.body.as_bytes().unwrap()).contains(r#"<a href="/new?a=1&amp;b=2">"#));
+    ///
+    /// let sneaky = Response::redirect("/café\r\nSet-Cookie: a=1", Redirect::Found);
+    /// assert_eq!(sneaky.headers.get("location"), Some("/caf%C3%A9%0D%0ASet-Cookie:%20a=1"));
+    /// ```
+    pub fn redirect(location: &str, kind: Redirect) -> Response {
+        let location = escape_location(location);
+        let href = escape_html(&location);
+        let body = format!("<!DOCTYPE html>\n<html><body><p>Moved to <a href=\"{}\">{}</a>.</p></body></html>\n", href, href);
+        Response::new(kind.status())
+            .with_header("Location", location.as_str())
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// Add a header, after any others with the same name
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Add a Set-Cookie header for 'cookie'
+    pub fn with_cookie(self, cookie: &Cookie) -> Response {
+        self.with_header("Set-Cookie", cookie.to_string())
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Stream the body from 'reader' rather than holding it in memory. With its length it
+    /// goes out with a Content-Length, without it chunked
+    pub fn with_reader(mut self, reader: impl Read + Send + 'static, len: Option<u64>) -> Response {
+        if let Some(len) = len {
+            self.headers.insert("Content-Length", len.to_string());
+        }
+        self.body = Body::Reader(Box::new(reader));
+        self
+    }
+
+    /// Write the response, with a Content-Length for the body unless a header already gives one
+    /// or it's a 1xx, 204 or 304, which never have a body, and the current Date unless there's one.
+    /// A Body::Reader without a Content-Length header is sent chunked, or to an HTTP/1.0
+    /// client until it ends, when the connection has to close (see Response::close_delimited)
+    ///
+    /// ```
+    /// use webserver::{Response, StatusCode};
+    ///
+    /// let injected = Response::new(StatusCode::Ok).with_header("X-Name", "a\r\nSet-Cookie: admin=1");
+    /// let mut written = Vec::new();
+    /// assert_eq!(injected.write_to(&mut written).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    /// assert!(written.is_empty());
+    /// ```
+    ///
+    /// # Errors
+    /// Returns the error writing to 'stream', or reading a Body::Reader. A reader that ends
+    /// short of its Content-Length is an UnexpectedEof, the client can't be told any other way
+    /// A header whose name isn't a token or whose value has a CR, LF or other control character
+    /// in it is an InvalidInput, and nothing is written
+    pub fn write_to(mut self, stream: &mut impl Write) -> io::Result<()> {
+        let chunked = self.chunked();
+        let close_delimited = self.close_delimited();
+        let has_body = self.has_body();
+        let mut out = self.head(chunked)?;
+        match &mut self.body {
+            Body::Bytes(bytes) => {
+                // one write for the lot, so small responses go out in a single packet
+                if has_body {
+                    out.extend_from_slice(bytes);
+                }
+                stream.write_all(&out)?;
+            }
+            Body::Reader(reader) => {
+                stream.write_all(&out)?;
+                if chunked {
+                    let mut chunks = ChunkedWriter::new(&mut *stream, Vec::new());
+                    copy(reader, &mut chunks, u64::MAX)?;
+                    chunks.finish()?;
+                } else if close_delimited {
+                    copy(reader, stream, u64::MAX)?;
+                } else if has_body {
+                    let len = self.headers.get("content-length").and_then(|len| len.trim().parse().ok()).unwrap_or(0);
+                    if copy(reader, stream, len)? < len {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "body ended before its Content-Length"));
+                    }
+                }
+            }
+        }
+        stream.flush()
+    }
+
+    /// Write the status line and headers but not the body, as the answer to a HEAD request:
+    /// Content-Length is still the length the body would have been
+    ///
+    /// # Errors
+    /// Returns the error writing to 'stream', or InvalidInput for a header that can't be sent
+    pub fn write_head(&self, stream: &mut impl Write) -> io::Result<()> {
+        stream.write_all(&self.head(self.chunked())?)?;
+        stream.flush()
+    }
+
+    /// Write the status line and headers with Transfer-Encoding: chunked, for a body whose
+    /// length isn't known up front. The body goes to the returned writer rather than coming
+    /// from self.body, and any Content-Length header is left out. Fields named in a Trailer
+    /// header can follow the body, see ChunkedWriter::finish_with_trailers
+    ///
+    /// # Errors
+    /// Returns the error writing to 'stream', or InvalidInput for a header that can't be sent
+    pub fn write_chunked<W: Write>(&self, mut stream: W) -> io::Result<ChunkedWriter<W>> {
+        stream.write_all(&self.head(true)?)?;
+        let declared = self.headers.get_all("trailer").flat_map(|value| value.split(',')).map(|name| name.trim().to_ascii_lowercase());
+        Ok(ChunkedWriter::new(stream, declared.filter(|name| !name.is_empty()).collect()))
+    }
+
+    // 1xx, 204 and 304 responses can't have a body. The first two can't have a Content-Length
+    // either, a 304's would be the full response's, which we don't know (RFC 9110 8.6)
+    pub(crate) fn has_body(&self) -> bool {
+        !self.status.is_informational() && self.status != StatusCode::NoContent && self.status != StatusCode::NotModified
+    }
+
+    /// Whether the body's end can only be told by closing the connection: a Body::Reader with
+    /// no Content-Length, going to an HTTP/1.0 client that doesn't know chunked encoding
+    pub fn close_delimited(&self) -> bool {
+        self.version == Version::Http10 && self.unsized_body()
+    }
+
+    // A reader with no Content-Length to go by has its length given by the chunks, for HTTP/1.1
+    fn chunked(&self) -> bool {
+        self.version == Version::Http11 && self.unsized_body()
+    }
+
+    fn unsized_body(&self) -> bool {
+        matches!(self.body, Body::Reader(_)) && !self.headers.contains("content-length") && self.has_body()
+    }
+
+    /// The name of the first header that can't be sent as it is: one whose name isn't a token,
+    /// or whose value has a control character other than tab in it
+    pub fn invalid_header(&self) -> Option<&str> {
+        self.headers.iter().find(|(name, value)| !is_token(name) || !is_valid_value(value)).map(|(name, _)| name)
+    }
+
+    // The status line and headers, with the body's length given by Transfer-Encoding when
+    // 'chunked' and otherwise by Content-Length, unless the headers already have one
+    // An InvalidInput error for any header that isn't fit to send
+    fn head(&self, chunked: bool) -> io::Result<Vec<u8>> {
+        if let Some(name) = self.invalid_header() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid header {:?}", name)));
+        }
+        let mut out = format!("{} {}\r\n", self.version, self.status).into_bytes();
+        if !self.headers.contains("date") {
+            out.extend_from_slice(format!("Date: {}\r\n", date::format(SystemTime::now())).as_bytes());
+        }
+        for (name, value) in &self.headers {
+            let framing = name.eq_ignore_ascii_case("content-length") || name.eq_ignore_ascii_case("transfer-encoding");
+            if chunked && framing {
+                continue;
+            }
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if chunked {
+            out.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+        } else if let Body::Bytes(bytes) = &self.body
+            && !self.headers.contains("content-length")
+            && self.has_body()
+        {
+            out.extend_from_slice(format!("Content-Length: {}\r\n", bytes.len()).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        Ok(out)
+    }
+}
+
+// Copy up to 'limit' bytes from 'reader' to 'stream' a CHUNK at a time, returning how many there were
+fn copy(reader: &mut dyn Read, stream: &mut impl Write, limit: u64) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK];
+    let mut copied = 0;
+    while copied < limit {
+        let want = (limit - copied).min(CHUNK as u64) as usize;
+        let n = match reader.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        stream.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+// 'location' with every byte that has no business in a URI percent-encoded: spaces, control
+// characters and the UTF-8 of anything past ASCII. Existing escapes are left as they are
+fn escape_location(location: &str) -> String {
+    let mut escaped = String::with_capacity(location.len());
+    for b in location.bytes() {
+        if b.is_ascii_graphic() {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+// 's' safe to put in HTML text or a quoted attribute
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
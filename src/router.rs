@@ -0,0 +1,156 @@
+// Routing requests to handlers by method and path
+use std::{fmt, sync::Arc};
+
+use crate::{Method, Request, Response, StatusCode};
+
+/// Answers the requests a Router sends it
+pub type RouteHandler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Handlers by method and path, for ServerBuilder::router
+///
+/// Paths match Request::path exactly, after it's been decoded and normalized, so "/a" is
+/// neither "/a/" nor "/a/b". A HEAD request goes to the GET handler unless the path has a HEAD
+/// one of its own, and OPTIONS gets the path's methods in an Allow header unless it has an
+/// OPTIONS handler. A method the path has no handler for gets a 405 saying which it has
+///
+/// ```
+/// use webserver::{Method, Request, Response, Router, StatusCode};
+///
+/// let mut router = Router::new();
+/// router.get("/", |_| Response::new(StatusCode::Ok).with_body("home"));
+/// router.post("/submit", |request| Response::new(StatusCode::Created).with_body(request.body.clone()));
+///
+/// let request = Request::parse(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+/// assert_eq!(router.dispatch(&request).unwrap().body.as_bytes(), Some(&b"home"[..]));
+/// let request = Request::parse(b"GET /submit HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+/// assert_eq!(router.dispatch(&request).unwrap().status, StatusCode::MethodNotAllowed);
+/// let request = Request::parse(b"GET /elsewhere HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+/// assert!(router.dispatch(&request).is_none());
+/// ```
+#[derive(Clone, Default)]
+pub struct Router {
+    // in the order they were added, a later handler for the same method and path replaces it
+    routes: Vec<(Method, String, RouteHandler)>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Have 'handler' answer 'method' requests for 'path', in place of any handler it had
+    pub fn route<H>(&mut self, method: Method, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        let path = path.into();
+        self.routes.retain(|(ours, route, _)| *ours != method || *route != path);
+        self.routes.push((method, path, Arc::new(handler)));
+        self
+    }
+
+    pub fn get<H>(&mut self, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post<H>(&mut self, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    pub fn put<H>(&mut self, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Put, path, handler)
+    }
+
+    pub fn patch<H>(&mut self, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Patch, path, handler)
+    }
+
+    pub fn delete<H>(&mut self, path: impl Into<String>, handler: H) -> &mut Self
+    where H: Fn(&Request) -> Response + Send + Sync + 'static
+    {
+        self.route(Method::Delete, path, handler)
+    }
+
+    /// The response for 'request' from the handler its method and path pick, or the 405 or
+    /// OPTIONS answer for a path with handlers for other methods. None when nothing is
+    /// routed to the path at all, for the caller to answer some other way
+    pub fn dispatch(&self, request: &Request) -> Option<Response> {
+        let handler = |method: Method| self.routes.iter().find(|(ours, path, _)| *ours == method && *path == request.path);
+        if let Some((_, _, handler)) = handler(request.method) {
+            return Some(handler(request));
+        }
+        if request.method == Method::Head
+            && let Some((_, _, handler)) = handler(Method::Get)
+        {
+            return Some(handler(request));
+        }
+        let allow = self.allow(&request.path)?;
+        let status = if request.method == Method::Options { StatusCode::NoContent } else { StatusCode::MethodNotAllowed };
+        Some(Response::new(status).with_header("Allow", allow))
+    }
+
+    // The methods 'path' answers to, for an Allow header, None if it has no handlers
+    fn allow(&self, path: &str) -> Option<String> {
+        let mut methods: Vec<Method> = self.routes.iter().filter(|(_, route, _)| route == path).map(|(method, _, _)| *method).collect();
+        if methods.is_empty() {
+            return None;
+        }
+        if methods.contains(&Method::Get) {
+            methods.push(Method::Head);
+        }
+        methods.push(Method::Options);
+        let mut names: Vec<&str> = Vec::new();
+        for method in methods {
+            if !names.contains(&method.as_str()) {
+                names.push(method.as_str());
+            }
+        }
+        Some(names.join(", "))
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.routes.iter().map(|(method, path, _)| (method, path))).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(line: &str) -> Request {
+        Request::parse(format!("{}\r\nHost: h\r\n\r\n", line).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut router = Router::new();
+        router
+            .get("/a", |_| Response::new(StatusCode::Ok).with_body("a"))
+            .post("/a", |request| Response::new(StatusCode::Created).with_body(request.path.clone()))
+            .delete("/b", |_| Response::new(StatusCode::NoContent));
+        let body = |line: &str| router.dispatch(&request(line)).map(|response| (response.status, response.body.as_bytes().unwrap_or_default().to_vec()));
+        assert_eq!(body("GET /a HTTP/1.1"), Some((StatusCode::Ok, b"a".to_vec())));
+        assert_eq!(body("HEAD /a HTTP/1.1"), Some((StatusCode::Ok, b"a".to_vec())));
+        assert_eq!(body("POST /./a HTTP/1.1"), Some((StatusCode::Created, b"/a".to_vec())));
+        assert_eq!(body("GET /a/ HTTP/1.1"), None);
+
+        let allow = |line: &str| router.dispatch(&request(line)).map(|response| (response.status, response.headers.get("allow").map(str::to_string)));
+        assert_eq!(allow("PUT /a HTTP/1.1"), Some((StatusCode::MethodNotAllowed, Some("GET, POST, HEAD, OPTIONS".to_string()))));
+        assert_eq!(allow("GET /b HTTP/1.1"), Some((StatusCode::MethodNotAllowed, Some("DELETE, OPTIONS".to_string()))));
+        assert_eq!(allow("OPTIONS /b HTTP/1.1"), Some((StatusCode::NoContent, Some("DELETE, OPTIONS".to_string()))));
+
+        // a second handler for a route takes its place
+        router.get("/a", |_| Response::new(StatusCode::Accepted));
+        assert_eq!(router.dispatch(&request("GET /a HTTP/1.1")).unwrap().status, StatusCode::Accepted);
+        assert_eq!(format!("{:?}", router), "[(Post, \"/a\"), (Delete, \"/b\"), (Get, \"/a\")]");
+    }
+}
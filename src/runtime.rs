@@ -0,0 +1,532 @@
+// Serving on a tokio runtime (tokio feature): the accept loops are tasks, and so is every
+// connection, so one waiting for its next request costs a little memory rather than a worker.
+// Blocking work still goes to the ThreadPool, the stock pages included
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    task::{Context, Poll},
+    thread,
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::oneshot,
+    time,
+};
+
+use crate::{
+    drain::TrackedConnection,
+    handler::{has_head, refuse, resume_site, Resume},
+    listener::{Connection, ConnectionInfo, Listener},
+    logging::log,
+    server::{LiveConnection, ACCEPT_POLL},
+    JobError, Server, StatusCode, ThreadPool, VirtualHosts,
+};
+
+/// A client connection as Server::serve_async hands it over
+pub enum AsyncConnection {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl AsyncConnection {
+    // Register a blocking Connection with the runtime, only raw sockets can be
+    fn from_std(stream: Connection) -> io::Result<AsyncConnection> {
+        stream.set_nonblocking(true)?;
+        match stream {
+            Connection::Tcp(stream) => Ok(AsyncConnection::Tcp(tokio::net::TcpStream::from_std(stream)?)),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Ok(AsyncConnection::Unix(tokio::net::UnixStream::from_std(stream)?)),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "only TCP and Unix sockets can be served asynchronously")),
+        }
+    }
+
+    // Back to a blocking Connection, for a worker to serve
+    fn into_std(self) -> io::Result<Connection> {
+        let stream = match self {
+            AsyncConnection::Tcp(stream) => Connection::Tcp(stream.into_std()?),
+            #[cfg(unix)]
+            AsyncConnection::Unix(stream) => Connection::Unix(stream.into_std()?),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+
+    /// The client's address, None for Unix socket clients
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            AsyncConnection::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            AsyncConnection::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for AsyncConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncConnection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AsyncConnection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncConnection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AsyncConnection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncConnection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AsyncConnection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncConnection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AsyncConnection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// One of the server's listeners, registered with the runtime
+enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl AsyncListener {
+    // A second handle on 'listener', the Server keeps the first for shutdown and upgrades
+    fn new(listener: &Listener) -> io::Result<AsyncListener> {
+        match listener {
+            Listener::Tcp(listener) => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                Ok(AsyncListener::Unix(tokio::net::UnixListener::from_std(listener)?))
+            }
+        }
+    }
+
+    // The next client, as the blocking Connection the sync accept loop would have got
+    async fn accept(&self) -> io::Result<Connection> {
+        let stream = match self {
+            AsyncListener::Tcp(listener) => Connection::Tcp(listener.accept().await?.0.into_std()?),
+            #[cfg(unix)]
+            AsyncListener::Unix(listener) => Connection::Unix(listener.accept().await?.0.into_std()?),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+impl ThreadPool {
+    /// Run the blocking 'f' on the pool from async code, without holding up the runtime
+    /// Unlike 'execute' it never waits for room: with the queue full the future resolves to
+    /// Err(JobError::Rejected) straight away. Err(JobError::Lost) if 'f' panics or the pool
+    /// shuts down before running it
+    ///
+    /// ```
+    /// let pool = webserver::ThreadPool::new(2);
+    /// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// assert_eq!(runtime.block_on(pool.execute_async(|| 20 + 22)), Ok(42));
+    /// ```
+    pub fn execute_async<F, T>(&self, f: F) -> impl Future<Output = Result<T, JobError>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let queued = match self.try_execute(move || drop(sender.send(f()))) {
+            Ok(_) => Ok(()),
+            Err(_) if self.is_shut_down() => Err(JobError::Lost),
+            Err(_) => Err(JobError::Rejected),
+        };
+        async move {
+            queued?;
+            receiver.await.map_err(|_| JobError::Lost)
+        }
+    }
+}
+
+impl Server {
+    /// serve_with_info for async handlers: accept on the tokio runtime this is awaited on,
+    /// and run each connection's handler as a task of its own until a ShutdownHandle is used or
+    /// max_requests connections have been handed to it, then wait for them like serve does
+    /// Handlers should hand anything blocking to the pool, see Server::pool and ThreadPool::execute_async
+    ///
+    /// Only plain TCP and Unix socket connections can be served this way: with TLS, the PROXY
+    /// protocol or wrap_connections set it logs an error and returns
+    ///
+    /// ```
+    /// use tokio::io::AsyncWriteExt;
+    ///
+    /// let server = webserver::Server::bind("127.0.0.1:0").unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let shutdown = server.shutdown_handle().unwrap();
+    /// let runtime = tokio::runtime::Runtime::new().unwrap();
+    /// let serving = runtime.spawn(server.serve_async(|mut conn, _| async move {
+    ///     conn.write_all(b"hi").await.unwrap();
+    /// }));
+    /// let mut reply = String::new();
+    /// std::io::Read::read_to_string(&mut std::net::TcpStream::connect(addr).unwrap(), &mut reply).unwrap();
+    /// assert_eq!(reply, "hi");
+    /// shutdown.shutdown();
+    /// runtime.block_on(serving).unwrap();
+    /// ```
+    pub async fn serve_async<H, F>(self, handler: H)
+    where
+        H: Fn(AsyncConnection, ConnectionInfo) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        if let Some(what) = self.layered() {
+            log!(Error, "serve_async can't serve connections over {}", what);
+            return;
+        }
+        self.run_async(move |server, stream, info, live| {
+            // each connection counts as a request, as with serve_with_info
+            server.open.count_request();
+            let served = handler(stream, info);
+            async move {
+                let _live = live;
+                served.await;
+            }
+        })
+        .await;
+    }
+
+    /// serve_static on a tokio runtime: connections wait for their next request as tasks, and
+    /// each request that has all arrived is served on the pool
+    /// With TLS, the PROXY protocol or wrap_connections set connections can't be waited on
+    /// like that, they're served by serve_static on the pool instead
+    pub async fn serve_static_async(self) {
+        if let Some(what) = self.layered() {
+            log!(Warn, "Connections over {} can't wait on the runtime, serving them on workers", what);
+            if tokio::task::spawn_blocking(move || self.serve_static()).await.is_err() {
+                log!(Error, "The accept loop panicked");
+            }
+            return;
+        }
+        let hosts = Arc::new(self.site_hosts());
+        self.run_async(move |server, stream, info, live| serve_pages(server, Arc::clone(&hosts), stream, info.id, live)).await;
+    }
+
+    // What sits between the socket and HTTP, which the runtime can't wait on
+    fn layered(&self) -> Option<&'static str> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Some("TLS");
+        }
+        if self.proxy_protocol {
+            return Some("the PROXY protocol");
+        }
+        self.wrapper.is_some().then_some("a wrap_connections wrapper")
+    }
+
+    // Accept on every listener until shutdown, spawning 'task' for each connection, then
+    // wind down like run does
+    async fn run_async<T, F>(self, task: T)
+    where
+        T: Fn(Arc<Server>, AsyncConnection, ConnectionInfo, LiveConnection) -> F + Send + Sync + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let server = Arc::new(self);
+        let task = Arc::new(task);
+        let accepted = Arc::new(AtomicUsize::new(0));
+        server.limit_requests();
+        // the admin socket is blocking, it gets a thread as it does with serve
+        let admin = server.admin.is_some().then(|| {
+            let (server, accepted) = (Arc::clone(&server), Arc::clone(&accepted));
+            thread::Builder::new().name("web-admin".to_string()).spawn(move || {
+                if let Some(admin) = &server.admin {
+                    server.admin_loop(admin, &accepted, &server.shutdown_handle());
+                }
+            })
+        });
+        let mut loops = Vec::new();
+        for listener in &server.listeners {
+            match AsyncListener::new(listener) {
+                Ok(async_listener) => {
+                    if let Ok(addr) = listener.local_addr() {
+                        log!(Info, "Listening on {}", addr);
+                    }
+                    let accepting = accept_loop(Arc::clone(&server), async_listener, Arc::clone(&accepted), Arc::clone(&task));
+                    loops.push(tokio::spawn(accepting));
+                }
+                Err(e) => log!(Error, "Failed to accept on {:?} asynchronously: {}", listener.local_addr(), e),
+            }
+        }
+        for accepting in loops {
+            if accepting.await.is_err() {
+                log!(Error, "An accept loop panicked");
+            }
+        }
+        let winding = tokio::task::spawn_blocking(move || {
+            match admin {
+                Some(Ok(admin)) => drop(admin.join()),
+                Some(Err(e)) => log!(Error, "Failed to start the admin thread: {}", e),
+                None => {}
+            }
+            server.wind_down();
+        });
+        if winding.await.is_err() {
+            log!(Error, "Shutting down panicked");
+        }
+    }
+}
+
+// Accept on 'listener' and spawn a 'task' per connection until shutdown
+async fn accept_loop<T, F>(server: Arc<Server>, listener: AsyncListener, accepted: Arc<AtomicUsize>, task: Arc<T>)
+where
+    T: Fn(Arc<Server>, AsyncConnection, ConnectionInfo, LiveConnection) -> F + Send + Sync + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    loop {
+        // the shutdown handle's wake-up connection only reaches one of several acceptors on a port
+        let stream = match time::timeout(ACCEPT_POLL, listener.accept()).await {
+            Ok(stream) => stream,
+            Err(_) if server.stop.load(Ordering::SeqCst) => break,
+            Err(_) => continue,
+        };
+        if server.stop.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let id = accepted.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+                if let Some((stream, live)) = server.admit(stream, id) {
+                    let info = ConnectionInfo { id, peer_addr: stream.peer_addr(), local_addr: stream.local_addr(), tls: None, peer_identity: None };
+                    match AsyncConnection::from_std(stream) {
+                        Ok(stream) => drop(tokio::spawn(task(Arc::clone(&server), stream, info, live))),
+                        Err(e) => log!(Warn, "Dropping connection {}: {}", id, e),
+                    }
+                }
+            }
+            Err(e) => log!(Error, "Error accepting connection: {}", e),
+        }
+    }
+}
+
+// The stock pages for one connection: wait for each request here, then serve it on the pool,
+// which hands the connection back between requests
+async fn serve_pages(server: Arc<Server>, hosts: Arc<VirtualHosts>, mut stream: AsyncConnection, id: u64, live: LiveConnection) {
+    let _live = live;
+    let tracked = server.open.tracked(id);
+    let mut resume = Resume::default();
+    loop {
+        match wait_for_request(&server, &mut stream, &mut resume, &tracked).await {
+            Ok(true) => {}
+            // the client hung up between requests, or shutdown closed an idle connection
+            Ok(false) => return,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                // an idle keep-alive connection just gets closed, a client we're still waiting on gets told
+                if resume.served == 0 || !resume.pending.is_empty() {
+                    log!(Debug, "Connection {} didn't finish its request in time", id);
+                    tracked.count_request();
+                    let mut page = Vec::new();
+                    refuse(&mut page, StatusCode::RequestTimeout, hosts.default_root(), &server.http);
+                    let _ = stream.write_all(&page).await;
+                }
+                return;
+            }
+            Err(e) => {
+                log!(Debug, "Dropping connection {}: {}", id, e);
+                return;
+            }
+        }
+        let stream_std = match stream.into_std() {
+            Ok(stream) => stream,
+            Err(e) => {
+                log!(Warn, "Dropping connection {}: {}", id, e);
+                return;
+            }
+        };
+        let overflow = stream_std.try_clone();
+        let (sender, receiver) = oneshot::channel();
+        let job = {
+            let (server, hosts) = (Arc::clone(&server), Arc::clone(&hosts));
+            let resume = std::mem::take(&mut resume);
+            move || drop(sender.send(resume_site(stream_std, &hosts, &server.http, &server.open.tracked(id), resume)))
+        };
+        if server.pool.try_execute(job).is_err() {
+            log!(Warn, "Queue full, turning a client away");
+            match overflow {
+                Ok(stream) => server.turn_away(stream),
+                Err(e) => log!(Error, "Queue full and failed to clone stream: {}", e),
+            }
+            return;
+        }
+        // None once the worker is done with the connection
+        let Ok(Some((stream_std, next))) = receiver.await else { return };
+        stream = match AsyncConnection::from_std(stream_std) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log!(Warn, "Dropping connection {}: {}", id, e);
+                return;
+            }
+        };
+        resume = next;
+    }
+}
+
+// Read until 'resume' holds a whole request head, or more than one can be, for a worker to take
+// over. false if the client closed the connection before sending any of it. TimedOut after the
+// read timeout passes without a byte, or the head deadline from the request's first one
+async fn wait_for_request(server: &Server, stream: &mut AsyncConnection, resume: &mut Resume, tracked: &TrackedConnection) -> io::Result<bool> {
+    let options = &server.http;
+    let mut started = (!resume.pending.is_empty()).then(Instant::now);
+    let mut buf = vec![0; options.read_buffer];
+    while !has_head(&resume.pending) && resume.pending.len() <= options.max_head_len {
+        // nothing's lost closing a keep-alive connection that's waiting, so shutdown can
+        let idle = resume.served > 0 && resume.pending.is_empty();
+        if idle && !tracked.set_idle(true) {
+            return Ok(false);
+        }
+        let deadline = started.map(|started| options.head_deadline.saturating_sub(started.elapsed()));
+        let wait = match (server.read_timeout, deadline) {
+            (Some(timeout), Some(deadline)) => Some(timeout.min(deadline)),
+            (timeout, deadline) => timeout.or(deadline),
+        };
+        let read = match wait {
+            Some(wait) => time::timeout(wait, stream.read(&mut buf)).await.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => stream.read(&mut buf).await,
+        };
+        if idle {
+            tracked.set_idle(false);
+        }
+        match read? {
+            // part way through a request the worker answers it with a 400
+            0 => return Ok(!resume.pending.is_empty()),
+            n => resume.pending.extend_from_slice(&buf[..n]),
+        }
+        started.get_or_insert_with(Instant::now);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Server;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        time::Duration,
+    };
+
+    fn get(stream: &mut TcpStream, body: &str) -> String {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(body.as_bytes()) {
+            let mut buf = [0; 256];
+            let n = stream.read(&mut buf).unwrap();
+            assert_ne!(n, 0, "closed after {:?}", String::from_utf8_lossy(&reply));
+            reply.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_idle_connections_wait_on_the_runtime() {
+        let root = std::env::temp_dir().join(format!("webserver-tokio-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hi").unwrap();
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .doc_root(&root)
+            .read_timeout(Some(Duration::from_millis(500)))
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let serving = runtime.spawn(server.serve_static_async());
+
+        // with one worker, the second connection only gets served if the first gave it up
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        for _ in 0..3 {
+            assert!(get(&mut first, "hi").contains("Connection: keep-alive"));
+            assert!(get(&mut second, "hi").starts_with("HTTP/1.1 200"));
+        }
+        // a request that arrives in pieces is only handed over once it's all there
+        first.write_all(b"GET / HT").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(get(&mut second, "hi").starts_with("HTTP/1.1 200"));
+        first.write_all(b"TP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = [0; 15];
+        first.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"HTTP/1.1 200 OK");
+
+        // quiet connections are closed after the read timeout, half a request gets a 408
+        let mut quiet = TcpStream::connect(addr).unwrap();
+        get(&mut quiet, "hi");
+        assert_eq!(quiet.read(&mut [0; 16]).unwrap(), 0);
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut reply = String::new();
+        slow.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 408"), "{}", reply);
+
+        // shutdown closes the idle ones straight away
+        shutdown.shutdown();
+        runtime.block_on(serving).unwrap();
+        let mut rest = Vec::new();
+        first.read_to_end(&mut rest).unwrap();
+        assert!(rest.ends_with(b"hi"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_async_handlers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).max_requests(2).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let pool = server.pool();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let serving = runtime.spawn(server.serve_async(move |mut conn, info| {
+            let pool = std::sync::Arc::clone(&pool);
+            async move {
+                let mut name = [0; 5];
+                conn.read_exact(&mut name).await.unwrap();
+                // the blocking part goes to the pool, the runtime carries on meanwhile
+                let shouted = pool.execute_async(move || name.to_ascii_uppercase()).await.unwrap();
+                conn.write_all(format!("{} {}", info.id, String::from_utf8_lossy(&shouted)).as_bytes()).await.unwrap();
+            }
+        }));
+
+        // the first connection waiting doesn't hold anything up for the second
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.write_all(b"world").unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "2 WORLD");
+        first.write_all(b"hello").unwrap();
+        reply.clear();
+        first.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "1 HELLO");
+        runtime.block_on(serving).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::VecDeque,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Condvar, Mutex, MutexGuard, RwLock},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "crossbeam")]
+use crate::shared_queue::{Bell, SharedQueue};
+use crate::{Job, Message, Priority};
+
+// Replaces the single Mutex<Receiver> every worker used to fight over.
+// Each worker owns a queue, new jobs are spread round robin across them and a worker
+// that runs dry steals from the others before going to sleep. Control messages
+// (Terminate etc) are pushed to one worker's queue and are never stolen.
+pub(crate) struct Scheduler {
+    queues: RwLock<Vec<Arc<LocalQueue>>>,
+    cursor: AtomicUsize,
+    // jobs sitting in any queue per priority, lets an idle worker know whether stealing is worth it
+    jobs: [AtomicUsize; Priority::COUNT],
+    sleep_lock: Mutex<()>,
+    wakeup: Condvar,
+    // jobs go through crossbeam channels every worker takes from instead, see SharedQueue
+    #[cfg(feature = "crossbeam")]
+    shared: Option<SharedQueue>,
+}
+
+pub(crate) struct LocalQueue {
+    id: usize,
+    lanes: Mutex<Lanes>,
+    #[cfg(feature = "crossbeam")]
+    bell: Bell,
+}
+
+// One deque per priority, control messages go after every job the worker already has
+#[derive(Default)]
+struct Lanes {
+    jobs: [VecDeque<Job>; Priority::COUNT],
+    control: VecDeque<Message>,
+}
+
+impl Lanes {
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.jobs.iter().all(VecDeque::is_empty)
+    }
+}
+
+impl LocalQueue {
+    fn lock(&self) -> MutexGuard<'_, Lanes> {
+        // a job never runs while a queue is locked so a poisoned lock still holds sane data
+        self.lanes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Scheduler {
+        Scheduler {
+            queues: RwLock::new(Vec::new()),
+            cursor: AtomicUsize::new(0),
+            jobs: Default::default(),
+            sleep_lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            #[cfg(feature = "crossbeam")]
+            shared: None,
+        }
+    }
+
+    /// A scheduler whose jobs all go through one SharedQueue rather than per-worker queues
+    #[cfg(feature = "crossbeam")]
+    pub(crate) fn with_shared_queue() -> Scheduler {
+        Scheduler { shared: Some(SharedQueue::new()), ..Scheduler::new() }
+    }
+
+    /// Get the queue for worker 'id', reusing the existing one when a dead worker is respawned
+    pub(crate) fn register(&self, id: usize) -> Arc<LocalQueue> {
+        let mut queues = self.queues.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(queue) = queues.iter().find(|queue| queue.id == id) {
+            return Arc::clone(queue);
+        }
+        let queue = Arc::new(LocalQueue {
+            id,
+            lanes: Mutex::new(Lanes::default()),
+            #[cfg(feature = "crossbeam")]
+            bell: Bell::new(),
+        });
+        queues.push(Arc::clone(&queue));
+        queue
+    }
+
+    /// Drop worker 'id's queue, any jobs still in it get handed to the other workers
+    pub(crate) fn unregister(&self, id: usize) -> usize {
+        let leftover = {
+            let mut queues = self.queues.write().unwrap_or_else(|e| e.into_inner());
+            let Some(index) = queues.iter().position(|queue| queue.id == id) else { return 0 };
+            let queue = queues.remove(index);
+            let mut lanes = queue.lock();
+            std::mem::take(&mut lanes.jobs)
+        };
+        let mut dropped = 0;
+        for (priority, jobs) in Priority::ALL.into_iter().zip(leftover) {
+            for job in jobs {
+                self.jobs[priority.index()].fetch_sub(1, Ordering::SeqCst);
+                if self.push_job(job, priority).is_err() {
+                    dropped += 1;
+                }
+            }
+        }
+        dropped
+    }
+
+    /// Queue a job on the next worker in line, gives it back if there are no workers at all
+    pub(crate) fn push_job(&self, job: Job, priority: Priority) -> Result<(), Job> {
+        {
+            let queues = self.queues.read().unwrap_or_else(|e| e.into_inner());
+            if queues.is_empty() {
+                return Err(job);
+            }
+            #[cfg(feature = "crossbeam")]
+            if let Some(shared) = &self.shared {
+                shared.push(job, priority);
+                return Ok(());
+            }
+            let index = self.cursor.fetch_add(1, Ordering::Relaxed) % queues.len();
+            queues[index].lock().jobs[priority.index()].push_back(job);
+            self.jobs[priority.index()].fetch_add(1, Ordering::SeqCst);
+        }
+        // any worker can steal it so waking one is enough
+        let _guard = self.sleep_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Queue a batch of jobs, taking each worker's lock once rather than once per job
+    /// Gives them all back if there are no workers at all
+    pub(crate) fn push_jobs(&self, mut jobs: Vec<Job>, priority: Priority) -> Result<(), Vec<Job>> {
+        if jobs.is_empty() {
+            return Ok(());
+        }
+        {
+            let queues = self.queues.read().unwrap_or_else(|e| e.into_inner());
+            if queues.is_empty() {
+                return Err(jobs);
+            }
+            #[cfg(feature = "crossbeam")]
+            if let Some(shared) = &self.shared {
+                jobs.into_iter().for_each(|job| shared.push(job, priority));
+                return Ok(());
+            }
+            let count = jobs.len();
+            let start = self.cursor.fetch_add(count, Ordering::Relaxed);
+            // same spread as pushing one at a time: job i goes to queue (start + i) % len
+            let mut shares: Vec<Vec<Job>> = (0..queues.len()).map(|_| Vec::new()).collect();
+            for (i, job) in jobs.drain(..).enumerate() {
+                shares[(start + i) % queues.len()].push(job);
+            }
+            for (queue, share) in queues.iter().zip(shares) {
+                if !share.is_empty() {
+                    queue.lock().jobs[priority.index()].extend(share);
+                }
+            }
+            self.jobs[priority.index()].fetch_add(count, Ordering::SeqCst);
+        }
+        let _guard = self.sleep_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.wakeup.notify_all();
+        Ok(())
+    }
+
+    /// Queue a control message for one particular worker, false if it has no queue
+    pub(crate) fn push_to(&self, id: usize, message: Message) -> bool {
+        {
+            let queues = self.queues.read().unwrap_or_else(|e| e.into_inner());
+            let Some(queue) = queues.iter().find(|queue| queue.id == id) else { return false };
+            queue.lock().control.push_back(message);
+            #[cfg(feature = "crossbeam")]
+            queue.bell.ring();
+        }
+        // we can't wake a specific thread, so wake them all and let the right one find it
+        let _guard = self.sleep_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.wakeup.notify_all();
+        true
+    }
+
+    /// Block until there is something for the worker owning 'local' to do
+    /// Our own jobs go first by priority, then control messages, then whatever we can steal
+    /// Returns None if nothing turned up within 'idle_timeout'
+    pub(crate) fn next(&self, local: &LocalQueue, idle_timeout: Option<Duration>) -> Option<Message> {
+        let deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+        #[cfg(feature = "crossbeam")]
+        if let Some(shared) = &self.shared {
+            return Scheduler::next_shared(shared, local, deadline);
+        }
+        loop {
+            if let Some(message) = self.pop_local(local) {
+                return Some(message);
+            }
+            if let Some(job) = self.steal(local.id) {
+                return Some(Message::NewJob(job));
+            }
+
+            // pushers take sleep_lock before notifying, so checking under it means we can't miss a wakeup
+            let guard = self.sleep_lock.lock().unwrap_or_else(|e| e.into_inner());
+            if !local.lock().is_empty() || self.pending() > 0 {
+                continue;
+            }
+            match deadline {
+                None => drop(self.wakeup.wait(guard).unwrap_or_else(|e| e.into_inner())),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    drop(self.wakeup.wait_timeout(guard, deadline - now).unwrap_or_else(|e| e.into_inner()));
+                }
+            }
+        }
+    }
+
+    // 'next' for the shared queue, the same order with the shared jobs standing in for our own
+    #[cfg(feature = "crossbeam")]
+    fn next_shared(shared: &SharedQueue, local: &LocalQueue, deadline: Option<Instant>) -> Option<Message> {
+        loop {
+            {
+                let mut lanes = local.lock();
+                if matches!(lanes.control.front(), Some(Message::Pause | Message::Resume)) {
+                    return lanes.control.pop_front();
+                }
+            }
+            if let Some(job) = shared.try_pop() {
+                return Some(Message::NewJob(job));
+            }
+            if let Some(message) = local.lock().control.pop_front() {
+                return Some(message);
+            }
+            // a message pushed since we looked has rung the bell, so this can't miss it
+            if !shared.wait(&local.bell, deadline) {
+                return None;
+            }
+        }
+    }
+
+    /// Block until there is a control message for the worker owning 'local', ignoring jobs
+    /// A paused worker waits here
+    pub(crate) fn next_control(&self, local: &LocalQueue) -> Message {
+        loop {
+            if let Some(message) = local.lock().control.pop_front() {
+                return message;
+            }
+            let guard = self.sleep_lock.lock().unwrap_or_else(|e| e.into_inner());
+            if !local.lock().control.is_empty() {
+                continue;
+            }
+            drop(self.wakeup.wait(guard).unwrap_or_else(|e| e.into_inner()));
+        }
+    }
+
+    /// Throw away every queued job, returns how many there were
+    pub(crate) fn drain_jobs(&self) -> usize {
+        #[cfg(feature = "crossbeam")]
+        if let Some(shared) = &self.shared {
+            return shared.drain();
+        }
+        let queues = self.queues.read().unwrap_or_else(|e| e.into_inner());
+        let mut drained = 0;
+        for queue in queues.iter() {
+            let mut lanes = queue.lock();
+            for (priority, jobs) in Priority::ALL.into_iter().zip(lanes.jobs.iter_mut()) {
+                self.jobs[priority.index()].fetch_sub(jobs.len(), Ordering::SeqCst);
+                drained += jobs.len();
+                jobs.clear();
+            }
+        }
+        drained
+    }
+
+    /// Jobs waiting in any queue
+    pub(crate) fn pending(&self) -> usize {
+        #[cfg(feature = "crossbeam")]
+        if let Some(shared) = &self.shared {
+            return shared.pending();
+        }
+        self.jobs.iter().map(|jobs| jobs.load(Ordering::SeqCst)).sum()
+    }
+
+    fn pop_local(&self, local: &LocalQueue) -> Option<Message> {
+        let mut lanes = local.lock();
+        // pausing has to take effect now, not after the jobs we already have
+        if matches!(lanes.control.front(), Some(Message::Pause | Message::Resume)) {
+            return lanes.control.pop_front();
+        }
+        for priority in Priority::ALL {
+            if let Some(job) = lanes.jobs[priority.index()].pop_front() {
+                self.jobs[priority.index()].fetch_sub(1, Ordering::SeqCst);
+                return Some(Message::NewJob(job));
+            }
+        }
+        lanes.control.pop_front()
+    }
+
+    // Take the oldest, most urgent job from someone else's queue, starting after our own to spread thieves out
+    fn steal(&self, thief: usize) -> Option<Job> {
+        let queues = self.queues.read().unwrap_or_else(|e| e.into_inner());
+        let start = queues.iter().position(|queue| queue.id == thief).map_or(0, |i| i + 1);
+        for priority in Priority::ALL {
+            if self.jobs[priority.index()].load(Ordering::SeqCst) == 0 {
+                continue;
+            }
+            for offset in 0..queues.len() {
+                let queue = &queues[(start + offset) % queues.len()];
+                if queue.id == thief {
+                    continue;
+                }
+                if let Some(job) = queue.lock().jobs[priority.index()].pop_front() {
+                    self.jobs[priority.index()].fetch_sub(1, Ordering::SeqCst);
+                    return Some(job);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_worker_steals_job() {
+        let scheduler = Scheduler::new();
+        let busy = scheduler.register(0);
+        let idle = scheduler.register(1);
+
+        // both jobs land on worker 0's queue by hand
+        busy.lock().jobs[Priority::Normal.index()].push_back(Box::new(|| {}));
+        busy.lock().jobs[Priority::Normal.index()].push_back(Box::new(|| {}));
+        scheduler.jobs[Priority::Normal.index()].store(2, Ordering::SeqCst);
+
+        assert!(matches!(scheduler.next(&idle, None), Some(Message::NewJob(_))));
+        assert_eq!(busy.lock().jobs[Priority::Normal.index()].len(), 1);
+    }
+
+    #[test]
+    fn test_control_messages_are_not_stolen() {
+        let scheduler = Scheduler::new();
+        let target = scheduler.register(0);
+        let other = scheduler.register(1);
+
+        assert!(scheduler.push_to(0, Message::Terminate));
+        assert!(scheduler.steal(other.id).is_none());
+        assert!(matches!(scheduler.next(&target, None), Some(Message::Terminate)));
+    }
+
+    #[test]
+    fn test_unregister_hands_jobs_to_others() {
+        let scheduler = Scheduler::new();
+        scheduler.register(0);
+        let survivor = scheduler.register(1);
+        scheduler.push_job(Box::new(|| {}), Priority::Normal).ok().unwrap();
+        scheduler.push_job(Box::new(|| {}), Priority::Low).ok().unwrap();
+
+        scheduler.unregister(0);
+        let lanes = survivor.lock();
+        assert_eq!(lanes.jobs[Priority::Normal.index()].len(), 1);
+        assert_eq!(lanes.jobs[Priority::Low.index()].len(), 1);
+        assert_eq!(scheduler.pending(), 2);
+    }
+
+    #[test]
+    fn test_high_priority_runs_first() {
+        let scheduler = Scheduler::new();
+        let local = scheduler.register(0);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for (priority, name) in [(Priority::Low, "low"), (Priority::Normal, "normal"), (Priority::High, "high")] {
+            let sender = sender.clone();
+            scheduler.push_job(Box::new(move || sender.send(name).unwrap()), priority).ok().unwrap();
+        }
+
+        for _ in 0..3 {
+            if let Some(Message::NewJob(job)) = scheduler.next(&local, None) {
+                job();
+            }
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), ["high", "normal", "low"]);
+    }
+
+    #[test]
+    fn test_next_times_out_when_idle() {
+        let scheduler = Scheduler::new();
+        let local = scheduler.register(0);
+        assert!(scheduler.next(&local, Some(Duration::from_millis(5))).is_none());
+    }
+
+    #[test]
+    fn test_push_jobs_spreads_batch() {
+        let scheduler = Scheduler::new();
+        let first = scheduler.register(0);
+        let second = scheduler.register(1);
+        let jobs: Vec<Job> = (0..5).map(|_| Box::new(|| {}) as Job).collect();
+
+        scheduler.push_jobs(jobs, Priority::Normal).ok().unwrap();
+        assert_eq!(first.lock().jobs[Priority::Normal.index()].len(), 3);
+        assert_eq!(second.lock().jobs[Priority::Normal.index()].len(), 2);
+        assert_eq!(scheduler.pending(), 5);
+    }
+
+    #[cfg(feature = "crossbeam")]
+    #[test]
+    fn test_shared_queue_order() {
+        let scheduler = Scheduler::with_shared_queue();
+        let first = scheduler.register(0);
+        let second = scheduler.register(1);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for (priority, name) in [(Priority::Low, "low"), (Priority::High, "high")] {
+            let sender = sender.clone();
+            scheduler.push_job(Box::new(move || sender.send(name).unwrap()), priority).ok().unwrap();
+        }
+        assert!(scheduler.push_to(0, Message::Terminate));
+        assert_eq!(scheduler.pending(), 2);
+
+        // any worker takes the jobs, most urgent first, and control messages wait behind them
+        for local in [&second, &first] {
+            if let Some(Message::NewJob(job)) = scheduler.next(local, None) {
+                job();
+            }
+        }
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), ["high", "low"]);
+        assert!(matches!(scheduler.next(&first, None), Some(Message::Terminate)));
+        assert!(scheduler.next(&second, Some(Duration::from_millis(5))).is_none());
+
+        // except pausing, which can't wait
+        scheduler.push_job(Box::new(|| {}), Priority::Normal).ok().unwrap();
+        assert!(scheduler.push_to(1, Message::Pause));
+        assert!(matches!(scheduler.next(&second, None), Some(Message::Pause)));
+        assert_eq!(scheduler.drain_jobs(), 1);
+    }
+
+    #[cfg(feature = "crossbeam")]
+    #[test]
+    fn test_shared_queue_wakes_waiting_worker() {
+        let scheduler = Arc::new(Scheduler::with_shared_queue());
+        let local = scheduler.register(0);
+        let waiting = {
+            let scheduler = Arc::clone(&scheduler);
+            std::thread::spawn(move || matches!(scheduler.next(&local, Some(Duration::from_secs(5))), Some(Message::Terminate)))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(scheduler.push_to(0, Message::Terminate));
+        assert!(waiting.join().unwrap());
+    }
+}
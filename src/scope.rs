@@ -0,0 +1,207 @@
+use std::{
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc, Condvar, Mutex},
+};
+
+use crate::{Job, JobHandle, JobToken, Priority, ThreadPool};
+
+/// Lets jobs borrow from the stack of whoever called ThreadPool::scope, see there
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    // invariant over both lifetimes, same trick std::thread::Scope uses
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// How many scoped jobs haven't finished yet, and whether any of them panicked
+struct ScopeState {
+    pending: Mutex<usize>,
+    all_done: Condvar,
+    panicked: AtomicBool,
+}
+
+impl ScopeState {
+    fn job_done(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        *pending -= 1;
+        if *pending == 0 {
+            self.all_done.notify_all();
+        }
+    }
+
+    fn wait_all(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        while *pending > 0 {
+            pending = self.all_done.wait(pending).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl ThreadPool {
+    /// Run 'f' with a Scope whose jobs may borrow anything that outlives this call,
+    /// like std::thread::scope but on the pool's workers
+    /// Every job queued through the scope has finished by the time this returns
+    ///
+    /// Don't call this from inside a job on the same pool, if every worker ends up
+    /// waiting on a scope there is nobody left to run the scoped jobs
+    ///
+    /// # Panics
+    /// Panics if 'f' panics or any scoped job panicked, after waiting for the rest to finish
+    ///
+    /// ```
+    /// let pool = webserver::ThreadPool::new(2);
+    /// let mut words = vec!["a", "b"];
+    /// let total = std::sync::atomic::AtomicUsize::new(0);
+    /// pool.scope(|s| {
+    ///     for word in &words {
+    ///         s.execute(|| total.fetch_add(word.len(), std::sync::atomic::Ordering::SeqCst));
+    ///     }
+    /// });
+    /// words.push("c"); // the borrows ended with the scope
+    /// assert_eq!(total.into_inner(), 2);
+    /// ```
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                all_done: Condvar::new(),
+                panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // nothing borrowed by the jobs may go away until they are all done, even if f panicked
+        scope.state.wait_all();
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::SeqCst) => panic!("a scoped job panicked"),
+            Ok(value) => value,
+        }
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Queue a job that may borrow from outside the scope
+    pub fn execute<F, T>(&'scope self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.execute_with_priority(f, Priority::Normal)
+    }
+
+    /// Same as 'execute' with a priority, see ThreadPool::execute_with_priority
+    pub fn execute_with_priority<F, T>(&'scope self, f: F, priority: Priority) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let (result_sender, receiver) = mpsc::channel();
+        *self.state.pending.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        let token = JobToken::new();
+        let scoped = ScopedJob {
+            f: Some(f),
+            result: Some(result_sender),
+            state: Arc::clone(&self.state),
+            token: token.clone(),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || scoped.run());
+        // SAFETY: ThreadPool::scope doesn't return until every ScopedJob has been run or dropped,
+        // and ScopedJob lets go of everything borrowed before it reports itself done,
+        // so nothing with the 'scope lifetime is touched after the scope ends
+        let job: Job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+
+        // a job that can't be queued is dropped right here, which marks it done
+        if self.pool.wait_for_slot() {
+            self.pool.submit(job, priority);
+        }
+        JobHandle::new(receiver, token)
+    }
+}
+
+// Wraps the user's closure so the scope hears about it whether it runs, panics or gets thrown away unrun
+struct ScopedJob<F, T> {
+    f: Option<F>,
+    result: Option<mpsc::Sender<T>>,
+    state: Arc<ScopeState>,
+    token: JobToken,
+}
+
+impl<F, T> ScopedJob<F, T>
+where F: FnOnce() -> T
+{
+    fn run(mut self) {
+        // a cancelled job is just dropped, which still reports it done
+        if self.token.is_cancelled() {
+            return;
+        }
+        let Some(f) = self.f.take() else { return };
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => {
+                if let Some(result) = self.result.take() {
+                    // the caller may have dropped the handle, that's fine
+                    let _ = result.send(value);
+                }
+            }
+            Err(payload) => {
+                self.state.panicked.store(true, Ordering::SeqCst);
+                // report done first, then let the worker see the panic so its hook still fires
+                drop(self);
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+impl<F, T> Drop for ScopedJob<F, T> {
+    fn drop(&mut self) {
+        // let go of the borrowed closure and the result channel before the scope is allowed to end
+        drop(self.f.take());
+        drop(self.result.take());
+        self.state.job_done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_scope_borrows_stack_data() {
+        let pool = ThreadPool::new(3);
+        let numbers = vec![1, 2, 3, 4];
+        let sum = AtomicUsize::new(0);
+
+        pool.scope(|s| {
+            for n in &numbers {
+                s.execute(|| sum.fetch_add(*n, Ordering::SeqCst));
+            }
+        });
+        assert_eq!(sum.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn test_scope_returns_job_results() {
+        let pool = ThreadPool::new(2);
+        let text = String::from("borrowed");
+        let len = pool.scope(|s| s.execute(|| text.len()).join());
+        assert_eq!(len, Ok(8));
+    }
+
+    #[test]
+    #[should_panic(expected = "a scoped job panicked")]
+    fn test_scope_propagates_job_panic() {
+        let pool = ThreadPool::with_panic_hook(2, |_, _| {});
+        pool.scope(|s| {
+            s.execute(|| panic!("boom"));
+        });
+    }
+}
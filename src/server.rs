@@ -0,0 +1,1818 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    admin::{self, ReloadHook},
+    drain::OpenConnections,
+    handler::{self, handle_site, HttpOptions},
+    headers,
+    listener::{Connection, ConnectionInfo, ConnectionWrapper, ListenAddr, Listener},
+    logging::log,
+    proxy,
+    socket::{self, ListenOptions},
+    Request, Router, StatusCode, ThreadPool, Upgraded, VirtualHosts,
+};
+#[cfg(feature = "tls")]
+use crate::{tls::TlsFiles, TlsStream};
+
+/// Accepts connections and hands each one to a handler on the thread pool
+/// It can listen on several addresses and Unix sockets, connections from all of them share the one pool
+///
+/// ```no_run
+/// let server = webserver::Server::bind("127.0.0.1:7878").unwrap();
+/// server.serve_static();
+/// ```
+pub struct Server {
+    pub(crate) listeners: Vec<Listener>,
+    pub(crate) pool: Arc<ThreadPool>,
+    doc_root: PathBuf,
+    // doc roots by Host header, None serves everything from doc_root
+    hosts: Option<VirtualHosts>,
+    pub(crate) http: HttpOptions,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) wrapper: Option<ConnectionWrapper>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<rustls::ServerConfig>>,
+    pub(crate) read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    grace_period: Duration,
+    // serve_static parks connections between requests instead of keeping a worker on them
+    pub(crate) event_loop: bool,
+    nodelay: bool,
+    pub(crate) proxy_protocol: bool,
+    pub(crate) admin: Option<TcpListener>,
+    pub(crate) reload: Option<ReloadHook>,
+    // connections accepted and not yet closed, queued ones included
+    pub(crate) live: Arc<AtomicUsize>,
+    pub(crate) open: OpenConnections,
+    pub(crate) stop: Arc<AtomicBool>,
+}
+
+// Counts a connection as live, and keeps it in OpenConnections, until the job holding it is
+// done with it (or dropped unrun)
+pub(crate) struct LiveConnection {
+    live: Arc<AtomicUsize>,
+    open: OpenConnections,
+    id: u64,
+}
+
+impl LiveConnection {
+    fn open(server: &Server, id: u64, stream: &Connection) -> (LiveConnection, usize) {
+        let count = server.live.fetch_add(1, Ordering::SeqCst) + 1;
+        server.open.add(id, stream);
+        (LiveConnection { live: Arc::clone(&server.live), open: server.open.clone(), id }, count)
+    }
+}
+
+impl Drop for LiveConnection {
+    fn drop(&mut self) {
+        self.open.remove(self.id);
+        self.live.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Stops a running Server from another thread, get one with Server::shutdown_handle
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stop: Arc<AtomicBool>,
+    addrs: Vec<ListenAddr>,
+}
+
+/// A Server accepting on its own thread, from Server::spawn
+/// Dropping it leaves the server running, call shutdown or join
+pub struct RunningServer {
+    handle: ShutdownHandle,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Configures a Server, every setting defaults to what Server::bind uses
+///
+/// ```no_run
+/// let server = webserver::Server::builder()
+///     .addr("[::]:8080")
+///     .addr("127.0.0.1:8081")
+///     .threads(8)
+///     .doc_root("static")
+///     .build()
+///     .unwrap();
+/// server.serve_static();
+/// ```
+pub struct ServerBuilder {
+    // with neither of these we listen on the default address
+    addrs: Vec<String>,
+    unix_sockets: Vec<PathBuf>,
+    threads: usize,
+    queue_len: usize,
+    doc_root: PathBuf,
+    virtual_hosts: Vec<(String, PathBuf)>,
+    job_timeout: Option<Duration>,
+    max_requests: Option<usize>,
+    listen: ListenOptions,
+    http: HttpOptions,
+    acceptors: usize,
+    systemd: bool,
+    nodelay: bool,
+    wrapper: Option<ConnectionWrapper>,
+    #[cfg(feature = "tls")]
+    tls: TlsFiles,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    grace_period: Duration,
+    event_loop: bool,
+    proxy_protocol: bool,
+    admin_addr: Option<String>,
+    reload: Option<ReloadHook>,
+    // who to run as once everything is bound
+    user: Option<String>,
+    group: Option<String>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            addrs: Vec::new(),
+            unix_sockets: Vec::new(),
+            threads: 4,
+            queue_len: 64,
+            doc_root: PathBuf::from("static"),
+            virtual_hosts: Vec::new(),
+            job_timeout: None,
+            max_requests: None,
+            listen: ListenOptions::default(),
+            http: HttpOptions::default(),
+            acceptors: 1,
+            systemd: false,
+            nodelay: true,
+            wrapper: None,
+            #[cfg(feature = "tls")]
+            tls: TlsFiles::default(),
+            read_timeout: Some(DEFAULT_IO_TIMEOUT),
+            write_timeout: Some(DEFAULT_IO_TIMEOUT),
+            max_connections: None,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            event_loop: false,
+            proxy_protocol: false,
+            admin_addr: None,
+            reload: None,
+            user: None,
+            group: None,
+        }
+    }
+
+    /// Address to listen on, "host:port". Call it again to listen on more addresses
+    /// Without any the server listens on 127.0.0.1:7878
+    /// "[::]:port" takes IPv4 clients as well, unless ipv6_only is set
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addrs.push(addr.into());
+        self
+    }
+
+    /// Listen on a Unix socket at 'path' too, e.g. to sit behind a reverse proxy on the same box
+    /// Setting only Unix sockets means the server doesn't listen on TCP at all
+    /// A stale socket file at 'path' is replaced, and the file is removed when the server is dropped
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix_sockets.push(path.into());
+        self
+    }
+
+    /// Use the sockets systemd passes in ($LISTEN_FDS) when the process is socket activated,
+    /// instead of binding addr and unix_socket. Without them those are bound as usual
+    /// systemd keeps the sockets open across restarts, so clients queue up rather than get refused
+    #[cfg(unix)]
+    pub fn systemd_sockets(mut self, enabled: bool) -> Self {
+        self.systemd = enabled;
+        self
+    }
+
+    /// Worker threads handling connections
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Connections that may wait for a worker before new ones get a 503
+    pub fn queue_len(mut self, queue_len: usize) -> Self {
+        self.queue_len = queue_len;
+        self
+    }
+
+    /// Directory index.html and 404.html are served from
+    /// With virtual hosts it's what requests for any other host get
+    pub fn doc_root(mut self, doc_root: impl Into<PathBuf>) -> Self {
+        self.doc_root = doc_root.into();
+        self
+    }
+
+    /// Biggest request head (request line and headers) serve_static accepts, bigger ones
+    /// get a 431. 8KB by default, raise it for clients with long URLs or lots of cookies
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.http.max_head_len = bytes;
+        self
+    }
+
+    /// Most header lines a request head can have, more get a 431. 100 by default
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.http.max_headers = count;
+        self
+    }
+
+    /// Longest a single header line can be, name and value, longer ones get a 431. 8190
+    /// bytes by default, which only bites when max_header_size is raised past it
+    pub fn max_header_line(mut self, bytes: usize) -> Self {
+        self.http.max_header_line = bytes;
+        self
+    }
+
+    /// Serve files ending in '.extension' as 'content_type', over the built in table
+    /// (html, css, js, png, svg, woff2, json, wasm, mp4 and the like). Anything neither
+    /// knows is sent as application/octet-stream
+    pub fn mime_type(mut self, extension: impl Into<String>, content_type: impl Into<String>) -> Self {
+        self.http.mime_types.push((extension.into(), content_type.into()));
+        self
+    }
+
+    /// The Server header on every response, "webserver/<version>" by default. Empty leaves
+    /// it out, for not telling the world what's running
+    ///
+    /// # Panics
+    /// Panics if 'value' has a CR, LF or other control character in it
+    pub fn server_header(mut self, value: impl Into<String>) -> Self {
+        let value = value.into();
+        assert!(headers::is_valid_value(&value), "server_header can't have control characters in it");
+        self.http.server = (!value.is_empty()).then_some(value);
+        self
+    }
+
+    /// Send requests for path 'from' to 'to' with a 301, query string and all. Paths have to
+    /// match exactly, after decoding and normalizing
+    pub fn redirect(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.http.redirects.push((from.into(), to.into()));
+        self
+    }
+
+    /// Have serve_static redirect requests for a path that isn't spelled the way it normalizes
+    /// ("/a/./b", "/a//b", "/%61") to the one that is, with a 301 (308 for methods other than
+    /// GET and HEAD). Off by default, when such requests are served the page they resolve to
+    pub fn canonical_redirects(mut self, enabled: bool) -> Self {
+        self.http.canonical_redirects = enabled;
+        self
+    }
+
+    /// ETags for serve_static's pages from a hash of their contents, rather than their size and
+    /// modification time. Costs reading the file to answer a conditional request, but the tags
+    /// survive copying the site elsewhere, and are strong. Off by default
+    pub fn content_etags(mut self, enabled: bool) -> Self {
+        self.http.content_etags = enabled;
+        self
+    }
+
+    /// Whether serve_static gzips bodies for clients sending Accept-Encoding: gzip, on by
+    /// default. Range requests are always sent as the file is
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.http.compress = enabled;
+        self
+    }
+
+    /// Smallest body worth compressing, smaller ones go as they are. 1KB by default
+    pub fn compress_min_size(mut self, bytes: u64) -> Self {
+        self.http.compress_min_size = bytes;
+        self
+    }
+
+    /// Content-Types to compress, replacing the default of text/*, JavaScript, JSON, XML,
+    /// SVG and wasm. An entry is an exact type or "type/*"
+    pub fn compress_types<I: IntoIterator<Item = S>, S: Into<String>>(mut self, types: I) -> Self {
+        self.http.compress_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Send 'status' responses with the page at 'path', from the doc root (the site's, for a
+    /// virtual host) unless it's absolute. By default 404s get 404.html and the 503s of an
+    /// overloaded server 503.html; statuses without a page, or whose page is missing, get a
+    /// line of text. An empty 'path' takes a status's page away
+    pub fn error_page(mut self, status: StatusCode, path: impl Into<PathBuf>) -> Self {
+        self.http.error_pages.retain(|(code, _)| *code != status);
+        self.http.error_pages.push((status, path.into()));
+        self
+    }
+
+    /// Have serve_static speak HTTP/2 to clients that want it: ones opening with the HTTP/2
+    /// preface, knowing beforehand that the server speaks it (h2c), and with tls, ones that
+    /// pick h2 in ALPN. A connection's streams are answered one at a time, and it keeps its
+    /// worker until it closes, as an upgraded one does. Off by default, when such clients are
+    /// sent a GOAWAY telling them to use HTTP/1.1
+    pub fn http2(mut self, enabled: bool) -> Self {
+        self.http.http2 = enabled;
+        self
+    }
+
+    /// Have serve_static hand requests to 'router' first: those for a path it has routes for get
+    /// their handler's response (or a 405), anything else the stock pages. Redirects, including
+    /// canonical_redirects, still come first
+    ///
+    /// ```
+    /// use webserver::{Response, Router, StatusCode};
+    ///
+    /// let mut router = Router::new();
+    /// router.get("/hello", |request| Response::new(StatusCode::Ok).with_body(format!("hello {}\n", request.query("name").unwrap_or_default())));
+    /// let builder = webserver::Server::builder().router(router);
+    /// # let _ = builder;
+    /// ```
+    pub fn router(mut self, router: Router) -> Self {
+        self.http.router = Some(Arc::new(router));
+        self
+    }
+
+    /// Have 'handler' take over connections whose request asks to Upgrade to 'protocol' ("websocket",
+    /// "h2c"), with Connection: upgrade, rather than serve_static answering it. See Upgraded for
+    /// how it switches. The connection keeps its worker for as long as the handler runs, so
+    /// long-lived protocols want a pool sized for them, but not its HTTP read and write timeouts
+    pub fn upgrade<H>(mut self, protocol: impl Into<String>, handler: H) -> Self
+    where H: Fn(&Request, Upgraded<'_>) + Send + Sync + 'static
+    {
+        self.http.upgrades.push((protocol.into(), Arc::new(handler)));
+        self
+    }
+
+    /// Biggest request body serve_static accepts, going by Content-Length. Bigger ones get a
+    /// 413 without any of the body being read. 1MB by default
+    pub fn max_body_size(mut self, bytes: u64) -> Self {
+        self.http.max_body_size = bytes;
+        self
+    }
+
+    /// How many bytes serve_static reads off a connection at a time, 1KB by default
+    /// Heads bigger than this are read in several goes, up to max_header_size
+    ///
+    /// # Panics
+    /// Panics if 'bytes' is 0
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "read_buffer_size must be at least 1");
+        self.http.read_buffer = bytes;
+        self
+    }
+
+    /// Have serve_static answer requests whose Host header is 'name' from 'doc_root',
+    /// call it once per site. See VirtualHosts for how names match
+    pub fn virtual_host(mut self, name: impl Into<String>, doc_root: impl Into<PathBuf>) -> Self {
+        self.virtual_hosts.push((name.into(), doc_root.into()));
+        self
+    }
+
+    /// Log workers that hold onto one connection longer than this, off by default
+    /// A connection's job takes in every request on it and the waits between them, so
+    /// keep-alive clients pass any limit shorter than the read timeout without anything being stuck
+    pub fn job_timeout(mut self, limit: Option<Duration>) -> Self {
+        self.job_timeout = limit;
+        self
+    }
+
+    /// Keep IPv6 listeners to IPv6 clients, so "0.0.0.0:port" and "[::]:port" can both be bound
+    /// Only changes anything on Linux, elsewhere the OS default applies
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.listen.ipv6_only = ipv6_only;
+        self
+    }
+
+    /// SO_REUSEADDR on listening sockets, on by default so a restart doesn't wait out TIME_WAIT
+    /// Like the other listen options it only changes anything on Linux
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.listen.reuse_address = reuse;
+        self
+    }
+
+    /// SO_REUSEPORT on listening sockets, so other sockets (or processes) that set it too can
+    /// bind the same port and the kernel spreads connections between them. Off by default
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.listen.reuse_port = reuse;
+        self
+    }
+
+    /// Accept on 'acceptors' threads per TCP address instead of one, each with its own
+    /// SO_REUSEPORT listener so the kernel spreads new connections between them and a busy
+    /// accept loop stops being the bottleneck. They all feed the one pool. 1 by default
+    /// Only Linux balances SO_REUSEPORT listeners, elsewhere this stays at 1
+    ///
+    /// # Panics
+    /// Panics if 'acceptors' is 0
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        assert!(acceptors > 0, "acceptors must be at least 1");
+        self.acceptors = acceptors;
+        self
+    }
+
+    /// How many connections the kernel queues for us before we accept them, 128 by default
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.listen.backlog = backlog;
+        self
+    }
+
+    /// TCP_NODELAY on accepted connections, on by default: responses go out in one write
+    /// so Nagle's algorithm only adds latency waiting for ACKs
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// How long a client may keep the server waiting for its request, 10 seconds by default
+    /// The stock handler answers a client that runs out of time with a 408, None waits forever
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// How long writing a response may stall on a client that isn't reading, 10 seconds by default
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Turn clients away with a 503 while this many connections are open, queued ones included
+    /// Unlimited by default, so only the pool's queue_len limits how many pile up
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Have serve_static wait for requests on an epoll event loop (Linux): a connection only takes
+    /// a worker once a whole request head has arrived, so idle keep-alive connections and slow
+    /// clients don't tie up the pool. Handlers given to serve own their connection and don't use it
+    pub fn event_loop(mut self, enabled: bool) -> Self {
+        self.event_loop = enabled;
+        self
+    }
+
+    /// How long a shutdown waits for connections already accepted, 10 seconds by default
+    /// Idle keep-alive connections are closed straight away and the rest are told Connection: close
+    /// with their next response. Whatever is still open at the end is closed under its handler
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Expect every connection to open with a PROXY protocol header (v1 or v2), as HAProxy and
+    /// most load balancers can send, and report the client address from it as the connection's
+    /// peer_addr instead of the balancer's. Connections without one are dropped
+    /// Only turn it on when nothing but the balancer can reach the server, anyone else could claim any address
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Serve HTTPS: the certificate chain (leaf first) and private key are read from the PEM
+    /// files 'cert' and 'key' when the server is built. The handshake runs on the worker,
+    /// under the read and write timeouts, before the handler or any wrap_connections wrapper
+    /// sees the connection, which it gets as Connection::Tls. Clients turned away while the
+    /// queue is full are just closed
+    ///
+    /// ```no_run
+    /// let server = webserver::Server::builder().addr("0.0.0.0:443").tls("cert.pem", "key.pem").build().unwrap();
+    /// server.serve_static();
+    /// ```
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.tls.server = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Ask TLS clients for a certificate, and only let in those with one issued by a CA in
+    /// the PEM bundle 'ca'. Its subject ("CN=alice,O=Example") is the connection's
+    /// peer_identity, in ConnectionInfo too, for handlers deciding what the client may do
+    /// Needs tls as well
+    #[cfg(feature = "tls")]
+    pub fn tls_client_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.tls.client_ca = Some(ca.into());
+        self
+    }
+
+    /// Still let TLS clients without a certificate in when tls_client_ca is set, with no
+    /// peer_identity, so handlers can send them elsewhere. Those with one must have a good one
+    #[cfg(feature = "tls")]
+    pub fn tls_client_optional(mut self, optional: bool) -> Self {
+        self.tls.client_optional = optional;
+        self
+    }
+
+    /// Stop serving after this many requests, for benchmarks and tests: serve_static counts
+    /// every request it reads, serve and serve_with_info each connection, since their handlers
+    /// read the requests themselves, and a client turned away with a 503 counts as one too
+    /// Connections still open when the last one comes in finish like at any other shutdown
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Run every accepted connection through 'wrapper' before the handler sees it
+    /// This is where a layer of your own goes, like a TLS library other than the tls feature's:
+    /// the wrapper runs on the worker, after any tls handshake, so a slow handshake of its own
+    /// doesn't hold up accepting, and an Err is logged and the connection dropped
+    /// Clients turned away while the queue is full are just closed, since a plain 503 would be garbage to them
+    ///
+    /// ```
+    /// use std::io::{self, Read};
+    /// use webserver::Connection;
+    ///
+    /// // a stand-in for a TLS stream: only lets the first 4 KiB of the request through
+    /// let builder = webserver::Server::builder().wrap_connections(|conn: Connection| {
+    ///     let peer = conn.peer_addr();
+    ///     Ok(Connection::wrapped(ReadLimit(conn.take(4096)), peer))
+    /// });
+    ///
+    /// struct ReadLimit(io::Take<Connection>);
+    /// impl io::Read for ReadLimit {
+    ///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    /// }
+    /// impl io::Write for ReadLimit {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.get_mut().write(buf) }
+    ///     fn flush(&mut self) -> io::Result<()> { self.0.get_mut().flush() }
+    /// }
+    /// # let _ = builder;
+    /// ```
+    pub fn wrap_connections<W>(mut self, wrapper: W) -> Self
+    where W: Fn(Connection) -> io::Result<Connection> + Send + Sync + 'static
+    {
+        self.wrapper = Some(Arc::new(wrapper));
+        self
+    }
+
+    /// Also listen for operator commands on 'addr', which has to be a loopback address since
+    /// anyone who can connect can stop the server. A command is one line, the answer starts
+    /// with ok or error:
+    ///
+    /// ```text
+    /// $ echo stats | nc 127.0.0.1 7879
+    /// ok queued=0 in_flight=1 completed=52 panicked=0 workers=4 open_connections=1 accepted=53
+    /// ```
+    ///
+    /// stats, reload-config (see on_reload) and shutdown (like a ShutdownHandle) are understood
+    pub fn admin_addr(mut self, addr: impl Into<String>) -> Self {
+        self.admin_addr = Some(addr.into());
+        self
+    }
+
+    /// What the admin socket's reload-config command runs, e.g. re-reading the config file and
+    /// applying what can change without a restart. Its message is passed back to the operator
+    pub fn on_reload<F>(mut self, reload: F) -> Self
+    where F: Fn() -> Result<String, String> + Send + Sync + 'static
+    {
+        self.reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// Switch to this user once every socket is bound, so a server started as root to get
+    /// port 80 doesn't go on serving as root. Its primary group is used unless group is set
+    /// Unix socket files are still created by root, and may not be removable by 'user' on exit
+    #[cfg(unix)]
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Switch to this group once every socket is bound, see user
+    #[cfg(unix)]
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// Spawn the pool and bind every address
+    ///
+    /// # Errors
+    /// Returns the error if an address can't be bound, the user or group can't be switched to,
+    /// the TLS certificate or key can't be read, or the pool's threads can't be spawned
+    ///
+    /// # Panics
+    /// Panics if threads or queue_len is 0
+    pub fn build(self) -> io::Result<Server> {
+        // before binding, they may only be readable by the user we start as
+        #[cfg(feature = "tls")]
+        let tls = self.tls.server_config(self.http.http2)?;
+        let mut listeners = Vec::new();
+        #[cfg(unix)]
+        if self.systemd {
+            listeners = crate::systemd::inherited_listeners();
+            if !listeners.is_empty() {
+                log!(Info, "Using {} socket(s) from systemd", listeners.len());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if listeners.is_empty() {
+            listeners = crate::upgrade::inherited_listeners();
+        }
+        let addrs: &[String] = if listeners.is_empty() { &self.addrs } else { &[] };
+        let (acceptors, listen) = self.acceptor_options();
+        for addr in addrs {
+            listeners.extend(bind_acceptors(addr, acceptors, listen)?);
+        }
+        #[cfg(unix)]
+        let unix_sockets: &[PathBuf] = if listeners.is_empty() { &self.unix_sockets } else { &[] };
+        #[cfg(unix)]
+        for path in unix_sockets {
+            let listener = Listener::bind_unix(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to bind {}: {}", path.display(), e)))?;
+            listeners.push(listener);
+        }
+        if listeners.is_empty() {
+            listeners.extend(bind_acceptors(DEFAULT_ADDR, acceptors, listen)?);
+        }
+        let admin = match &self.admin_addr {
+            #[cfg(target_os = "linux")]
+            Some(_) if let Some(admin) = crate::upgrade::inherited_admin() => Some(admin),
+            Some(addr) => Some(
+                admin::bind(addr).map_err(|e| io::Error::new(e.kind(), format!("failed to bind admin socket {}: {}", addr, e)))?,
+            ),
+            None => None,
+        };
+        // the admin socket's upgrade command hands these to another process, a wake-up
+        // connection might land there, so every accept has to give up now and then
+        #[cfg(target_os = "linux")]
+        if let Some(admin) = &admin {
+            use std::os::fd::AsRawFd;
+            for fd in listeners.iter().map(AsRawFd::as_raw_fd).chain([admin.as_raw_fd()]) {
+                socket::set_accept_timeout(&fd, ACCEPT_POLL)?;
+            }
+        }
+        // before the pool's threads exist, so none of them ever runs privileged
+        #[cfg(unix)]
+        if self.user.is_some() || self.group.is_some() {
+            crate::privileges::drop_to(self.user.as_deref(), self.group.as_deref())
+                .map_err(|e| io::Error::new(e.kind(), format!("failed to drop privileges: {}", e)))?;
+        }
+        let event_loop = self.use_event_loop();
+        let pool = web_pool(self.threads, self.queue_len, self.job_timeout)?;
+        let mut server = Server::from_listeners(listeners, pool);
+        if !self.virtual_hosts.is_empty() {
+            let hosts = VirtualHosts::new(&self.doc_root);
+            server.hosts = Some(self.virtual_hosts.iter().fold(hosts, |hosts, (name, root)| hosts.host(name, root)));
+        }
+        server.doc_root = self.doc_root;
+        server.http = self.http;
+        server.max_requests = self.max_requests;
+        server.wrapper = self.wrapper;
+        #[cfg(feature = "tls")]
+        {
+            server.tls = tls;
+        }
+        server.read_timeout = self.read_timeout;
+        server.write_timeout = self.write_timeout;
+        server.max_connections = self.max_connections;
+        server.grace_period = self.grace_period;
+        server.event_loop = event_loop;
+        server.nodelay = self.nodelay;
+        server.proxy_protocol = self.proxy_protocol;
+        server.admin = admin;
+        server.reload = self.reload;
+        #[cfg(target_os = "linux")]
+        crate::upgrade::notify_ready();
+        Ok(server)
+    }
+
+    // Whether the event loop can be used, it needs epoll and the connection's own socket
+    fn use_event_loop(&self) -> bool {
+        if !self.event_loop {
+            return false;
+        }
+        if !cfg!(target_os = "linux") {
+            log!(Warn, "The event loop needs epoll, which is Linux only, serving without it");
+            return false;
+        }
+        if self.wrapper.is_some() || self.proxy_protocol {
+            log!(Warn, "The event loop can't wait on wrapped or PROXY protocol connections, serving without it");
+            return false;
+        }
+        #[cfg(feature = "tls")]
+        if self.tls.server.is_some() {
+            log!(Warn, "The event loop can't wait on TLS connections, serving without it");
+            return false;
+        }
+        true
+    }
+
+    // Extra acceptors need SO_REUSEPORT to share a port, and an accept timeout since a
+    // shutdown's wake-up connection only lands on one of them
+    fn acceptor_options(&self) -> (usize, ListenOptions) {
+        if self.acceptors == 1 {
+            return (1, self.listen);
+        }
+        if !cfg!(target_os = "linux") {
+            log!(Warn, "Multiple acceptors need SO_REUSEPORT load balancing, which is Linux only, using 1");
+            return (1, self.listen);
+        }
+        let listen = ListenOptions { reuse_port: true, accept_timeout: Some(ACCEPT_POLL), ..self.listen };
+        (self.acceptors, listen)
+    }
+}
+
+// Bind 'acceptors' listeners to 'addr', the later ones to the port the first got in case it was 0
+fn bind_acceptors(addr: &str, acceptors: usize, listen: ListenOptions) -> io::Result<Vec<Listener>> {
+    let failed = |e: io::Error| io::Error::new(e.kind(), format!("failed to bind {}: {}", addr, e));
+    let first = socket::bind(addr, listen).map_err(failed)?;
+    let bound = first.local_addr()?.to_string();
+    let mut listeners = vec![Listener::Tcp(first)];
+    for _ in 1..acceptors {
+        listeners.push(Listener::Tcp(socket::bind(&bound, listen).map_err(failed)?));
+    }
+    Ok(listeners)
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Listen on 'addr' with the default settings: 4 workers, room for 64 queued
+    /// connections and pages served from ./static
+    /// Port 0 lets the OS pick a free port, local_addr says which
+    ///
+    /// # Errors
+    /// Returns the error if the address can't be bound or the pool's threads can't be spawned
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Server> {
+        let defaults = ServerBuilder::new();
+        let pool = web_pool(defaults.threads, defaults.queue_len, defaults.job_timeout)?;
+        Server::with_pool(addr, pool)
+    }
+
+    /// Listen on 'addr' and run connections on 'pool'
+    ///
+    /// # Errors
+    /// Returns the error if the address can't be bound
+    pub fn with_pool(addr: impl ToSocketAddrs, pool: ThreadPool) -> io::Result<Server> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Server::from_listeners(vec![Listener::Tcp(listener)], pool))
+    }
+
+    fn from_listeners(listeners: Vec<Listener>, pool: ThreadPool) -> Server {
+        Server {
+            listeners,
+            pool: Arc::new(pool),
+            doc_root: ServerBuilder::new().doc_root,
+            hosts: None,
+            http: HttpOptions::default(),
+            max_requests: None,
+            wrapper: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            read_timeout: Some(DEFAULT_IO_TIMEOUT),
+            write_timeout: Some(DEFAULT_IO_TIMEOUT),
+            max_connections: None,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            event_loop: false,
+            nodelay: true,
+            proxy_protocol: false,
+            admin: None,
+            reload: None,
+            live: Arc::new(AtomicUsize::new(0)),
+            open: OpenConnections::default(),
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The first TCP address actually bound, handy when binding port 0
+    /// so tests running side by side don't fight over a fixed port
+    ///
+    /// ```
+    /// use std::{io::{Read, Write}, net::TcpStream, thread};
+    ///
+    /// let server = webserver::Server::bind("127.0.0.1:0").unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// assert_ne!(addr.port(), 0);
+    ///
+    /// let shutdown = server.shutdown_handle().unwrap();
+    /// let serving = thread::spawn(move || server.serve(|mut conn| conn.write_all(b"hi").unwrap()));
+    /// let mut reply = String::new();
+    /// TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+    /// assert_eq!(reply, "hi");
+    /// shutdown.shutdown();
+    /// serving.join().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    /// Returns a NotFound error if the server only listens on Unix sockets
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addrs()?
+            .into_iter()
+            .find_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(addr),
+                #[cfg(unix)]
+                ListenAddr::Unix(_) => None,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not listening on TCP"))
+    }
+
+    /// Every address bound, TCP ones first in the order they were added to the builder
+    /// An address served by several acceptors is only listed once
+    pub fn local_addrs(&self) -> io::Result<Vec<ListenAddr>> {
+        let mut addrs = Vec::new();
+        for listener in &self.listeners {
+            let addr = listener.local_addr()?;
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// A handle that makes serve return once the connections already accepted are done
+    ///
+    /// # Errors
+    /// Returns the error if the listener's address can't be read
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        let mut addrs = self.local_addrs()?;
+        // the admin socket's loop needs waking too
+        addrs.extend(self.admin_addr().map(ListenAddr::Tcp));
+        Ok(ShutdownHandle { stop: Arc::clone(&self.stop), addrs })
+    }
+
+    /// Where serve_static reads its pages from
+    pub fn doc_root(&self) -> &Path {
+        &self.doc_root
+    }
+
+    /// The pool connections are served on, for queueing blocking work of your own alongside them
+    /// It's shut down once the server stops serving
+    pub fn pool(&self) -> Arc<ThreadPool> {
+        Arc::clone(&self.pool)
+    }
+
+    /// Run serve on a new "web-accept" thread and return right away, so the caller can
+    /// stop it later from wherever it likes
+    ///
+    /// ```
+    /// use std::{io::{Read, Write}, net::TcpStream};
+    ///
+    /// let server = webserver::Server::bind("127.0.0.1:0").unwrap();
+    /// let addr = server.local_addr().unwrap();
+    /// let running = server.spawn(|mut conn| conn.write_all(b"hi").unwrap()).unwrap();
+    /// let mut reply = String::new();
+    /// TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+    /// assert_eq!(reply, "hi");
+    /// running.shutdown().unwrap();
+    /// ```
+    ///
+    /// # Errors
+    /// Returns the error if the listener's address can't be read or the thread can't be spawned
+    pub fn spawn<H>(self, handler: H) -> io::Result<RunningServer>
+    where H: Fn(Connection) + Send + Sync + 'static
+    {
+        self.spawn_with_info(move |stream, _| handler(stream))
+    }
+
+    /// spawn for a serve_with_info handler
+    ///
+    /// # Errors
+    /// Returns the error if the listener's address can't be read or the thread can't be spawned
+    pub fn spawn_with_info<H>(self, handler: H) -> io::Result<RunningServer>
+    where H: Fn(Connection, ConnectionInfo) + Send + Sync + 'static
+    {
+        let handle = self.shutdown_handle()?;
+        let thread = thread::Builder::new().name("web-accept".to_string()).spawn(move || self.serve_with_info(handler))?;
+        Ok(RunningServer { handle, thread })
+    }
+
+    /// Serve the stock pages from the doc root like handle_connection,
+    /// or from each virtual host's root like handle_virtual_hosts if any were set
+    /// With ServerBuilder::event_loop set, connections wait for their requests on the event loop
+    pub fn serve_static(self) {
+        #[cfg(target_os = "linux")]
+        if self.event_loop {
+            self.serve_events();
+            return;
+        }
+        // the handler counts the requests it reads
+        let handler = Arc::new(self.static_handler());
+        self.run(&|stream, id, live| self.dispatch(stream, id, &handler, live), None);
+    }
+
+    /// serve_static on its own thread, like spawn
+    ///
+    /// # Errors
+    /// Returns the error if the listener's address can't be read or the thread can't be spawned
+    pub fn spawn_static(self) -> io::Result<RunningServer> {
+        let handle = self.shutdown_handle()?;
+        let thread = thread::Builder::new().name("web-accept".to_string()).spawn(move || self.serve_static())?;
+        Ok(RunningServer { handle, thread })
+    }
+
+    // The stock handler for our doc root, or virtual hosts
+    fn static_handler(&self) -> impl Fn(Connection, ConnectionInfo) + Send + Sync + 'static {
+        let hosts = self.site_hosts();
+        let http = self.http.clone();
+        let open = self.open.clone();
+        move |stream, info| handle_site(stream, &hosts, &http, Some(&open.tracked(info.id)))
+    }
+
+    // Where the stock pages come from, no hosts is everything from the doc root
+    pub(crate) fn site_hosts(&self) -> VirtualHosts {
+        self.hosts.clone().unwrap_or_else(|| VirtualHosts::new(&self.doc_root))
+    }
+
+    /// Accept connections and run 'handler' on each one in the pool until a ShutdownHandle is
+    /// used or max_requests connections have been handed to it, then shut the pool down
+    /// Clients that arrive while the pool's queue is full, or max_connections are open,
+    /// get a 503 straight from the accept loop
+    ///
+    /// The first listener is served on the calling thread, any others (including extra acceptors) get a thread each
+    pub fn serve<H>(self, handler: H)
+    where H: Fn(Connection) + Send + Sync + 'static
+    {
+        self.serve_with_info(move |stream, _| handler(stream));
+    }
+
+    /// serve, with the connection's ConnectionInfo (peer and local address, TLS details, id)
+    /// passed alongside it for logging and address based decisions
+    pub fn serve_with_info<H>(self, handler: H)
+    where H: Fn(Connection, ConnectionInfo) + Send + Sync + 'static
+    {
+        let handler = Arc::new(handler);
+        // the requests are the handler's business, so each connection counts as one
+        let dispatch = |stream, id, live| {
+            self.dispatch(stream, id, &handler, live);
+            self.open.count_request();
+        };
+        self.run(&dispatch, None);
+    }
+
+    // Accept on every listener until shutdown, passing each connection to 'dispatch',
+    // with 'background' on a thread of its own alongside the accept loops
+    pub(crate) fn run(
+        &self,
+        dispatch: &(dyn Fn(Connection, u64, LiveConnection) + Sync),
+        background: Option<(&str, &(dyn Fn() + Sync))>,
+    ) {
+        let accepted = AtomicUsize::new(0);
+        let shutdown = self.shutdown_handle();
+        self.limit_requests();
+        thread::scope(|scope| {
+            if let Some((name, background)) = background {
+                let spawned = thread::Builder::new().name(name.to_string()).spawn_scoped(scope, background);
+                if let Err(e) = spawned {
+                    log!(Error, "Failed to start the {} thread: {}", name, e);
+                }
+            }
+            if let Some(admin) = &self.admin {
+                let spawned = thread::Builder::new()
+                    .name("web-admin".to_string())
+                    .spawn_scoped(scope, || self.admin_loop(admin, &accepted, &shutdown));
+                if let Err(e) = spawned {
+                    log!(Error, "Failed to start the admin thread: {}", e);
+                }
+            }
+            for listener in &self.listeners[1..] {
+                let spawned = thread::Builder::new()
+                    .name("web-accept".to_string())
+                    .spawn_scoped(scope, || self.accept_loop(listener, dispatch, &accepted));
+                if let Err(e) = spawned {
+                    log!(Error, "Failed to start accept thread for {:?}: {}", listener.local_addr(), e);
+                }
+            }
+            self.accept_loop(&self.listeners[0], dispatch, &accepted);
+        });
+        self.wind_down();
+    }
+
+    // Shut down once max_requests have been counted, by whichever handler counts the last one
+    pub(crate) fn limit_requests(&self) {
+        let Some(max) = self.max_requests else { return };
+        let (shutdown, stop) = (self.shutdown_handle(), Arc::clone(&self.stop));
+        self.open.stop_after(max, move || match shutdown {
+            Ok(shutdown) => shutdown.shutdown(),
+            Err(_) => stop.store(true, Ordering::SeqCst),
+        });
+    }
+
+    // Once nothing is accepting any more, wait for the open connections and stop the pool
+    pub(crate) fn wind_down(&self) {
+        self.drain();
+        let report = self.pool.shutdown(FORCE_CLOSE_WAIT);
+        let stats = self.pool.stats();
+        log!(
+            Info,
+            "Shutting Down: {} jobs completed, {} panicked, {} dropped",
+            stats.completed, stats.panicked, report.dropped_jobs
+        );
+    }
+
+    // Let the connections already accepted finish, for up to the grace period, then close
+    // what's left so no worker is still writing to a socket once the pool is gone
+    fn drain(&self) {
+        let open = self.live.load(Ordering::SeqCst);
+        if open > 0 {
+            log!(Info, "Draining {} connection(s), for up to {:?}", open, self.grace_period);
+        }
+        self.open.drain();
+        let deadline = Instant::now() + self.grace_period;
+        while self.live.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let left = self.open.close_all();
+        if left > 0 {
+            log!(Warn, "Closed {} connection(s) still open after the grace period", left);
+        }
+    }
+
+    fn accept_loop(
+        &self,
+        listener: &Listener,
+        dispatch: &(dyn Fn(Connection, u64, LiveConnection) + Sync),
+        accepted: &AtomicUsize,
+    ) {
+        if let Ok(addr) = listener.local_addr() {
+            log!(Info, "Listening on {}", addr);
+        }
+        // wait for messages which will either be a stream or an error
+        loop {
+            let stream = listener.accept();
+            // the connection that woke us up is the shutdown handle poking the listener
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    let count = accepted.fetch_add(1, Ordering::SeqCst) + 1;
+                    if let Some((stream, live)) = self.admit(stream, count as u64) {
+                        dispatch(stream, count as u64, live);
+                    }
+                }
+                // the accept timeout several acceptors on one port use to notice a shutdown
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    log!(Error, "Error accepting connection: {}", e);
+                }
+            }
+        }
+    }
+
+    // Set up a connection just accepted as number 'id' and count it as live, None if there
+    // were already max_connections open and the client has been turned away
+    pub(crate) fn admit(&self, stream: Connection, id: u64) -> Option<(Connection, LiveConnection)> {
+        log!(Debug, "Accepted connection from {}", stream);
+        // so a client that connects and goes quiet can't hold a worker forever
+        if let Err(e) = stream
+            .set_read_timeout(self.read_timeout)
+            .and_then(|()| stream.set_write_timeout(self.write_timeout))
+            .and_then(|()| stream.set_nodelay(self.nodelay))
+        {
+            log!(Warn, "Failed to set socket options for {}: {}", stream, e);
+        }
+        let (live, open) = LiveConnection::open(self, id, &stream);
+        if let Some(max) = self.max_connections
+            && open > max
+        {
+            log!(Warn, "{} connections open, turning a client away", max);
+            self.turn_away(stream);
+            return None;
+        }
+        Some((stream, live))
+    }
+
+    // Run 'handler' on the pool, 'live' is released once the connection is done with
+    fn dispatch<H>(&self, stream: Connection, id: u64, handler: &Arc<H>, live: LiveConnection)
+    where H: Fn(Connection, ConnectionInfo) + Send + Sync + 'static
+    {
+        // keep a second handle on the socket so we can still turn the client away if the queue is full
+        let overflow = stream.try_clone();
+        // while we can still see the socket, wrapping hides it
+        let local_addr = stream.local_addr();
+        let handler = Arc::clone(handler);
+        let wrapper = self.wrapper.clone();
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let proxy_protocol = self.proxy_protocol;
+        let job = move || {
+            let _live = live;
+            // read on the worker, a slow balancer mustn't hold up the accept loop
+            let stream = if proxy_protocol {
+                match behind_proxy(stream) {
+                    Some(stream) => stream,
+                    None => return,
+                }
+            } else {
+                stream
+            };
+            #[cfg(feature = "tls")]
+            let stream = match &tls {
+                None => stream,
+                Some(config) => {
+                    let peer = stream.to_string();
+                    match TlsStream::accept(stream, config) {
+                        Ok(stream) => Connection::Tls(Box::new(stream)),
+                        Err(e) => {
+                            log!(Warn, "Dropping connection from {}, TLS handshake failed: {}", peer, e);
+                            return;
+                        }
+                    }
+                }
+            };
+            let stream = match wrapper {
+                None => stream,
+                Some(wrapper) => {
+                    let peer = stream.to_string();
+                    match wrapper(stream) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log!(Warn, "Dropping connection from {}: {}", peer, e);
+                            return;
+                        }
+                    }
+                }
+            };
+            let info = ConnectionInfo {
+                id,
+                peer_addr: stream.peer_addr(),
+                local_addr,
+                tls: stream.tls().cloned(),
+                peer_identity: stream.peer_identity().map(str::to_string),
+            };
+            handler(stream, info);
+        };
+        if self.pool.try_execute(job).is_err() {
+            log!(Warn, "Queue full, turning a client away");
+            match overflow {
+                Ok(stream) => self.turn_away(stream),
+                Err(e) => log!(Error, "Queue full and failed to clone stream: {}", e),
+            }
+        }
+    }
+
+    pub(crate) fn turn_away(&self, mut stream: Connection) {
+        // a 503 is an answer as much as any other
+        self.open.count_request();
+        // the client expects whatever the wrapper speaks, so it only gets the close
+        if self.wrapper.is_none() && !self.is_tls() {
+            service_unavailable(&mut stream, &self.doc_root, &self.http);
+        }
+    }
+
+    // Whether clients expect a TLS handshake rather than plain HTTP
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop accepting, connections it already took still get served
+    pub fn shutdown(&self) {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for addr in &self.addrs {
+            if let Err(e) = addr.wake() {
+                log!(Warn, "Failed to wake the accept loop on {} for shutdown: {}", addr, e);
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+}
+
+impl RunningServer {
+    /// A handle for stopping the server from yet another thread, e.g. a signal handler
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handle.clone()
+    }
+
+    /// Stop accepting and wait for the connections already accepted to be served
+    ///
+    /// # Errors
+    /// Returns the panic if the accept thread panicked
+    pub fn shutdown(self) -> thread::Result<()> {
+        self.handle.shutdown();
+        self.join()
+    }
+
+    /// Wait for the server to stop, after a ShutdownHandle was used or max_requests were served
+    ///
+    /// # Errors
+    /// Returns the panic if the accept thread panicked
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+// 7878 spells out rust on a phone
+const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+// Long enough for handlers whose sockets were just closed to notice and return
+const FORCE_CLOSE_WAIT: Duration = Duration::from_secs(1);
+// How long shutdown can take to reach an acceptor the wake-up connection missed
+pub(crate) const ACCEPT_POLL: Duration = Duration::from_millis(250);
+
+// Take the PROXY protocol header off 'stream' and make the client it names the peer
+// None if there's no valid header and the connection should be dropped
+fn behind_proxy(mut stream: Connection) -> Option<Connection> {
+    match proxy::read_header(&mut stream) {
+        Ok(Some(client)) => {
+            log!(Debug, "{} is relaying for {}", stream, client);
+            Some(Connection::wrapped(stream, Some(client)))
+        }
+        // the balancer's own health check
+        Ok(None) => Some(stream),
+        Err(e) => {
+            log!(Warn, "Dropping connection from {}, no PROXY protocol header: {}", stream, e);
+            None
+        }
+    }
+}
+
+fn web_pool(threads: usize, queue_len: usize, job_timeout: Option<Duration>) -> io::Result<ThreadPool> {
+    // cap the backlog so a flood can't eat all our memory
+    let builder = ThreadPool::builder().num_threads(threads).queue_len(queue_len).thread_name("web-worker");
+    match job_timeout {
+        Some(limit) => builder.job_timeout(limit).build(),
+        None => builder.build(),
+    }
+}
+
+// The server is backed up, tell the client to come back later instead of queueing forever
+fn service_unavailable(stream: &mut Connection, doc_root: &Path, http: &HttpOptions) {
+    let response = handler::error_response(StatusCode::ServiceUnavailable, doc_root, http).with_header("Retry-After", "1");
+    if let Err(e) = handler::stamp(response, http, false).write_to(stream) {
+        log!(Error, "Failed to write 503 response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TlsInfo;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    #[test]
+    fn test_serve_runs_handler() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve(|mut stream| {
+                stream.write_all(b"hello").unwrap();
+            })
+        });
+
+        for _ in 0..2 {
+            let mut reply = String::new();
+            TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, "hello");
+        }
+        shutdown.shutdown();
+        assert!(shutdown.is_shutdown());
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_builder_serves_doc_root() {
+        let root = std::env::temp_dir().join(format!("webserver-root-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "custom index").unwrap();
+        std::fs::write(root.join("404.html"), "custom 404").unwrap();
+
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).doc_root(&root).max_requests(2).build().unwrap();
+        assert_eq!(server.doc_root(), root);
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve_static());
+
+        let get = |request: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert!(get(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").ends_with("\r\n\r\ncustom index"));
+        assert!(get(b"GET /nope HTTP/1.1\r\nHost: h\r\nConnection: close\r\n\r\n").starts_with("HTTP/1.1 404"));
+        serving.join().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_listeners_share_the_pool() {
+        let server = Server::builder().addr("127.0.0.1:0").addr("127.0.0.1:0").threads(1).max_requests(2).build().unwrap();
+        let addrs = server.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        let serving = thread::spawn(move || {
+            server.serve(|mut stream| {
+                let name = thread::current().name().map(str::to_string).unwrap_or_default();
+                stream.write_all(name.as_bytes()).unwrap();
+            })
+        });
+
+        for addr in addrs {
+            let ListenAddr::Tcp(addr) = addr else { panic!("expected a TCP listener, got {}", addr) };
+            let mut reply = String::new();
+            TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, "web-worker-0");
+        }
+        serving.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_multiple_acceptors() {
+        let server = Server::builder().addr("127.0.0.1:0").acceptors(4).build().unwrap();
+        assert_eq!(server.listeners.len(), 4);
+        assert_eq!(server.local_addrs().unwrap().len(), 1);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve(|mut stream| stream.write_all(b"hi").unwrap()));
+
+        for _ in 0..20 {
+            let mut reply = String::new();
+            TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, "hi");
+        }
+        // the acceptors the wake-up connection misses notice on their accept timeout
+        let started = std::time::Instant::now();
+        shutdown.shutdown();
+        serving.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_shutdown_drains_connections() {
+        let root = std::env::temp_dir().join(format!("webserver-drain-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hi").unwrap();
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).doc_root(&root).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = server.spawn_static().unwrap();
+
+        // a keep-alive client sitting between requests gets closed without waiting out the grace period
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = Vec::new();
+        while !reply.ends_with(b"hi") {
+            let mut buf = [0; 256];
+            let n = idle.read(&mut buf).unwrap();
+            reply.extend_from_slice(&buf[..n]);
+        }
+        assert!(String::from_utf8_lossy(&reply).contains("Connection: keep-alive"));
+        let started = std::time::Instant::now();
+        running.shutdown().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // one that never finishes is closed under its handler once the grace period is up
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).grace_period(Duration::from_millis(100)).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = server
+            .spawn(|mut stream| {
+                let _ = stream.write_all(b"started");
+                let _ = stream.read_to_end(&mut Vec::new());
+            })
+            .unwrap();
+        let mut stuck = TcpStream::connect(addr).unwrap();
+        stuck.read_exact(&mut [0; 7]).unwrap();
+        let started = std::time::Instant::now();
+        running.shutdown().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(stuck.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_silent_client_times_out() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .max_requests(1)
+            .read_timeout(Some(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve_static());
+
+        // connect and say nothing
+        let mut reply = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 408"), "{}", reply);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_outlasts_read_timeout() {
+        let echo = |_: &Request, mut upgraded: Upgraded<'_>| {
+            if upgraded.switch(crate::Response::new(StatusCode::SwitchingProtocols)).is_ok() {
+                let mut line = [0; 5];
+                upgraded.read_exact(&mut line).unwrap();
+                upgraded.write_all(&line).unwrap();
+            }
+        };
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .max_requests(1)
+            .read_timeout(Some(Duration::from_millis(50)))
+            .upgrade("echo", echo)
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve_static());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: h\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n").unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 "));
+        // well past the read timeout HTTP requests get
+        thread::sleep(Duration::from_millis(300));
+        stream.write_all(b"hello").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "hello");
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let server = Server::builder().addr("127.0.0.1:0").threads(2).max_connections(1).max_requests(2).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            // hold the connection open until the client hangs up its side
+            server.serve(|mut stream| {
+                stream.read_to_end(&mut Vec::new()).unwrap();
+                stream.write_all(b"ok").unwrap();
+            })
+        });
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut reply = String::new();
+        TcpStream::connect(addr).unwrap().read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 503"), "{}", reply);
+        assert!(reply.contains("Retry-After"));
+
+        first.shutdown(std::net::Shutdown::Write).unwrap();
+        reply.clear();
+        first.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "ok");
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_requests_counts_requests() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).doc_root(&root).max_requests(2).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || server.serve_static());
+
+        // both on one keep-alive connection, after which the server stops by itself
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: h\r\n\r\nGET /missing HTTP/1.1\r\nHost: h\r\n\r\n").unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200") && reply.contains("HTTP/1.1 404"), "{}", reply);
+        serving.join().unwrap();
+    }
+
+    // A TLS client trusting the test CA, connected to 'addr' as localhost, showing the
+    // certificate in testdata/tls/'name'.pem if there is a name
+    #[cfg(feature = "tls")]
+    fn tls_client(addr: SocketAddr, name: Option<&str>) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        tls_client_with(addr, tls_config(name))
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_client_with(addr: SocketAddr, config: rustls::ClientConfig) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+        let conn = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap())
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_config(name: Option<&str>) -> rustls::ClientConfig {
+        use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(dir.join("ca.pem")).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        match name {
+            Some(name) => {
+                let certs = CertificateDer::pem_file_iter(dir.join(format!("{}.pem", name))).unwrap().map(Result::unwrap).collect();
+                let key = PrivateKeyDer::from_pem_file(dir.join(format!("{}.key", name))).unwrap();
+                config.with_client_auth_cert(certs, key).unwrap()
+            }
+            None => config.with_no_client_auth(),
+        }
+    }
+
+    // GET / over HTTP/2 on 'stream', which the server already knows to expect it on, and the
+    // response's header block and body
+    fn h2_get(stream: &mut (impl Read + Write)) -> (Vec<u8>, Vec<u8>) {
+        let mut block = Vec::new();
+        for (name, value) in [(":method", "GET"), (":scheme", "https"), (":path", "/"), (":authority", "localhost")] {
+            block.extend_from_slice(&[&[0, name.len() as u8][..], name.as_bytes(), &[value.len() as u8], value.as_bytes()].concat());
+        }
+        let mut out = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0".to_vec();
+        // HEADERS, END_STREAM and END_HEADERS, on stream 1
+        out.extend_from_slice(&[&(block.len() as u32).to_be_bytes()[1..], &[1, 0x5, 0, 0, 0, 1], &block].concat());
+        stream.write_all(&out).unwrap();
+        let (mut head, mut body) = (Vec::new(), Vec::new());
+        loop {
+            let mut frame = [0; 9];
+            stream.read_exact(&mut frame).unwrap();
+            let mut payload = vec![0; u32::from_be_bytes([0, frame[0], frame[1], frame[2]]) as usize];
+            stream.read_exact(&mut payload).unwrap();
+            match (frame[3], frame[8]) {
+                (1, 1) => head = payload,
+                (0, 1) => body.extend_from_slice(&payload),
+                _ => continue,
+            }
+            if frame[4] & 0x1 != 0 {
+                return (head, body);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http2() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+        let index = std::fs::read(root.join("index.html")).unwrap();
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).doc_root(&root).http2(true).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = server.spawn_static().unwrap();
+        // prior knowledge, h2c
+        let (head, body) = h2_get(&mut TcpStream::connect(addr).unwrap());
+        assert!(head.starts_with(b"\0\x07:status\x03200"));
+        assert_eq!(body, index);
+        running.shutdown().unwrap();
+
+        // and over TLS, for a client picking h2 with ALPN
+        #[cfg(feature = "tls")]
+        {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+            let builder = Server::builder().addr("127.0.0.1:0").threads(1).doc_root(&root).http2(true);
+            let server = builder.tls(dir.join("server.pem"), dir.join("server.key")).build().unwrap();
+            let addr = server.local_addr().unwrap();
+            let running = server.spawn_static().unwrap();
+            let mut config = tls_config(None);
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            let mut client = tls_client_with(addr, config);
+            let (head, body) = h2_get(&mut client);
+            assert_eq!(client.conn.alpn_protocol(), Some(&b"h2"[..]));
+            assert!(head.starts_with(b"\0\x07:status\x03200"));
+            assert_eq!(body, index);
+            drop(client);
+            running.shutdown().unwrap();
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .max_requests(3)
+            .tls(dir.join("server.pem"), dir.join("server.key"))
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve_with_info(|stream, info| {
+                let tls = info.tls.clone().unwrap();
+                assert_eq!((tls.version.as_str(), tls.alpn), ("TLSv1.3", None));
+                assert!(matches!(stream, Connection::Tls(_)) && stream.peer_addr().is_some());
+                handle_site(stream, &VirtualHosts::new(Path::new(env!("CARGO_MANIFEST_DIR")).join("static")), &HttpOptions::default(), None);
+            })
+        });
+
+        for _ in 0..2 {
+            let mut client = tls_client(addr, None);
+            client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            let mut reply = String::new();
+            // ends with a close_notify, so a cut off response would be an error
+            client.read_to_string(&mut reply).unwrap();
+            assert!(reply.starts_with("HTTP/1.1 200 OK\r\n") && reply.contains("</html>"), "{}", reply);
+        }
+        // a plain HTTP client is dropped without an answer it could mistake for one
+        let mut plain = TcpStream::connect(addr).unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reply = Vec::new();
+        let _ = plain.read_to_end(&mut reply);
+        assert!(!reply.starts_with(b"HTTP/"));
+        serving.join().unwrap();
+
+        let missing = Server::builder().addr("127.0.0.1:0").tls(dir.join("missing.pem"), dir.join("server.key")).build();
+        assert_eq!(missing.err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+        let swapped = Server::builder().addr("127.0.0.1:0").tls(dir.join("server.key"), dir.join("server.pem")).build();
+        assert_eq!(swapped.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_tls_client_certificates() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        let serve = |optional: bool, connections: usize| {
+            let server = Server::builder()
+                .addr("127.0.0.1:0")
+                .threads(1)
+                .max_requests(connections)
+                .tls(dir.join("server.pem"), dir.join("server.key"))
+                .tls_client_ca(dir.join("ca.pem"))
+                .tls_client_optional(optional)
+                .build()
+                .unwrap();
+            let addr = server.local_addr().unwrap();
+            let serving = thread::spawn(move || {
+                server.serve_with_info(|mut stream, info| {
+                    assert_eq!(stream.peer_identity(), info.peer_identity.as_deref());
+                    let _ = stream.write_all(info.peer_identity.unwrap_or_else(|| "anonymous".to_string()).as_bytes());
+                })
+            });
+            (addr, serving)
+        };
+        let talk = |addr, name| {
+            let mut client = tls_client(addr, name);
+            let mut reply = String::new();
+            client.write_all(b"hello").and_then(|()| client.read_to_string(&mut reply)).map(|_| reply)
+        };
+
+        let (addr, serving) = serve(false, 2);
+        assert_eq!(talk(addr, Some("client")).unwrap(), "CN=test client,O=Example\\, Ltd.,C=GB");
+        // no certificate, no handshake
+        assert!(talk(addr, None).is_err());
+        serving.join().unwrap();
+
+        let (addr, serving) = serve(true, 2);
+        assert_eq!(talk(addr, None).unwrap(), "anonymous");
+        assert_eq!(talk(addr, Some("client")).unwrap(), "CN=test client,O=Example\\, Ltd.,C=GB");
+        serving.join().unwrap();
+
+        let without_cert = Server::builder().addr("127.0.0.1:0").tls_client_ca(dir.join("ca.pem")).build();
+        assert_eq!(without_cert.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn test_wrapped_connections() {
+        // flips the case of everything the handler writes, a stand-in for encrypting it
+        struct Flip(Connection);
+        impl Read for Flip {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Write for Flip {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let flipped: Vec<u8> = buf.iter().map(|b| if b.is_ascii_lowercase() { b.to_ascii_uppercase() } else { b.to_ascii_lowercase() }).collect();
+                self.0.write(&flipped)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush()
+            }
+        }
+
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .max_requests(2)
+            .wrap_connections(|conn| {
+                let peer = conn.peer_addr();
+                let mut probe = [0; 1];
+                // reject clients that open with a 'x' like a failed handshake would
+                match conn.try_clone()?.read(&mut probe)? {
+                    1 if probe[0] == b'x' => Err(io::Error::new(io::ErrorKind::InvalidData, "bad hello")),
+                    _ => Ok(Connection::wrapped(Flip(conn), peer).with_peer_identity("CN=test client")),
+                }
+            })
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve(|mut stream| {
+                assert!(stream.peer_addr().is_some());
+                assert_eq!(stream.peer_identity(), Some("CN=test client"));
+                stream.write_all(b"Hello").unwrap();
+            })
+        });
+
+        let talk = |hello: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(hello).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert_eq!(talk(b"h"), "hELLO");
+        assert_eq!(talk(b"x"), "");
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let server = Server::builder()
+            .addr("127.0.0.1:0")
+            .threads(1)
+            .max_requests(2)
+            .wrap_connections(|conn| {
+                let peer = conn.peer_addr();
+                let tls = TlsInfo { version: "TLSv1.3".to_string(), cipher: "TLS13_AES_128_GCM_SHA256".to_string(), alpn: None };
+                Ok(Connection::wrapped(conn, peer).with_tls(tls))
+            })
+            .build()
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve_with_info(move |mut stream, info| {
+                assert_eq!(info.local_addr, Some(ListenAddr::Tcp(addr)));
+                assert_eq!(info.tls.unwrap().version, "TLSv1.3");
+                assert_eq!(info.peer_identity, None);
+                write!(stream, "{} {}", info.id, info.peer_addr.unwrap()).unwrap();
+            })
+        });
+
+        for id in 1..=2 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert_eq!(reply, format!("{} {}", id, stream.local_addr().unwrap()));
+        }
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let server = Server::builder().addr("127.0.0.1:0").threads(1).max_requests(2).proxy_protocol(true).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve(|mut stream| {
+                let peer = stream.peer_addr().unwrap();
+                let mut request = [0; 5];
+                stream.read_exact(&mut request).unwrap();
+                write!(stream, "{} {}", peer, String::from_utf8_lossy(&request)).unwrap();
+            })
+        });
+
+        let talk = |hello: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(hello).unwrap();
+            let mut reply = String::new();
+            // closing with the request unread resets the connection, which is fine for the rejected one
+            let _ = stream.read_to_string(&mut reply);
+            reply
+        };
+        assert_eq!(talk(b"PROXY TCP4 203.0.113.7 127.0.0.1 56324 80\r\nGET /"), "203.0.113.7:56324 GET /");
+        // straight from a client, not through the balancer
+        assert_eq!(talk(b"GET / HTTP/1.1\r\nHost: h\r\n\r\n"), "");
+        serving.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_only() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("webserver-{}.sock", std::process::id()));
+        // a leftover socket from a crashed run shouldn't stop us binding
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = Server::builder().unix_socket(&path).threads(1).build().unwrap();
+        assert_eq!(server.local_addrs().unwrap(), [ListenAddr::Unix(path.clone())]);
+        assert_eq!(server.local_addr().unwrap_err().kind(), io::ErrorKind::NotFound);
+        let shutdown = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || {
+            server.serve(|mut stream| {
+                let peer = stream.to_string();
+                stream.write_all(peer.as_bytes()).unwrap();
+            })
+        });
+
+        let mut reply = String::new();
+        UnixStream::connect(&path).unwrap().read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "unix socket client");
+        shutdown.shutdown();
+        serving.join().unwrap();
+        assert!(!path.exists());
+    }
+}
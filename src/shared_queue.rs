@@ -0,0 +1,78 @@
+use std::time::Instant;
+
+use crossbeam_channel::{Receiver, Select, Sender};
+
+use crate::{Job, Priority};
+
+// The other way to hand out jobs, set with ThreadPoolBuilder::shared_queue: one crossbeam
+// channel per priority that every worker receives from. Nothing is stolen since nothing
+// belongs to one worker, and a push wakes a single waiting receiver without a shared lock.
+// Control messages still go to each worker's own queue, which rings its Bell.
+pub(crate) struct SharedQueue {
+    lanes: [(Sender<Job>, Receiver<Job>); Priority::COUNT],
+}
+
+// Wakes one worker blocked on the shared queue when a control message arrives for it
+// At most one ring is kept, a worker checks its control messages after every wakeup anyway
+pub(crate) struct Bell {
+    ring: Sender<()>,
+    rung: Receiver<()>,
+}
+
+impl Bell {
+    pub(crate) fn new() -> Bell {
+        let (ring, rung) = crossbeam_channel::bounded(1);
+        Bell { ring, rung }
+    }
+
+    pub(crate) fn ring(&self) {
+        // full means it's already ringing
+        let _ = self.ring.try_send(());
+    }
+}
+
+impl SharedQueue {
+    pub(crate) fn new() -> SharedQueue {
+        SharedQueue { lanes: std::array::from_fn(|_| crossbeam_channel::unbounded()) }
+    }
+
+    pub(crate) fn push(&self, job: Job, priority: Priority) {
+        // we hold a receiver ourselves, so the channel can't be disconnected
+        let _ = self.lanes[priority.index()].0.send(job);
+    }
+
+    // The oldest job of the most urgent priority waiting
+    pub(crate) fn try_pop(&self) -> Option<Job> {
+        self.lanes.iter().find_map(|(_, jobs)| jobs.try_recv().ok())
+    }
+
+    // Block until a job is waiting or 'bell' rings, false if 'deadline' passed first
+    // Another worker may take the job before we get to it, callers just look again
+    pub(crate) fn wait(&self, bell: &Bell, deadline: Option<Instant>) -> bool {
+        let mut select = Select::new();
+        for (_, jobs) in &self.lanes {
+            select.recv(jobs);
+        }
+        let rung = select.recv(&bell.rung);
+        let ready = match deadline {
+            None => select.ready(),
+            Some(deadline) => match select.ready_deadline(deadline) {
+                Ok(ready) => ready,
+                Err(_) => return false,
+            },
+        };
+        if ready == rung {
+            let _ = bell.rung.try_recv();
+        }
+        true
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.lanes.iter().map(|(_, jobs)| jobs.len()).sum()
+    }
+
+    // Throw away every queued job, returns how many there were
+    pub(crate) fn drain(&self) -> usize {
+        self.lanes.iter().map(|(_, jobs)| jobs.try_iter().count()).sum()
+    }
+}
@@ -0,0 +1,116 @@
+use std::{
+    io::{self, Read},
+    os::{
+        raw::c_int,
+        unix::{io::IntoRawFd, net::UnixStream},
+    },
+    process,
+    sync::{atomic::{AtomicI32, Ordering}, Mutex},
+    thread,
+};
+
+use crate::{logging::log, Server, ShutdownHandle};
+
+// Same numbers on Linux and the BSDs
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+const SIG_ERR: usize = usize::MAX;
+
+// signal and write come from libc which std already links, so no extra crate is needed
+unsafe extern "C" {
+    fn signal(signum: c_int, handler: usize) -> usize;
+    fn write(fd: c_int, buf: *const u8, count: usize) -> isize;
+}
+
+// Write end of the socket the handler pokes, -1 until the handlers are installed
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+// Servers to stop on the next signal
+static HANDLES: Mutex<Vec<ShutdownHandle>> = Mutex::new(Vec::new());
+static INSTALLED: Mutex<bool> = Mutex::new(false);
+
+impl Server {
+    /// Stop accepting on SIGINT (Ctrl-C) or SIGTERM, serve then lets the requests already
+    /// accepted finish before returning. A second signal exits right away
+    /// Only Unix for now
+    ///
+    /// # Errors
+    /// Returns the error if the handlers or the thread watching for them can't be set up
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        let handle = self.shutdown_handle()?;
+        install()?;
+        HANDLES.lock().unwrap_or_else(|e| e.into_inner()).push(handle);
+        Ok(())
+    }
+}
+
+fn install() -> io::Result<()> {
+    let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+    if *installed {
+        return Ok(());
+    }
+    // a signal handler can hardly do anything safely, so it just wakes this thread up
+    let (sender, receiver) = UnixStream::pair()?;
+    sender.set_nonblocking(true)?;
+    thread::Builder::new().name("signal-watcher".to_string()).spawn(move || watch(receiver))?;
+    WAKE_FD.store(sender.into_raw_fd(), Ordering::SeqCst);
+    for signum in [SIGINT, SIGTERM] {
+        // SAFETY: on_signal only loads an atomic and calls write, both async-signal-safe
+        if unsafe { signal(signum, on_signal as *const () as usize) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    *installed = true;
+    Ok(())
+}
+
+extern "C" fn on_signal(_signum: c_int) {
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let byte = 1u8;
+        // SAFETY: fd is the write end we leaked in install, nonblocking so a flood of signals can't hang us
+        unsafe { write(fd, &byte, 1) };
+    }
+}
+
+fn watch(mut receiver: UnixStream) {
+    let mut byte = [0u8];
+    let mut signals = 0;
+    loop {
+        match receiver.read(&mut byte) {
+            Ok(1) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            _ => return,
+        }
+        signals += 1;
+        if signals > 1 {
+            log!(Warn, "Got a second signal, exiting without waiting for in-flight requests");
+            process::exit(130);
+        }
+        log!(Info, "Got a signal, finishing in-flight requests (signal again to quit now)");
+        for handle in HANDLES.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+            handle.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" {
+        fn raise(signum: c_int) -> c_int;
+    }
+
+    #[test]
+    fn test_sigterm_stops_server() {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        server.shutdown_on_signals().unwrap();
+        let handle = server.shutdown_handle().unwrap();
+        let serving = thread::spawn(move || server.serve(|_| {}));
+
+        // SAFETY: our handler is installed, so this doesn't take the test process down
+        assert_eq!(unsafe { raise(SIGTERM) }, 0);
+        serving.join().unwrap();
+        assert!(handle.is_shutdown());
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::logging::log;
+
+// How listening sockets get set up, ServerBuilder fills it in
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ListenOptions {
+    // IPv6 listeners take IPv4 clients too unless this is set, whatever the OS default is
+    pub(crate) ipv6_only: bool,
+    pub(crate) reuse_address: bool,
+    pub(crate) reuse_port: bool,
+    pub(crate) backlog: u32,
+    // accept() gives up with WouldBlock after this long, so the loop can check for shutdown
+    // on listeners a wake-up connection might never reach
+    pub(crate) accept_timeout: Option<Duration>,
+}
+
+impl Default for ListenOptions {
+    fn default() -> Self {
+        // the same as std's TcpListener::bind on Unix
+        ListenOptions { ipv6_only: false, reuse_address: true, reuse_port: false, backlog: 128, accept_timeout: None }
+    }
+}
+
+/// Bind the first address 'addr' resolves to, like TcpListener::bind
+pub(crate) fn bind(addr: &str, options: ListenOptions) -> io::Result<TcpListener> {
+    let resolved: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    if resolved.len() > 1 {
+        log!(Debug, "{} resolved to {:?}, using the first one that binds", addr, resolved);
+    }
+    let mut last_err = None;
+    for addr in resolved {
+        match bind_addr(addr, options) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_addr(addr: SocketAddr, options: ListenOptions) -> io::Result<TcpListener> {
+    // no raw socket setup here yet, so the OS defaults win
+    let defaults = ListenOptions::default();
+    if options.reuse_port != defaults.reuse_port
+        || options.backlog != defaults.backlog
+        || options.accept_timeout != defaults.accept_timeout
+    {
+        log!(Warn, "Listen socket options are only supported on Linux, using the defaults");
+    }
+    TcpListener::bind(addr)
+}
+
+/// Make accept() on 'listener' give up with WouldBlock after 'timeout'
+#[cfg(target_os = "linux")]
+pub(crate) use sys::set_accept_timeout;
+
+#[cfg(target_os = "linux")]
+fn bind_addr(addr: SocketAddr, options: ListenOptions) -> io::Result<TcpListener> {
+    sys::bind(addr, options)
+}
+
+// std can't set socket options before bind, so on Linux we build the socket ourselves
+// The calls come from libc which std already links, so no extra crate is needed
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io,
+        net::{SocketAddr, TcpListener},
+        os::{fd::{AsRawFd, FromRawFd}, raw::{c_int, c_long}},
+        time::Duration,
+    };
+
+    use super::ListenOptions;
+
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+    const SOCK_STREAM: c_int = 1;
+    const SOCK_CLOEXEC: c_int = 0o2000000;
+    const SOL_SOCKET: c_int = 1;
+    const SO_REUSEADDR: c_int = 2;
+    const SO_REUSEPORT: c_int = 15;
+    const SO_RCVTIMEO: c_int = 20;
+    const IPPROTO_IPV6: c_int = 41;
+    const IPV6_V6ONLY: c_int = 26;
+
+    #[repr(C)]
+    struct SockaddrIn {
+        family: u16,
+        port: u16,
+        addr: [u8; 4],
+        zero: [u8; 8],
+    }
+
+    #[repr(C)]
+    struct SockaddrIn6 {
+        family: u16,
+        port: u16,
+        flowinfo: u32,
+        addr: [u8; 16],
+        scope_id: u32,
+    }
+
+    #[repr(C)]
+    struct Timeval {
+        sec: c_long,
+        usec: c_long,
+    }
+
+    unsafe extern "C" {
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const u8, len: u32) -> c_int;
+        #[link_name = "bind"]
+        fn bind_fd(fd: c_int, addr: *const u8, len: u32) -> c_int;
+        fn listen(fd: c_int, backlog: c_int) -> c_int;
+    }
+
+    fn check(ret: c_int) -> io::Result<()> {
+        if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    fn set_option(listener: &TcpListener, level: c_int, name: c_int, value: bool) -> io::Result<()> {
+        let value = c_int::from(value);
+        // SAFETY: value lives across the call and len matches its size
+        check(unsafe { setsockopt(listener.as_raw_fd(), level, name, (&value as *const c_int).cast(), size_of::<c_int>() as u32) })
+    }
+
+    // SO_RCVTIMEO, which Linux applies to accept() too
+    pub(crate) fn set_accept_timeout(listener: &impl AsRawFd, timeout: Duration) -> io::Result<()> {
+        let value = Timeval {
+            sec: c_long::try_from(timeout.as_secs()).unwrap_or(c_long::MAX),
+            // under a million, fits any c_long
+            usec: timeout.subsec_micros() as c_long,
+        };
+        // SAFETY: value lives across the call and len matches its size
+        check(unsafe {
+            setsockopt(listener.as_raw_fd(), SOL_SOCKET, SO_RCVTIMEO, (&value as *const Timeval).cast(), size_of::<Timeval>() as u32)
+        })
+    }
+
+    pub(super) fn bind(addr: SocketAddr, options: ListenOptions) -> io::Result<TcpListener> {
+        let family = if addr.is_ipv6() { AF_INET6 } else { AF_INET };
+        // SAFETY: plain socket(2) call, the result is checked before use
+        let fd = unsafe { socket(c_int::from(family), SOCK_STREAM | SOCK_CLOEXEC, 0) };
+        check(fd)?;
+        // SAFETY: fd is a fresh socket we own, from here on the listener closes it even on error
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+        // on by default like std, so a restarted server doesn't wait out TIME_WAIT
+        set_option(&listener, SOL_SOCKET, SO_REUSEADDR, options.reuse_address)?;
+        if options.reuse_port {
+            set_option(&listener, SOL_SOCKET, SO_REUSEPORT, true)?;
+        }
+        if let Some(timeout) = options.accept_timeout {
+            set_accept_timeout(&listener, timeout)?;
+        }
+        let ret = match addr {
+            SocketAddr::V4(v4) => {
+                let raw = SockaddrIn { family, port: v4.port().to_be(), addr: v4.ip().octets(), zero: [0; 8] };
+                // SAFETY: raw is a sockaddr_in and len is its size
+                unsafe { bind_fd(fd, (&raw as *const SockaddrIn).cast(), size_of::<SockaddrIn>() as u32) }
+            }
+            SocketAddr::V6(v6) => {
+                set_option(&listener, IPPROTO_IPV6, IPV6_V6ONLY, options.ipv6_only)?;
+                let raw = SockaddrIn6 {
+                    family,
+                    port: v6.port().to_be(),
+                    flowinfo: v6.flowinfo().to_be(),
+                    addr: v6.ip().octets(),
+                    scope_id: v6.scope_id(),
+                };
+                // SAFETY: raw is a sockaddr_in6 and len is its size
+                unsafe { bind_fd(fd, (&raw as *const SockaddrIn6).cast(), size_of::<SockaddrIn6>() as u32) }
+            }
+        };
+        check(ret)?;
+        let backlog = c_int::try_from(options.backlog).unwrap_or(c_int::MAX);
+        // SAFETY: fd is a bound stream socket
+        check(unsafe { listen(listener.as_raw_fd(), backlog) })?;
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_bind_ipv4() {
+        let listener = bind("127.0.0.1:0", ListenOptions::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv4());
+        TcpStream::connect(addr).unwrap();
+        listener.accept().unwrap();
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        // not every box has IPv6, nothing to check there
+        let Ok(listener) = bind("[::]:0", ListenOptions::default()) else { return };
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        // IPv4 clients show up as ::ffff:a.b.c.d
+        assert_eq!(peer.ip().to_canonical().to_string(), "127.0.0.1");
+
+        let Ok(listener) = bind("[::]:0", ListenOptions { ipv6_only: true, ..ListenOptions::default() }) else { return };
+        let port = listener.local_addr().unwrap().port();
+        if cfg!(target_os = "linux") {
+            assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuse_port() {
+        let options = ListenOptions { reuse_port: true, backlog: 16, ..ListenOptions::default() };
+        let first = bind("127.0.0.1:0", options).unwrap();
+        let addr = first.local_addr().unwrap().to_string();
+        // a second socket can share the port only if both asked for it
+        let second = bind(&addr, options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), first.local_addr().unwrap());
+        assert!(bind(&addr, ListenOptions::default()).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_accept_timeout() {
+        let options = ListenOptions { accept_timeout: Some(Duration::from_millis(50)), ..ListenOptions::default() };
+        let listener = bind("127.0.0.1:0", options).unwrap();
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
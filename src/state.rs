@@ -0,0 +1,94 @@
+use std::{any::Any, cell::RefCell, sync::Arc};
+
+use crate::{JobHandle, ThreadPool};
+
+/// Builds the state each worker hands to its jobs, called with the worker id on the worker's own thread
+pub type StateFactory = Arc<dyn Fn(usize) -> Box<dyn Any> + Send + Sync>;
+
+thread_local! {
+    // only ever set on pool worker threads
+    static WORKER_STATE: RefCell<Option<Box<dyn Any>>> = const { RefCell::new(None) };
+}
+
+// Called once when a worker thread starts
+pub(crate) fn init_worker_state(factory: &StateFactory, id: usize) {
+    let state = factory(id);
+    WORKER_STATE.with(|cell| *cell.borrow_mut() = Some(state));
+}
+
+/// Run 'f' with the current worker's state, set up by ThreadPoolBuilder::worker_state
+/// Returns None when not called from a worker, or the state isn't an S
+pub fn with_worker_state<S, R>(f: impl FnOnce(&mut S) -> R) -> Option<R>
+where S: 'static
+{
+    // take the state out while f runs so a nested call sees None instead of a double borrow,
+    // and put it back even if f panics
+    struct Restore(Option<Box<dyn Any>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let state = self.0.take();
+            WORKER_STATE.with(|cell| *cell.borrow_mut() = state);
+        }
+    }
+
+    let mut restore = Restore(WORKER_STATE.with(|cell| cell.borrow_mut().take()));
+    let state = restore.0.as_mut()?.downcast_mut::<S>()?;
+    Some(f(state))
+}
+
+impl ThreadPool {
+    /// Like 'execute' but the job gets a mutable borrow of the state of whichever worker runs it
+    ///
+    /// # Panics
+    /// The job panics (and its handle reports JobError::Lost) if the pool wasn't
+    /// built with a worker_state of type S
+    pub fn execute_with_state<S, F, T>(&self, f: F) -> JobHandle<T>
+    where
+        S: 'static,
+        F: FnOnce(&mut S) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.execute(move || {
+            with_worker_state(f).unwrap_or_else(|| {
+                panic!("worker has no state of type {}", std::any::type_name::<S>())
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{JobError, ThreadPoolBuilder};
+
+    #[test]
+    fn test_worker_state_is_reused() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .worker_state(|_| Vec::<u8>::with_capacity(8192))
+            .build()
+            .unwrap();
+
+        pool.execute_with_state(|buffer: &mut Vec<u8>| buffer.extend_from_slice(b"abc")).join().unwrap();
+        // same worker, same buffer
+        let seen = pool.execute_with_state(|buffer: &mut Vec<u8>| (buffer.len(), buffer.capacity())).join();
+        assert_eq!(seen, Ok((3, 8192)));
+    }
+
+    #[test]
+    fn test_worker_state_wrong_type() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .worker_state(|id| id)
+            .panic_hook(|_, _| {})
+            .build()
+            .unwrap();
+        assert_eq!(pool.execute_with_state(|_: &mut String| ()).join(), Err(JobError::Lost));
+        assert_eq!(pool.execute_with_state(|id: &mut usize| *id).join(), Ok(0));
+    }
+
+    #[test]
+    fn test_no_worker_state_off_pool() {
+        assert_eq!(with_worker_state(|_: &mut usize| ()), None);
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Snapshot of what the pool is doing, from ThreadPool::stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Jobs a worker is running right now
+    pub in_flight: usize,
+    /// Jobs that ran to completion, including ones that panicked
+    pub completed: u64,
+    /// Jobs that panicked
+    pub panicked: u64,
+    pub workers: Vec<WorkerStats>,
+}
+
+/// Per worker numbers, reset when a worker is respawned
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStats {
+    pub id: usize,
+    pub busy: bool,
+    pub jobs_run: u64,
+    /// Time spent inside jobs
+    pub busy_time: Duration,
+    /// Time since the worker was spawned
+    pub uptime: Duration,
+}
+
+impl WorkerStats {
+    /// Fraction of its lifetime this worker has spent running jobs, 0.0 to 1.0
+    pub fn utilization(&self) -> f64 {
+        if self.uptime.is_zero() {
+            return 0.0;
+        }
+        (self.busy_time.as_secs_f64() / self.uptime.as_secs_f64()).min(1.0)
+    }
+}
+
+/// Latency percentiles over the most recent jobs, from ThreadPool::timings
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingStats {
+    /// From being queued to a worker picking the job up
+    pub queue_wait: Percentiles,
+    /// From a worker picking the job up to it returning
+    pub run_time: Percentiles,
+}
+
+/// All zero until the first job has been recorded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// How many jobs these were worked out from, at most the last 4096
+    pub samples: usize,
+}
+
+// Only the most recent jobs are kept so the numbers follow the current load and memory stays flat
+const TIMING_WINDOW: usize = 4096;
+
+// Filled in by the workers when the pool is built with record_timings
+#[derive(Default)]
+pub(crate) struct Timings {
+    queue_wait: Mutex<Samples>,
+    run_time: Mutex<Samples>,
+}
+
+impl Timings {
+    pub(crate) fn record_wait(&self, waited: Duration) {
+        self.queue_wait.lock().unwrap_or_else(|e| e.into_inner()).push(waited);
+    }
+
+    pub(crate) fn record_run(&self, ran_for: Duration) {
+        self.run_time.lock().unwrap_or_else(|e| e.into_inner()).push(ran_for);
+    }
+
+    pub(crate) fn snapshot(&self) -> TimingStats {
+        TimingStats {
+            queue_wait: self.queue_wait.lock().unwrap_or_else(|e| e.into_inner()).percentiles(),
+            run_time: self.run_time.lock().unwrap_or_else(|e| e.into_inner()).percentiles(),
+        }
+    }
+}
+
+// Ring buffer of the last TIMING_WINDOW durations
+#[derive(Default)]
+struct Samples {
+    values: Vec<Duration>,
+    next: usize,
+}
+
+impl Samples {
+    fn push(&mut self, value: Duration) {
+        if self.values.len() < TIMING_WINDOW {
+            self.values.push(value);
+        } else {
+            self.values[self.next] = value;
+        }
+        self.next = (self.next + 1) % TIMING_WINDOW;
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        if self.values.is_empty() {
+            return Percentiles::default();
+        }
+        let mut sorted = self.values.clone();
+        sorted.sort_unstable();
+        // nearest rank, so p99 of a handful of samples is the slowest one
+        let rank = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+        Percentiles { p50: rank(50), p95: rank(95), p99: rank(99), samples: sorted.len() }
+    }
+}
+
+// Pool wide counters, bumped by the workers
+#[derive(Default)]
+pub(crate) struct PoolCounters {
+    pub(crate) in_flight: AtomicUsize,
+    pub(crate) completed: AtomicU64,
+    pub(crate) panicked: AtomicU64,
+}
+
+// One per worker, shared between the Worker handle and its thread
+pub(crate) struct WorkerCounters {
+    started: Instant,
+    busy: AtomicBool,
+    jobs_run: AtomicU64,
+    busy_nanos: AtomicU64,
+    // when the current job started, in nanos since 'started' plus one so zero can mean idle
+    job_started_at: AtomicU64,
+    // the watchdog already reported the current job
+    timed_out: AtomicBool,
+    // the watchdog wants this worker gone once its job returns
+    replace: AtomicBool,
+}
+
+impl WorkerCounters {
+    pub(crate) fn new() -> WorkerCounters {
+        WorkerCounters {
+            started: Instant::now(),
+            busy: AtomicBool::new(false),
+            jobs_run: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            job_started_at: AtomicU64::new(0),
+            timed_out: AtomicBool::new(false),
+            replace: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn job_started(&self, pool: &PoolCounters) {
+        self.busy.store(true, Ordering::SeqCst);
+        self.timed_out.store(false, Ordering::SeqCst);
+        self.job_started_at.store(self.started.elapsed().as_nanos() as u64 + 1, Ordering::SeqCst);
+        pool.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn job_finished(&self, pool: &PoolCounters, ran_for: Duration, panicked: bool) {
+        self.busy.store(false, Ordering::SeqCst);
+        self.job_started_at.store(0, Ordering::SeqCst);
+        self.jobs_run.fetch_add(1, Ordering::SeqCst);
+        self.busy_nanos.fetch_add(ran_for.as_nanos() as u64, Ordering::SeqCst);
+        pool.in_flight.fetch_sub(1, Ordering::SeqCst);
+        pool.completed.fetch_add(1, Ordering::SeqCst);
+        if panicked {
+            pool.panicked.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // How long the current job has run if that is past 'limit' and nobody has been told yet
+    pub(crate) fn check_timeout(&self, limit: Duration) -> Option<Duration> {
+        let started_at = self.job_started_at.load(Ordering::SeqCst).checked_sub(1)?;
+        let ran_for = self.started.elapsed().saturating_sub(Duration::from_nanos(started_at));
+        if ran_for <= limit || self.timed_out.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(ran_for)
+    }
+
+    pub(crate) fn request_replacement(&self) {
+        self.replace.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn replacement_requested(&self) -> bool {
+        self.replace.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn snapshot(&self, id: usize) -> WorkerStats {
+        WorkerStats {
+            id,
+            busy: self.busy.load(Ordering::SeqCst),
+            jobs_run: self.jobs_run.load(Ordering::SeqCst),
+            busy_time: Duration::from_nanos(self.busy_nanos.load(Ordering::SeqCst)),
+            uptime: self.started.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_counters() {
+        let pool = PoolCounters::default();
+        let worker = WorkerCounters::new();
+
+        worker.job_started(&pool);
+        assert!(worker.snapshot(0).busy);
+        assert_eq!(pool.in_flight.load(Ordering::SeqCst), 1);
+
+        worker.job_finished(&pool, Duration::from_millis(3), true);
+        let stats = worker.snapshot(0);
+        assert!(!stats.busy);
+        assert_eq!(stats.jobs_run, 1);
+        assert_eq!(stats.busy_time, Duration::from_millis(3));
+        assert_eq!(pool.completed.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.panicked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_check_timeout_reports_once() {
+        let pool = PoolCounters::default();
+        let worker = WorkerCounters::new();
+        assert_eq!(worker.check_timeout(Duration::ZERO), None);
+
+        worker.job_started(&pool);
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(worker.check_timeout(Duration::from_millis(1)).is_some());
+        assert_eq!(worker.check_timeout(Duration::from_millis(1)), None);
+        worker.job_finished(&pool, Duration::from_millis(2), false);
+        assert_eq!(worker.check_timeout(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let timings = Timings::default();
+        assert_eq!(timings.snapshot().run_time, Percentiles::default());
+        for ms in 1..=100 {
+            timings.record_run(Duration::from_millis(ms));
+        }
+        let run_time = timings.snapshot().run_time;
+        assert_eq!(run_time.p50, Duration::from_millis(50));
+        assert_eq!(run_time.p95, Duration::from_millis(95));
+        assert_eq!(run_time.p99, Duration::from_millis(99));
+        assert_eq!(run_time.samples, 100);
+    }
+
+    #[test]
+    fn test_timing_window_keeps_recent() {
+        let timings = Timings::default();
+        for _ in 0..TIMING_WINDOW {
+            timings.record_wait(Duration::from_secs(1));
+        }
+        for _ in 0..TIMING_WINDOW {
+            timings.record_wait(Duration::from_millis(1));
+        }
+        let queue_wait = timings.snapshot().queue_wait;
+        assert_eq!(queue_wait.p99, Duration::from_millis(1));
+        assert_eq!(queue_wait.samples, TIMING_WINDOW);
+    }
+
+    #[test]
+    fn test_utilization_is_clamped() {
+        let stats = WorkerStats {
+            id: 0,
+            busy: false,
+            jobs_run: 1,
+            busy_time: Duration::from_secs(2),
+            uptime: Duration::from_secs(1),
+        };
+        assert_eq!(stats.utilization(), 1.0);
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+
+// One variant per registered code, with its reason phrase
+macro_rules! status_codes {
+    ($($variant:ident = $code:literal, $reason:literal;)*) => {
+        /// HTTP status codes, every one in the IANA registry
+        ///
+        /// ```
+        /// use webserver::StatusCode;
+        ///
+        /// let status = StatusCode::try_from(404).unwrap();
+        /// assert_eq!(status, StatusCode::NotFound);
+        /// assert_eq!(status.reason(), "Not Found");
+        /// assert!(status.is_client_error());
+        /// assert_eq!(status.to_string(), "404 Not Found");
+        /// assert_eq!(StatusCode::try_from(299), Err(299));
+        /// ```
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum StatusCode {
+            $($variant = $code,)*
+        }
+
+        impl StatusCode {
+            /// The canonical reason phrase, "Not Found" for 404
+            pub fn reason(self) -> &'static str {
+                match self {
+                    $(StatusCode::$variant => $reason,)*
+                }
+            }
+        }
+
+        impl TryFrom<u16> for StatusCode {
+            /// The code itself, when it isn't a registered one
+            type Error = u16;
+
+            fn try_from(code: u16) -> Result<StatusCode, u16> {
+                match code {
+                    $($code => Ok(StatusCode::$variant),)*
+                    other => Err(other),
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl StatusCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// 1xx
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    /// 2xx
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    /// 3xx
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    /// 4xx
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    /// 5xx
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> u16 {
+        status.as_u16()
+    }
+}
+
+// "404 Not Found", as it goes in the status line
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        for code in 0..1000 {
+            if let Ok(status) = StatusCode::try_from(code) {
+                assert_eq!(status.as_u16(), code);
+                assert!(!status.reason().is_empty());
+            }
+        }
+        assert_eq!(StatusCode::try_from(418), Err(418));
+        assert!(StatusCode::Continue.is_informational());
+        assert!(StatusCode::NoContent.is_success());
+        assert!(StatusCode::NotModified.is_redirection());
+        assert!(StatusCode::ServiceUnavailable.is_server_error() && !StatusCode::ServiceUnavailable.is_client_error());
+        assert_eq!(u16::from(StatusCode::Ok), 200);
+    }
+}
@@ -0,0 +1,218 @@
+// Upgrade (RFC 9110 7.8): handing a connection over to another protocol, like WebSockets or
+// h2c, with a 101 Switching Protocols
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    handler::{self, HttpOptions},
+    listener::SetTimeouts,
+    logging::log,
+    Request, Response, StatusCode, Version,
+};
+
+/// Takes over connections whose request asks to switch to its protocol, set with ServerBuilder::upgrade
+pub type UpgradeHandler = Arc<dyn Fn(&Request, Upgraded<'_>) + Send + Sync>;
+
+// Whatever connection the request came in on
+pub(crate) trait ReadWrite: Read + Write + SetTimeouts {}
+
+impl<S: Read + Write + SetTimeouts> ReadWrite for S {}
+
+/// The connection of a request asking to switch to a protocol an UpgradeHandler takes on
+///
+/// Nothing has been sent yet. The handler either calls switch, sending the 101, and from then
+/// on speaks the new protocol through Read and Write, or turns the request down with refuse.
+/// Either way the connection closes once it's done with, it never goes back to serving HTTP;
+/// one dropped unanswered gets a 500. Reading or writing before the switch is an InvalidInput error
+///
+/// The switch clears the connection's read and write timeouts, which were there for HTTP, so
+/// a quiet WebSocket isn't cut off; set_read_timeout and set_write_timeout put in whatever
+/// the new protocol wants. The handler runs on a pool worker, which the connection holds
+/// for as long as it's open, so the pool needs a worker for every upgraded connection on
+/// top of those serving HTTP
+///
+/// ```
+/// use std::io::{Read, Write};
+/// use webserver::{Response, StatusCode};
+///
+/// let echo = |_: &webserver::Request, mut upgraded: webserver::Upgraded<'_>| {
+///     if upgraded.protocol() != "echo" {
+///         return upgraded.refuse(Response::new(StatusCode::BadRequest));
+///     }
+///     if upgraded.switch(Response::new(StatusCode::SwitchingProtocols)).is_ok() {
+///         let mut line = [0; 5];
+///         if upgraded.read_exact(&mut line).is_ok() {
+///             let _ = upgraded.write_all(&line);
+///         }
+///     }
+/// };
+/// let builder = webserver::Server::builder().upgrade("echo", echo);
+/// # let _ = builder;
+/// ```
+pub struct Upgraded<'a> {
+    stream: &'a mut dyn ReadWrite,
+    // read off the connection after the request, the new protocol's first bytes
+    pending: Vec<u8>,
+    protocol: String,
+    request: &'a Request,
+    options: &'a HttpOptions,
+    switched: bool,
+    answered: bool,
+}
+
+impl<'a> Upgraded<'a> {
+    pub(crate) fn new(stream: &'a mut dyn ReadWrite, pending: Vec<u8>, protocol: String, request: &'a Request, options: &'a HttpOptions) -> Self {
+        Upgraded { stream, pending, protocol, request, options, switched: false, answered: false }
+    }
+
+    /// The protocol being switched to, as the client's Upgrade header spelled it ("websocket/13")
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Send 'response' as the 101 Switching Protocols, whatever its status was, with the
+    /// Connection and Upgrade headers filled in and any body left off. Whatever else the new
+    /// protocol wants in the 101, like Sec-WebSocket-Accept, goes on 'response'
+    ///
+    /// # Errors
+    /// Returns the error writing to the connection, or InvalidInput when it has been answered already
+    pub fn switch(&mut self, mut response: Response) -> io::Result<()> {
+        if self.answered {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "upgrade already answered"));
+        }
+        self.answered = true;
+        response.status = StatusCode::SwitchingProtocols;
+        response.version = Version::Http11;
+        if !self.request.id.is_empty() && !response.headers.contains("x-request-id") {
+            response.headers.append("X-Request-Id", self.request.id.as_str());
+        }
+        let mut response = handler::stamp(response, self.options, false);
+        response.headers.insert("Connection", "Upgrade");
+        if !response.headers.contains("upgrade") {
+            response.headers.append("Upgrade", self.protocol.as_str());
+        }
+        if let Err(e) = response.write_to(&mut self.stream) {
+            log!(Error, "{} Failed to write 101 response: {}", self.request.id, e);
+            return Err(e);
+        }
+        handler::access_log(self.request, StatusCode::SwitchingProtocols);
+        self.switched = true;
+        if let Err(e) = self.set_read_timeout(None).and_then(|()| self.set_write_timeout(None))
+            && e.kind() != ErrorKind::Unsupported
+        {
+            log!(Warn, "{} Failed to clear timeouts of upgraded connection: {}", self.request.id, e);
+        }
+        Ok(())
+    }
+
+    /// How long a read waits for the client before failing with WouldBlock or TimedOut, None
+    /// for as long as it takes, which is what switch leaves it at
+    ///
+    /// # Errors
+    /// Returns the OS error, and Unsupported for a connection that isn't a socket the server
+    /// accepted itself, like a wrapped one
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// How long a write waits for the client to make room before failing, None by default once switched
+    ///
+    /// # Errors
+    /// As for set_read_timeout
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Answer the request with 'response' instead of switching, and close the connection
+    pub fn refuse(mut self, response: Response) {
+        if !self.answered {
+            self.answered = true;
+            handler::send(&mut self.stream, self.request, self.options, false, response);
+        }
+    }
+
+    fn check_switched(&self) -> io::Result<()> {
+        if self.switched {
+            Ok(())
+        } else {
+            Err(io::Error::new(ErrorKind::InvalidInput, "no 101 sent yet, call switch first"))
+        }
+    }
+}
+
+impl Read for Upgraded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_switched()?;
+        if self.pending.is_empty() {
+            return self.stream.read(buf);
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Upgraded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_switched()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Drop for Upgraded<'_> {
+    fn drop(&mut self) {
+        if !self.answered {
+            log!(Error, "{} The {} upgrade handler returned without answering", self.request.id, self.protocol);
+            handler::send(&mut self.stream, self.request, self.options, false, handler::plain(StatusCode::InternalServerError));
+        }
+    }
+}
+
+impl fmt::Debug for Upgraded<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgraded").field("protocol", &self.protocol).field("switched", &self.switched).finish()
+    }
+}
+
+// The protocol 'request' asks to switch to that one of upgrades handles, and its handler:
+// the first on the Upgrade header we have one for, matched by name with or without its
+// version ("websocket" takes "websocket/13"). Only with Connection: upgrade, which a proxy
+// that didn't understand the request would have stripped, and never for HTTP/1.0, whose
+// proxies might pass on an Upgrade header they should have dropped
+pub(crate) fn handler_for<'a>(request: &Request, upgrades: &'a [(String, UpgradeHandler)]) -> Option<(String, &'a UpgradeHandler)> {
+    if upgrades.is_empty() || request.version == Version::Http10 || !request.headers.has_token("connection", "upgrade") {
+        return None;
+    }
+    let offered = request.headers.get_all("upgrade").flat_map(|value| value.split(',')).map(str::trim);
+    offered.filter(|protocol| !protocol.is_empty()).find_map(|protocol| {
+        let name = protocol.split('/').next().unwrap_or(protocol);
+        upgrades
+            .iter()
+            .find(|(ours, _)| ours.eq_ignore_ascii_case(protocol) || ours.eq_ignore_ascii_case(name))
+            .map(|(_, handler)| (protocol.to_string(), handler))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handler_for() {
+        let upgrades: Vec<(String, UpgradeHandler)> = vec![("websocket".to_string(), Arc::new(|_: &Request, _: Upgraded<'_>| {}))];
+        let protocol = |head: &[u8]| handler_for(&Request::parse(head).unwrap(), &upgrades).map(|(protocol, _)| protocol);
+        assert_eq!(protocol(b"GET / HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: h2c, WebSocket/13\r\n\r\n").as_deref(), Some("WebSocket/13"));
+        assert_eq!(protocol(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"), None);
+        assert_eq!(protocol(b"GET / HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: h2c\r\n\r\n"), None);
+        assert_eq!(protocol(b"GET / HTTP/1.0\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    env, io,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixListener,
+    },
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{listener::Listener, logging::log};
+
+// systemd hands sockets over starting after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+// the fds can only be owned once, a second server asking for them gets nothing
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Listeners passed in by systemd socket activation ($LISTEN_PID and $LISTEN_FDS), in order
+/// Empty when the process wasn't socket activated
+pub(crate) fn inherited_listeners() -> Vec<Listener> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let count = listen_fds(pid.as_deref(), fds.as_deref(), process::id());
+    if count == 0 || TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .filter_map(|fd| {
+            // SAFETY: systemd passed us these fds and TAKEN makes sure only one Listener owns each
+            match unsafe { adopt(fd) } {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    log!(Error, "Ignoring socket fd {} from systemd: {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// How many fds systemd passed, 0 if the variables are missing, bad, or meant for another process
+fn listen_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> RawFd {
+    let (Some(pid), Some(fds)) = (pid, fds) else { return 0 };
+    if pid.trim().parse::<u32>() != Ok(own_pid) {
+        return 0;
+    }
+    fds.trim().parse::<RawFd>().ok().filter(|count| *count > 0).unwrap_or(0)
+}
+
+/// Wrap a listening socket fd in whichever listener type matches it
+///
+/// # Safety
+/// The caller must own 'fd' and not use or close it afterwards
+pub(crate) unsafe fn adopt(fd: RawFd) -> io::Result<Listener> {
+    // SAFETY: passed on from our caller
+    let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    let tcp = TcpListener::from(owned);
+    // an old process serving it on a tokio runtime leaves it nonblocking, the flag goes with the socket
+    tcp.set_nonblocking(false)?;
+    // getsockname on a Unix socket doesn't give back an IP address, so this tells them apart
+    if tcp.local_addr().is_ok() {
+        return Ok(Listener::Tcp(tcp));
+    }
+    let unix = UnixListener::from(OwnedFd::from(tcp));
+    match unix.local_addr()?.as_pathname() {
+        Some(path) => Ok(Listener::Unix { path: path.to_path_buf(), listener: unix, owned: AtomicBool::new(false) }),
+        None => Err(io::Error::new(io::ErrorKind::Unsupported, "only TCP and named Unix sockets are supported")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ListenAddr;
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), 0);
+        assert_eq!(listen_fds(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("lots"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("-1"), 42), 0);
+    }
+
+    #[test]
+    fn test_adopt_tcp_and_unix() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        // SAFETY: into_raw_fd gives up ownership
+        let adopted = unsafe { adopt(tcp.into_raw_fd()) }.unwrap();
+        assert_eq!(adopted.local_addr().unwrap(), ListenAddr::Tcp(addr));
+
+        let path = env::temp_dir().join(format!("webserver-systemd-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        // SAFETY: into_raw_fd gives up ownership
+        let adopted = unsafe { adopt(unix.into_raw_fd()) }.unwrap();
+        assert_eq!(adopted.local_addr().unwrap(), ListenAddr::Unix(path.clone()));
+        drop(adopted);
+        // it's systemd's socket file, not ours to remove
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    cmp::{Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{package, Job, JobHandle, LogLevel, Shared, ThreadPool};
+
+// A single thread per pool holding jobs until they are due, then handing them to the workers.
+// Started the first time something is scheduled so pools that never use it don't pay for the thread
+pub(crate) struct Timer {
+    state: Arc<TimerState>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+struct TimerState {
+    entries: Mutex<TimerEntries>,
+    changed: Condvar,
+}
+
+struct TimerEntries {
+    heap: BinaryHeap<Reverse<Entry>>,
+    // tie breaker so jobs due at the same instant keep the order they were scheduled in
+    next_seq: u64,
+    stopped: bool,
+}
+
+struct Entry {
+    at: Instant,
+    seq: u64,
+    task: Task,
+}
+
+enum Task {
+    Once(Job),
+    Every(Recurring),
+}
+
+// Goes back on the heap each time it fires until its handle cancels it
+struct Recurring {
+    interval: Duration,
+    job: Arc<dyn Fn() + Send + Sync>,
+    flags: Arc<RecurringFlags>,
+}
+
+struct RecurringFlags {
+    cancelled: AtomicBool,
+    // a run that is still going when the next one comes due makes that one get skipped
+    running: AtomicBool,
+}
+
+/// Returned by ThreadPool::execute_every, lets the recurrence be stopped
+/// Dropping it leaves the job recurring until the pool shuts down
+pub struct RecurringHandle {
+    flags: Arc<RecurringFlags>,
+}
+
+impl RecurringHandle {
+    /// Stop any further runs, one already underway still finishes
+    pub fn cancel(&self) {
+        self.flags.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flags.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl Timer {
+    fn start(shared: Arc<Shared>) -> Timer {
+        let state = Arc::new(TimerState {
+            entries: Mutex::new(TimerEntries { heap: BinaryHeap::new(), next_seq: 0, stopped: false }),
+            changed: Condvar::new(),
+        });
+        let name = match &shared.thread.name_prefix {
+            Some(prefix) => format!("{}-timer", prefix),
+            None => "pool-timer".to_string(),
+        };
+        let (thread_state, thread_shared) = (Arc::clone(&state), Arc::clone(&shared));
+        let thread = match thread::Builder::new().name(name).spawn(move || Timer::run(&thread_state, &thread_shared)) {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                shared.report(LogLevel::Error, format_args!("Failed to start timer thread: {}", e));
+                // nothing would ever fire, so refuse everything from the start
+                state.entries.lock().unwrap_or_else(|e| e.into_inner()).stopped = true;
+                None
+            }
+        };
+        Timer { state, thread: Mutex::new(thread) }
+    }
+
+    fn schedule(&self, at: Instant, task: Task) {
+        let mut entries = self.state.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.stopped {
+            // dropping the job tells its handle it was lost
+            return;
+        }
+        entries.push(at, task);
+        self.state.changed.notify_one();
+    }
+
+    /// Stop the timer thread, anything still waiting to fire is dropped. Returns how many that was
+    pub(crate) fn stop(&self, shared: &Shared) -> usize {
+        let dropped = {
+            let mut entries = self.state.entries.lock().unwrap_or_else(|e| e.into_inner());
+            entries.stopped = true;
+            self.state.changed.notify_one();
+            // recurring jobs have no handle waiting on a result, so only count one-off jobs
+            std::mem::take(&mut entries.heap)
+                .into_iter()
+                .filter(|Reverse(entry)| matches!(entry.task, Task::Once(_)))
+                .count()
+        };
+        let thread = self.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(thread) = thread
+            && let Err(e) = thread.join()
+        {
+            shared.report(LogLevel::Error, format_args!("Failed to join timer thread: {:?}", e));
+        }
+        dropped
+    }
+
+    fn run(state: &TimerState, shared: &Arc<Shared>) {
+        let mut entries = state.entries.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if entries.stopped {
+                return;
+            }
+            let now = Instant::now();
+            let wait = match entries.heap.peek() {
+                Some(Reverse(entry)) if entry.at <= now => {
+                    let Some(Reverse(entry)) = entries.heap.pop() else { continue };
+                    let job = match entry.task {
+                        Task::Once(job) => Some(job),
+                        Task::Every(recurring) => {
+                            let job = recurring.next_run();
+                            if !recurring.flags.cancelled.load(Ordering::SeqCst) {
+                                // fixed rate, but don't try to catch up on ticks we slept through
+                                let mut next = entry.at + recurring.interval;
+                                if next <= now {
+                                    next = now + recurring.interval;
+                                }
+                                entries.push(next, Task::Every(recurring));
+                            }
+                            job
+                        }
+                    };
+                    drop(entries);
+                    if let Some(job) = job {
+                        // it was already accepted when scheduled, don't block the timer on a full queue
+                        shared.push_unbounded(job);
+                    }
+                    entries = state.entries.lock().unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+                Some(Reverse(entry)) => Some(entry.at - now),
+                None => None,
+            };
+            entries = match wait {
+                Some(wait) => state.changed.wait_timeout(entries, wait).unwrap_or_else(|e| e.into_inner()).0,
+                None => state.changed.wait(entries).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+impl TimerEntries {
+    fn push(&mut self, at: Instant, task: Task) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(Reverse(Entry { at, seq, task }));
+    }
+}
+
+impl Recurring {
+    // The job to queue for this tick, None if cancelled or the last run hasn't finished
+    fn next_run(&self) -> Option<Job> {
+        if self.flags.cancelled.load(Ordering::SeqCst) || self.flags.running.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        // clears running however the run ends, including a panic or the job being dropped unrun
+        struct Done(Arc<RecurringFlags>);
+        impl Drop for Done {
+            fn drop(&mut self) {
+                self.0.running.store(false, Ordering::SeqCst);
+            }
+        }
+
+        let done = Done(Arc::clone(&self.flags));
+        let job = Arc::clone(&self.job);
+        Some(Box::new(move || {
+            if !done.0.cancelled.load(Ordering::SeqCst) {
+                job();
+            }
+            drop(done);
+        }))
+    }
+}
+
+impl ThreadPool {
+    /// Queue 'f' once 'delay' has passed, the handle works just like one from 'execute'
+    pub fn execute_after<F, T>(&self, delay: Duration, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.execute_at(Instant::now() + delay, f)
+    }
+
+    /// Queue 'f' at 'at', or straight away if that is already in the past
+    /// Jobs still waiting when the pool shuts down are dropped and report JobError::Lost
+    pub fn execute_at<F, T>(&self, at: Instant, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, handle) = package(f);
+        if !self.is_shut_down() {
+            self.timer().schedule(at, Task::Once(job));
+        }
+        handle
+    }
+
+    /// Run 'f' every 'interval', the first run is one interval from now
+    /// A run is skipped if the previous one is still going, and a panicking run doesn't stop the rest
+    ///
+    /// # Panics
+    /// Panics if 'interval' is zero
+    pub fn execute_every<F>(&self, interval: Duration, f: F) -> RecurringHandle
+    where F: Fn() + Send + Sync + 'static
+    {
+        assert!(!interval.is_zero(), "interval must be non-zero");
+        let flags = Arc::new(RecurringFlags { cancelled: AtomicBool::new(false), running: AtomicBool::new(false) });
+        let recurring = Recurring { interval, job: Arc::new(f), flags: Arc::clone(&flags) };
+        if !self.is_shut_down() {
+            self.timer().schedule(Instant::now() + interval, Task::Every(recurring));
+        }
+        RecurringHandle { flags }
+    }
+
+    fn timer(&self) -> &Timer {
+        self.timer.get_or_init(|| Timer::start(Arc::clone(&self.shared)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JobError;
+
+    #[test]
+    fn test_execute_after_waits() {
+        let pool = ThreadPool::new(2);
+        let scheduled = Instant::now();
+        let ran_at = pool.execute_after(Duration::from_millis(30), Instant::now).join().unwrap();
+        assert!(ran_at - scheduled >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_execute_at_keeps_order() {
+        let pool = ThreadPool::new(1);
+        let now = Instant::now();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let later = sender.clone();
+        let second = pool.execute_at(now + Duration::from_millis(20), move || later.send(2).unwrap());
+        let first = pool.execute_at(now + Duration::from_millis(10), move || sender.send(1).unwrap());
+        first.join().unwrap();
+        second.join().unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn test_pending_timers_dropped_on_shutdown() {
+        let pool = ThreadPool::new(1);
+        let handle = pool.execute_after(Duration::from_secs(60), || 1);
+        pool.shutdown(Duration::from_secs(1));
+        assert_eq!(handle.join(), Err(JobError::Lost));
+    }
+
+    #[test]
+    fn test_execute_every_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let handle = pool.execute_every(Duration::from_millis(5), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        while runs.load(Ordering::SeqCst) < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.cancel();
+        assert!(handle.is_cancelled());
+        // let anything dispatched before the cancel drain out
+        thread::sleep(Duration::from_millis(20));
+        let after_cancel = runs.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(runs.load(Ordering::SeqCst), after_cancel);
+    }
+}
@@ -0,0 +1,331 @@
+// HTTPS through rustls, with the tls feature: loading the certificate and key, and the
+// handshake that turns an accepted Connection into a Connection::Tls. Client certificates
+// are checked against a CA bundle when asked for, and their subject kept for handlers
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    net::Shutdown,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ProtocolVersion, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::{Connection, TlsInfo};
+
+/// A connection the TLS handshake has been done on, what Connection::Tls holds
+pub struct TlsStream {
+    stream: StreamOwned<ServerConnection, Connection>,
+    info: TlsInfo,
+    // the subject of the client's certificate, when it showed one
+    identity: Option<String>,
+}
+
+// Where ServerBuilder::tls said the certificate chain and key are, and tls_client_ca the
+// CAs client certificates have to chain to, read when the server is built
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TlsFiles {
+    // the certificate and key, None serves plain HTTP
+    pub(crate) server: Option<(PathBuf, PathBuf)>,
+    pub(crate) client_ca: Option<PathBuf>,
+    // whether a client can go without a certificate
+    pub(crate) client_optional: bool,
+}
+
+impl TlsFiles {
+    // A rustls config serving the chain in the cert file, leaf first, with the private key in
+    // the key file, offering h2 in ALPN when 'http2'. None without them
+    pub(crate) fn server_config(&self, http2: bool) -> io::Result<Option<Arc<ServerConfig>>> {
+        let Some((cert, key)) = &self.server else {
+            if self.client_ca.is_some() {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "client certificates need a server certificate and key too"));
+            }
+            return Ok(None);
+        };
+        let certs = read_certs(cert)?;
+        let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let builder = match &self.client_ca {
+            None => builder.with_no_client_auth(),
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("bad CA certificate in {}: {}", path.display(), e)))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = if self.client_optional { verifier.allow_unauthenticated() } else { verifier };
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?)
+            }
+        };
+        let mut config = builder
+            .with_single_cert(certs, key_der)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("bad certificate or key in {}: {}", key.display(), e)))?;
+        // a client that picks h2 opens with the HTTP/2 preface, which the handler looks out for
+        config.alpn_protocols = if http2 { vec![b"h2".to_vec(), b"http/1.1".to_vec()] } else { vec![b"http/1.1".to_vec()] };
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+// Every certificate in PEM file 'path', in order
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("no certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => io::Error::new(e.kind(), format!("failed to read {}: {}", path.display(), e)),
+        e => io::Error::new(ErrorKind::InvalidInput, format!("bad PEM in {}: {}", path.display(), e)),
+    }
+}
+
+impl TlsStream {
+    // Do the handshake on 'stream', under its read and write timeouts, before any handler sees it
+    pub(crate) fn accept(mut stream: Connection, config: &Arc<ServerConfig>) -> io::Result<TlsStream> {
+        let mut conn = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        let version = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            other => format!("{:?}", other),
+        };
+        let info = TlsInfo {
+            version,
+            cipher: conn.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite())).unwrap_or_default(),
+            alpn: conn.alpn_protocol().map(|protocol| String::from_utf8_lossy(protocol).into_owned()),
+        };
+        // the verifier has already checked it chains to one of our CAs
+        let identity = conn.peer_certificates().and_then(|certs| certs.first()).and_then(|cert| subject(cert));
+        Ok(TlsStream { stream: StreamOwned::new(conn, stream), info, identity })
+    }
+
+    pub(crate) fn info(&self) -> &TlsInfo {
+        &self.info
+    }
+
+    pub(crate) fn peer_identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    // The connection underneath, for its addresses and timeouts
+    pub(crate) fn socket(&self) -> &Connection {
+        &self.stream.sock
+    }
+
+    // Say we're done with a close_notify before shutting the socket, so the client can tell
+    // it got everything
+    pub(crate) fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.close_notify()?;
+        }
+        self.stream.sock.shutdown(how)
+    }
+
+    fn close_notify(&mut self) -> io::Result<()> {
+        self.stream.conn.send_close_notify();
+        while self.stream.conn.wants_write() {
+            if self.stream.conn.write_tls(&mut self.stream.sock)? == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        // a handler that just lets the connection go still ends it properly
+        let _ = self.close_notify();
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            // plenty of clients hang up without a close_notify, HTTP's own framing is what
+            // catches a body cut short
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream")
+            .field("socket", &self.stream.sock)
+            .field("info", &self.info)
+            .field("identity", &self.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+// The subject of DER certificate 'cert' as an RFC 4514 string, most specific part first:
+// "CN=alice,O=Example\, Ltd.,C=GB"
+fn subject(cert: &[u8]) -> Option<String> {
+    let (certificate, _) = der(cert, SEQUENCE)?;
+    let (mut tbs, _) = der(certificate, SEQUENCE)?;
+    // the version is explicitly tagged [0], and left out for v1
+    if tbs.first() == Some(&0xa0) {
+        tbs = der(tbs, 0xa0)?.1;
+    }
+    // serial number, signature algorithm, issuer and validity come before the subject
+    for tag in [INTEGER, SEQUENCE, SEQUENCE, SEQUENCE] {
+        tbs = der(tbs, tag)?.1;
+    }
+    let (mut name, _) = der(tbs, SEQUENCE)?;
+    let mut rdns = Vec::new();
+    while !name.is_empty() {
+        let (mut set, rest) = der(name, SET)?;
+        name = rest;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (attribute, rest) = der(set, SEQUENCE)?;
+            set = rest;
+            let (oid, value) = der(attribute, OID)?;
+            attributes.push(attribute_string(oid, value)?);
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+const INTEGER: u8 = 0x02;
+const OID: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+
+// The contents of the DER element with 'tag' at the start of 'input', and what follows it
+fn der(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = input.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        // long form, the low bits say how many length bytes follow
+        let bytes = usize::from(len & 0x7f);
+        if bytes == 0 || bytes > 4 || rest.len() < bytes {
+            return None;
+        }
+        let (len, after) = rest.split_at(bytes);
+        rest = after;
+        len.iter().fold(0, |len, b| (len << 8) | usize::from(*b))
+    };
+    (rest.len() >= len).then(|| rest.split_at(len))
+}
+
+// "CN=alice", from an AttributeTypeAndValue's OID and the DER value after it
+fn attribute_string(oid: &[u8], value: &[u8]) -> Option<String> {
+    let oid = oid_string(oid)?;
+    let name = match oid.as_str() {
+        "2.5.4.3" => "CN",
+        "2.5.4.6" => "C",
+        "2.5.4.7" => "L",
+        "2.5.4.8" => "ST",
+        "2.5.4.9" => "STREET",
+        "2.5.4.10" => "O",
+        "2.5.4.11" => "OU",
+        "0.9.2342.19200300.100.1.1" => "UID",
+        "0.9.2342.19200300.100.1.25" => "DC",
+        _ => &oid,
+    };
+    let (&tag, _) = value.split_first()?;
+    let text = match tag {
+        // UTF8String, PrintableString, IA5String
+        0x0c | 0x13 | 0x16 => der(value, tag).and_then(|(text, _)| std::str::from_utf8(text).ok()).map(str::to_string),
+        // BMPString, UTF-16 big endian
+        0x1e => der(value, tag).and_then(|(text, _)| {
+            let units: Vec<u16> = text.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16(&units).ok()
+        }),
+        _ => None,
+    };
+    match text {
+        Some(text) => Some(format!("{}={}", name, escape(&text))),
+        // anything else goes as the hex of its DER encoding, as RFC 4514 2.4 says
+        None => Some(format!("{}=#{}", name, value.iter().map(|b| format!("{:02x}", b)).collect::<String>())),
+    }
+}
+
+// Dotted decimal, "2.5.4.3"
+fn oid_string(oid: &[u8]) -> Option<String> {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for &b in oid {
+        arc = arc.checked_mul(128)? | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+    let (&first, rest) = arcs.split_first()?;
+    let (x, y) = if first < 80 { (first / 40, first % 40) } else { (2, first - 80) };
+    Some([x, y].iter().chain(rest).map(u64::to_string).collect::<Vec<_>>().join("."))
+}
+
+// RFC 4514 2.4: the characters that mean something in a DN string get a backslash
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' if i == 0 => escaped.push_str("\\#"),
+            ' ' if i == 0 || i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_cert(name: &str) -> CertificateDer<'static> {
+        read_certs(&Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls").join(name)).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_subject() {
+        assert_eq!(subject(&first_cert("client.pem")).as_deref(), Some("CN=test client,O=Example\\, Ltd.,C=GB"));
+        assert_eq!(subject(&first_cert("ca.pem")).as_deref(), Some("CN=webserver test CA,O=webserver tests"));
+        assert_eq!(subject(b"\x30\x03\x02\x01"), None);
+        assert_eq!(oid_string(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d]).as_deref(), Some("1.2.840.113549"));
+        assert_eq!(escape(" #a+b\0 "), "\\ #a\\+b\\00\\ ");
+        assert_eq!(escape("#a"), "\\#a");
+    }
+}
@@ -0,0 +1,159 @@
+// Just enough TOML for the config file: [tables], key = value pairs, strings, integers,
+// booleans and single-line arrays of those. Keys come back as "table.key"
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// Every key in 'text' in file order, errors name the offending line
+pub(crate) fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut table = String::new();
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let at = |message: String| format!("line {}: {}", number + 1, message);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']').ok_or_else(|| at("unclosed table header".to_string()))?.trim();
+            if !is_key(name) {
+                return Err(at(format!("invalid table name {:?}", name)));
+            }
+            table = name.to_string();
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| at("expected key = value".to_string()))?;
+        let key = key.trim();
+        if !is_key(key) {
+            return Err(at(format!("invalid key {:?}", key)));
+        }
+        let key = if table.is_empty() { key.to_string() } else { format!("{}.{}", table, key) };
+        if entries.iter().any(|(seen, _)| *seen == key) {
+            return Err(at(format!("{} is set twice", key)));
+        }
+        let (value, rest) = parse_value(value.trim()).map_err(at)?;
+        if !rest.trim().is_empty() {
+            return Err(at(format!("unexpected {:?} after the value", rest.trim())));
+        }
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty() && key.split('.').all(|part| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+// A # outside of a string starts a comment
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Parse one value off the front of 's', handing back whatever follows it
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(out), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    other => return Err(format!("unsupported escape \\{}", other.map_or(String::new(), String::from))),
+                },
+                c => out.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err("expected , or ] in array".to_string());
+            }
+        }
+    }
+    let end = s.find([',', ']', ' ', '\t']).unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    let value = match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        "" => return Err("missing value".to_string()),
+        _ => Value::Integer(word.replace('_', "").parse().map_err(|_| format!("unsupported value {:?}", word))?),
+    };
+    Ok((value, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tables_and_values() {
+        let text = r#"
+            # top level
+            name = "web # not a comment"
+            [server]
+            port = 8_080 # trailing comment
+            verbose = false
+            hosts = ["a", "b\"c",]
+        "#;
+        assert_eq!(parse(text).unwrap(), vec![
+            ("name".to_string(), Value::String("web # not a comment".to_string())),
+            ("server.port".to_string(), Value::Integer(8080)),
+            ("server.verbose".to_string(), Value::Boolean(false)),
+            ("server.hosts".to_string(), Value::Array(vec![Value::String("a".to_string()), Value::String("b\"c".to_string())])),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        assert_eq!(parse("a = 1\nb = nope").unwrap_err(), "line 2: unsupported value \"nope\"");
+        assert!(parse("a = \"open").unwrap_err().starts_with("line 1"));
+        assert!(parse("[server\nport = 1").is_err());
+        assert!(parse("a = 1\na = 2").is_err());
+        assert!(parse("a = 1 2").is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        raw::c_int,
+        unix::net::UnixStream,
+    },
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{listener::Listener, logging::log, systemd, Server};
+
+// How the new process finds the sockets the old one handed over, fd numbers survive exec
+const LISTEN_FDS_ENV: &str = "WEBSERVER_UPGRADE_FDS";
+const ADMIN_FD_ENV: &str = "WEBSERVER_UPGRADE_ADMIN_FD";
+// and tells the old one it's serving, so the old one can stop
+const READY_FD_ENV: &str = "WEBSERVER_UPGRADE_READY_FD";
+
+// How long the old process waits on the new one before giving up and carrying on serving
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const F_GETFD: c_int = 1;
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+// fcntl comes from libc which std already links, so no extra crate is needed
+unsafe extern "C" {
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+}
+
+// each fd can only be owned once, a second server asking for them gets nothing
+static LISTENERS_TAKEN: AtomicBool = AtomicBool::new(false);
+static ADMIN_TAKEN: AtomicBool = AtomicBool::new(false);
+static READY_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Listeners handed over by the process we're upgrading, empty when we weren't started that way
+pub(crate) fn inherited_listeners() -> Vec<Listener> {
+    let Some(fds) = env_fds(LISTEN_FDS_ENV) else { return Vec::new() };
+    if LISTENERS_TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+    let listeners: Vec<Listener> = fds
+        .into_iter()
+        .filter_map(|fd| {
+            // SAFETY: the old process passed these for us alone and LISTENERS_TAKEN makes sure only one Listener owns each
+            match unsafe { systemd::adopt(fd) } {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    log!(Error, "Ignoring socket fd {} from the old process: {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    log!(Info, "Took over {} socket(s) from the old process", listeners.len());
+    listeners
+}
+
+/// The old process's admin socket, if it handed one over
+pub(crate) fn inherited_admin() -> Option<TcpListener> {
+    let fd = *env_fds(ADMIN_FD_ENV)?.first()?;
+    if ADMIN_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    // SAFETY: as for the listeners, ADMIN_TAKEN guards it
+    Some(TcpListener::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Tell the process we're upgrading from that we're ready to serve, it stops accepting then
+pub(crate) fn notify_ready() {
+    let Some(fd) = env_fds(READY_FD_ENV).and_then(|fds| fds.first().copied()) else { return };
+    if READY_TAKEN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // SAFETY: READY_TAKEN makes sure we only take ownership once
+    let mut ready = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if let Err(e) = ready.write_all(b"1") {
+        log!(Warn, "Failed to tell the old process we're ready: {}", e);
+    }
+}
+
+// A comma separated list of fds from 'var', None if it's not set or not valid
+fn env_fds(var: &str) -> Option<Vec<RawFd>> {
+    parse_fds(&env::var(var).ok()?)
+}
+
+// fds 0 to 2 are stdio, never a socket someone handed us
+fn parse_fds(value: &str) -> Option<Vec<RawFd>> {
+    value.split(',').map(|fd| fd.trim().parse().ok().filter(|fd| *fd > 2)).collect()
+}
+
+// Whether 'fd' survives exec into a child process
+fn set_inheritable(fd: RawFd, inheritable: bool) -> io::Result<()> {
+    // SAFETY: fcntl on an fd we hold open, the result is checked
+    let flags = unsafe { fcntl(fd, F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if inheritable { flags & !FD_CLOEXEC } else { flags | FD_CLOEXEC };
+    // SAFETY: as above
+    if unsafe { fcntl(fd, F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Server {
+    // Start the binary on disk again with the same arguments, handing it our sockets, and wait
+    // for it to say it's serving. After that both processes accept from the same sockets until
+    // we stop, so nobody trying to connect is refused. Returns the new process's id
+    pub(crate) fn upgrade(&self) -> io::Result<u32> {
+        let exe = env::current_exe()?;
+        let (ready, child_ready) = UnixStream::pair()?;
+        let mut fds: Vec<RawFd> = self.listeners.iter().map(AsRawFd::as_raw_fd).collect();
+        let join = |fds: &[RawFd]| fds.iter().map(RawFd::to_string).collect::<Vec<_>>().join(",");
+        let mut command = Command::new(exe);
+        command.args(env::args_os().skip(1)).env(LISTEN_FDS_ENV, join(&fds));
+        if let Some(admin) = &self.admin {
+            command.env(ADMIN_FD_ENV, admin.as_raw_fd().to_string());
+            fds.push(admin.as_raw_fd());
+        }
+        let child_ready = child_ready.into_raw_fd();
+        command.env(READY_FD_ENV, child_ready.to_string());
+        fds.push(child_ready);
+
+        // only for the exec, every other process we start keeps not seeing them
+        let spawned = fds.iter().try_for_each(|fd| set_inheritable(*fd, true)).and_then(|()| command.spawn());
+        for fd in &fds {
+            if let Err(e) = set_inheritable(*fd, false) {
+                log!(Warn, "Failed to make fd {} close on exec again: {}", fd, e);
+            }
+        }
+        // SAFETY: we took it out of the UnixStream above and nothing else has closed it
+        drop(unsafe { OwnedFd::from_raw_fd(child_ready) });
+        let mut child = spawned?;
+        let pid = child.id();
+        log!(Info, "Started pid {} to take over, waiting for it to be ready", pid);
+
+        let mut byte = [0];
+        let answer = ready.set_read_timeout(Some(READY_TIMEOUT)).and_then(|()| (&ready).read(&mut byte));
+        if !matches!(answer, Ok(1)) {
+            // it died, or is stuck, either way we carry on serving
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::other(format!("pid {} didn't get ready to serve", pid)));
+        }
+        // its socket files now, it'll clean them up
+        for listener in &self.listeners {
+            listener.disown();
+        }
+        Ok(pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fds() {
+        assert_eq!(parse_fds("3,4, 7"), Some(vec![3, 4, 7]));
+        assert_eq!(parse_fds("3,x"), None);
+        assert_eq!(parse_fds("1"), None);
+    }
+
+    #[test]
+    fn test_set_inheritable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = listener.as_raw_fd();
+        // SAFETY: fd is open for the whole test
+        let cloexec = || unsafe { fcntl(fd, F_GETFD) } & FD_CLOEXEC != 0;
+        assert!(cloexec());
+        set_inheritable(fd, true).unwrap();
+        assert!(!cloexec());
+        set_inheritable(fd, false).unwrap();
+        assert!(cloexec());
+    }
+}
@@ -0,0 +1,186 @@
+// Percent-decoding and encoding for request targets, RFC 3986 2.1, the query string pairs
+// HTML forms send, where '+' also stands for a space, and path normalization
+use std::fmt;
+
+use crate::StatusCode;
+
+// '%XX' becomes the byte XX, a '%' without two hex digits after it is kept as it is
+// 'plus' turns '+' into a space, for query strings but not paths
+pub(crate) fn percent_decode(s: &str, plus: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() && hex(bytes[i + 1]).is_some() && hex(bytes[i + 2]).is_some() => {
+                decoded.push(hex(bytes[i + 1]).unwrap() << 4 | hex(bytes[i + 2]).unwrap());
+                i += 3;
+                continue;
+            }
+            b'+' if plus => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+// Decode 'raw' and resolve its "." and ".." segments (RFC 3986 5.2.4) and empty ones, so
+// "/a//b/../%63" is "/a/c"; ".." never climbs above the root. Decoding comes first so an
+// encoded "%2e%2e" or "%2f" can't sneak a segment past. Paths that don't decode to UTF-8,
+// or carry a NUL, are refused with the reason why
+pub(crate) fn normalize_path(raw: &str) -> Result<String, &'static str> {
+    let decoded = percent_decode(raw, false);
+    if decoded.contains(&0) {
+        return Err("encoded NUL");
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| "not UTF-8")?;
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut path = String::with_capacity(decoded.len());
+    for segment in &segments {
+        path.push('/');
+        path.push_str(segment);
+    }
+    if trailing_slash || path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+// A normalized path as it goes in a URL: everything a path segment can't carry as it is
+// percent-encoded (RFC 3986 3.3), everything it can left alone, so each path has one spelling
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// The decoded name and value pairs of a query string, in order, from Request::query_pairs
+///
+/// Bytes that don't decode to UTF-8 come out as U+FFFD, a pair without '=' has an empty value
+pub struct QueryPairs<'a>(std::str::Split<'a, char>);
+
+impl<'a> QueryPairs<'a> {
+    pub(crate) fn new(query: &'a str) -> QueryPairs<'a> {
+        QueryPairs(query.split('&'))
+    }
+}
+
+impl Iterator for QueryPairs<'_> {
+    type Item = (String, String);
+
+    fn next(&mut self) -> Option<(String, String)> {
+        // "a=1&&b=2" has an empty pair in the middle, skip it
+        let pair = self.0.by_ref().find(|pair| !pair.is_empty())?;
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode = |s| String::from_utf8_lossy(&percent_decode(s, true)).into_owned();
+        Some((decode(name), decode(value)))
+    }
+}
+
+/// Why Request::form couldn't read a body as a form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormError {
+    /// The request's Content-Type isn't application/x-www-form-urlencoded
+    NotForm,
+    /// The body isn't percent-encoded text, it has bytes that aren't UTF-8 in it
+    Encoding,
+}
+
+impl FormError {
+    /// The status to answer with: 415 when it isn't a form at all, otherwise 400
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FormError::NotForm => StatusCode::UnsupportedMediaType,
+            FormError::Encoding => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::NotForm => write!(f, "not application/x-www-form-urlencoded"),
+            FormError::Encoding => write!(f, "form body isn't UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c", false), b"a b+c");
+        assert_eq!(percent_decode("a%20b+c", true), b"a b c");
+        assert_eq!(percent_decode("%e2%9C%93", false), "\u{2713}".as_bytes());
+        assert_eq!(percent_decode("100%", false), b"100%");
+        assert_eq!(percent_decode("%zz%4", false), b"%zz%4");
+        assert_eq!(percent_decode("%00", false), b"\0");
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        for (raw, path) in [
+            ("/", "/"),
+            ("/a/b", "/a/b"),
+            ("/a/b/", "/a/b/"),
+            ("//a///b", "/a/b"),
+            ("/a/./b/../c", "/a/c"),
+            ("/a/b/..", "/a/"),
+            ("/../../etc/passwd", "/etc/passwd"),
+            ("/%2e%2E/x", "/x"),
+            ("/a%2f..%2fb", "/b"),
+            ("/hello%20world", "/hello world"),
+            ("/%E2%9C%93", "/\u{2713}"),
+        ] {
+            assert_eq!(normalize_path(raw).as_deref(), Ok(path), "{}", raw);
+        }
+        assert!(normalize_path("/a%00b").is_err());
+        assert!(normalize_path("/%ff").is_err());
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("/a/b-c_d.e~"), "/a/b-c_d.e~");
+        assert_eq!(encode_path("/hello world/100%?#"), "/hello%20world/100%25%3F%23");
+        assert_eq!(encode_path("/\u{2713}"), "/%E2%9C%93");
+        // the spelling normalize_path and encode_path agree on is the canonical one
+        for raw in ["/a/./b/../c", "/%63", "/x%2fy", "/hello%20world/"] {
+            let canonical = encode_path(&normalize_path(raw).unwrap());
+            assert_eq!(encode_path(&normalize_path(&canonical).unwrap()), canonical);
+        }
+    }
+
+    #[test]
+    fn test_query_pairs() {
+        let pairs: Vec<_> = QueryPairs::new("q=rust+lang&&page=2&flag&x=%26%3D&bad=%ff").collect();
+        let expected = [("q", "rust lang"), ("page", "2"), ("flag", ""), ("x", "&="), ("bad", "\u{fffd}")];
+        assert_eq!(pairs, expected.map(|(n, v)| (n.to_string(), v.to_string())));
+        assert_eq!(QueryPairs::new("").count(), 0);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Which document root serves which Host, so one server can carry several sites
+/// Names are matched without case, port or trailing dot; unknown or missing hosts get the default root
+///
+/// ```
+/// use std::path::Path;
+/// use webserver::VirtualHosts;
+///
+/// let hosts = VirtualHosts::new("static").host("site-a.example", "sites/a").host("site-b.example", "sites/b");
+/// assert_eq!(hosts.doc_root(Some("Site-A.example:8080")), Path::new("sites/a"));
+/// assert_eq!(hosts.doc_root(Some("unknown.example")), Path::new("static"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualHosts {
+    default: PathBuf,
+    hosts: HashMap<String, PathBuf>,
+}
+
+impl VirtualHosts {
+    /// No hosts yet, everything is served from 'default_root'
+    pub fn new(default_root: impl Into<PathBuf>) -> VirtualHosts {
+        VirtualHosts { default: default_root.into(), hosts: HashMap::new() }
+    }
+
+    /// Serve requests for 'name' from 'doc_root', replacing any root set for it before
+    pub fn host(mut self, name: &str, doc_root: impl Into<PathBuf>) -> Self {
+        self.hosts.insert(normalize(name), doc_root.into());
+        self
+    }
+
+    /// The root for a request whose Host header was 'host'
+    pub fn doc_root(&self, host: Option<&str>) -> &Path {
+        host.and_then(|host| self.hosts.get(&normalize(host))).unwrap_or(&self.default)
+    }
+
+    /// Where requests for hosts we don't know go
+    pub fn default_root(&self) -> &Path {
+        &self.default
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+}
+
+// "Example.COM.:8080" and "example.com" are the same site, "[::1]:80" is "[::1]"
+fn normalize(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.rsplit_once(':') {
+        // a colon inside brackets is part of an IPv6 address, not a port
+        Some((name, port)) if !port.contains(']') && port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching() {
+        let hosts = VirtualHosts::new("default").host("a.example", "a").host("[::1]", "v6");
+        assert_eq!(hosts.doc_root(Some("a.example")), Path::new("a"));
+        assert_eq!(hosts.doc_root(Some("A.Example.:443")), Path::new("a"));
+        assert_eq!(hosts.doc_root(Some("[::1]:7878")), Path::new("v6"));
+        assert_eq!(hosts.doc_root(Some("b.example")), Path::new("default"));
+        assert_eq!(hosts.doc_root(None), Path::new("default"));
+        assert!(!hosts.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{logging::log, stats::WorkerCounters, LogLevel, Shared};
+
+/// Called with the worker id and how long its job has been running once it passes the job timeout
+pub type TimeoutHook = Arc<dyn Fn(usize, Duration) + Send + Sync>;
+
+// Set with ThreadPoolBuilder::job_timeout
+pub(crate) struct JobTimeout {
+    pub(crate) limit: Duration,
+    pub(crate) hook: TimeoutHook,
+    // retire the stuck worker once its job returns and spawn a fresh one in its place straight away
+    pub(crate) replace: bool,
+}
+
+// Every worker registers its counters here while it is alive so the watchdog can see how long its job has run
+#[derive(Default)]
+pub(crate) struct Watched {
+    workers: Mutex<Vec<(usize, Arc<WorkerCounters>)>>,
+}
+
+impl Watched {
+    pub(crate) fn add(&self, id: usize, counters: &Arc<WorkerCounters>) {
+        self.workers.lock().unwrap_or_else(|e| e.into_inner()).push((id, Arc::clone(counters)));
+    }
+
+    pub(crate) fn remove(&self, counters: &Arc<WorkerCounters>) {
+        self.workers.lock().unwrap_or_else(|e| e.into_inner()).retain(|(_, watched)| !Arc::ptr_eq(watched, counters));
+    }
+}
+
+// A thread that wakes a few times per timeout period and looks for jobs that have overstayed
+// Jobs can't be interrupted, all we can do is tell someone and stop giving the worker more work
+pub(crate) struct Watchdog {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Watchdog {
+    pub(crate) fn start(shared: Arc<Shared>) -> io::Result<Watchdog> {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let name = match &shared.thread.name_prefix {
+            Some(prefix) => format!("{}-watchdog", prefix),
+            None => "pool-watchdog".to_string(),
+        };
+        let thread = thread::Builder::new().name(name).spawn(move || Watchdog::run(&thread_stop, &shared))?;
+        Ok(Watchdog { stop, thread: Mutex::new(Some(thread)) })
+    }
+
+    pub(crate) fn stop(&self, shared: &Shared) {
+        let (stopped, wakeup) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_one();
+        let thread = self.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(thread) = thread
+            && let Err(e) = thread.join()
+        {
+            shared.report(LogLevel::Error, format_args!("Failed to join watchdog thread: {:?}", e));
+        }
+    }
+
+    fn run(stop: &(Mutex<bool>, Condvar), shared: &Shared) {
+        let Some(timeout) = &shared.job_timeout else { return };
+        // late by at most a quarter of the limit, without spinning on tiny limits
+        let tick = (timeout.limit / 4).clamp(Duration::from_millis(1), Duration::from_secs(1));
+        let (stopped, wakeup) = stop;
+        let mut guard = stopped.lock().unwrap_or_else(|e| e.into_inner());
+        while !*guard {
+            guard = wakeup.wait_timeout(guard, tick).unwrap_or_else(|e| e.into_inner()).0;
+            let overdue: Vec<_> = shared
+                .watched
+                .workers
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .filter_map(|(id, counters)| Some((*id, counters.check_timeout(timeout.limit)?, Arc::clone(counters))))
+                .collect();
+            for (id, ran_for, counters) in overdue {
+                if timeout.replace {
+                    counters.request_replacement();
+                }
+                (timeout.hook)(id, ran_for);
+            }
+        }
+    }
+}
+
+// Default timeout hook, log it so a stuck worker doesn't go unnoticed
+pub(crate) fn default_timeout_hook(id: usize, ran_for: Duration) {
+    log!(Warn, "Worker {} has been running the same job for {:?}", id, ran_for);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ExitReason, PoolObserver, ThreadPoolBuilder};
+    use std::{
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn test_job_timeout_hook_fires_once() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .job_timeout(Duration::from_millis(20))
+            .on_job_timeout(move |id, _| sender.lock().unwrap().send(id).unwrap())
+            .build()
+            .unwrap();
+        pool.execute(|| std::thread::sleep(Duration::from_millis(100))).join().unwrap();
+        pool.execute(|| {}).join().unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [0]);
+    }
+
+    struct Exits(Mutex<mpsc::Sender<(usize, ExitReason)>>);
+
+    impl PoolObserver for Exits {
+        fn on_worker_exit(&self, worker: usize, reason: ExitReason) {
+            self.0.lock().unwrap().send((worker, reason)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stuck_worker_replaced() {
+        let (exits, exited) = mpsc::channel();
+        let (timeouts, timed_out) = mpsc::channel();
+        let timeouts = Mutex::new(timeouts);
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .job_timeout(Duration::from_millis(10))
+            .on_job_timeout(move |id, _| timeouts.lock().unwrap().send(id).unwrap())
+            .replace_timed_out_workers(true)
+            .observer(Exits(Mutex::new(exits)))
+            .build()
+            .unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let stuck = pool.execute(move || blocked.recv().unwrap());
+        assert_eq!(timed_out.recv().unwrap(), 0);
+
+        // the replacement is spawned on the next submit, so this runs even though worker 0 is still stuck
+        assert_eq!(pool.execute(|| 1).join(), Ok(1));
+        assert_eq!(pool.size(), 2);
+
+        release.send(()).unwrap();
+        stuck.join().unwrap();
+        assert_eq!(exited.recv().unwrap(), (0, ExitReason::TimedOut));
+        drop(pool);
+        assert_eq!(exited.recv().unwrap(), (1, ExitReason::Terminated));
+    }
+}